@@ -9,6 +9,12 @@
 //! - Treasury management for agents
 
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::ed25519_program;
+use anchor_lang::solana_program::native_token::LAMPORTS_PER_SOL;
+use anchor_lang::solana_program::sysvar::instructions::{
+    load_current_index_checked, load_instruction_at_checked,
+};
+use anchor_lang::Discriminator;
 // anchor-spl removed for rustc 1.79 compatibility
 // Token functionality can be added when platform-tools updates
 
@@ -17,15 +23,63 @@ declare_id!("5LqS68L9kfrB5h2D3NjJ9d8jEJz7egkyXUWEySGNZUeg");
 /// Maximum invoices per batch settlement
 pub const MAX_BATCH_SIZE: usize = 50;
 
-/// Maximum memo length
+/// Window after settlement in which a batch can still be reversed (1 hour)
+pub const BATCH_REVERSAL_WINDOW_SECONDS: i64 = 3600;
+
+/// Maximum invoices per bulk status query (bounded by return data size)
+pub const MAX_STATUS_QUERY_SIZE: usize = 25;
+
+/// Maximum recipients an invoice payment can be split across
+pub const MAX_SPLIT_RECIPIENTS: usize = 8;
+
+/// Maximum agents the protocol ban list can hold
+pub const MAX_BANNED_AGENTS: usize = 64;
+
+/// Maximum settlers a treasury can authorize
+pub const MAX_AUTHORIZED_SETTLERS: usize = 5;
+
+/// Maximum snapshots a treasury's ring buffer can retain
+pub const MAX_TREASURY_SNAPSHOTS: usize = 16;
+
+/// Default maximum memo length (used when no protocol config is supplied)
 pub const MAX_MEMO_LENGTH: usize = 256;
 
-/// Dispute window in seconds (24 hours)
+/// Hard ceiling on the configurable maximum memo length
+pub const MAX_MEMO_LENGTH_CEILING: u16 = 1024;
+
+/// Maximum length of the note a payer attaches to an invoice payment
+pub const MAX_PAYER_MEMO_LENGTH: usize = 128;
+
+/// Default dispute window in seconds (24 hours)
 pub const DISPUTE_WINDOW_SECONDS: i64 = 86400;
 
+/// Minimum per-request dispute window in seconds (1 hour)
+pub const MIN_DISPUTE_WINDOW_SECONDS: i64 = 3600;
+
+/// Maximum per-request dispute window in seconds (30 days)
+pub const MAX_DISPUTE_WINDOW_SECONDS: i64 = 30 * 86400;
+
+/// Furthest into the future a user-supplied timestamp may be (10 years)
+pub const MAX_FUTURE_SECONDS: i64 = 10 * 365 * 86400;
+
+/// Maximum requester review period before payment release (30 days)
+pub const MAX_REVIEW_PERIOD_SECONDS: i64 = 30 * 86400;
+
+/// Challenge period for unilateral channel closes in seconds (24 hours)
+pub const CHANNEL_CHALLENGE_PERIOD_SECONDS: i64 = 86400;
+
+/// Share of an SLA bond refunded to the requester on late completion (50%)
+pub const SLA_SLASH_BPS: u16 = 5_000;
+
 /// Maximum dispute reason length
 pub const MAX_DISPUTE_REASON_LENGTH: usize = 512;
 
+/// Longest a dispute may stay under review, extensions included (30 days)
+pub const MAX_DISPUTE_REVIEW_SECONDS: i64 = 30 * 86400;
+
+/// Maximum number of requests disputed in one `initiate_disputes_batch` call
+pub const MAX_DISPUTE_BATCH_SIZE: usize = 5;
+
 #[program]
 pub mod agentfund {
     use super::*;
@@ -36,17 +90,135 @@ pub mod agentfund {
         bump: u8,
     ) -> Result<()> {
         let treasury = &mut ctx.accounts.treasury;
-        treasury.owner = ctx.accounts.owner.key();
-        treasury.bump = bump;
-        treasury.total_received = 0;
-        treasury.total_settled = 0;
-        treasury.pending_invoices = 0;
-        treasury.created_at = Clock::get()?.unix_timestamp;
+        init_treasury(
+            treasury,
+            ctx.accounts.owner.key(),
+            bump,
+            Clock::get()?.unix_timestamp,
+        );
         
         msg!("Treasury initialized for agent: {}", treasury.owner);
         Ok(())
     }
 
+    /// Restrict which signers may settle batches into this treasury
+    /// An empty list leaves settlement open to anyone
+    pub fn set_authorized_settlers(
+        ctx: Context<SetAuthorizedSettlers>,
+        settlers: Vec<Pubkey>,
+    ) -> Result<()> {
+        require!(
+            settlers.len() <= MAX_AUTHORIZED_SETTLERS,
+            AgentFundError::TooManySettlers
+        );
+
+        let treasury = &mut ctx.accounts.treasury;
+        treasury.authorized_settlers = settlers;
+
+        emit!(AuthorizedSettlersUpdated {
+            treasury: treasury.key(),
+            settlers: treasury.authorized_settlers.clone(),
+        });
+
+        Ok(())
+    }
+
+    /// Close an agent's treasury and reclaim its rent
+    /// Only allowed once no invoices are outstanding and every lamport above
+    /// the rent reserve (unwithdrawn earnings) has been withdrawn
+    pub fn close_treasury(ctx: Context<CloseTreasury>) -> Result<()> {
+        let treasury = &ctx.accounts.treasury;
+
+        require!(
+            treasury.pending_invoices == 0,
+            AgentFundError::TreasuryNotEmpty
+        );
+        let treasury_info = treasury.to_account_info();
+        let reserve = Rent::get()?.minimum_balance(treasury_info.data_len());
+        require!(
+            treasury_info.lamports() <= reserve,
+            AgentFundError::TreasuryNotEmpty
+        );
+
+        msg!("Treasury closed for agent: {}", treasury.owner);
+        emit!(TreasuryClosed {
+            owner: treasury.owner,
+            total_received: treasury.total_received,
+            total_settled: treasury.total_settled,
+        });
+
+        Ok(())
+    }
+
+    /// Create the ring buffer holding a treasury's last `capacity` snapshots
+    pub fn initialize_treasury_snapshots(
+        ctx: Context<InitializeTreasurySnapshots>,
+        capacity: u8,
+    ) -> Result<()> {
+        require!(
+            capacity > 0 && usize::from(capacity) <= MAX_TREASURY_SNAPSHOTS,
+            AgentFundError::InvalidSnapshotCapacity
+        );
+
+        let snapshots = &mut ctx.accounts.snapshots;
+        snapshots.treasury = ctx.accounts.treasury.key();
+        snapshots.capacity = capacity;
+        snapshots.next_index = 0;
+        snapshots.total_taken = 0;
+        snapshots.entries = Vec::new();
+        snapshots.bump = *ctx.bumps.get("snapshots").unwrap();
+
+        Ok(())
+    }
+
+    /// Record the treasury's current totals, overwriting the oldest snapshot
+    /// once the ring buffer is full
+    pub fn snapshot_treasury(ctx: Context<SnapshotTreasury>) -> Result<()> {
+        let treasury = &ctx.accounts.treasury;
+        let snapshot = TreasurySnapshot {
+            total_received: treasury.total_received,
+            total_settled: treasury.total_settled,
+            pending_invoices: treasury.pending_invoices,
+            taken_at: Clock::get()?.unix_timestamp,
+        };
+
+        let snapshots = &mut ctx.accounts.snapshots;
+        snapshots.record(snapshot.clone())?;
+
+        emit!(TreasurySnapshotTaken {
+            treasury: treasury.key(),
+            total_received: snapshot.total_received,
+            total_settled: snapshot.total_settled,
+            pending_invoices: snapshot.pending_invoices,
+            taken_at: snapshot.taken_at,
+        });
+
+        Ok(())
+    }
+
+    /// Move completed-request earnings held in the treasury to its owner
+    /// Only lamports credited as earnings can leave this way, so other
+    /// treasury inflows and its rent reserve are never drawn on
+    pub fn withdraw_earnings(ctx: Context<WithdrawEarnings>, amount: u64) -> Result<()> {
+        require!(amount > 0, AgentFundError::InvalidAmount);
+        let profile = &mut ctx.accounts.agent_profile;
+        debit_earnings(
+            profile,
+            &ctx.accounts.treasury.to_account_info(),
+            &ctx.accounts.owner.to_account_info(),
+            amount,
+        )?;
+
+        emit_ledger_entry(&ctx.accounts.treasury, LedgerEntryKind::Withdrawn, amount)?;
+        emit!(EarningsWithdrawn {
+            agent: profile.owner,
+            amount,
+            remaining: profile.available_earnings,
+        });
+
+        Ok(())
+    }
+
     /// Create a payment invoice
     pub fn create_invoice(
         ctx: Context<CreateInvoice>,
@@ -54,41 +226,216 @@ pub mod agentfund {
         amount: u64,
         memo: String,
         expires_at: i64,
+        allowed_payer: Option<Pubkey>,
+        depends_on: Option<[u8; 32]>,
     ) -> Result<()> {
-        require!(memo.len() <= MAX_MEMO_LENGTH, AgentFundError::MemoTooLong);
-        require!(amount > 0, AgentFundError::InvalidAmount);
-        require!(expires_at > Clock::get()?.unix_timestamp, AgentFundError::InvalidExpiry);
+        let invoice = Invoice {
+            id: invoice_id,
+            recipient: ctx.accounts.recipient.key(),
+            amount,
+            memo,
+            status: InvoiceStatus::Pending,
+            created_at: Clock::get()?.unix_timestamp,
+            expires_at,
+            paid_at: None,
+            payer: None,
+            allowed_payer,
+            held_amount: 0,
+            depends_on,
+            dependents: 0,
+            payer_memo_hash: None,
+            split_recipients: Vec::new(),
+        };
+        open_invoice(ctx, invoice)
+    }
+
+    /// Create an invoice whose payment is shared among collaborators
+    /// Each recipient receives its basis points of the amount net of fees;
+    /// the shares must add up to the whole
+    pub fn create_split_invoice(
+        ctx: Context<CreateInvoice>,
+        invoice_id: [u8; 32],
+        amount: u64,
+        memo: String,
+        expires_at: i64,
+        split_recipients: Vec<SplitRecipient>,
+    ) -> Result<()> {
+        require!(
+            !split_recipients.is_empty() && split_recipients.len() <= MAX_SPLIT_RECIPIENTS,
+            AgentFundError::InvalidSplitRecipients
+        );
+        let total_bps = split_recipients
+            .iter()
+            .try_fold(0u16, |total, split| total.checked_add(split.bps))
+            .ok_or(AgentFundError::SplitBpsMismatch)?;
+        require!(total_bps == MAX_BPS, AgentFundError::SplitBpsMismatch);
 
+        let invoice = Invoice {
+            id: invoice_id,
+            recipient: ctx.accounts.recipient.key(),
+            amount,
+            memo,
+            status: InvoiceStatus::Pending,
+            created_at: Clock::get()?.unix_timestamp,
+            expires_at,
+            paid_at: None,
+            payer: None,
+            allowed_payer: None,
+            held_amount: 0,
+            depends_on: None,
+            dependents: 0,
+            payer_memo_hash: None,
+            split_recipients,
+        };
+        open_invoice(ctx, invoice)
+    }
+
+    /// Push back the expiry of an unpaid invoice, keeping its id and history
+    pub fn extend_invoice(ctx: Context<ExtendInvoice>, new_expires_at: i64) -> Result<()> {
         let invoice = &mut ctx.accounts.invoice;
-        invoice.id = invoice_id;
-        invoice.recipient = ctx.accounts.recipient.key();
-        invoice.amount = amount;
-        invoice.memo = memo;
-        invoice.status = InvoiceStatus::Pending;
-        invoice.created_at = Clock::get()?.unix_timestamp;
-        invoice.expires_at = expires_at;
-        invoice.paid_at = None;
-        invoice.payer = None;
 
-        // Update treasury pending count
+        require!(
+            invoice.status == InvoiceStatus::Pending,
+            AgentFundError::InvoiceNotPending
+        );
+        require!(new_expires_at > invoice.expires_at, AgentFundError::InvalidExpiry);
+        require!(
+            new_expires_at <= Clock::get()?.unix_timestamp.saturating_add(MAX_FUTURE_SECONDS),
+            AgentFundError::ExpiryTooFar
+        );
+
+        let old_expires_at = invoice.expires_at;
+        invoice.expires_at = new_expires_at;
+
+        emit!(InvoiceExtended {
+            invoice_id: invoice.id,
+            old_expires_at,
+            new_expires_at,
+        });
+
+        Ok(())
+    }
+
+    /// Mark a lapsed invoice as expired; permissionless crank so stale
+    /// invoices stop counting towards the treasury's pending total
+    pub fn expire_invoice(ctx: Context<ExpireInvoice>) -> Result<()> {
+        let invoice = &mut ctx.accounts.invoice;
+
+        require!(
+            invoice.status == InvoiceStatus::Pending,
+            AgentFundError::InvoiceNotPending
+        );
+        let now = Clock::get()?.unix_timestamp;
+        require!(now >= invoice.expires_at, AgentFundError::InvoiceNotExpired);
+
+        invoice.status = InvoiceStatus::Expired;
+
         let treasury = &mut ctx.accounts.treasury;
-        treasury.pending_invoices += 1;
+        treasury.pending_invoices = treasury.pending_invoices.saturating_sub(1);
 
-        msg!("Invoice created: {} lamports", amount);
-        emit!(InvoiceCreated {
-            invoice_id,
+        emit!(InvoiceMarkedExpired {
+            invoice_id: invoice.id,
             recipient: invoice.recipient,
-            amount,
-            expires_at,
+            expired_at: now,
+        });
+
+        Ok(())
+    }
+
+    /// Expire every past-due pending invoice passed in `remaining_accounts`
+    /// (writable), all issued to the treasury's owner. Invoices that are
+    /// already settled or not yet due are skipped. Returns how many expired
+    pub fn expire_invoices_batch<'info>(
+        ctx: Context<'_, '_, '_, 'info, ExpireInvoicesBatch<'info>>,
+    ) -> Result<u32> {
+        require!(!ctx.remaining_accounts.is_empty(), AgentFundError::EmptyBatch);
+        require!(
+            ctx.remaining_accounts.len() <= MAX_BATCH_SIZE,
+            AgentFundError::BatchTooLarge
+        );
+
+        let treasury = &mut ctx.accounts.treasury;
+        let now = Clock::get()?.unix_timestamp;
+
+        let mut expired: u32 = 0;
+        for account in ctx.remaining_accounts.iter() {
+            require_keys_eq!(*account.owner, crate::ID, AgentFundError::InvalidInvoiceAccount);
+            require!(account.is_writable, AgentFundError::InvalidInvoiceAccount);
+            let mut invoice = Invoice::try_deserialize(&mut &account.try_borrow_data()?[..])?;
+            require_keys_eq!(invoice.recipient, treasury.owner, AgentFundError::InvalidInvoiceAccount);
+
+            if invoice.status != InvoiceStatus::Pending || now < invoice.expires_at {
+                continue;
+            }
+
+            // Written back immediately so a repeated invoice is skipped on
+            // its second appearance
+            invoice.status = InvoiceStatus::Expired;
+            invoice.try_serialize(&mut &mut account.try_borrow_mut_data()?[..])?;
+            expired += 1;
+
+            emit!(InvoiceMarkedExpired {
+                invoice_id: invoice.id,
+                recipient: invoice.recipient,
+                expired_at: now,
+            });
+        }
+
+        treasury.pending_invoices = treasury.pending_invoices.saturating_sub(expired as u64);
+
+        msg!("Expired {} of {} invoices", expired, ctx.remaining_accounts.len());
+        Ok(expired)
+    }
+
+    /// Pay an invoice (direct payment), optionally attaching a payer note
+    /// such as an order reference for reconciliation
+    /// A split invoice takes its recipients, in order, as writable
+    /// remaining accounts
+    pub fn pay_invoice<'info>(
+        ctx: Context<'_, '_, '_, 'info, PayInvoice<'info>>,
+        payer_memo: Option<String>,
+    ) -> Result<()> {
+        ctx.accounts.pay(payer_memo, ctx.remaining_accounts)
+    }
+
+    /// Pay an invoice and close it in one step, returning its rent to the
+    /// recipient; suited to one-shot micro-invoices
+    pub fn pay_and_close_invoice<'info>(ctx: Context<'_, '_, '_, 'info, PayInvoice<'info>>) -> Result<()> {
+        // Dependents prove their milestone was met by presenting this
+        // invoice as paid, so it has to outlive them
+        require!(
+            ctx.accounts.invoice.dependents == 0,
+            AgentFundError::InvoiceHasDependents
+        );
+        ctx.accounts.pay(None, ctx.remaining_accounts)?;
+
+        // Its history lives on in the InvoicePaid/InvoiceClosed events
+        let invoice = &ctx.accounts.invoice;
+        let invoice_id = invoice.id;
+        let rent_returned = invoice.to_account_info().lamports();
+        ctx.accounts.invoice.close(ctx.accounts.recipient.to_account_info())?;
+
+        emit!(InvoiceClosed {
+            invoice_id,
+            recipient: ctx.accounts.recipient.key(),
+            rent_returned,
         });
 
         Ok(())
     }
 
-    /// Pay an invoice (direct payment)
-    pub fn pay_invoice(ctx: Context<PayInvoice>) -> Result<()> {
+    /// Pay an invoice by shifting balance within an open payment channel
+    /// No lamports leave the channel escrow; only the off-chain balances move,
+    /// to a new state the recipient countersigns
+    pub fn pay_invoice_from_channel(
+        ctx: Context<PayInvoiceFromChannel>,
+        _channel_id: [u8; 32],
+        nonce: u64,
+    ) -> Result<()> {
         let invoice = &mut ctx.accounts.invoice;
-        
+        let channel = &mut ctx.accounts.channel;
+        let payer = ctx.accounts.payer.key();
+
         require!(
             invoice.status == InvoiceStatus::Pending,
             AgentFundError::InvoiceNotPending
@@ -97,955 +444,8082 @@ pub mod agentfund {
             Clock::get()?.unix_timestamp < invoice.expires_at,
             AgentFundError::InvoiceExpired
         );
-
-        // Transfer SOL from payer to recipient
-        let transfer_ix = anchor_lang::solana_program::system_instruction::transfer(
-            &ctx.accounts.payer.key(),
-            &ctx.accounts.recipient.key(),
-            invoice.amount,
+        invoice.require_allowed_payer(&payer)?;
+        invoice.require_dependency_paid(ctx.accounts.dependency.as_deref())?;
+        invoice.require_unsplit()?;
+        require!(
+            channel.status == ChannelStatus::Open,
+            AgentFundError::ChannelNotOpen
         );
-        anchor_lang::solana_program::program::invoke(
-            &transfer_ix,
-            &[
-                ctx.accounts.payer.to_account_info(),
-                ctx.accounts.recipient.to_account_info(),
-                ctx.accounts.system_program.to_account_info(),
-            ],
+
+        // Move the invoice amount from the payer's side to the recipient's
+        // side. The recipient countersigns the resulting state at a nonce
+        // above any it signed off-chain, so no older state can undo the shift
+        channel.shift_balance(&payer, &invoice.recipient, invoice.amount, nonce)?;
+        require_channel_state_signature(
+            channel,
+            &ctx.accounts.instructions,
+            &invoice.recipient,
+            channel.balance_a,
+            channel.balance_b,
+            channel.nonce,
         )?;
 
         // Update invoice status
         invoice.status = InvoiceStatus::Paid;
         invoice.paid_at = Some(Clock::get()?.unix_timestamp);
-        invoice.payer = Some(ctx.accounts.payer.key());
+        invoice.payer = Some(payer);
 
         // Update treasury
         let treasury = &mut ctx.accounts.treasury;
         treasury.total_received += invoice.amount;
         treasury.pending_invoices = treasury.pending_invoices.saturating_sub(1);
+        emit_ledger_entry(treasury, LedgerEntryKind::Received, invoice.amount)?;
 
-        msg!("Invoice paid: {} lamports", invoice.amount);
-        emit!(InvoicePaid {
+        msg!("Invoice paid from channel: {} lamports", invoice.amount);
+        let seq = next_event_seq(&mut ctx.accounts.config)?;
+        emit_invoice_paid(InvoicePaid {
             invoice_id: invoice.id,
-            payer: ctx.accounts.payer.key(),
+            payer,
+            treasury: treasury.key(),
             amount: invoice.amount,
+            fee: 0,
+            payer_memo: None,
+            seq,
+        });
+        emit!(ChannelUpdated {
+            channel_id: channel.id,
+            balance_a: channel.balance_a,
+            balance_b: channel.balance_b,
+            nonce: channel.nonce,
+            state_hash: channel.state_hash,
         });
 
         Ok(())
     }
 
-    /// Settle a batch of micropayments
-    pub fn settle_batch(
-        ctx: Context<SettleBatch>,
-        batch_id: [u8; 32],
-        invoice_ids: Vec<[u8; 32]>,
-        total_amount: u64,
-    ) -> Result<()> {
+    /// Hold `amount` from the payer in the invoice's escrow without paying it
+    /// The recipient later captures up to the hold once the work is done
+    pub fn preauthorize_invoice(ctx: Context<PreauthorizeInvoice>, amount: u64) -> Result<()> {
+        let invoice = &mut ctx.accounts.invoice;
+
         require!(
-            invoice_ids.len() <= MAX_BATCH_SIZE,
-            AgentFundError::BatchTooLarge
+            invoice.status == InvoiceStatus::Pending,
+            AgentFundError::InvoiceNotPending
         );
         require!(
-            invoice_ids.len() > 0,
-            AgentFundError::EmptyBatch
+            Clock::get()?.unix_timestamp < invoice.expires_at,
+            AgentFundError::InvoiceExpired
         );
+        invoice.require_allowed_payer(&ctx.accounts.payer.key())?;
+        invoice.require_dependency_paid(ctx.accounts.dependency.as_deref())?;
+        invoice.require_unsplit()?;
+        require!(amount > 0, AgentFundError::InvalidAmount);
 
-        let batch = &mut ctx.accounts.batch;
-        batch.id = batch_id;
-        batch.recipient = ctx.accounts.recipient.key();
-        batch.invoice_count = invoice_ids.len() as u32;
-        batch.total_amount = total_amount;
-        batch.settled_at = Clock::get()?.unix_timestamp;
-        batch.settler = ctx.accounts.settler.key();
-
-        // Transfer total amount
         let transfer_ix = anchor_lang::solana_program::system_instruction::transfer(
-            &ctx.accounts.settler.key(),
-            &ctx.accounts.recipient.key(),
-            total_amount,
+            &ctx.accounts.payer.key(),
+            &ctx.accounts.escrow.key(),
+            amount,
         );
         anchor_lang::solana_program::program::invoke(
             &transfer_ix,
             &[
-                ctx.accounts.settler.to_account_info(),
-                ctx.accounts.recipient.to_account_info(),
+                ctx.accounts.payer.to_account_info(),
+                ctx.accounts.escrow.to_account_info(),
                 ctx.accounts.system_program.to_account_info(),
             ],
         )?;
 
-        // Update treasury
-        let treasury = &mut ctx.accounts.treasury;
-        treasury.total_settled += total_amount;
-        treasury.pending_invoices = treasury.pending_invoices.saturating_sub(invoice_ids.len() as u64);
+        invoice.status = InvoiceStatus::Authorized;
+        invoice.payer = Some(ctx.accounts.payer.key());
+        invoice.held_amount = amount;
 
-        msg!("Batch settled: {} invoices, {} lamports", invoice_ids.len(), total_amount);
-        emit!(BatchSettled {
-            batch_id,
-            invoice_count: invoice_ids.len() as u32,
-            total_amount,
-            recipient: ctx.accounts.recipient.key(),
+        msg!("Invoice preauthorized: {} lamports held", amount);
+        emit!(InvoicePreauthorized {
+            invoice_id: invoice.id,
+            payer: ctx.accounts.payer.key(),
+            amount,
         });
 
         Ok(())
     }
 
-    /// Open a payment channel between two agents
-    pub fn open_channel(
-        ctx: Context<OpenChannel>,
-        channel_id: [u8; 32],
-        deposit: u64,
+    /// Capture up to the held amount as payment, refunding the rest to the payer
+    pub fn capture_preauthorized(
+        ctx: Context<CapturePreauthorized>,
+        capture_amount: u64,
     ) -> Result<()> {
-        require!(deposit > 0, AgentFundError::InvalidAmount);
-
-        let channel = &mut ctx.accounts.channel;
-        channel.id = channel_id;
-        channel.party_a = ctx.accounts.party_a.key();
-        channel.party_b = ctx.accounts.party_b.key();
-        channel.deposit_a = deposit;
-        channel.deposit_b = 0;
-        channel.balance_a = deposit;
-        channel.balance_b = 0;
-        channel.nonce = 0;
-        channel.status = ChannelStatus::Open;
-        channel.opened_at = Clock::get()?.unix_timestamp;
-        channel.closed_at = None;
+        let invoice = &mut ctx.accounts.invoice;
 
-        // Transfer deposit to channel escrow
-        let transfer_ix = anchor_lang::solana_program::system_instruction::transfer(
-            &ctx.accounts.party_a.key(),
-            &ctx.accounts.channel_escrow.key(),
-            deposit,
+        require!(
+            invoice.status == InvoiceStatus::Authorized,
+            AgentFundError::InvoiceNotAuthorized
         );
-        anchor_lang::solana_program::program::invoke(
-            &transfer_ix,
-            &[
-                ctx.accounts.party_a.to_account_info(),
-                ctx.accounts.channel_escrow.to_account_info(),
-                ctx.accounts.system_program.to_account_info(),
-            ],
+        require!(
+            Clock::get()?.unix_timestamp < invoice.expires_at,
+            AgentFundError::InvoiceExpired
+        );
+        require!(capture_amount > 0, AgentFundError::InvalidAmount);
+        if capture_amount > invoice.held_amount {
+            return Err(amount_error(
+                AgentFundError::CaptureExceedsHold,
+                invoice.held_amount,
+                capture_amount,
+            ));
+        }
+
+        // The protocol fee applies to captures as it does to direct payments
+        let config = &ctx.accounts.config;
+        let fee_bps = tiered_fee_bps(
+            config,
+            config.invoice_fee_bps,
+            ctx.accounts.recipient_profile.as_deref(),
         )?;
+        let fee = bps_of(capture_amount, fee_bps)?;
+        let net = capture_amount
+            .checked_sub(fee)
+            .filter(|net| *net > 0)
+            .ok_or(AgentFundError::FeeExceedsAmount)?;
+        let refunded = invoice.held_amount - capture_amount;
 
-        msg!("Channel opened with {} lamports deposit", deposit);
-        emit!(ChannelOpened {
-            channel_id,
-            party_a: channel.party_a,
-            party_b: channel.party_b,
-            deposit,
+        let escrow_bump = *ctx.bumps.get("escrow").unwrap();
+        let escrow_seeds: &[&[u8]] = &[b"invoice_escrow", invoice.id.as_ref(), &[escrow_bump]];
+        for (to, amount) in [
+            (ctx.accounts.recipient.to_account_info(), net),
+            (ctx.accounts.fee_collector.to_account_info(), fee),
+            (ctx.accounts.payer.to_account_info(), refunded),
+        ] {
+            if amount > 0 {
+                transfer_from_escrow(
+                    &ctx.accounts.escrow,
+                    &to,
+                    &ctx.accounts.system_program,
+                    amount,
+                    escrow_seeds,
+                )?;
+            }
+        }
+
+        invoice.status = InvoiceStatus::Paid;
+        invoice.paid_at = Some(Clock::get()?.unix_timestamp);
+        invoice.held_amount = 0;
+
+        let treasury = &mut ctx.accounts.treasury;
+        treasury.total_received = treasury
+            .total_received
+            .checked_add(net)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        treasury.pending_invoices = treasury.pending_invoices.saturating_sub(1);
+        emit_ledger_entry(treasury, LedgerEntryKind::Received, net)?;
+
+        msg!("Preauthorization captured: {} lamports ({} refunded)", capture_amount, refunded);
+        emit!(PreauthorizationCaptured {
+            invoice_id: invoice.id,
+            payer: ctx.accounts.payer.key(),
+            captured: capture_amount,
+            fee,
+            refunded,
         });
 
         Ok(())
     }
 
-    /// Close a payment channel and settle final balances
-    pub fn close_channel(
-        ctx: Context<CloseChannel>,
-        final_balance_a: u64,
-        final_balance_b: u64,
-        nonce: u64,
-    ) -> Result<()> {
-        let channel = &mut ctx.accounts.channel;
-        
-        require!(
-            channel.status == ChannelStatus::Open,
-            AgentFundError::ChannelNotOpen
-        );
+    /// Release a preauthorization, returning the full hold to the payer
+    /// The recipient may void at any time; the payer only once the invoice
+    /// has expired, so a hold can never be stranded
+    pub fn void_preauthorization(ctx: Context<VoidPreauthorization>) -> Result<()> {
+        let invoice = &mut ctx.accounts.invoice;
+
         require!(
-            nonce > channel.nonce,
-            AgentFundError::InvalidNonce
+            invoice.status == InvoiceStatus::Authorized,
+            AgentFundError::InvoiceNotAuthorized
         );
+        let authority = ctx.accounts.authority.key();
         require!(
-            final_balance_a + final_balance_b == channel.deposit_a + channel.deposit_b,
-            AgentFundError::BalanceMismatch
+            authority == invoice.recipient
+                || (authority == ctx.accounts.payer.key()
+                    && Clock::get()?.unix_timestamp >= invoice.expires_at),
+            AgentFundError::Unauthorized
         );
 
-        // Update channel state
-        channel.balance_a = final_balance_a;
-        channel.balance_b = final_balance_b;
-        channel.nonce = nonce;
-        channel.status = ChannelStatus::Closed;
-        channel.closed_at = Some(Clock::get()?.unix_timestamp);
+        let refunded = invoice.held_amount;
+        let escrow_bump = *ctx.bumps.get("escrow").unwrap();
+        transfer_from_escrow(
+            &ctx.accounts.escrow,
+            &ctx.accounts.payer,
+            &ctx.accounts.system_program,
+            refunded,
+            &[b"invoice_escrow", invoice.id.as_ref(), &[escrow_bump]],
+        )?;
 
-        // Transfer final balances from escrow
-        // (In production: proper escrow PDA with seeds)
-        
-        msg!("Channel closed. Final: A={}, B={}", final_balance_a, final_balance_b);
-        emit!(ChannelClosed {
-            channel_id: channel.id,
-            final_balance_a,
-            final_balance_b,
+        // The invoice is payable again
+        invoice.status = InvoiceStatus::Pending;
+        invoice.payer = None;
+        invoice.held_amount = 0;
+
+        msg!("Preauthorization voided: {} lamports returned", refunded);
+        emit!(PreauthorizationVoided {
+            invoice_id: invoice.id,
+            payer: ctx.accounts.payer.key(),
+            refunded,
         });
 
         Ok(())
     }
 
-    // === Agent Registry Instructions ===
+    /// Return a canonical Solana Pay transfer URI for a pending invoice:
+    /// `solana:<recipient>?amount=<SOL>&reference=<invoice id>&memo=<memo>`
+    /// The invoice id is rendered as a base58 key and the memo is
+    /// percent-encoded (and omitted when empty). Intended for simulated calls
+    pub fn get_invoice_uri(ctx: Context<GetInvoiceUri>) -> Result<String> {
+        let invoice = &ctx.accounts.invoice;
+        require!(
+            invoice.status == InvoiceStatus::Pending,
+            AgentFundError::InvoiceNotPending
+        );
 
-    /// Register an agent in the marketplace
-    pub fn register_agent(
-        ctx: Context<RegisterAgent>,
-        name: String,
-        description: String,
-        capabilities: Vec<String>,
-        base_price: u64,
-        bump: u8,
-    ) -> Result<()> {
-        require!(name.len() <= MAX_NAME_LENGTH, AgentFundError::NameTooLong);
-        require!(description.len() <= MAX_DESCRIPTION_LENGTH, AgentFundError::DescriptionTooLong);
-        require!(capabilities.len() <= MAX_CAPABILITIES, AgentFundError::TooManyCapabilities);
-        
-        for cap in &capabilities {
-            require!(cap.len() <= MAX_CAPABILITY_LENGTH, AgentFundError::CapabilityTooLong);
+        let mut uri = format!(
+            "solana:{}?amount={}&reference={}",
+            invoice.recipient,
+            format_sol(invoice.amount),
+            Pubkey::new_from_array(invoice.id)
+        );
+        if !invoice.memo.is_empty() {
+            uri.push_str("&memo=");
+            uri.push_str(&percent_encode(&invoice.memo));
         }
+        require!(
+            uri.len() <= anchor_lang::solana_program::program::MAX_RETURN_DATA,
+            AgentFundError::InvoiceUriTooLong
+        );
 
-        let profile = &mut ctx.accounts.agent_profile;
-        profile.owner = ctx.accounts.owner.key();
-        profile.name = name.clone();
-        profile.description = description;
-        profile.capabilities = capabilities.clone();
-        profile.base_price = base_price;
-        profile.treasury = ctx.accounts.treasury.key();
-        profile.is_active = true;
-        profile.total_requests = 0;
-        profile.total_earnings = 0;
-        profile.registered_at = Clock::get()?.unix_timestamp;
-        profile.last_active_at = Clock::get()?.unix_timestamp;
-        profile.bump = bump;
+        Ok(uri)
+    }
 
-        msg!("Agent registered: {}", name);
-        emit!(AgentRegistered {
-            agent: profile.owner,
-            name,
-            capabilities,
-            base_price,
-        });
+    /// Return the status of every invoice passed in `remaining_accounts`
+    /// Intended for simulated calls, so dashboards can poll many invoices at once
+    pub fn get_invoice_statuses(ctx: Context<GetInvoiceStatuses>) -> Result<Vec<InvoiceStatusEntry>> {
+        require!(
+            ctx.remaining_accounts.len() <= MAX_STATUS_QUERY_SIZE,
+            AgentFundError::TooManyAccounts
+        );
 
-        Ok(())
+        let mut entries = Vec::with_capacity(ctx.remaining_accounts.len());
+        for account in ctx.remaining_accounts {
+            require_keys_eq!(*account.owner, crate::ID, AgentFundError::InvalidInvoiceAccount);
+            let invoice = Invoice::try_deserialize(&mut &account.try_borrow_data()?[..])?;
+            entries.push(InvoiceStatusEntry {
+                invoice_id: invoice.id,
+                status: invoice.status as u8,
+            });
+        }
+
+        Ok(entries)
     }
 
-    /// Update agent profile
-    pub fn update_agent_profile(
-        ctx: Context<UpdateAgentProfile>,
-        name: Option<String>,
-        description: Option<String>,
-        capabilities: Option<Vec<String>>,
-        base_price: Option<u64>,
-        is_active: Option<bool>,
+    /// Settle a batch of micropayments
+    /// Invoices are passed as writable remaining accounts in the same order
+    /// as `invoice_ids`
+    pub fn settle_batch<'info>(
+        ctx: Context<'_, '_, '_, 'info, SettleBatch<'info>>,
+        batch_id: [u8; 32],
+        invoice_ids: Vec<[u8; 32]>,
+        total_amount: u64,
     ) -> Result<()> {
-        let profile = &mut ctx.accounts.agent_profile;
+        require!(
+            invoice_ids.len() <= MAX_BATCH_SIZE,
+            AgentFundError::BatchTooLarge
+        );
+        require!(
+            invoice_ids.len() > 0,
+            AgentFundError::EmptyBatch
+        );
+        // The batch account is `init_if_needed`; a recorded settlement time
+        // means this id was already used
+        require!(ctx.accounts.batch.settled_at == 0, AgentFundError::BatchIdAlreadyExists);
+        require!(
+            ctx.remaining_accounts.len() == invoice_ids.len(),
+            AgentFundError::InvalidInvoiceAccount
+        );
+        let settlers = &ctx.accounts.treasury.authorized_settlers;
+        require!(
+            settlers.is_empty() || settlers.contains(&ctx.accounts.settler.key()),
+            AgentFundError::UnauthorizedSettler
+        );
 
-        if let Some(n) = name {
-            require!(n.len() <= MAX_NAME_LENGTH, AgentFundError::NameTooLong);
-            profile.name = n;
-        }
-        if let Some(d) = description {
-            require!(d.len() <= MAX_DESCRIPTION_LENGTH, AgentFundError::DescriptionTooLong);
-            profile.description = d;
-        }
-        if let Some(caps) = capabilities {
-            require!(caps.len() <= MAX_CAPABILITIES, AgentFundError::TooManyCapabilities);
-            for cap in &caps {
-                require!(cap.len() <= MAX_CAPABILITY_LENGTH, AgentFundError::CapabilityTooLong);
-            }
-            profile.capabilities = caps;
-        }
-        if let Some(price) = base_price {
-            profile.base_price = price;
+        // Each invoice flips to `Paid` here, so one already settled by an
+        // earlier batch (or any other path) is rejected rather than counted
+        // against `pending_invoices` twice. The claimed total must match the
+        // verified invoice amounts before it is credited to `total_settled`
+        let recipient = ctx.accounts.recipient.key();
+        let settler = ctx.accounts.settler.key();
+        let now = Clock::get()?.unix_timestamp;
+        let mut invoiced: u64 = 0;
+        for (account, invoice_id) in ctx.remaining_accounts.iter().zip(&invoice_ids) {
+            let amount = mark_invoice_paid(account, invoice_id, &recipient, &settler, now)?;
+            invoiced = invoiced
+                .checked_add(amount)
+                .ok_or(AgentFundError::ArithmeticOverflow)?;
         }
-        if let Some(active) = is_active {
-            profile.is_active = active;
+        if invoiced != total_amount {
+            return Err(amount_error(AgentFundError::BatchAmountMismatch, invoiced, total_amount));
         }
 
-        profile.last_active_at = Clock::get()?.unix_timestamp;
+        let batch = &mut ctx.accounts.batch;
+        batch.id = batch_id;
+        batch.recipient = ctx.accounts.recipient.key();
+        batch.invoice_count = invoice_ids.len() as u32;
+        batch.total_amount = total_amount;
+        batch.settled_at = now;
+        batch.settler = settler;
+        batch.status = BatchStatus::Settled;
+        batch.invoice_ids = invoice_ids.clone();
 
-        emit!(AgentUpdated {
-            agent: profile.owner,
-            is_active: profile.is_active,
+        // Transfer total amount
+        let transfer_ix = anchor_lang::solana_program::system_instruction::transfer(
+            &ctx.accounts.settler.key(),
+            &ctx.accounts.recipient.key(),
+            total_amount,
+        );
+        anchor_lang::solana_program::program::invoke(
+            &transfer_ix,
+            &[
+                ctx.accounts.settler.to_account_info(),
+                ctx.accounts.recipient.to_account_info(),
+                ctx.accounts.system_program.to_account_info(),
+            ],
+        )?;
+
+        // Update treasury
+        let treasury = &mut ctx.accounts.treasury;
+        treasury.total_settled = treasury
+            .total_settled
+            .checked_add(total_amount)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        treasury.pending_invoices = treasury.pending_invoices.saturating_sub(invoice_ids.len() as u64);
+        emit_ledger_entry(treasury, LedgerEntryKind::Settled, total_amount)?;
+
+        msg!("Batch settled: {} invoices, {} lamports", invoice_ids.len(), total_amount);
+        let seq = next_event_seq(&mut ctx.accounts.config)?;
+        emit!(BatchSettled {
+            batch_id,
+            invoice_count: invoice_ids.len() as u32,
+            total_amount,
+            recipient: ctx.accounts.recipient.key(),
+            treasury: treasury.key(),
+            seq,
         });
 
         Ok(())
     }
 
-    /// Request a service from another agent
-    pub fn request_service(
-        ctx: Context<CreateServiceRequest>,
-        request_id: [u8; 32],
-        capability: String,
-        amount: u64,
-    ) -> Result<()> {
-        let provider = &ctx.accounts.provider_profile;
-        
-        require!(provider.is_active, AgentFundError::AgentNotActive);
+    /// Reverse a batch submitted in error, shortly after settlement
+    /// The recipient must cooperate by signing, since the settled lamports
+    /// return from their wallet to the settler. The batch's invoices are
+    /// passed as writable remaining accounts and reopen as `Pending`
+    pub fn reverse_batch<'info>(ctx: Context<'_, '_, '_, 'info, ReverseBatch<'info>>) -> Result<()> {
+        let batch = &mut ctx.accounts.batch;
+        require!(batch.status == BatchStatus::Settled, AgentFundError::BatchNotSettled);
+        let now = Clock::get()?.unix_timestamp;
+        let deadline = batch
+            .settled_at
+            .checked_add(BATCH_REVERSAL_WINDOW_SECONDS)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        require!(now <= deadline, AgentFundError::BatchReversalWindowClosed);
         require!(
-            provider.capabilities.contains(&capability),
-            AgentFundError::CapabilityNotSupported
+            ctx.remaining_accounts.len() == batch.invoice_count as usize,
+            AgentFundError::InvalidInvoiceAccount
         );
-        require!(amount >= provider.base_price, AgentFundError::InvalidAmount);
 
-        // Transfer to escrow
+        for account in ctx.remaining_accounts {
+            reopen_batch_invoice(account, batch)?;
+        }
+
         let transfer_ix = anchor_lang::solana_program::system_instruction::transfer(
-            &ctx.accounts.requester.key(),
-            &ctx.accounts.escrow.key(),
-            amount,
+            &ctx.accounts.recipient.key(),
+            &ctx.accounts.settler.key(),
+            batch.total_amount,
         );
         anchor_lang::solana_program::program::invoke(
             &transfer_ix,
             &[
-                ctx.accounts.requester.to_account_info(),
-                ctx.accounts.escrow.to_account_info(),
+                ctx.accounts.recipient.to_account_info(),
+                ctx.accounts.settler.to_account_info(),
                 ctx.accounts.system_program.to_account_info(),
             ],
         )?;
 
-        let request = &mut ctx.accounts.request;
-        request.id = request_id;
-        request.requester = ctx.accounts.requester.key();
-        request.provider = ctx.accounts.provider_owner.key();
-        request.capability = capability.clone();
-        request.amount = amount;
-        request.status = RequestStatus::Pending;
-        request.created_at = Clock::get()?.unix_timestamp;
-        request.completed_at = None;
-        request.result_hash = None;
+        batch.status = BatchStatus::Reversed;
 
-        msg!("Service requested: {} for {} lamports", capability, amount);
-        emit!(ServiceRequested {
-            request_id,
-            requester: request.requester,
-            provider: request.provider,
-            capability,
-            amount,
+        let treasury = &mut ctx.accounts.treasury;
+        treasury.total_settled = treasury
+            .total_settled
+            .checked_sub(batch.total_amount)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        treasury.pending_invoices = treasury
+            .pending_invoices
+            .checked_add(batch.invoice_count as u64)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        emit_ledger_entry(treasury, LedgerEntryKind::Reversed, batch.total_amount)?;
+
+        msg!("Batch reversed: {} lamports returned to settler", batch.total_amount);
+        emit!(BatchReversed {
+            batch_id: batch.id,
+            invoice_count: batch.invoice_count,
+            total_amount: batch.total_amount,
+            recipient: batch.recipient,
+            settler: batch.settler,
         });
 
         Ok(())
     }
 
-    /// Complete a service request and release payment
-    pub fn complete_service(
-        ctx: Context<CompleteServiceRequest>,
-        result_hash: [u8; 32],
+    /// Return a batch's status, or `None` if the id was never settled
+    /// Intended for simulated calls, so clients can check an id before reuse
+    pub fn get_batch_status(ctx: Context<GetBatchStatus>, _batch_id: [u8; 32]) -> Result<Option<BatchStatus>> {
+        let batch = &ctx.accounts.batch;
+        if batch.data_is_empty() {
+            return Ok(None);
+        }
+        require_keys_eq!(*batch.owner, crate::ID, AgentFundError::InvalidBatchAccount);
+        let batch = BatchSettlement::try_deserialize(&mut &batch.try_borrow_data()?[..])?;
+        Ok(Some(batch.status))
+    }
+
+    /// Fund a batch escrow the recipient can later claim against invoices
+    /// Unlike `settle_batch`, the settler's promise is backed up front: the
+    /// lamports sit in the escrow until the recipient claims them or, after
+    /// `claim_window_seconds`, the settler takes them back
+    pub fn fund_batch_escrow(
+        ctx: Context<FundBatchEscrow>,
+        batch_id: [u8; 32],
+        total_amount: u64,
+        claim_window_seconds: i64,
     ) -> Result<()> {
-        let request = &mut ctx.accounts.request;
-        
         require!(
-            request.status == RequestStatus::Pending,
-            AgentFundError::RequestNotPending
+            ctx.accounts.escrowed_batch.funded_at == 0,
+            AgentFundError::BatchIdAlreadyExists
+        );
+        require!(total_amount > 0, AgentFundError::InvalidAmount);
+        require!(
+            claim_window_seconds > 0 && claim_window_seconds <= MAX_FUTURE_SECONDS,
+            AgentFundError::InvalidExpiry
         );
 
-        // Update request
-        request.status = RequestStatus::Completed;
-        request.completed_at = Some(Clock::get()?.unix_timestamp);
-        request.result_hash = Some(result_hash);
-
-        // Update provider stats
-        let profile = &mut ctx.accounts.provider_profile;
-        profile.total_requests += 1;
-        profile.total_earnings += request.amount;
-        profile.last_active_at = Clock::get()?.unix_timestamp;
-
-        // Update treasury
-        let treasury = &mut ctx.accounts.provider_treasury;
-        treasury.total_received += request.amount;
+        require_payer_balance(&ctx.accounts.settler, total_amount)?;
+        let transfer_ix = anchor_lang::solana_program::system_instruction::transfer(
+            &ctx.accounts.settler.key(),
+            &ctx.accounts.batch_escrow.key(),
+            total_amount,
+        );
+        anchor_lang::solana_program::program::invoke(
+            &transfer_ix,
+            &[
+                ctx.accounts.settler.to_account_info(),
+                ctx.accounts.batch_escrow.to_account_info(),
+                ctx.accounts.system_program.to_account_info(),
+            ],
+        )?;
 
-        // Transfer from escrow to provider
-        // (simplified - in production use PDA signing)
+        let now = Clock::get()?.unix_timestamp;
+        let escrowed = &mut ctx.accounts.escrowed_batch;
+        escrowed.id = batch_id;
+        escrowed.settler = ctx.accounts.settler.key();
+        escrowed.recipient = ctx.accounts.recipient.key();
+        escrowed.total_amount = total_amount;
+        escrowed.funded_at = now;
+        escrowed.claim_deadline = now
+            .checked_add(claim_window_seconds)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        escrowed.status = BatchEscrowStatus::Funded;
+        escrowed.escrow_bump = *ctx.bumps.get("batch_escrow").unwrap();
+        escrowed.bump = *ctx.bumps.get("escrowed_batch").unwrap();
 
-        msg!("Service completed, {} lamports released", request.amount);
-        emit!(ServiceCompleted {
-            request_id: request.id,
-            provider: ctx.accounts.provider.key(),
-            amount: request.amount,
+        msg!("Batch escrow funded: {} lamports", total_amount);
+        emit!(BatchEscrowFunded {
+            batch_id,
+            settler: escrowed.settler,
+            recipient: escrowed.recipient,
+            total_amount,
+            claim_deadline: escrowed.claim_deadline,
         });
 
         Ok(())
     }
 
-    // === Dispute Resolution ===
-
-    /// Initiate a dispute on a service request
-    /// Either requester or provider can initiate within dispute window
-    pub fn initiate_dispute(
-        ctx: Context<InitiateDispute>,
-        reason: String,
+    /// Claim a funded batch escrow by presenting the invoices it pays
+    /// Invoices are passed as writable remaining accounts in the same order
+    /// as `invoice_ids`; each must be payable by the settler and together
+    /// they must add up to the escrowed total
+    pub fn claim_batch_escrow<'info>(
+        ctx: Context<'_, '_, '_, 'info, ClaimBatchEscrow<'info>>,
+        invoice_ids: Vec<[u8; 32]>,
     ) -> Result<()> {
-        let request = &mut ctx.accounts.request;
-        let dispute = &mut ctx.accounts.dispute;
-
+        require!(!invoice_ids.is_empty(), AgentFundError::EmptyBatch);
+        require!(invoice_ids.len() <= MAX_BATCH_SIZE, AgentFundError::BatchTooLarge);
         require!(
-            request.status == RequestStatus::Pending || request.status == RequestStatus::Completed,
-            AgentFundError::CannotDispute
+            ctx.remaining_accounts.len() == invoice_ids.len(),
+            AgentFundError::InvalidInvoiceAccount
         );
-
-        // Must be within dispute window (24 hours after creation/completion)
-        let now = Clock::get()?.unix_timestamp;
-        let reference_time = request.completed_at.unwrap_or(request.created_at);
+        let escrowed = &mut ctx.accounts.escrowed_batch;
         require!(
-            now - reference_time <= DISPUTE_WINDOW_SECONDS,
-            AgentFundError::DisputeWindowClosed
+            escrowed.status == BatchEscrowStatus::Funded,
+            AgentFundError::BatchEscrowNotFunded
         );
+        let now = Clock::get()?.unix_timestamp;
+        require!(now <= escrowed.claim_deadline, AgentFundError::BatchClaimWindowClosed);
 
-        // Update request status
-        request.status = RequestStatus::Disputed;
+        let mut invoiced: u64 = 0;
+        for (account, invoice_id) in ctx.remaining_accounts.iter().zip(&invoice_ids) {
+            let amount = mark_invoice_paid(account, invoice_id, &escrowed.recipient, &escrowed.settler, now)?;
+            invoiced = invoiced
+                .checked_add(amount)
+                .ok_or(AgentFundError::ArithmeticOverflow)?;
+        }
+        if invoiced != escrowed.total_amount {
+            return Err(amount_error(AgentFundError::BatchTotalMismatch, escrowed.total_amount, invoiced));
+        }
 
-        // Initialize dispute
-        dispute.request_id = request.id;
-        dispute.initiator = ctx.accounts.initiator.key();
-        dispute.reason = reason.clone();
-        dispute.status = DisputeStatus::Open;
-        dispute.created_at = now;
-        dispute.resolved_at = None;
-        dispute.resolution = None;
+        transfer_from_escrow(
+            &ctx.accounts.batch_escrow,
+            &ctx.accounts.recipient.to_account_info(),
+            &ctx.accounts.system_program,
+            escrowed.total_amount,
+            &[b"batch_escrow", escrowed.id.as_ref(), &[escrowed.escrow_bump]],
+        )?;
+        escrowed.status = BatchEscrowStatus::Claimed;
 
-        msg!("Dispute initiated for request by {}", dispute.initiator);
-        emit!(DisputeInitiated {
-            request_id: request.id,
-            initiator: dispute.initiator,
-            reason,
+        let treasury = &mut ctx.accounts.treasury;
+        treasury.total_settled = treasury
+            .total_settled
+            .checked_add(escrowed.total_amount)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        treasury.pending_invoices = treasury.pending_invoices.saturating_sub(invoice_ids.len() as u64);
+        emit_ledger_entry(treasury, LedgerEntryKind::Settled, escrowed.total_amount)?;
+
+        msg!("Batch escrow claimed: {} invoices", invoice_ids.len());
+        emit!(BatchEscrowClaimed {
+            batch_id: escrowed.id,
+            recipient: escrowed.recipient,
+            invoice_count: invoice_ids.len() as u32,
+            total_amount: escrowed.total_amount,
         });
 
         Ok(())
     }
 
+    /// Return an unclaimed batch escrow to the settler once the claim
+    /// window has passed
+    pub fn reclaim_batch_escrow(ctx: Context<ReclaimBatchEscrow>) -> Result<()> {
+        let escrowed = &mut ctx.accounts.escrowed_batch;
+        require!(
+            escrowed.status == BatchEscrowStatus::Funded,
+            AgentFundError::BatchEscrowNotFunded
+        );
+        require!(
+            Clock::get()?.unix_timestamp > escrowed.claim_deadline,
+            AgentFundError::BatchClaimWindowOpen
+        );
+
+        transfer_from_escrow(
+            &ctx.accounts.batch_escrow,
+            &ctx.accounts.settler.to_account_info(),
+            &ctx.accounts.system_program,
+            escrowed.total_amount,
+            &[b"batch_escrow", escrowed.id.as_ref(), &[escrowed.escrow_bump]],
+        )?;
+        escrowed.status = BatchEscrowStatus::Reclaimed;
+
+        msg!("Batch escrow reclaimed: {} lamports", escrowed.total_amount);
+        emit!(BatchEscrowReclaimed {
+            batch_id: escrowed.id,
+            settler: escrowed.settler,
+            total_amount: escrowed.total_amount,
+        });
+
+        Ok(())
+    }
+
+    /// Settle several invoices between two channel parties in one step
+    /// The total is netted against the payer's channel balance first; only
+    /// the residual beyond that balance moves on-chain. The netted state is
+    /// recorded at `nonce` and must be countersigned by the recipient.
+    /// Invoices are passed as writable remaining accounts in the same order
+    /// as `invoice_ids`
+    pub fn settle_via_channel<'info>(
+        ctx: Context<'_, '_, '_, 'info, SettleViaChannel<'info>>,
+        invoice_ids: Vec<[u8; 32]>,
+        _channel_id: [u8; 32],
+        nonce: u64,
+    ) -> Result<()> {
+        require!(!invoice_ids.is_empty(), AgentFundError::EmptyBatch);
+        require!(invoice_ids.len() <= MAX_BATCH_SIZE, AgentFundError::BatchTooLarge);
+        require!(
+            ctx.remaining_accounts.len() == invoice_ids.len(),
+            AgentFundError::InvalidInvoiceAccount
+        );
+
+        let channel = &mut ctx.accounts.channel;
+        require!(
+            channel.status == ChannelStatus::Open,
+            AgentFundError::ChannelNotOpen
+        );
+        let payer = ctx.accounts.payer.key();
+        let recipient = ctx.accounts.recipient.key();
+        let now = Clock::get()?.unix_timestamp;
+
+        let mut total: u64 = 0;
+        for (account, invoice_id) in ctx.remaining_accounts.iter().zip(&invoice_ids) {
+            let amount = mark_invoice_paid(account, invoice_id, &recipient, &payer, now)?;
+            total = total
+                .checked_add(amount)
+                .ok_or(AgentFundError::ArithmeticOverflow)?;
+        }
+
+        // Net as much as the payer's side of the channel covers; this also
+        // rejects a payer/recipient pair that is not the channel's parties.
+        // As with `pay_invoice_from_channel`, the recipient's signature keeps
+        // an older off-chain state from undoing the netting
+        let netted = total.min(channel.balance_of(&payer)?);
+        channel.shift_balance(&payer, &recipient, netted, nonce)?;
+        require_channel_state_signature(
+            channel,
+            &ctx.accounts.instructions,
+            &recipient,
+            channel.balance_a,
+            channel.balance_b,
+            channel.nonce,
+        )?;
+
+        let residual = total - netted;
+        if residual > 0 {
+            let transfer_ix = anchor_lang::solana_program::system_instruction::transfer(
+                &payer,
+                &recipient,
+                residual,
+            );
+            anchor_lang::solana_program::program::invoke(
+                &transfer_ix,
+                &[
+                    ctx.accounts.payer.to_account_info(),
+                    ctx.accounts.recipient.to_account_info(),
+                    ctx.accounts.system_program.to_account_info(),
+                ],
+            )?;
+        }
+
+        // Update treasury
+        let treasury = &mut ctx.accounts.treasury;
+        treasury.total_received = treasury
+            .total_received
+            .checked_add(total)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        treasury.pending_invoices = treasury
+            .pending_invoices
+            .saturating_sub(invoice_ids.len() as u64);
+        emit_ledger_entry(treasury, LedgerEntryKind::Received, total)?;
+
+        msg!(
+            "Settled {} invoices via channel: {} netted, {} on-chain",
+            invoice_ids.len(),
+            netted,
+            residual
+        );
+        let seq = next_event_seq(&mut ctx.accounts.config)?;
+        emit!(ChannelSettlement {
+            channel_id: channel.id,
+            invoice_count: invoice_ids.len() as u32,
+            total_amount: total,
+            netted_amount: netted,
+            residual_amount: residual,
+            seq,
+        });
+        emit!(ChannelUpdated {
+            channel_id: channel.id,
+            balance_a: channel.balance_a,
+            balance_b: channel.balance_b,
+            nonce: channel.nonce,
+            state_hash: channel.state_hash,
+        });
+
+        Ok(())
+    }
+
+    /// Open a payment channel between two agents
+    /// The channel stays pending until party B accepts it
+    pub fn open_channel(
+        ctx: Context<OpenChannel>,
+        channel_id: [u8; 32],
+        deposit: u64,
+    ) -> Result<()> {
+        require!(deposit > 0, AgentFundError::InvalidAmount);
+        // The channel account is `init_if_needed`; a recorded opening time
+        // means this id was already used
+        require!(ctx.accounts.channel.opened_at == 0, AgentFundError::ChannelIdAlreadyExists);
+        require!(
+            deposit >= ctx.accounts.config.min_channel_deposit,
+            AgentFundError::DepositBelowMinimum
+        );
+        require_keys_neq!(
+            ctx.accounts.party_a.key(),
+            ctx.accounts.party_b.key(),
+            AgentFundError::SelfDealingNotAllowed
+        );
+
+        let channel = &mut ctx.accounts.channel;
+        channel.id = channel_id;
+        channel.party_a = ctx.accounts.party_a.key();
+        channel.party_b = ctx.accounts.party_b.key();
+        channel.deposit_a = deposit;
+        channel.deposit_b = 0;
+        channel.balance_a = deposit;
+        channel.balance_b = 0;
+        channel.nonce = 0;
+        channel.status = ChannelStatus::PendingAcceptance;
+        channel.opened_at = Clock::get()?.unix_timestamp;
+        channel.closed_at = None;
+        channel.close_deadline = None;
+        channel.close_nonce = 0;
+        channel.escrow_bump = *ctx.bumps.get("channel_escrow").unwrap();
+        channel.state_hash = channel.state_commitment();
+
+        // Transfer deposit to channel escrow
+        require_payer_balance(&ctx.accounts.party_a, deposit)?;
+        let transfer_ix = anchor_lang::solana_program::system_instruction::transfer(
+            &ctx.accounts.party_a.key(),
+            &ctx.accounts.channel_escrow.key(),
+            deposit,
+        );
+        anchor_lang::solana_program::program::invoke(
+            &transfer_ix,
+            &[
+                ctx.accounts.party_a.to_account_info(),
+                ctx.accounts.channel_escrow.to_account_info(),
+                ctx.accounts.system_program.to_account_info(),
+            ],
+        )?;
+
+        msg!("Channel opened with {} lamports deposit", deposit);
+        let seq = bump_event_seq(&mut ctx.accounts.config)?;
+        emit!(ChannelOpened {
+            channel_id,
+            party_a: channel.party_a,
+            party_b: channel.party_b,
+            deposit,
+            seq,
+        });
+        emit_party_channel_registered(channel);
+
+        Ok(())
+    }
+
+    /// Open a channel funded by both parties in one transaction
+    /// Both parties sign, so the channel skips acceptance and opens active
+    /// with each side's deposit as its starting balance
+    pub fn open_channel_dual(
+        ctx: Context<OpenChannelDual>,
+        channel_id: [u8; 32],
+        deposit_a: u64,
+        deposit_b: u64,
+    ) -> Result<()> {
+        require!(deposit_a > 0 && deposit_b > 0, AgentFundError::InvalidAmount);
+        require!(ctx.accounts.channel.opened_at == 0, AgentFundError::ChannelIdAlreadyExists);
+        let min_channel_deposit = ctx.accounts.config.min_channel_deposit;
+        require!(
+            deposit_a >= min_channel_deposit && deposit_b >= min_channel_deposit,
+            AgentFundError::DepositBelowMinimum
+        );
+        require_keys_neq!(
+            ctx.accounts.party_a.key(),
+            ctx.accounts.party_b.key(),
+            AgentFundError::SelfDealingNotAllowed
+        );
+
+        let accounts = &ctx.accounts;
+        accounts.deposit(&accounts.party_a, deposit_a)?;
+        accounts.deposit(&accounts.party_b, deposit_b)?;
+
+        let channel = &mut ctx.accounts.channel;
+        channel.id = channel_id;
+        channel.party_a = ctx.accounts.party_a.key();
+        channel.party_b = ctx.accounts.party_b.key();
+        channel.deposit_a = deposit_a;
+        channel.deposit_b = deposit_b;
+        channel.balance_a = deposit_a;
+        channel.balance_b = deposit_b;
+        channel.nonce = 0;
+        channel.status = ChannelStatus::Open;
+        channel.opened_at = Clock::get()?.unix_timestamp;
+        channel.closed_at = None;
+        channel.close_deadline = None;
+        channel.close_nonce = 0;
+        channel.escrow_bump = *ctx.bumps.get("channel_escrow").unwrap();
+        channel.state_hash = channel.state_commitment();
+
+        msg!("Channel opened with {} + {} lamports deposited", deposit_a, deposit_b);
+        let seq = bump_event_seq(&mut ctx.accounts.config)?;
+        // One event for the whole escrow; the per-party split is on the account
+        emit!(ChannelOpened {
+            channel_id,
+            party_a: channel.party_a,
+            party_b: channel.party_b,
+            deposit: deposit_a
+                .checked_add(deposit_b)
+                .ok_or(AgentFundError::ArithmeticOverflow)?,
+            seq,
+        });
+        emit_party_channel_registered(channel);
+
+        Ok(())
+    }
+
+    /// Party B's consent to a channel opened against them; activates it
+    pub fn accept_channel(ctx: Context<AcceptChannel>) -> Result<()> {
+        let channel = &mut ctx.accounts.channel;
+
+        require!(
+            channel.status == ChannelStatus::PendingAcceptance,
+            AgentFundError::ChannelNotPendingAcceptance
+        );
+        channel.status = ChannelStatus::Open;
+
+        emit!(ChannelAccepted {
+            channel_id: channel.id,
+            party_a: channel.party_a,
+            party_b: channel.party_b,
+        });
+
+        Ok(())
+    }
+
+    /// Withdraw a channel party B has not yet accepted, refunding party A
+    pub fn cancel_pending_channel(ctx: Context<CancelPendingChannel>) -> Result<()> {
+        let channel = &mut ctx.accounts.channel;
+
+        require!(
+            channel.status == ChannelStatus::PendingAcceptance,
+            AgentFundError::ChannelNotPendingAcceptance
+        );
+
+        let refunded = channel.deposit_a;
+        transfer_from_escrow(
+            &ctx.accounts.channel_escrow,
+            &ctx.accounts.party_a.to_account_info(),
+            &ctx.accounts.system_program,
+            refunded,
+            &[b"escrow", channel.id.as_ref(), &[channel.escrow_bump]],
+        )?;
+
+        channel.balance_a = 0;
+        channel.status = ChannelStatus::Closed;
+        channel.closed_at = Some(Clock::get()?.unix_timestamp);
+
+        emit!(ChannelCancelled {
+            channel_id: channel.id,
+            party_a: channel.party_a,
+            refunded,
+        });
+
+        Ok(())
+    }
+
+    /// Let party A reclaim the deposit from an accepted channel that was never
+    /// used, closing it. Unlike a force close this needs no posted state: the
+    /// channel must still be pristine (nonce zero, no party B funds) and idle
+    /// for the configured period since opening
+    pub fn withdraw_unused_deposit(ctx: Context<WithdrawUnusedDeposit>) -> Result<()> {
+        let channel = &mut ctx.accounts.channel;
+
+        require!(
+            channel.status == ChannelStatus::Open,
+            AgentFundError::ChannelNotOpen
+        );
+        require!(
+            channel.nonce == 0 && channel.deposit_b == 0 && channel.balance_a == channel.deposit_a,
+            AgentFundError::ChannelNotPristine
+        );
+        let now = Clock::get()?.unix_timestamp;
+        let idle_until = channel
+            .opened_at
+            .checked_add(ctx.accounts.config.channel_idle_reclaim_seconds)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        require!(now >= idle_until, AgentFundError::ChannelNotIdle);
+
+        let refunded = channel.deposit_a;
+        transfer_from_escrow(
+            &ctx.accounts.channel_escrow,
+            &ctx.accounts.party_a.to_account_info(),
+            &ctx.accounts.system_program,
+            refunded,
+            &[b"escrow", channel.id.as_ref(), &[channel.escrow_bump]],
+        )?;
+
+        channel.balance_a = 0;
+        channel.status = ChannelStatus::Closed;
+        channel.closed_at = Some(now);
+
+        let seq = bump_event_seq(&mut ctx.accounts.config)?;
+        emit!(ChannelDepositReclaimed {
+            channel_id: channel.id,
+            party_a: channel.party_a,
+            refunded,
+            seq,
+        });
+
+        Ok(())
+    }
+
+    /// Close a payment channel cooperatively and pay out final balances
+    /// Both parties sign the close
+    pub fn close_channel(
+        ctx: Context<CloseChannel>,
+        final_balance_a: u64,
+        final_balance_b: u64,
+        nonce: u64,
+    ) -> Result<()> {
+        let channel = &mut ctx.accounts.channel;
+        
+        require!(
+            channel.status == ChannelStatus::Open,
+            AgentFundError::ChannelNotOpen
+        );
+        require_newer_nonce(channel.nonce, nonce)?;
+
+        // The protocol close fee comes out of the pooled deposits
+        let total_deposits = channel
+            .deposit_a
+            .checked_add(channel.deposit_b)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        let fee = bps_of(total_deposits, ctx.accounts.config.channel_close_fee_bps)?;
+        require_balances_conserved(channel, final_balance_a, final_balance_b, fee)?;
+
+        pay_out_channel(
+            channel,
+            &ctx.accounts.channel_escrow,
+            &ctx.accounts.system_program,
+            [
+                (&ctx.accounts.fee_collector, fee),
+                (&ctx.accounts.party_a.to_account_info(), final_balance_a),
+                (&ctx.accounts.party_b.to_account_info(), final_balance_b),
+            ],
+        )?;
+
+        // Update channel state
+        channel.balance_a = final_balance_a;
+        channel.balance_b = final_balance_b;
+        channel.nonce = nonce;
+        channel.status = ChannelStatus::Closed;
+        channel.closed_at = Some(Clock::get()?.unix_timestamp);
+        channel.state_hash = channel.state_commitment();
+
+        msg!("Channel closed. Final: A={}, B={}, fee={}", final_balance_a, final_balance_b, fee);
+        let seq = bump_event_seq(&mut ctx.accounts.config)?;
+        emit!(ChannelClosed {
+            channel_id: channel.id,
+            final_balance_a,
+            final_balance_b,
+            nonce,
+            fee,
+            seq,
+        });
+        emit_party_channel_closed(channel, final_balance_a, final_balance_b);
+
+        Ok(())
+    }
+
+    /// Add party B's deposit to an open channel
+    pub fn fund_channel(
+        ctx: Context<FundChannel>,
+        amount: u64,
+    ) -> Result<()> {
+        require!(amount > 0, AgentFundError::InvalidAmount);
+        require!(
+            ctx.accounts.channel.status == ChannelStatus::Open,
+            AgentFundError::ChannelNotOpen
+        );
+
+        // Transfer deposit to channel escrow
+        let transfer_ix = anchor_lang::solana_program::system_instruction::transfer(
+            &ctx.accounts.party_b.key(),
+            &ctx.accounts.channel_escrow.key(),
+            amount,
+        );
+        anchor_lang::solana_program::program::invoke(
+            &transfer_ix,
+            &[
+                ctx.accounts.party_b.to_account_info(),
+                ctx.accounts.channel_escrow.to_account_info(),
+                ctx.accounts.system_program.to_account_info(),
+            ],
+        )?;
+
+        let channel = &mut ctx.accounts.channel;
+        channel.deposit_b = channel.deposit_b
+            .checked_add(amount)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        channel.balance_b = channel.balance_b
+            .checked_add(amount)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        channel.state_hash = channel.state_commitment();
+
+        msg!("Channel funded with {} lamports by party B", amount);
+        emit!(ChannelFunded {
+            channel_id: channel.id,
+            amount,
+            balance_a: channel.balance_a,
+            balance_b: channel.balance_b,
+            nonce: channel.nonce,
+        });
+
+        Ok(())
+    }
+
+    /// Record a cooperatively signed channel state
+    pub fn update_channel(
+        ctx: Context<UpdateChannel>,
+        balance_a: u64,
+        balance_b: u64,
+        nonce: u64,
+    ) -> Result<()> {
+        let channel = &mut ctx.accounts.channel;
+
+        require!(
+            channel.status == ChannelStatus::Open,
+            AgentFundError::ChannelNotOpen
+        );
+        require_newer_nonce(channel.nonce, nonce)?;
+        require_balances_conserved(channel, balance_a, balance_b, 0)?;
+
+        channel.balance_a = balance_a;
+        channel.balance_b = balance_b;
+        channel.nonce = nonce;
+        channel.state_hash = channel.state_commitment();
+
+        emit!(ChannelUpdated {
+            channel_id: channel.id,
+            balance_a,
+            balance_b,
+            nonce,
+            state_hash: channel.state_hash,
+        });
+
+        Ok(())
+    }
+
+    /// Pay part of each party's balance out of the escrow without closing
+    /// Both parties sign. The splice settles the channel to date: what each
+    /// party leaves in becomes its new deposit
+    pub fn splice_channel(
+        ctx: Context<SpliceChannel>,
+        withdraw_a: u64,
+        withdraw_b: u64,
+        nonce: u64,
+    ) -> Result<()> {
+        let channel = &mut ctx.accounts.channel;
+
+        require!(
+            channel.status == ChannelStatus::Open,
+            AgentFundError::ChannelNotOpen
+        );
+        require_newer_nonce(channel.nonce, nonce)?;
+        require!(withdraw_a > 0 || withdraw_b > 0, AgentFundError::InvalidAmount);
+
+        let balance_a = channel.balance_a
+            .checked_sub(withdraw_a)
+            .ok_or(AgentFundError::InsufficientChannelBalance)?;
+        let balance_b = channel.balance_b
+            .checked_sub(withdraw_b)
+            .ok_or(AgentFundError::InsufficientChannelBalance)?;
+        // Withdrawals are outflows like a fee: what stays plus what leaves
+        // must still account for every deposited lamport
+        let withdrawn = withdraw_a
+            .checked_add(withdraw_b)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        require_balances_conserved(channel, balance_a, balance_b, withdrawn)?;
+
+        let seeds: &[&[u8]] = &[b"escrow", channel.id.as_ref(), &[channel.escrow_bump]];
+        if withdraw_a > 0 {
+            transfer_from_escrow(
+                &ctx.accounts.channel_escrow,
+                &ctx.accounts.party_a.to_account_info(),
+                &ctx.accounts.system_program,
+                withdraw_a,
+                seeds,
+            )?;
+        }
+        if withdraw_b > 0 {
+            transfer_from_escrow(
+                &ctx.accounts.channel_escrow,
+                &ctx.accounts.party_b.to_account_info(),
+                &ctx.accounts.system_program,
+                withdraw_b,
+                seeds,
+            )?;
+        }
+
+        channel.deposit_a = balance_a;
+        channel.deposit_b = balance_b;
+        channel.balance_a = balance_a;
+        channel.balance_b = balance_b;
+        channel.nonce = nonce;
+        channel.state_hash = channel.state_commitment();
+
+        emit!(ChannelSpliced {
+            channel_id: channel.id,
+            withdraw_a,
+            withdraw_b,
+            balance_a,
+            balance_b,
+            nonce,
+        });
+
+        Ok(())
+    }
+
+    /// Begin a unilateral close with the caller's latest state
+    /// The counterparty may challenge with a newer state until the deadline
+    pub fn initiate_channel_close(
+        ctx: Context<ChannelPartyAction>,
+        balance_a: u64,
+        balance_b: u64,
+        nonce: u64,
+    ) -> Result<()> {
+        let channel = &ctx.accounts.channel;
+
+        require!(
+            channel.status == ChannelStatus::Open,
+            AgentFundError::ChannelNotOpen
+        );
+        // Closing on the recorded on-chain state is allowed as-is, since both
+        // parties signed it. Any other state must be strictly newer and carry
+        // the counterparty's signature; accepting a different state at the
+        // current nonce would let a party replay a superseded balance split.
+        if nonce != channel.nonce
+            || balance_a != channel.balance_a
+            || balance_b != channel.balance_b
+        {
+            require_newer_nonce(channel.nonce, nonce)?;
+            require_balances_conserved(channel, balance_a, balance_b, 0)?;
+            ctx.accounts.require_counterparty_signature(balance_a, balance_b, nonce)?;
+        }
+
+        let channel = &mut ctx.accounts.channel;
+        let deadline = Clock::get()?.unix_timestamp + CHANNEL_CHALLENGE_PERIOD_SECONDS;
+        channel.balance_a = balance_a;
+        channel.balance_b = balance_b;
+        channel.nonce = nonce;
+        channel.status = ChannelStatus::Closing;
+        channel.close_deadline = Some(deadline);
+        channel.close_nonce = nonce;
+        channel.state_hash = channel.state_commitment();
+
+        msg!("Channel closing, challenge deadline {}", deadline);
+        emit!(ChannelClosing {
+            channel_id: channel.id,
+            initiator: ctx.accounts.party.key(),
+            balance_a,
+            balance_b,
+            nonce,
+            close_deadline: deadline,
+        });
+
+        Ok(())
+    }
+
+    /// Challenge a pending close with a newer state
+    pub fn challenge_channel_close(
+        ctx: Context<ChannelPartyAction>,
+        balance_a: u64,
+        balance_b: u64,
+        nonce: u64,
+    ) -> Result<()> {
+        let channel = &ctx.accounts.channel;
+
+        require!(
+            channel.status == ChannelStatus::Closing || channel.status == ChannelStatus::Disputed,
+            AgentFundError::ChannelNotClosing
+        );
+        require!(
+            Clock::get()?.unix_timestamp < channel.close_deadline.unwrap_or(0),
+            AgentFundError::ChallengePeriodExpired
+        );
+        // The challenge must beat the state posted by the pending close
+        // specifically; otherwise the closer's own state could be re-posted
+        require!(nonce > channel.close_nonce, AgentFundError::StaleChallengeNonce);
+        require_newer_nonce(channel.nonce, nonce)?;
+        require_balances_conserved(channel, balance_a, balance_b, 0)?;
+        ctx.accounts.require_counterparty_signature(balance_a, balance_b, nonce)?;
+
+        let channel = &mut ctx.accounts.channel;
+        channel.balance_a = balance_a;
+        channel.balance_b = balance_b;
+        channel.nonce = nonce;
+        channel.close_nonce = nonce;
+        channel.status = ChannelStatus::Disputed;
+        channel.state_hash = channel.state_commitment();
+
+        msg!("Channel close challenged at nonce {}", nonce);
+        emit!(ChannelDisputed {
+            channel_id: channel.id,
+            challenger: ctx.accounts.party.key(),
+            balance_a,
+            balance_b,
+            nonce,
+        });
+
+        Ok(())
+    }
+
+    /// Finalize a unilateral close once the challenge period has elapsed
+    /// Each party pays the close fee on its own balance; the rest is paid out
+    pub fn finalize_channel_close(ctx: Context<FinalizeChannelClose>) -> Result<()> {
+        let channel = &mut ctx.accounts.channel;
+
+        require!(
+            channel.status == ChannelStatus::Closing || channel.status == ChannelStatus::Disputed,
+            AgentFundError::ChannelNotClosing
+        );
+        let now = Clock::get()?.unix_timestamp;
+        require!(
+            now >= channel.close_deadline.unwrap_or(0),
+            AgentFundError::ChallengePeriodActive
+        );
+
+        let fee_bps = ctx.accounts.config.channel_close_fee_bps;
+        let fee_a = bps_of(channel.balance_a, fee_bps)?;
+        let fee_b = bps_of(channel.balance_b, fee_bps)?;
+        let fee = fee_a
+            .checked_add(fee_b)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        let final_balance_a = channel.balance_a - fee_a;
+        let final_balance_b = channel.balance_b - fee_b;
+
+        pay_out_channel(
+            channel,
+            &ctx.accounts.channel_escrow,
+            &ctx.accounts.system_program,
+            [
+                (&ctx.accounts.fee_collector, fee),
+                (&ctx.accounts.party_a, final_balance_a),
+                (&ctx.accounts.party_b, final_balance_b),
+            ],
+        )?;
+
+        channel.balance_a = final_balance_a;
+        channel.balance_b = final_balance_b;
+        channel.status = ChannelStatus::Closed;
+        channel.closed_at = Some(now);
+        channel.state_hash = channel.state_commitment();
+
+        msg!("Channel closed. Final: A={}, B={}, fee={}", final_balance_a, final_balance_b, fee);
+        let seq = bump_event_seq(&mut ctx.accounts.config)?;
+        emit!(ChannelClosed {
+            channel_id: channel.id,
+            final_balance_a,
+            final_balance_b,
+            nonce: channel.nonce,
+            fee,
+            seq,
+        });
+        emit_party_channel_closed(channel, channel.balance_a, channel.balance_b);
+
+        Ok(())
+    }
+
+    /// Return the channel's current balances, nonce and status, plus the
+    /// challenge deadline while a close is pending. Intended for simulated calls
+    pub fn get_channel_state(ctx: Context<GetChannelState>) -> Result<ChannelState> {
+        let channel = &ctx.accounts.channel;
+        let closing = matches!(channel.status, ChannelStatus::Closing | ChannelStatus::Disputed);
+
+        Ok(ChannelState {
+            balance_a: channel.balance_a,
+            balance_b: channel.balance_b,
+            nonce: channel.nonce,
+            status: channel.status.clone(),
+            challenge_deadline: if closing { channel.close_deadline } else { None },
+        })
+    }
+
+    // === Agent Registry Instructions ===
+
+    /// Register an agent in the marketplace
+    pub fn register_agent(
+        ctx: Context<RegisterAgent>,
+        name: String,
+        description: String,
+        capabilities: Vec<String>,
+        base_price: u64,
+        bump: u8,
+        tags: Vec<[u8; 16]>,
+    ) -> Result<()> {
+        require!(
+            !is_banned(&ctx.accounts.banned_agents, &ctx.accounts.owner.key())?,
+            AgentFundError::AgentBanned
+        );
+        let profile = &mut ctx.accounts.agent_profile;
+        init_agent_profile(
+            profile,
+            ctx.accounts.owner.key(),
+            ctx.accounts.treasury.key(),
+            AgentRegistration { name, description, capabilities, base_price, tags },
+        )?;
+        profile.bump = bump;
+        update_marketplace_stats(&mut ctx.accounts.stats, MarketplaceStats::record_agent)?;
+
+        Ok(())
+    }
+
+    /// Register an agent and create its treasury in a single transaction
+    pub fn register_agent_with_treasury(
+        ctx: Context<RegisterAgentWithTreasury>,
+        name: String,
+        description: String,
+        capabilities: Vec<String>,
+        base_price: u64,
+        tags: Vec<[u8; 16]>,
+    ) -> Result<()> {
+        require!(
+            !is_banned(&ctx.accounts.banned_agents, &ctx.accounts.owner.key())?,
+            AgentFundError::AgentBanned
+        );
+        let treasury = &mut ctx.accounts.treasury;
+        init_treasury(
+            treasury,
+            ctx.accounts.owner.key(),
+            *ctx.bumps.get("treasury").unwrap(),
+            Clock::get()?.unix_timestamp,
+        );
+        msg!("Treasury initialized for agent: {}", treasury.owner);
+
+        let profile = &mut ctx.accounts.agent_profile;
+        init_agent_profile(
+            profile,
+            ctx.accounts.owner.key(),
+            ctx.accounts.treasury.key(),
+            AgentRegistration { name, description, capabilities, base_price, tags },
+        )?;
+        profile.bump = *ctx.bumps.get("agent_profile").unwrap();
+        update_marketplace_stats(&mut ctx.accounts.stats, MarketplaceStats::record_agent)?;
+
+        Ok(())
+    }
+
+    /// Create the per-owner registry that tracks indexed agent profiles
+    pub fn initialize_agent_registry(ctx: Context<InitializeAgentRegistry>) -> Result<()> {
+        let registry = &mut ctx.accounts.registry;
+        registry.owner = ctx.accounts.owner.key();
+        registry.agent_count = 0;
+        registry.bump = *ctx.bumps.get("registry").unwrap();
+
+        msg!("Agent registry initialized for owner: {}", registry.owner);
+        Ok(())
+    }
+
+    /// Create the singleton holding marketplace-wide counters
+    pub fn initialize_marketplace_stats(ctx: Context<InitializeMarketplaceStats>) -> Result<()> {
+        let stats = &mut ctx.accounts.stats;
+        stats.total_agents = 0;
+        stats.total_requests = 0;
+        stats.total_volume = 0;
+        stats.total_completed = 0;
+        stats.bump = *ctx.bumps.get("stats").unwrap();
+
+        msg!("Marketplace stats initialized");
+        Ok(())
+    }
+
+    /// Register an additional agent under one owner at the next free index
+    /// Indexed agents share the owner's treasury; the registry caps how many
+    /// one operator can run
+    pub fn register_agent_indexed(
+        ctx: Context<RegisterAgentIndexed>,
+        index: u8,
+        name: String,
+        description: String,
+        capabilities: Vec<String>,
+        base_price: u64,
+        tags: Vec<[u8; 16]>,
+    ) -> Result<()> {
+        require!(
+            !is_banned(&ctx.accounts.banned_agents, &ctx.accounts.owner.key())?,
+            AgentFundError::AgentBanned
+        );
+        let registry = &mut ctx.accounts.registry;
+        require!(
+            registry.agent_count < MAX_AGENTS_PER_OWNER,
+            AgentFundError::AgentRegistryFull
+        );
+        require!(index == registry.agent_count, AgentFundError::InvalidAgentIndex);
+        registry.agent_count += 1;
+
+        let profile = &mut ctx.accounts.agent_profile;
+        init_agent_profile(
+            profile,
+            ctx.accounts.owner.key(),
+            ctx.accounts.treasury.key(),
+            AgentRegistration { name, description, capabilities, base_price, tags },
+        )?;
+        profile.agent_index = Some(index);
+        profile.bump = *ctx.bumps.get("agent_profile").unwrap();
+        update_marketplace_stats(&mut ctx.accounts.stats, MarketplaceStats::record_agent)?;
+
+        Ok(())
+    }
+
+    /// Update agent profile
+    pub fn update_agent_profile(
+        ctx: Context<UpdateAgentProfile>,
+        name: Option<String>,
+        description: Option<String>,
+        capabilities: Option<Vec<String>>,
+        base_price: Option<u64>,
+        is_active: Option<bool>,
+        tags: Option<Vec<[u8; 16]>>,
+    ) -> Result<()> {
+        let profile = &mut ctx.accounts.agent_profile;
+        let now = Clock::get()?.unix_timestamp;
+
+        if let Some(n) = name {
+            require!(n.len() <= MAX_NAME_LENGTH, AgentFundError::NameTooLong);
+            profile.name = n;
+        }
+        if let Some(d) = description {
+            require!(d.len() <= MAX_DESCRIPTION_LENGTH, AgentFundError::DescriptionTooLong);
+            profile.description = d;
+        }
+        if let Some(caps) = capabilities {
+            require!(caps.len() <= MAX_CAPABILITIES, AgentFundError::TooManyCapabilities);
+            for cap in &caps {
+                require!(cap.len() <= MAX_CAPABILITY_LENGTH, AgentFundError::CapabilityTooLong);
+            }
+            profile.set_capabilities(normalize_capabilities(caps)?);
+        }
+        if let Some(price) = base_price {
+            profile.set_base_price(price, now)?;
+            if let Some(pending_base_price) = profile.pending_base_price {
+                emit!(AgentPriceIncreaseScheduled {
+                    agent: profile.owner,
+                    base_price: profile.base_price,
+                    pending_base_price,
+                    effective_at: profile.price_effective_at,
+                });
+            }
+        }
+        if let Some(active) = is_active {
+            profile.set_active(active, now);
+        }
+        if let Some(tags) = tags {
+            require!(tags.len() <= MAX_TAGS, AgentFundError::TooManyTags);
+            profile.tags = tags;
+            emit!(AgentTagsUpdated {
+                agent: profile.owner,
+                tags: profile.tags.clone(),
+            });
+        }
+
+        profile.last_active_at = now;
+
+        emit!(AgentUpdated {
+            agent: profile.owner,
+            is_active: profile.is_active,
+        });
+
+        Ok(())
+    }
+
+    /// Register an alternate name that requests may use for a listed capability
+    pub fn add_capability_alias(
+        ctx: Context<UpdateAgentProfile>,
+        alias: String,
+        canonical: String,
+    ) -> Result<()> {
+        let profile = &mut ctx.accounts.agent_profile;
+        let alias = normalize_capability(&alias);
+        let canonical = normalize_capability(&canonical);
+
+        require!(
+            !alias.is_empty() && alias.len() <= MAX_CAPABILITY_LENGTH,
+            AgentFundError::CapabilityTooLong
+        );
+        require!(
+            profile.capabilities.contains(&canonical),
+            AgentFundError::CapabilityNotSupported
+        );
+        require!(
+            !profile.capabilities.contains(&alias)
+                && !profile.capability_aliases.iter().any(|entry| entry.alias == alias),
+            AgentFundError::DuplicateCapabilityAlias
+        );
+        require!(
+            profile.capability_aliases.len() < MAX_CAPABILITY_ALIASES,
+            AgentFundError::TooManyCapabilityAliases
+        );
+
+        profile.capability_aliases.push(CapabilityAlias {
+            alias: alias.clone(),
+            canonical: canonical.clone(),
+        });
+
+        emit!(CapabilityAliasAdded {
+            agent: profile.owner,
+            alias,
+            canonical,
+        });
+
+        Ok(())
+    }
+
+    /// Cap how many requests may be open against this agent at once
+    /// Zero removes the cap
+    pub fn set_max_concurrent_requests(
+        ctx: Context<UpdateAgentProfile>,
+        max_concurrent_requests: u32,
+    ) -> Result<()> {
+        let profile = &mut ctx.accounts.agent_profile;
+        profile.max_concurrent_requests = max_concurrent_requests;
+        profile.last_active_at = Clock::get()?.unix_timestamp;
+
+        emit!(AgentCapacityUpdated {
+            agent: profile.owner,
+            max_concurrent_requests,
+        });
+
+        Ok(())
+    }
+
+    /// Pause or resume requests for one capability without deactivating
+    /// the whole agent
+    pub fn set_capability_active(
+        ctx: Context<UpdateAgentProfile>,
+        capability: String,
+        active: bool,
+    ) -> Result<()> {
+        let profile = &mut ctx.accounts.agent_profile;
+        let index = profile
+            .capabilities
+            .iter()
+            .position(|cap| *cap == normalize_capability(&capability))
+            .ok_or(AgentFundError::CapabilityNotSupported)?;
+        let len = profile.capabilities.len();
+        profile.capability_active.resize(len, true);
+        profile.capability_active[index] = active;
+        profile.last_active_at = Clock::get()?.unix_timestamp;
+
+        emit!(CapabilityAvailabilityChanged {
+            agent: profile.owner,
+            capability: profile.capabilities[index].clone(),
+            active,
+        });
+
+        Ok(())
+    }
+
+    /// Stop or resume taking new requests, e.g. during maintenance
+    /// The agent stays listed and its open requests can still be completed
+    pub fn set_accepting_requests(ctx: Context<UpdateAgentProfile>, accepting: bool) -> Result<()> {
+        let profile = &mut ctx.accounts.agent_profile;
+        profile.accepting_requests = accepting;
+        profile.last_active_at = Clock::get()?.unix_timestamp;
+
+        emit!(AgentAcceptingRequestsChanged {
+            agent: profile.owner,
+            accepting,
+        });
+
+        Ok(())
+    }
+
+    /// Delist an agent inactive beyond the configured staleness threshold
+    /// Permissionless so marketplace crawlers can prune abandoned listings;
+    /// the owner can reactivate through `update_agent_profile`
+    pub fn mark_stale_agent(ctx: Context<MarkStaleAgent>) -> Result<()> {
+        let profile = &mut ctx.accounts.agent_profile;
+        require!(profile.is_active, AgentFundError::AgentNotActive);
+        let now = Clock::get()?.unix_timestamp;
+        require!(
+            profile.is_stale(now, ctx.accounts.config.staleness_threshold_seconds),
+            AgentFundError::AgentNotStale
+        );
+
+        profile.set_active(false, now);
+
+        msg!("Agent marked stale: {}", profile.owner);
+        emit!(AgentMarkedStale {
+            agent: profile.owner,
+            last_active_at: profile.last_active_at,
+            marked_at: now,
+        });
+
+        Ok(())
+    }
+
+    /// Return a page of an agent's capabilities (delivered via return data)
+    /// Lets size-limited clients page through without decoding the whole profile
+    pub fn get_capabilities_page(
+        ctx: Context<GetCapabilitiesPage>,
+        offset: u32,
+        limit: u32,
+    ) -> Result<Vec<String>> {
+        let capabilities = &ctx.accounts.agent_profile.capabilities;
+        let offset = offset as usize;
+        let limit = limit as usize;
+
+        require!(
+            offset <= capabilities.len() && limit > 0 && limit <= MAX_CAPABILITIES,
+            AgentFundError::InvalidPagination
+        );
+
+        let end = offset.saturating_add(limit).min(capabilities.len());
+        Ok(capabilities[offset..end].to_vec())
+    }
+
+    /// Migrate an agent profile created under an older schema version
+    /// Grows the account to the current size and fills in defaults for new fields
+    pub fn migrate_agent_profile(ctx: Context<MigrateAgentProfile>) -> Result<()> {
+        let profile_info = ctx.accounts.agent_profile.to_account_info();
+        let owner = &ctx.accounts.owner;
+
+        // Older layouts may not deserialize as the current struct until resized,
+        // so validate the discriminator and owner field from raw bytes first
+        {
+            let data = profile_info.try_borrow_data()?;
+            require!(
+                data.len() >= 8 + 32 && data[..8] == AgentProfile::DISCRIMINATOR,
+                AgentFundError::InvalidAgentProfile
+            );
+            require!(data[8..40] == owner.key().to_bytes(), AgentFundError::Unauthorized);
+        }
+
+        if profile_info.data_len() < AgentProfile::SPACE {
+            let required = Rent::get()?.minimum_balance(AgentProfile::SPACE);
+            let shortfall = required.saturating_sub(profile_info.lamports());
+            if shortfall > 0 {
+                let transfer_ix = anchor_lang::solana_program::system_instruction::transfer(
+                    &owner.key(),
+                    &profile_info.key(),
+                    shortfall,
+                );
+                anchor_lang::solana_program::program::invoke(
+                    &transfer_ix,
+                    &[
+                        owner.to_account_info(),
+                        profile_info.clone(),
+                        ctx.accounts.system_program.to_account_info(),
+                    ],
+                )?;
+            }
+            // New bytes are zeroed, which Borsh reads back as default values
+            profile_info.realloc(AgentProfile::SPACE, true)?;
+        }
+
+        let mut profile = AgentProfile::try_deserialize(&mut &profile_info.try_borrow_data()?[..])?;
+        let from_version = profile.schema_version;
+        if from_version >= AGENT_PROFILE_SCHEMA_VERSION {
+            msg!("Agent profile already at schema version {}", from_version);
+            return Ok(());
+        }
+
+        // Fields added after `from_version` needing non-zero defaults go here
+        if from_version < 6 {
+            profile.capability_request_counts = vec![0; profile.capabilities.len()];
+        }
+        if from_version < 10 {
+            profile.capability_active = vec![true; profile.capabilities.len()];
+        }
+        if from_version < 11 {
+            profile.accepting_requests = true;
+        }
+        profile.schema_version = AGENT_PROFILE_SCHEMA_VERSION;
+        profile.try_serialize(&mut &mut profile_info.try_borrow_mut_data()?[..])?;
+
+        msg!("Agent profile migrated: v{} -> v{}", from_version, AGENT_PROFILE_SCHEMA_VERSION);
+        emit!(AgentProfileMigrated {
+            agent: profile.owner,
+            from_version,
+            to_version: AGENT_PROFILE_SCHEMA_VERSION,
+        });
+
+        Ok(())
+    }
+
+    /// Request a service from another agent
+    /// A provider with a zero base price accepts free requests, which are
+    /// tracked through completion without escrowing anything
+    pub fn request_service(
+        ctx: Context<CreateServiceRequest>,
+        request_id: [u8; 32],
+        capability: String,
+        amount: u64,
+        dispute_window_seconds: Option<i64>,
+        review_period_seconds: Option<i64>,
+        metadata_uri: Option<String>,
+    ) -> Result<()> {
+        let provider = &mut ctx.accounts.provider_profile;
+        
+        require!(provider.is_active, AgentFundError::AgentNotActive);
+        require!(provider.accepting_requests, AgentFundError::AgentNotAcceptingRequests);
+        provider.apply_due_price(Clock::get()?.unix_timestamp);
+        let capability = provider.available_capability(&capability)?;
+        if amount < provider.base_price {
+            return Err(amount_error(AgentFundError::InvalidAmount, provider.base_price, amount));
+        }
+
+        ctx.accounts.fund(
+            request_id,
+            capability,
+            amount,
+            RequestTerms {
+                dispute_window_seconds,
+                review_period_seconds,
+                metadata_uri,
+            },
+            *ctx.bumps.get("escrow").unwrap(),
+        )
+    }
+
+    /// Request several of a provider's capabilities as one escrowed order
+    /// The provider must support every listed capability; the minimum price
+    /// is the base price once per capability
+    pub fn request_service_bundle(
+        ctx: Context<CreateServiceRequest>,
+        request_id: [u8; 32],
+        capabilities: Vec<String>,
+        amount: u64,
+    ) -> Result<()> {
+        require!(
+            !capabilities.is_empty() && capabilities.len() <= MAX_BUNDLE_CAPABILITIES,
+            AgentFundError::InvalidBundleSize
+        );
+
+        let provider = &mut ctx.accounts.provider_profile;
+        require!(provider.is_active, AgentFundError::AgentNotActive);
+        require!(provider.accepting_requests, AgentFundError::AgentNotAcceptingRequests);
+        provider.apply_due_price(Clock::get()?.unix_timestamp);
+
+        let mut bundle: Vec<String> = Vec::with_capacity(capabilities.len());
+        for requested in &capabilities {
+            let capability = provider.available_capability(requested)?;
+            require!(
+                !bundle.contains(&capability),
+                AgentFundError::DuplicateBundleCapability
+            );
+            bundle.push(capability);
+        }
+        let price = provider
+            .base_price
+            .checked_mul(bundle.len() as u64)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        if amount < price {
+            return Err(amount_error(AgentFundError::InvalidAmount, price, amount));
+        }
+
+        ctx.accounts.fund(
+            request_id,
+            bundle[0].clone(),
+            amount,
+            RequestTerms::default(),
+            *ctx.bumps.get("escrow").unwrap(),
+        )?;
+        ctx.accounts.request.bundle = bundle.clone();
+
+        emit!(ServiceBundleRequested {
+            request_id,
+            capabilities: bundle,
+            amount,
+        });
+
+        Ok(())
+    }
+
+    /// Open a request on a relayer's transaction, funded from the
+    /// requester's treasury earnings. The requester authorizes the debit by
+    /// signing `delegated_request_message` off-chain; the relayer submits
+    /// that signature as an ed25519 program instruction just before this one
+    pub fn request_service_delegated(
+        ctx: Context<CreateDelegatedServiceRequest>,
+        request_id: [u8; 32],
+        capability: String,
+        amount: u64,
+        expires_at: i64,
+    ) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        require!(now < expires_at, AgentFundError::DelegationExpired);
+        let message = delegated_request_message(
+            &request_id,
+            &ctx.accounts.provider_owner.key(),
+            &capability,
+            amount,
+            expires_at,
+        );
+        require!(
+            ed25519_signature_matches(
+                &ctx.accounts.instructions,
+                &ctx.accounts.requester.key(),
+                &message,
+            )?,
+            AgentFundError::InvalidDelegatedSignature
+        );
+
+        let provider = &mut ctx.accounts.provider_profile;
+        require!(provider.is_active, AgentFundError::AgentNotActive);
+        require!(provider.accepting_requests, AgentFundError::AgentNotAcceptingRequests);
+        provider.apply_due_price(now);
+        let capability = provider.available_capability(&capability)?;
+        if amount < provider.base_price {
+            return Err(amount_error(AgentFundError::InvalidAmount, provider.base_price, amount));
+        }
+
+        ctx.accounts.fund(request_id, capability, amount, *ctx.bumps.get("escrow").unwrap())
+    }
+
+    /// Accept a pending service request (provider side)
+    /// Once accepted, the requester can no longer cancel. The provider may back
+    /// an SLA by posting a bond that is partly slashed on late completion.
+    pub fn accept_service_request(
+        ctx: Context<AcceptServiceRequest>,
+        sla_deadline_seconds: Option<i64>,
+        sla_bond: u64,
+    ) -> Result<()> {
+        let request = &mut ctx.accounts.request;
+
+        require!(
+            request.status == RequestStatus::Pending,
+            AgentFundError::RequestNotPending
+        );
+        require!(
+            sla_deadline_seconds.map_or(sla_bond == 0, |secs| secs > 0),
+            AgentFundError::InvalidSlaTerms
+        );
+        require!(
+            sla_deadline_seconds.unwrap_or(0) <= MAX_FUTURE_SECONDS,
+            AgentFundError::ExpiryTooFar
+        );
+
+        if sla_bond > 0 {
+            // Bond is held alongside the payment in the request escrow
+            let transfer_ix = anchor_lang::solana_program::system_instruction::transfer(
+                &ctx.accounts.provider.key(),
+                &ctx.accounts.escrow.key(),
+                sla_bond,
+            );
+            anchor_lang::solana_program::program::invoke(
+                &transfer_ix,
+                &[
+                    ctx.accounts.provider.to_account_info(),
+                    ctx.accounts.escrow.to_account_info(),
+                    ctx.accounts.system_program.to_account_info(),
+                ],
+            )?;
+        }
+
+        let now = Clock::get()?.unix_timestamp;
+        request.status = RequestStatus::InProgress;
+        request.accepted_at = Some(now);
+        request.sla_deadline_seconds = sla_deadline_seconds;
+        request.sla_bond = sla_bond;
+
+        // Track how quickly the provider picks up work
+        let profile = &mut ctx.accounts.provider_profile;
+        profile.last_active_at = now;
+        let response_time = now.saturating_sub(request.created_at).max(0) as u64;
+        profile.total_response_time = profile
+            .total_response_time
+            .checked_add(response_time)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        profile.response_samples = profile
+            .response_samples
+            .checked_add(1)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+
+        msg!("Service request accepted by {}", request.provider);
+        emit!(ServiceAccepted {
+            request_id: request.id,
+            provider: request.provider,
+            accepted_at: now,
+            average_response_seconds: profile.average_response_seconds(),
+        });
+
+        Ok(())
+    }
+
+    /// Cancel a pending service request and refund the escrow (requester side)
+    pub fn cancel_service_request(ctx: Context<CancelServiceRequest>) -> Result<()> {
+        let request = &mut ctx.accounts.request;
+
+        require!(
+            request.status != RequestStatus::InProgress,
+            AgentFundError::RequestAlreadyAccepted
+        );
+        require!(
+            request.status == RequestStatus::Pending,
+            AgentFundError::RequestNotPending
+        );
+        request.require_principal_in_escrow()?;
+
+        if request.amount > 0 {
+            transfer_from_escrow(
+                &ctx.accounts.escrow,
+                &ctx.accounts.requester.to_account_info(),
+                &ctx.accounts.system_program,
+                request.amount,
+                &[b"request_escrow", request.id.as_ref(), &[request.escrow_bump]],
+            )?;
+        }
+
+        request.status = RequestStatus::Cancelled;
+
+        let provider = &mut ctx.accounts.provider_profile;
+        provider.open_requests = provider.open_requests.saturating_sub(1);
+
+        msg!("Service request cancelled, {} lamports refunded", request.amount);
+        emit!(ServiceCancelled {
+            request_id: request.id,
+            requester: request.requester,
+            refunded: request.amount,
+        });
+
+        Ok(())
+    }
+
+    /// Reclaim the escrow of undelivered work once its dispute window has
+    /// closed (requester side). Disputes take precedence: a request that was
+    /// ever disputed is settled only through its dispute, even if withdrawn
+    pub fn reclaim_service_request(ctx: Context<ReclaimServiceRequest>) -> Result<()> {
+        ctx.accounts.require_refundable()?;
+        let now = Clock::get()?.unix_timestamp;
+        require!(
+            now > ctx.accounts.request.dispute_deadline()?,
+            AgentFundError::ReclaimTooEarly
+        );
+        ctx.accounts.refund()
+    }
+
+    /// Reclaim the escrow of undelivered work from a provider that has
+    /// deactivated, without waiting for the dispute window to close
+    /// Work accepted before the deactivation is still the provider's to
+    /// deliver and goes through the normal reclaim path instead
+    pub fn refund_on_provider_deactivation(ctx: Context<ReclaimServiceRequest>) -> Result<()> {
+        ctx.accounts.require_refundable()?;
+        let profile = &ctx.accounts.provider_profile;
+        require!(!profile.is_active, AgentFundError::ProviderStillActive);
+        if let Some(accepted_at) = ctx.accounts.request.accepted_at {
+            require!(
+                profile.deactivated_at.is_some_and(|at| accepted_at > at),
+                AgentFundError::AcceptedBeforeDeactivation
+            );
+        }
+        ctx.accounts.refund()
+    }
+
+    /// Complete a service request; payment is held for the requester's review
+    pub fn complete_service(
+        ctx: Context<CompleteServiceRequest>,
+        result_hash: [u8; 32],
+        hash_algorithm: Option<HashAlgorithm>,
+    ) -> Result<()> {
+        let request = &ctx.accounts.request;
+        
+        require!(
+            request.status == RequestStatus::Pending || request.status == RequestStatus::InProgress,
+            AgentFundError::RequestNotPending
+        );
+
+        let seq = next_event_seq(&mut ctx.accounts.config)?;
+        record_service_completion(
+            &mut ctx.accounts.request,
+            &mut ctx.accounts.provider_profile,
+            &mut ctx.accounts.stats,
+            result_hash,
+            hash_algorithm,
+            seq,
+        )
+    }
+
+    /// Release a completed request's payment to the provider's treasury
+    /// Callable by anyone once the review period has passed, or earlier if
+    /// the requester signs to approve the result
+    pub fn finalize_completion(ctx: Context<FinalizeCompletion>) -> Result<()> {
+        let approved_early = ctx.accounts.requester.is_signer;
+        ctx.accounts.release(approved_early)
+    }
+
+    /// Complete a request on the word of its designated oracle and release
+    /// payment at once; the attestation stands in for the requester's review
+    pub fn attest_completion(
+        ctx: Context<AttestCompletion>,
+        result_hash: [u8; 32],
+        hash_algorithm: Option<HashAlgorithm>,
+    ) -> Result<()> {
+        let oracle = ctx.accounts.oracle.key();
+        let completion = &mut ctx.accounts.completion;
+        let request = &completion.request;
+
+        require!(
+            request.oracle == Some(oracle),
+            AgentFundError::UnauthorizedOracle
+        );
+        require!(
+            request.status == RequestStatus::Pending || request.status == RequestStatus::InProgress,
+            AgentFundError::RequestNotPending
+        );
+
+        let seq = bump_event_seq(&mut completion.config)?;
+        record_service_completion(
+            &mut completion.request,
+            &mut completion.provider_profile,
+            &mut ctx.accounts.stats,
+            result_hash,
+            hash_algorithm,
+            seq,
+        )?;
+        emit!(CompletionAttested {
+            request_id: completion.request.id,
+            oracle,
+            result_hash,
+        });
+
+        completion.release(true)
+    }
+
+    // === Quote Negotiation ===
+
+    /// Ask a provider for a custom price on a capability before committing
+    pub fn request_quote(
+        ctx: Context<RequestQuote>,
+        rfq_id: [u8; 32],
+        capability: String,
+        spec_hash: [u8; 32],
+    ) -> Result<()> {
+        let provider = &ctx.accounts.provider_profile;
+
+        require!(provider.is_active, AgentFundError::AgentNotActive);
+        require!(provider.accepting_requests, AgentFundError::AgentNotAcceptingRequests);
+        let capability = provider.available_capability(&capability)?;
+
+        let rfq = &mut ctx.accounts.rfq;
+        rfq.id = rfq_id;
+        rfq.requester = ctx.accounts.requester.key();
+        rfq.provider = ctx.accounts.provider_owner.key();
+        rfq.capability = capability.clone();
+        rfq.spec_hash = spec_hash;
+        rfq.price = 0;
+        rfq.valid_until = 0;
+        rfq.status = QuoteStatus::Open;
+        rfq.created_at = Clock::get()?.unix_timestamp;
+
+        msg!("Quote requested: {}", capability);
+        emit!(QuoteRequested {
+            rfq_id,
+            requester: rfq.requester,
+            provider: rfq.provider,
+            capability,
+            spec_hash,
+        });
+
+        Ok(())
+    }
+
+    /// Respond to a quote request with a price, good until `valid_until`
+    /// May be called again to revise the quote until it is accepted
+    pub fn submit_quote(ctx: Context<SubmitQuote>, price: u64, valid_until: i64) -> Result<()> {
+        let rfq = &mut ctx.accounts.rfq;
+
+        require!(
+            matches!(rfq.status, QuoteStatus::Open | QuoteStatus::Quoted),
+            AgentFundError::QuoteClosed
+        );
+        require!(price > 0, AgentFundError::InvalidAmount);
+        let now = Clock::get()?.unix_timestamp;
+        require!(valid_until > now, AgentFundError::InvalidExpiry);
+        require!(
+            valid_until <= now.saturating_add(MAX_FUTURE_SECONDS),
+            AgentFundError::ExpiryTooFar
+        );
+
+        rfq.price = price;
+        rfq.valid_until = valid_until;
+        rfq.status = QuoteStatus::Quoted;
+
+        msg!("Quote submitted: {} lamports", price);
+        emit!(QuoteSubmitted {
+            rfq_id: rfq.id,
+            price,
+            valid_until,
+        });
+
+        Ok(())
+    }
+
+    /// Accept an unexpired quote, escrowing the quoted price in a new
+    /// service request that then follows the normal lifecycle
+    pub fn accept_quote(
+        ctx: Context<AcceptQuote>,
+        request_id: [u8; 32],
+        dispute_window_seconds: Option<i64>,
+        review_period_seconds: Option<i64>,
+    ) -> Result<()> {
+        let rfq = &mut ctx.accounts.rfq;
+
+        require!(rfq.status == QuoteStatus::Quoted, AgentFundError::QuoteNotSubmitted);
+        require!(
+            Clock::get()?.unix_timestamp < rfq.valid_until,
+            AgentFundError::QuoteExpired
+        );
+        require!(
+            ctx.accounts.service.provider_profile.is_active,
+            AgentFundError::AgentNotActive
+        );
+        require!(
+            ctx.accounts.service.provider_profile.accepting_requests,
+            AgentFundError::AgentNotAcceptingRequests
+        );
+
+        rfq.status = QuoteStatus::Accepted;
+        let (rfq_id, price, capability) = (rfq.id, rfq.price, rfq.capability.clone());
+
+        emit!(QuoteAccepted {
+            rfq_id,
+            request_id,
+            price,
+        });
+        ctx.accounts.service.fund(
+            request_id,
+            capability,
+            price,
+            RequestTerms {
+                dispute_window_seconds,
+                review_period_seconds,
+                metadata_uri: None,
+            },
+            *ctx.bumps.get("escrow").unwrap(),
+        )
+    }
+
+    // === Dispute Resolution ===
+
+    /// Initiate a dispute on a service request
+    /// Either requester or provider can initiate within dispute window
+    pub fn initiate_dispute(
+        ctx: Context<InitiateDispute>,
+        reason: String,
+    ) -> Result<()> {
+        let request = &mut ctx.accounts.request;
+        let dispute = &mut ctx.accounts.dispute;
+
+        let fee = ctx.accounts.config.dispute_fee;
+        open_dispute(
+            request,
+            dispute,
+            &mut ctx.accounts.provider_profile,
+            ctx.accounts.initiator.key(),
+            &reason,
+            &ctx.accounts.config,
+            Clock::get()?.unix_timestamp,
+        )?;
+
+        // The fee is staked in the dispute account itself until resolution
+        stake_dispute_fee(
+            &ctx.accounts.initiator,
+            &dispute.to_account_info(),
+            &ctx.accounts.system_program,
+            fee,
+        )?;
+
+        msg!("Dispute initiated for request by {}", dispute.initiator);
+        let seq = bump_event_seq(&mut ctx.accounts.config)?;
+        emit!(DisputeInitiated {
+            request_id: request.id,
+            initiator: dispute.initiator,
+            reason,
+            seq,
+        });
+
+        Ok(())
+    }
+
+    /// Open disputes on several requests between the same requester and
+    /// provider at once. `remaining_accounts` holds `(request, dispute)` pairs,
+    /// each dispute being the uninitialized PDA for its request; the batch
+    /// fails as a whole if any request cannot be disputed.
+    pub fn initiate_disputes_batch<'info>(
+        ctx: Context<'_, '_, '_, 'info, InitiateDisputesBatch<'info>>,
+        reason: String,
+    ) -> Result<()> {
+        let pairs = ctx.remaining_accounts;
+        require!(
+            !pairs.is_empty()
+                && pairs.chunks_exact(2).remainder().is_empty()
+                && pairs.len() / 2 <= MAX_DISPUTE_BATCH_SIZE,
+            AgentFundError::InvalidDisputeBatch
+        );
+        require!(
+            reason.len() <= MAX_DISPUTE_REASON_LENGTH,
+            AgentFundError::DisputeReasonTooLong
+        );
+
+        let initiator = ctx.accounts.initiator.key();
+        let now = Clock::get()?.unix_timestamp;
+        let rent = Rent::get()?.minimum_balance(Dispute::SPACE);
+        let fee = ctx.accounts.config.dispute_fee;
+        let mut parties: Option<(Pubkey, Pubkey)> = None;
+
+        for pair in pairs.chunks(2) {
+            let (request_info, dispute_info) = (&pair[0], &pair[1]);
+            require_keys_eq!(*request_info.owner, crate::ID, AgentFundError::InvalidDisputeBatch);
+            require!(request_info.is_writable, AgentFundError::InvalidDisputeBatch);
+            let mut request = ServiceRequest::try_deserialize(&mut &request_info.try_borrow_data()?[..])?;
+
+            // Every request must involve the initiator and the same counterparty
+            require!(
+                initiator == request.requester || initiator == request.provider,
+                AgentFundError::Unauthorized
+            );
+            let request_parties = (request.requester, request.provider);
+            require!(
+                *parties.get_or_insert(request_parties) == request_parties
+                    && ctx.accounts.provider_profile.owner == request.provider,
+                AgentFundError::DisputeBatchMismatch
+            );
+
+            let (dispute_key, dispute_bump) =
+                Pubkey::find_program_address(&[b"dispute", request.id.as_ref()], &crate::ID);
+            require_keys_eq!(dispute_info.key(), dispute_key, AgentFundError::InvalidDisputeBatch);
+
+            let mut dispute = Dispute::default();
+            open_dispute(
+                &mut request,
+                &mut dispute,
+                &mut ctx.accounts.provider_profile,
+                initiator,
+                &reason,
+                &ctx.accounts.config,
+                now,
+            )?;
+
+            let create_ix = anchor_lang::solana_program::system_instruction::create_account(
+                &initiator,
+                &dispute_key,
+                rent,
+                Dispute::SPACE as u64,
+                &crate::ID,
+            );
+            anchor_lang::solana_program::program::invoke_signed(
+                &create_ix,
+                &[
+                    ctx.accounts.initiator.to_account_info(),
+                    dispute_info.clone(),
+                    ctx.accounts.system_program.to_account_info(),
+                ],
+                &[&[b"dispute", request.id.as_ref(), &[dispute_bump]]],
+            )?;
+            dispute.try_serialize(&mut &mut dispute_info.try_borrow_mut_data()?[..])?;
+            request.try_serialize(&mut &mut request_info.try_borrow_mut_data()?[..])?;
+            stake_dispute_fee(
+                &ctx.accounts.initiator,
+                dispute_info,
+                &ctx.accounts.system_program,
+                fee,
+            )?;
+
+            let seq = bump_event_seq(&mut ctx.accounts.config)?;
+            emit!(DisputeInitiated {
+                request_id: request.id,
+                initiator,
+                reason: reason.clone(),
+                seq,
+            });
+        }
+
+        msg!("{} disputes initiated by {}", pairs.len() / 2, initiator);
+        Ok(())
+    }
+
     /// Resolve a dispute (currently by provider/requester agreement)
     /// In production: could use an arbiter DAO or oracle
     pub fn resolve_dispute(
         ctx: Context<ResolveDispute>,
         resolution: DisputeResolution,
     ) -> Result<()> {
-        let dispute = &mut ctx.accounts.dispute;
-        let request = &mut ctx.accounts.request;
+        let dispute = &mut ctx.accounts.dispute;
+        let request = &mut ctx.accounts.request;
+
+        let awaiting = if dispute.escalated_to.is_some() {
+            DisputeStatus::UnderReview
+        } else {
+            DisputeStatus::Open
+        };
+        require!(dispute.status == awaiting, AgentFundError::DisputeNotOpen);
+        request.require_principal_in_escrow()?;
+
+        let now = Clock::get()?.unix_timestamp;
+
+        // An escalated dispute belongs to the DAO alone, which takes no fee.
+        // Otherwise the designated arbiter is compensated out of the escrow
+        // and may resolve alone; parties resolving between themselves pay no
+        // fee but must both sign, so neither can settle in their own favor
+        let config = &ctx.accounts.config;
+        let arbiter_fee = if let Some(dao) = dispute.escalated_to {
+            require_keys_eq!(ctx.accounts.resolver.key(), dao, AgentFundError::Unauthorized);
+            0
+        } else if ctx.accounts.resolver.key() == config.arbiter {
+            bps_of(request.amount, config.arbiter_fee_bps)?
+        } else {
+            require!(
+                ctx.accounts.requester.is_signer && ctx.accounts.provider.is_signer,
+                AgentFundError::BothPartiesMustSign
+            );
+            0
+        };
+        let (requester_amount, provider_amount) =
+            dispute_payouts(request.amount, arbiter_fee, &resolution)?;
+
+        // A redo carries the provider's share into a follow-up request's
+        // escrow instead of paying it out
+        let redo = matches!(resolution, DisputeResolution::RedoRequest { .. });
+        let provider_share_to = if redo {
+            require!(provider_amount > 0, AgentFundError::InvalidAmount);
+            require!(
+                ctx.accounts.redo_request.is_some() && ctx.accounts.provider_profile.is_some(),
+                AgentFundError::RedoAccountsMissing
+            );
+            ctx.accounts
+                .redo_escrow
+                .as_ref()
+                .ok_or(AgentFundError::RedoAccountsMissing)?
+                .to_account_info()
+        } else {
+            ctx.accounts.provider.to_account_info()
+        };
+        
+        // Apply resolution
+        match resolution {
+            DisputeResolution::RefundRequester => {
+                // Refund full amount to requester
+                request.status = RequestStatus::Refunded;
+                msg!("Dispute resolved: full refund to requester");
+            }
+            DisputeResolution::PayProvider => {
+                // Pay full amount to provider
+                request.status = RequestStatus::Completed;
+                msg!("Dispute resolved: full payment to provider");
+            }
+            DisputeResolution::Split { requester_pct } => {
+                // Split payment based on percentage
+                request.status = RequestStatus::Completed;
+                msg!("Dispute resolved: {}% to requester, {}% to provider", 
+                     requester_pct, 100 - requester_pct);
+            }
+            DisputeResolution::PartialRefund { refund_amount } => {
+                // Refund an exact amount, remainder to provider
+                request.status = RequestStatus::Completed;
+                msg!("Dispute resolved: {} lamports refunded to requester", refund_amount);
+            }
+            DisputeResolution::RedoRequest { provider_pct } => {
+                // Refund the requester's share, provider's share funds a redo
+                request.status = RequestStatus::Completed;
+                msg!("Dispute resolved: {}% carried to a redo request", provider_pct);
+            }
+        }
+
+        // A disputed request was never finalized, so any SLA bond is still in
+        // escrow and goes back to the provider; SLA timeliness is only judged
+        // at finalization
+        let bond_refund = request.sla_bond;
+
+        // The initiator wins if they come out with at least as much as the
+        // counterparty: RefundRequester is a win for the requester, PayProvider
+        // for the provider, and a Split or PartialRefund for whichever side
+        // gets half or more (ties favor the initiator). A winner gets their
+        // dispute fee back; a loser forfeits it to the counterparty
+        let initiator_is_requester = dispute.initiator == request.requester;
+        let initiator_won = if initiator_is_requester {
+            requester_amount >= provider_amount
+        } else {
+            provider_amount >= requester_amount
+        };
+        let fee_to = if initiator_won == initiator_is_requester {
+            ctx.accounts.requester.to_account_info()
+        } else {
+            ctx.accounts.provider.to_account_info()
+        };
+
+        // Disburse escrow
+        let escrow_seeds: &[&[u8]] = &[b"request_escrow", request.id.as_ref(), &[request.escrow_bump]];
+        for (recipient, amount) in [
+            (ctx.accounts.resolver.to_account_info(), arbiter_fee),
+            (ctx.accounts.requester.to_account_info(), requester_amount),
+            (provider_share_to, provider_amount),
+            (ctx.accounts.provider.to_account_info(), bond_refund),
+        ] {
+            if amount > 0 {
+                transfer_from_escrow(
+                    &ctx.accounts.escrow,
+                    &recipient,
+                    &ctx.accounts.system_program,
+                    amount,
+                    escrow_seeds,
+                )?;
+            }
+        }
+
+        // The dispute account is program-owned, so its staked fee moves directly
+        let dispute_fee = dispute.fee;
+        if dispute_fee > 0 {
+            let dispute_info = dispute.to_account_info();
+            **dispute_info.try_borrow_mut_lamports()? = dispute_info
+                .lamports()
+                .checked_sub(dispute_fee)
+                .ok_or(AgentFundError::ArithmeticOverflow)?;
+            **fee_to.try_borrow_mut_lamports()? = fee_to
+                .lamports()
+                .checked_add(dispute_fee)
+                .ok_or(AgentFundError::ArithmeticOverflow)?;
+        }
+
+        dispute.status = DisputeStatus::Resolved;
+        dispute.resolved_at = Some(now);
+        dispute.resolution = Some(resolution.clone());
+
+        let seq = bump_event_seq(&mut ctx.accounts.config)?;
+        emit!(DisputeResolved {
+            request_id: request.id,
+            resolution,
+            requester_amount,
+            provider_amount,
+            arbiter_fee,
+            dispute_fee,
+            initiator_won,
+            seq,
+        });
+
+        if redo {
+            let redo_request = ctx.accounts.redo_request.as_mut().unwrap();
+            redo_request.id = redo_request_id(&request.id);
+            redo_request.requester = request.requester;
+            redo_request.provider = request.provider;
+            redo_request.capability = request.capability.clone();
+            redo_request.amount = provider_amount;
+            redo_request.status = RequestStatus::Pending;
+            redo_request.created_at = now;
+            redo_request.completed_at = None;
+            redo_request.result_hash = None;
+            redo_request.dispute_window_seconds = request.dispute_window_seconds;
+            redo_request.accepted_at = None;
+            redo_request.sla_deadline_seconds = None;
+            redo_request.sla_bond = 0;
+            redo_request.review_period_seconds = request.review_period_seconds;
+            redo_request.escrow_bump = *ctx.bumps.get("redo_escrow").unwrap();
+            redo_request.yield_principal = 0;
+            redo_request.oracle = request.oracle;
+            redo_request.parent_request = Some(request.key());
+            redo_request.bundle = request.bundle.clone();
+            redo_request.hash_algorithm = HashAlgorithm::default();
+            redo_request.metadata_uri = request.metadata_uri.clone();
+            redo_request.provider_index = request.provider_index;
+
+            let provider_profile = ctx.accounts.provider_profile.as_mut().unwrap();
+            provider_profile.open_requests += 1;
+
+            let seq = bump_event_seq(&mut ctx.accounts.config)?;
+            emit!(RedoRequestCreated {
+                parent_request_id: request.id,
+                request_id: redo_request.id,
+                amount: provider_amount,
+                seq,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Withdraw an open dispute, e.g. after the parties settle off-chain
+    /// The request returns to the status it had when disputed and the
+    /// initiator's staked fee is refunded
+    pub fn cancel_dispute(ctx: Context<CancelDispute>) -> Result<()> {
+        let dispute = &mut ctx.accounts.dispute;
+        let request = &mut ctx.accounts.request;
+
+        require!(
+            dispute.status == DisputeStatus::Open,
+            AgentFundError::DisputeNotOpen
+        );
+
+        // Work that stopped counting as open when disputed counts again
+        request.status = dispute.prior_status.clone();
+        if request.status != RequestStatus::PendingReview {
+            let provider = &mut ctx.accounts.provider_profile;
+            provider.open_requests = provider.open_requests.saturating_add(1);
+        }
+
+        let fee_refunded = dispute.fee;
+        if fee_refunded > 0 {
+            let dispute_info = dispute.to_account_info();
+            let initiator_info = ctx.accounts.initiator.to_account_info();
+            **dispute_info.try_borrow_mut_lamports()? = dispute_info
+                .lamports()
+                .checked_sub(fee_refunded)
+                .ok_or(AgentFundError::ArithmeticOverflow)?;
+            **initiator_info.try_borrow_mut_lamports()? = initiator_info
+                .lamports()
+                .checked_add(fee_refunded)
+                .ok_or(AgentFundError::ArithmeticOverflow)?;
+        }
+
+        dispute.status = DisputeStatus::Withdrawn;
+        dispute.resolved_at = Some(Clock::get()?.unix_timestamp);
+
+        emit!(DisputeWithdrawn {
+            request_id: request.id,
+            initiator: dispute.initiator,
+            restored_status: request.status.clone(),
+            fee_refunded,
+        });
+
+        Ok(())
+    }
+
+    /// Hand an open dispute to the configured DAO once it has stayed open
+    /// past `dispute_escalation_seconds`. Only the disputing parties may
+    /// escalate; from then on only the DAO's authority can resolve it, by
+    /// calling `resolve_dispute` (typically via CPI signed by its PDA)
+    pub fn escalate_dispute(ctx: Context<EscalateDispute>) -> Result<()> {
+        let dispute = &mut ctx.accounts.dispute;
+        let request = &ctx.accounts.request;
+        let config = &ctx.accounts.config;
+
+        require!(
+            dispute.status == DisputeStatus::Open,
+            AgentFundError::DisputeNotOpen
+        );
+        let party = ctx.accounts.party.key();
+        require!(
+            party == request.requester || party == request.provider,
+            AgentFundError::Unauthorized
+        );
+        let dao = config.dispute_dao.ok_or(AgentFundError::DisputeDaoNotConfigured)?;
+        let now = Clock::get()?.unix_timestamp;
+        require!(
+            now >= dispute.created_at.saturating_add(config.dispute_escalation_seconds),
+            AgentFundError::EscalationTooEarly
+        );
+
+        dispute.status = DisputeStatus::UnderReview;
+        dispute.escalated_to = Some(dao);
+
+        emit!(DisputeEscalated {
+            request_id: request.id,
+            escalated_by: party,
+            escalated_to: dao,
+        });
+
+        Ok(())
+    }
+
+    /// Give an open or escalated dispute more review time
+    /// Either party or the arbiter may extend, up to `MAX_DISPUTE_REVIEW_SECONDS`
+    /// after the dispute was opened
+    pub fn extend_dispute_review(ctx: Context<ExtendDisputeReview>, additional_seconds: i64) -> Result<()> {
+        let dispute = &mut ctx.accounts.dispute;
+        let request = &ctx.accounts.request;
+
+        require!(
+            matches!(dispute.status, DisputeStatus::Open | DisputeStatus::UnderReview),
+            AgentFundError::DisputeNotOpen
+        );
+        let party = ctx.accounts.party.key();
+        require!(
+            party == request.requester || party == request.provider || party == ctx.accounts.config.arbiter,
+            AgentFundError::Unauthorized
+        );
+        require!(additional_seconds > 0, AgentFundError::InvalidDisputeReview);
+        let review_deadline = dispute
+            .review_deadline
+            .checked_add(additional_seconds)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        require!(
+            review_deadline <= dispute.created_at.saturating_add(MAX_DISPUTE_REVIEW_SECONDS),
+            AgentFundError::InvalidDisputeReview
+        );
+        dispute.review_deadline = review_deadline;
+
+        emit!(DisputeReviewExtended {
+            request_id: request.id,
+            extended_by: party,
+            review_deadline,
+        });
+
+        Ok(())
+    }
+
+    // === Shared Escrow Vault ===
+
+    /// Create a provider's shared vault, holding escrow for many requests in
+    /// one account instead of a rent-paying escrow PDA per request
+    pub fn initialize_vault(ctx: Context<InitializeVault>) -> Result<()> {
+        let vault = &mut ctx.accounts.vault;
+        vault.provider = ctx.accounts.provider.key();
+        vault.bump = *ctx.bumps.get("vault").unwrap();
+        vault.total_held = 0;
+        vault.entries = Vec::new();
+
+        msg!("Vault initialized for provider: {}", vault.provider);
+        Ok(())
+    }
+
+    /// Escrow funds for an open request in its provider's vault
+    /// Only the request's requester deposits, once per request
+    pub fn vault_deposit(
+        ctx: Context<VaultDeposit>,
+        request_id: [u8; 32],
+        amount: u64,
+    ) -> Result<()> {
+        require!(amount >= MIN_VAULT_DEPOSIT, AgentFundError::VaultDepositTooSmall);
+        require!(
+            matches!(
+                ctx.accounts.request.status,
+                RequestStatus::Pending | RequestStatus::InProgress | RequestStatus::PendingReview
+            ),
+            AgentFundError::RequestAlreadySettled
+        );
+
+        let vault = &mut ctx.accounts.vault;
+        require!(
+            !vault.entries.iter().any(|entry| entry.request_id == request_id),
+            AgentFundError::DuplicateVaultEntry
+        );
+        require!(vault.entries.len() < MAX_VAULT_ENTRIES, AgentFundError::VaultFull);
+
+        let transfer_ix = anchor_lang::solana_program::system_instruction::transfer(
+            &ctx.accounts.depositor.key(),
+            &vault.key(),
+            amount,
+        );
+        anchor_lang::solana_program::program::invoke(
+            &transfer_ix,
+            &[
+                ctx.accounts.depositor.to_account_info(),
+                vault.to_account_info(),
+                ctx.accounts.system_program.to_account_info(),
+            ],
+        )?;
+
+        vault.total_held = vault
+            .total_held
+            .checked_add(amount)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        vault.entries.push(VaultEntry {
+            request_id,
+            depositor: ctx.accounts.depositor.key(),
+            amount,
+            deposited_at: Clock::get()?.unix_timestamp,
+        });
+
+        emit!(VaultDeposited {
+            provider: vault.provider,
+            request_id,
+            depositor: ctx.accounts.depositor.key(),
+            amount,
+        });
+
+        Ok(())
+    }
+
+    /// Release a request's escrowed funds from the vault to the provider
+    /// Only the depositor can release; other entries are untouched
+    pub fn vault_release(ctx: Context<VaultRelease>, request_id: [u8; 32]) -> Result<()> {
+        let vault = &mut ctx.accounts.vault;
+        let entry = vault.take_entry(&request_id, &ctx.accounts.depositor.key())?;
+
+        pay_from_vault(&vault.to_account_info(), &ctx.accounts.provider, entry.amount)?;
+
+        emit!(VaultReleased {
+            provider: vault.provider,
+            request_id,
+            amount: entry.amount,
+        });
+
+        Ok(())
+    }
+
+    /// Return a request's escrowed funds from the vault to the depositor
+    /// Allowed once the request was cancelled, refunded or recovered, or when
+    /// the entry is older than `VAULT_ENTRY_EXPIRY_SECONDS` and the work was
+    /// never completed
+    pub fn vault_refund(ctx: Context<VaultRefund>, request_id: [u8; 32]) -> Result<()> {
+        let request = &ctx.accounts.request;
+        let vault = &mut ctx.accounts.vault;
+        let entry = vault.take_entry(&request_id, &ctx.accounts.depositor.key())?;
+
+        let expires_at = entry
+            .deposited_at
+            .checked_add(VAULT_ENTRY_EXPIRY_SECONDS)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        let expired = request.completed_at.is_none() && Clock::get()?.unix_timestamp >= expires_at;
+        require!(
+            expired
+                || matches!(
+                    request.status,
+                    RequestStatus::Cancelled | RequestStatus::Refunded | RequestStatus::Recovered
+                ),
+            AgentFundError::VaultEntryNotRefundable
+        );
+
+        pay_from_vault(
+            &vault.to_account_info(),
+            &ctx.accounts.depositor.to_account_info(),
+            entry.amount,
+        )?;
+
+        emit!(VaultRefunded {
+            provider: vault.provider,
+            request_id,
+            depositor: entry.depositor,
+            amount: entry.amount,
+        });
+
+        Ok(())
+    }
+
+    // === Protocol Administration ===
+
+    /// Create the protocol-wide configuration singleton
+    /// Only the program's upgrade authority can create it, becoming its admin
+    pub fn initialize_protocol_config(
+        ctx: Context<InitializeProtocolConfig>,
+        arbiter: Pubkey,
+        arbiter_fee_bps: u16,
+    ) -> Result<()> {
+        require!(arbiter_fee_bps <= MAX_BPS, AgentFundError::InvalidFeeBps);
+
+        let config = &mut ctx.accounts.config;
+        config.admin = ctx.accounts.admin.key();
+        config.arbiter = arbiter;
+        config.arbiter_fee_bps = arbiter_fee_bps;
+        config.bump = *ctx.bumps.get("config").unwrap();
+        config.event_seq = 0;
+        config.max_memo_length = MAX_MEMO_LENGTH as u16;
+        config.invoice_fee_bps = 0;
+        config.fee_collector = config.admin;
+        config.channel_close_fee_bps = 0;
+        config.escrow_recovery_seconds = DEFAULT_ESCROW_RECOVERY_SECONDS;
+        config.completion_release_delay_seconds = 0;
+        config.dispute_fee = 0;
+        config.min_invoice_amount = 0;
+        config.yield_strategy = None;
+        config.channel_idle_reclaim_seconds = DEFAULT_CHANNEL_IDLE_RECLAIM_SECONDS;
+        config.dispute_dao = None;
+        config.dispute_escalation_seconds = DEFAULT_DISPUTE_ESCALATION_SECONDS;
+        config.min_channel_deposit = 0;
+        config.fee_tier_thresholds = [0; FEE_TIERS];
+        config.fee_tier_discount_bps = [0; FEE_TIERS];
+        config.staleness_threshold_seconds = DEFAULT_STALENESS_THRESHOLD_SECONDS;
+        config.dispute_review_seconds = DEFAULT_DISPUTE_REVIEW_SECONDS;
+
+        msg!("Protocol config initialized, admin: {}", config.admin);
+        emit!(ProtocolConfigUpdated {
+            admin: config.admin,
+            arbiter,
+            arbiter_fee_bps,
+            max_memo_length: config.max_memo_length,
+            invoice_fee_bps: config.invoice_fee_bps,
+            fee_collector: config.fee_collector,
+            channel_close_fee_bps: config.channel_close_fee_bps,
+            escrow_recovery_seconds: config.escrow_recovery_seconds,
+            completion_release_delay_seconds: config.completion_release_delay_seconds,
+            dispute_fee: config.dispute_fee,
+            min_invoice_amount: config.min_invoice_amount,
+            yield_strategy: config.yield_strategy,
+            channel_idle_reclaim_seconds: config.channel_idle_reclaim_seconds,
+            dispute_dao: config.dispute_dao,
+            dispute_escalation_seconds: config.dispute_escalation_seconds,
+            min_channel_deposit: config.min_channel_deposit,
+            fee_tier_thresholds: config.fee_tier_thresholds,
+            fee_tier_discount_bps: config.fee_tier_discount_bps,
+            staleness_threshold_seconds: config.staleness_threshold_seconds,
+            dispute_review_seconds: config.dispute_review_seconds,
+        });
+
+        Ok(())
+    }
+
+    /// Update protocol configuration (admin only)
+    pub fn update_protocol_config(
+        ctx: Context<UpdateProtocolConfig>,
+        params: ProtocolConfigParams,
+    ) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+
+        if let Some(admin) = params.admin {
+            config.admin = admin;
+        }
+        if let Some(arbiter) = params.arbiter {
+            config.arbiter = arbiter;
+        }
+        if let Some(bps) = params.arbiter_fee_bps {
+            require!(bps <= MAX_BPS, AgentFundError::InvalidFeeBps);
+            config.arbiter_fee_bps = bps;
+        }
+        if let Some(max_memo_length) = params.max_memo_length {
+            require!(
+                max_memo_length > 0 && max_memo_length <= MAX_MEMO_LENGTH_CEILING,
+                AgentFundError::InvalidMemoLimit
+            );
+            config.max_memo_length = max_memo_length;
+        }
+        if let Some(bps) = params.invoice_fee_bps {
+            require!(bps < MAX_BPS, AgentFundError::InvalidFeeBps);
+            config.invoice_fee_bps = bps;
+        }
+        if let Some(fee_collector) = params.fee_collector {
+            config.fee_collector = fee_collector;
+        }
+        if let Some(bps) = params.channel_close_fee_bps {
+            require!(bps < MAX_BPS, AgentFundError::InvalidFeeBps);
+            config.channel_close_fee_bps = bps;
+        }
+        if let Some(seconds) = params.escrow_recovery_seconds {
+            require!(seconds >= MIN_ESCROW_RECOVERY_SECONDS, AgentFundError::RecoveryPeriodTooShort);
+            config.escrow_recovery_seconds = seconds;
+        }
+        if let Some(seconds) = params.completion_release_delay_seconds {
+            require!(
+                (0..=MAX_REVIEW_PERIOD_SECONDS).contains(&seconds),
+                AgentFundError::InvalidReviewPeriod
+            );
+            config.completion_release_delay_seconds = seconds;
+        }
+        if let Some(fee) = params.dispute_fee {
+            config.dispute_fee = fee;
+        }
+        if let Some(amount) = params.min_invoice_amount {
+            config.min_invoice_amount = amount;
+        }
+        if let Some(strategy) = params.yield_strategy {
+            config.yield_strategy = (strategy != Pubkey::default()).then_some(strategy);
+        }
+        if let Some(seconds) = params.channel_idle_reclaim_seconds {
+            require!(seconds > 0, AgentFundError::InvalidRecoveryPeriod);
+            config.channel_idle_reclaim_seconds = seconds;
+        }
+        if let Some(dao) = params.dispute_dao {
+            config.dispute_dao = (dao != Pubkey::default()).then_some(dao);
+        }
+        if let Some(seconds) = params.dispute_escalation_seconds {
+            require!(seconds >= 0, AgentFundError::InvalidRecoveryPeriod);
+            config.dispute_escalation_seconds = seconds;
+        }
+        if let Some(amount) = params.min_channel_deposit {
+            config.min_channel_deposit = amount;
+        }
+        if let Some(thresholds) = params.fee_tier_thresholds {
+            let enabled: Vec<u64> = thresholds.iter().copied().filter(|t| *t > 0).collect();
+            require!(
+                enabled.windows(2).all(|pair| pair[0] < pair[1]),
+                AgentFundError::InvalidFeeTiers
+            );
+            config.fee_tier_thresholds = thresholds;
+        }
+        if let Some(discounts) = params.fee_tier_discount_bps {
+            require!(
+                discounts.iter().all(|bps| *bps <= MAX_BPS),
+                AgentFundError::InvalidFeeBps
+            );
+            config.fee_tier_discount_bps = discounts;
+        }
+        if let Some(seconds) = params.staleness_threshold_seconds {
+            require!(seconds > 0, AgentFundError::InvalidRecoveryPeriod);
+            config.staleness_threshold_seconds = seconds;
+        }
+        if let Some(seconds) = params.dispute_review_seconds {
+            require!(
+                seconds > 0 && seconds <= MAX_DISPUTE_REVIEW_SECONDS,
+                AgentFundError::InvalidDisputeReview
+            );
+            config.dispute_review_seconds = seconds;
+        }
+
+        emit!(ProtocolConfigUpdated {
+            admin: config.admin,
+            arbiter: config.arbiter,
+            arbiter_fee_bps: config.arbiter_fee_bps,
+            max_memo_length: config.max_memo_length,
+            invoice_fee_bps: config.invoice_fee_bps,
+            fee_collector: config.fee_collector,
+            channel_close_fee_bps: config.channel_close_fee_bps,
+            escrow_recovery_seconds: config.escrow_recovery_seconds,
+            completion_release_delay_seconds: config.completion_release_delay_seconds,
+            dispute_fee: config.dispute_fee,
+            min_invoice_amount: config.min_invoice_amount,
+            yield_strategy: config.yield_strategy,
+            channel_idle_reclaim_seconds: config.channel_idle_reclaim_seconds,
+            dispute_dao: config.dispute_dao,
+            dispute_escalation_seconds: config.dispute_escalation_seconds,
+            min_channel_deposit: config.min_channel_deposit,
+            fee_tier_thresholds: config.fee_tier_thresholds,
+            fee_tier_discount_bps: config.fee_tier_discount_bps,
+            staleness_threshold_seconds: config.staleness_threshold_seconds,
+            dispute_review_seconds: config.dispute_review_seconds,
+        });
+
+        Ok(())
+    }
+
+    /// Add an agent to the protocol ban list (admin only)
+    /// Banned owners cannot register profiles and their existing profiles
+    /// stop receiving requests. Banning a listed agent again is a no-op
+    pub fn ban_agent(ctx: Context<BanAgent>, agent: Pubkey) -> Result<()> {
+        let list = &mut ctx.accounts.banned_agents;
+        list.bump = *ctx.bumps.get("banned_agents").unwrap();
+        if !list.agents.contains(&agent) {
+            require!(list.agents.len() < MAX_BANNED_AGENTS, AgentFundError::BanListFull);
+            list.agents.push(agent);
+        }
+
+        msg!("Agent banned: {}", agent);
+        emit!(AgentBanStatusChanged { agent, banned: true });
+        Ok(())
+    }
+
+    /// Remove an agent from the protocol ban list (admin only)
+    pub fn unban_agent(ctx: Context<UnbanAgent>, agent: Pubkey) -> Result<()> {
+        let list = &mut ctx.accounts.banned_agents;
+        let index = list
+            .agents
+            .iter()
+            .position(|banned| *banned == agent)
+            .ok_or(AgentFundError::AgentNotBanned)?;
+        list.agents.swap_remove(index);
+
+        msg!("Agent unbanned: {}", agent);
+        emit!(AgentBanStatusChanged { agent, banned: false });
+        Ok(())
+    }
+
+    /// Return a request escrow that has been stuck past the recovery period
+    /// (admin only). The payment goes back to the requester and any SLA bond
+    /// to the provider. Only escrow with no other way out qualifies: a stalled
+    /// dispute, or open work that was once disputed and so can no longer be
+    /// reclaimed. Settled requests, requests in review (released to the
+    /// provider by `finalize_completion`) and requests accepted, completed or
+    /// disputed within the period are never touched.
+    pub fn recover_stuck_escrow(ctx: Context<RecoverStuckEscrow>) -> Result<()> {
+        let request = &ctx.accounts.request;
+        let dispute_info = ctx.accounts.dispute.to_account_info();
+        let was_disputed = !dispute_info.data_is_empty();
+
+        require!(
+            match request.status {
+                RequestStatus::Disputed => true,
+                RequestStatus::Pending | RequestStatus::InProgress => was_disputed,
+                _ => false,
+            },
+            AgentFundError::EscrowNotStuck
+        );
+
+        let mut last_activity = request.last_activity();
+        if was_disputed {
+            let dispute = Account::<Dispute>::try_from(&dispute_info)?;
+            last_activity = last_activity
+                .max(dispute.created_at)
+                .max(dispute.resolved_at.unwrap_or(0));
+        }
+
+        let now = Clock::get()?.unix_timestamp;
+        let recovery_seconds = ctx
+            .accounts
+            .config
+            .escrow_recovery_seconds
+            .max(MIN_ESCROW_RECOVERY_SECONDS);
+        let recoverable_at = last_activity
+            .checked_add(recovery_seconds)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        require!(now >= recoverable_at, AgentFundError::EscrowNotStuck);
+
+        request.require_principal_in_escrow()?;
+        let held = ctx.accounts.escrow.lamports();
+        require!(held > 0, AgentFundError::EscrowNotStuck);
+        let bond = request.sla_bond.min(held);
+        let refunded = held - bond;
+
+        let escrow_seeds: &[&[u8]] = &[b"request_escrow", request.id.as_ref(), &[request.escrow_bump]];
+        for (recipient, amount) in [
+            (ctx.accounts.requester.to_account_info(), refunded),
+            (ctx.accounts.provider.to_account_info(), bond),
+        ] {
+            if amount > 0 {
+                transfer_from_escrow(
+                    &ctx.accounts.escrow,
+                    &recipient,
+                    &ctx.accounts.system_program,
+                    amount,
+                    escrow_seeds,
+                )?;
+            }
+        }
+
+        // Open work stops counting against the provider's capacity; disputed
+        // requests already stopped counting when the dispute opened
+        let request = &mut ctx.accounts.request;
+        if matches!(request.status, RequestStatus::Pending | RequestStatus::InProgress) {
+            let provider = &mut ctx.accounts.provider_profile;
+            provider.open_requests = provider.open_requests.saturating_sub(1);
+        }
+        request.status = RequestStatus::Recovered;
+
+        msg!("Recovered {} lamports from stuck escrow", held);
+        emit!(StuckEscrowRecovered {
+            request_id: request.id,
+            requester: request.requester,
+            amount: refunded,
+            bond_returned: bond,
+        });
+
+        Ok(())
+    }
+
+    /// Route instructions that only exist in feature builds. Without any
+    /// such feature this fails exactly like Anchor's default fallback, so
+    /// unknown instructions keep their error code
+    pub fn fallback(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> Result<()> {
+        #[cfg(feature = "yield-routing")]
+        if let Some(result) = crate::yield_routing::dispatch(program_id, accounts, data) {
+            return result;
+        }
+        let _ = (program_id, accounts, data);
+        Err(anchor_lang::error::ErrorCode::InstructionFallbackNotFound.into())
+    }
+}
+
+// === Helpers ===
+
+/// Record a treasury counter change so indexers can rebuild a full ledger
+/// Call after every mutation of `total_received` or `total_settled`
+fn emit_ledger_entry(treasury: &Account<Treasury>, kind: LedgerEntryKind, delta: u64) -> Result<()> {
+    emit!(TreasuryLedgerEntry {
+        treasury: treasury.key(),
+        kind,
+        delta,
+        new_total_received: treasury.total_received,
+        new_total_settled: treasury.total_settled,
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+    Ok(())
+}
+
+/// Emit one `PartyChannelRegistered` per party so each can find the channel
+/// by filtering on their own key
+fn emit_party_channel_registered(channel: &PaymentChannel) {
+    for (party, counterparty) in [
+        (channel.party_a, channel.party_b),
+        (channel.party_b, channel.party_a),
+    ] {
+        emit!(PartyChannelRegistered {
+            party,
+            counterparty,
+            channel_id: channel.id,
+        });
+    }
+}
+
+/// Emit one `PartyChannelClosed` per party with their final balance
+fn emit_party_channel_closed(channel: &PaymentChannel, final_balance_a: u64, final_balance_b: u64) {
+    for (party, counterparty, final_balance) in [
+        (channel.party_a, channel.party_b, final_balance_a),
+        (channel.party_b, channel.party_a, final_balance_b),
+    ] {
+        emit!(PartyChannelClosed {
+            party,
+            counterparty,
+            channel_id: channel.id,
+            final_balance,
+        });
+    }
+}
+
+/// Move the initiator's dispute fee into the dispute account
+fn stake_dispute_fee<'info>(
+    initiator: &Signer<'info>,
+    dispute: &AccountInfo<'info>,
+    system_program: &Program<'info, System>,
+    fee: u64,
+) -> Result<()> {
+    if fee == 0 {
+        return Ok(());
+    }
+    let transfer_ix = anchor_lang::solana_program::system_instruction::transfer(
+        &initiator.key(),
+        &dispute.key(),
+        fee,
+    );
+    anchor_lang::solana_program::program::invoke(
+        &transfer_ix,
+        &[
+            initiator.to_account_info(),
+            dispute.clone(),
+            system_program.to_account_info(),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Validate that a request can be disputed and record the dispute against it
+fn open_dispute(
+    request: &mut ServiceRequest,
+    dispute: &mut Dispute,
+    provider: &mut AgentProfile,
+    initiator: Pubkey,
+    reason: &str,
+    config: &ProtocolConfig,
+    now: i64,
+) -> Result<()> {
+    require!(
+        matches!(
+            request.status,
+            RequestStatus::Pending | RequestStatus::InProgress | RequestStatus::PendingReview
+        ),
+        AgentFundError::CannotDispute
+    );
+
+    // Finalized requests have already been paid out of escrow, so only
+    // work still under way or in review can be disputed; open work no
+    // longer counts as open for the provider
+    if request.status != RequestStatus::PendingReview {
+        provider.open_requests = provider.open_requests.saturating_sub(1);
+    }
+
+    // Must be within the request's dispute window after creation/completion;
+    // reclaiming opens only once it has closed, so the two never overlap
+    require!(now <= request.dispute_deadline()?, AgentFundError::DisputeWindowClosed);
+
+    // Update request status, remembering it in case the dispute is withdrawn
+    dispute.prior_status = request.status.clone();
+    request.status = RequestStatus::Disputed;
+
+    // Initialize dispute
+    dispute.request_id = request.id;
+    dispute.initiator = initiator;
+    dispute.reason = reason.to_string();
+    dispute.status = DisputeStatus::Open;
+    dispute.created_at = now;
+    dispute.resolved_at = None;
+    dispute.resolution = None;
+    dispute.fee = config.dispute_fee;
+    dispute.escalated_to = None;
+    dispute.review_deadline = now
+        .checked_add(config.dispute_review_seconds)
+        .ok_or(AgentFundError::ArithmeticOverflow)?;
+
+    Ok(())
+}
+
+/// Initialize a fresh treasury; shared by every path that creates one
+fn init_treasury(treasury: &mut Treasury, owner: Pubkey, bump: u8, now: i64) {
+    treasury.owner = owner;
+    treasury.bump = bump;
+    treasury.total_received = 0;
+    treasury.total_settled = 0;
+    treasury.pending_invoices = 0;
+    treasury.created_at = now;
+    treasury.authorized_settlers = Vec::new();
+}
+
+/// Require a submitted channel state to be strictly newer than the highest seen
+///
+/// `PaymentChannel.nonce` always holds the highest nonce the program has accepted.
+/// Every signed off-chain state carries a nonce, so without this check a party
+/// could replay an older (but validly signed) state that favoured them, e.g.
+/// submitting nonce 3 after nonce 7 moved funds to the counterparty. Gaps are
+/// allowed (parties need not post every intermediate state), replays are not.
+fn require_newer_nonce(highest_seen: u64, submitted: u64) -> Result<()> {
+    require!(submitted > highest_seen, AgentFundError::InvalidNonce);
+    Ok(())
+}
+
+/// Require `signer`'s ed25519 signature over the `state_commitment` of the
+/// channel state `(balance_a, balance_b, nonce)`
+fn require_channel_state_signature(
+    channel: &PaymentChannel,
+    instructions: &AccountInfo,
+    signer: &Pubkey,
+    balance_a: u64,
+    balance_b: u64,
+    nonce: u64,
+) -> Result<()> {
+    require!(
+        ed25519_signature_matches(instructions, signer, &channel.commitment_for(balance_a, balance_b, nonce))?,
+        AgentFundError::InvalidCounterpartySignature
+    );
+    Ok(())
+}
+
+/// Require final channel balances plus any fee to account for every deposited lamport
+fn require_balances_conserved(
+    channel: &PaymentChannel,
+    balance_a: u64,
+    balance_b: u64,
+    fee: u64,
+) -> Result<()> {
+    let total_deposits = channel
+        .deposit_a
+        .checked_add(channel.deposit_b)
+        .ok_or(AgentFundError::ArithmeticOverflow)?;
+    let total_out = balance_a
+        .checked_add(balance_b)
+        .and_then(|sum| sum.checked_add(fee))
+        .ok_or(AgentFundError::ArithmeticOverflow)?;
+    if total_out != total_deposits {
+        return Err(amount_error(AgentFundError::BalanceMismatch, total_deposits, total_out));
+    }
+    Ok(())
+}
+
+/// Render lamports as a decimal SOL amount without trailing zeros
+fn format_sol(lamports: u64) -> String {
+    let whole = lamports / LAMPORTS_PER_SOL;
+    let fraction = lamports % LAMPORTS_PER_SOL;
+    if fraction == 0 {
+        return whole.to_string();
+    }
+    let fraction = format!("{:09}", fraction);
+    format!("{}.{}", whole, fraction.trim_end_matches('0'))
+}
+
+/// Percent-encode everything but RFC 3986 unreserved characters
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Log the amounts behind a failed comparison in a parseable
+/// `error_context:` line, then return `error`
+/// Clients building retry logic read the line from the transaction logs
+fn amount_error(error: AgentFundError, expected: u64, actual: u64) -> Error {
+    msg!(
+        "error_context: error={} expected={} actual={}",
+        error.name(),
+        expected,
+        actual
+    );
+    error!(error)
+}
+
+/// Require `payer` to cover `amount` while staying rent exempt, so an
+/// underfunded wallet gets a specific error (with the shortfall logged)
+/// instead of an opaque system program failure
+fn require_payer_balance(payer: &AccountInfo, amount: u64) -> Result<()> {
+    let reserve = Rent::get()?.minimum_balance(payer.data_len());
+    let available = payer.lamports().saturating_sub(reserve);
+    if available < amount {
+        msg!("Payer short by {} lamports", amount - available);
+        return Err(amount_error(AgentFundError::InsufficientPayerBalance, amount, available));
+    }
+    Ok(())
+}
+
+/// Mark the invoice held in `account` as paid by `payer`, returning its amount
+/// Invoices arrive as remaining accounts, so the account is checked against
+/// the expected id and recipient before anything is trusted. The status is
+/// written back immediately so an invoice repeated in the same call fails
+/// the pending check on its second appearance
+fn mark_invoice_paid(
+    account: &AccountInfo,
+    invoice_id: &[u8; 32],
+    recipient: &Pubkey,
+    payer: &Pubkey,
+    now: i64,
+) -> Result<u64> {
+    require_keys_eq!(*account.owner, crate::ID, AgentFundError::InvalidInvoiceAccount);
+    require!(account.is_writable, AgentFundError::InvalidInvoiceAccount);
+    let mut invoice = Invoice::try_deserialize(&mut &account.try_borrow_data()?[..])?;
+    require!(invoice.id == *invoice_id, AgentFundError::InvalidInvoiceAccount);
+    require_keys_eq!(invoice.recipient, *recipient, AgentFundError::InvalidInvoiceAccount);
+    require!(
+        invoice.status != InvoiceStatus::Expired && now < invoice.expires_at,
+        AgentFundError::InvoiceExpired
+    );
+    require!(
+        invoice.status == InvoiceStatus::Pending,
+        AgentFundError::InvoiceNotPending
+    );
+    invoice.require_allowed_payer(payer)?;
+    invoice.require_dependency_paid(None)?;
+    invoice.require_unsplit()?;
+
+    invoice.status = InvoiceStatus::Paid;
+    invoice.paid_at = Some(now);
+    invoice.payer = Some(*payer);
+    invoice.try_serialize(&mut &mut account.try_borrow_mut_data()?[..])?;
+    Ok(invoice.amount)
+}
+
+/// Return an invoice settled by `batch` to `Pending`
+/// Only invoices the batch recorded at settlement qualify; one repeated in
+/// the same call is no longer `Paid` on its second appearance
+fn reopen_batch_invoice(account: &AccountInfo, batch: &BatchSettlement) -> Result<()> {
+    require_keys_eq!(*account.owner, crate::ID, AgentFundError::InvalidInvoiceAccount);
+    require!(account.is_writable, AgentFundError::InvalidInvoiceAccount);
+    let mut invoice = Invoice::try_deserialize(&mut &account.try_borrow_data()?[..])?;
+    require!(
+        invoice.status == InvoiceStatus::Paid
+            && invoice.recipient == batch.recipient
+            && batch.invoice_ids.contains(&invoice.id),
+        AgentFundError::InvalidInvoiceAccount
+    );
+
+    invoice.status = InvoiceStatus::Pending;
+    invoice.paid_at = None;
+    invoice.payer = None;
+    invoice.try_serialize(&mut &mut account.try_borrow_mut_data()?[..])?;
+    Ok(())
+}
+
+/// Advance the protocol-wide event sequence and return the new value
+fn bump_event_seq(config: &mut ProtocolConfig) -> Result<u64> {
+    config.event_seq = config.event_seq
+        .checked_add(1)
+        .ok_or(AgentFundError::ArithmeticOverflow)?;
+    Ok(config.event_seq)
+}
+
+/// Move a delivered request into review and count it for its provider and
+/// the marketplace; shared by provider completion and oracle attestation
+fn record_service_completion(
+    request: &mut ServiceRequest,
+    profile: &mut AgentProfile,
+    stats: &mut Option<Account<MarketplaceStats>>,
+    result_hash: [u8; 32],
+    hash_algorithm: Option<HashAlgorithm>,
+    seq: u64,
+) -> Result<()> {
+    let now = Clock::get()?.unix_timestamp;
+    request.status = RequestStatus::PendingReview;
+    request.completed_at = Some(now);
+    request.result_hash = Some(result_hash);
+    request.hash_algorithm = hash_algorithm.unwrap_or_default();
+
+    // The work is delivered, so it no longer counts as open
+    profile.open_requests = profile.open_requests.saturating_sub(1);
+    profile.last_active_at = now;
+    let capability_requests = profile.record_capability_use(&request.capability)?;
+
+    msg!("Service completed, {} lamports held for review", request.amount);
+    emit!(ServiceCompleted {
+        request_id: request.id,
+        provider: request.provider,
+        amount: request.amount,
+        capability: request.capability.clone(),
+        capability_requests,
+        hash_algorithm: request.hash_algorithm.clone(),
+        seq,
+    });
+    update_marketplace_stats(stats, MarketplaceStats::record_completion)
+}
+
+/// Sequence number for an event, or 0 when no config account was supplied
+fn next_event_seq(config: &mut Option<Account<ProtocolConfig>>) -> Result<u64> {
+    match config {
+        Some(config) => bump_event_seq(config),
+        None => Ok(0),
+    }
+}
+
+/// Apply `update` to the marketplace stats when the account was supplied
+/// and announce the new totals
+fn update_marketplace_stats(
+    stats: &mut Option<Account<MarketplaceStats>>,
+    update: impl FnOnce(&mut MarketplaceStats) -> Result<()>,
+) -> Result<()> {
+    let Some(stats) = stats else {
+        return Ok(());
+    };
+    update(stats)?;
+    emit!(MarketplaceStatsUpdated {
+        total_agents: stats.total_agents,
+        total_requests: stats.total_requests,
+        total_volume: stats.total_volume,
+        total_completed: stats.total_completed,
+    });
+    Ok(())
+}
+
+/// Whether `agent` is on the protocol ban list; until the list is first
+/// written it bans no one
+fn is_banned(banned_agents: &AccountInfo, agent: &Pubkey) -> Result<bool> {
+    if banned_agents.data_is_empty() {
+        return Ok(false);
+    }
+    let list = BannedAgents::try_deserialize(&mut &banned_agents.try_borrow_data()?[..])?;
+    Ok(list.agents.contains(agent))
+}
+
+/// Validate a new invoice and create its account at the exact size it needs
+fn open_invoice(ctx: Context<CreateInvoice>, invoice: Invoice) -> Result<()> {
+    require!(
+        invoice.memo.len() <= ctx.accounts.config.max_memo_length as usize,
+        AgentFundError::MemoTooLong
+    );
+    require!(invoice.amount > 0, AgentFundError::InvalidAmount);
+    require!(
+        invoice.amount >= ctx.accounts.config.min_invoice_amount,
+        AgentFundError::AmountBelowMinimum
+    );
+    let now = invoice.created_at;
+    require!(invoice.expires_at > now, AgentFundError::InvalidExpiry);
+    require!(
+        invoice.expires_at <= now.saturating_add(MAX_FUTURE_SECONDS),
+        AgentFundError::ExpiryTooFar
+    );
+
+    // Count the new invoice against the one it depends on, which then has
+    // to stay around until the dependent can be paid
+    if let Some(depends_on) = invoice.depends_on {
+        let dependency = ctx
+            .accounts
+            .dependency
+            .as_mut()
+            .ok_or(AgentFundError::DependencyNotFound)?;
+        require!(dependency.id == depends_on, AgentFundError::DependencyNotFound);
+        dependency.dependents = dependency
+            .dependents
+            .checked_add(1)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+    }
+
+    let space = Invoice::space(invoice.memo.len(), invoice.split_recipients.len());
+
+    // Created by hand rather than with `init` so the rent can come from
+    // a sponsoring `rent_payer` when one signs, else from the recipient
+    let rent_payer = match &ctx.accounts.rent_payer {
+        Some(rent_payer) => rent_payer.to_account_info(),
+        None => ctx.accounts.recipient.to_account_info(),
+    };
+    let invoice_info = ctx.accounts.invoice.to_account_info();
+    require!(invoice_info.data_is_empty(), AgentFundError::InvoiceIdAlreadyExists);
+    let create_ix = anchor_lang::solana_program::system_instruction::create_account(
+        &rent_payer.key(),
+        &invoice_info.key(),
+        Rent::get()?.minimum_balance(space),
+        space as u64,
+        &crate::ID,
+    );
+    anchor_lang::solana_program::program::invoke_signed(
+        &create_ix,
+        &[
+            rent_payer,
+            invoice_info.clone(),
+            ctx.accounts.system_program.to_account_info(),
+        ],
+        &[&[b"invoice", invoice.id.as_ref(), &[*ctx.bumps.get("invoice").unwrap()]]],
+    )?;
+    invoice.try_serialize(&mut &mut invoice_info.try_borrow_mut_data()?[..])?;
+
+    // Update treasury pending count, if the recipient has one
+    let treasury = match &mut ctx.accounts.treasury {
+        Some(treasury) => {
+            treasury.pending_invoices += 1;
+            treasury.key()
+        }
+        None => treasury_address(&invoice.recipient),
+    };
+
+    msg!("Invoice created: {} lamports", invoice.amount);
+    let seq = bump_event_seq(&mut ctx.accounts.config)?;
+    emit_invoice_created(InvoiceCreated {
+        invoice_id: invoice.id,
+        recipient: invoice.recipient,
+        treasury,
+        amount: invoice.amount,
+        expires_at: invoice.expires_at,
+        seq,
+    });
+
+    Ok(())
+}
+
+/// Emit `event`, or only its id and amount in `compact-events` builds
+fn emit_invoice_created(event: InvoiceCreated) {
+    if cfg!(feature = "compact-events") {
+        emit!(InvoiceCreatedCompact {
+            invoice_id: event.invoice_id,
+            amount: event.amount,
+        });
+    } else {
+        emit!(event);
+    }
+}
+
+/// Emit `event`, or only its id and amount in `compact-events` builds
+fn emit_invoice_paid(event: InvoicePaid) {
+    if cfg!(feature = "compact-events") {
+        emit!(InvoicePaidCompact {
+            invoice_id: event.invoice_id,
+            amount: event.amount,
+        });
+    } else {
+        emit!(event);
+    }
+}
+
+/// Treasury PDA of `owner`, whether or not it has been initialized
+fn treasury_address(owner: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"treasury", owner.as_ref()], &crate::ID).0
+}
+
+/// Protocol fee rate after the volume discount earned by `profile`, if any
+fn tiered_fee_bps(config: &ProtocolConfig, fee_bps: u16, profile: Option<&AgentProfile>) -> Result<u16> {
+    let tier = profile.map_or(0, |profile| profile.volume_tier(&config.fee_tier_thresholds));
+    if tier == 0 {
+        return Ok(fee_bps);
+    }
+    let discount = bps_of(fee_bps as u64, config.fee_tier_discount_bps[tier as usize - 1])?;
+    Ok(fee_bps - discount as u16)
+}
+
+/// Compute `amount * bps / 10_000` without intermediate overflow
+fn bps_of(amount: u64, bps: u16) -> Result<u64> {
+    let fee = (amount as u128)
+        .checked_mul(bps as u128)
+        .ok_or(AgentFundError::ArithmeticOverflow)?
+        / MAX_BPS as u128;
+    u64::try_from(fee).map_err(|_| error!(AgentFundError::ArithmeticOverflow))
+}
+
+/// Split an escrowed amount into (requester, provider) shares after fees
+fn dispute_payouts(
+    amount: u64,
+    arbiter_fee: u64,
+    resolution: &DisputeResolution,
+) -> Result<(u64, u64)> {
+    let remaining = amount
+        .checked_sub(arbiter_fee)
+        .ok_or(AgentFundError::FeeExceedsEscrow)?;
+    let requester_amount = match resolution {
+        DisputeResolution::RefundRequester => remaining,
+        DisputeResolution::PayProvider => 0,
+        DisputeResolution::Split { requester_pct } => {
+            require!(*requester_pct <= 100, AgentFundError::InvalidSplitPct);
+            ((remaining as u128) * (*requester_pct as u128) / 100) as u64
+        }
+        DisputeResolution::PartialRefund { refund_amount } => {
+            require!(*refund_amount <= remaining, AgentFundError::RefundExceedsEscrow);
+            *refund_amount
+        }
+        DisputeResolution::RedoRequest { provider_pct } => {
+            require!(*provider_pct <= 100, AgentFundError::InvalidSplitPct);
+            remaining - ((remaining as u128) * (*provider_pct as u128) / 100) as u64
+        }
+    };
+    Ok((requester_amount, remaining - requester_amount))
+}
+
+/// Reject providers whose treasury was closed or has fallen below its
+/// rent-exempt minimum, so funds are never routed to a vanishing agent
+fn require_provider_healthy(treasury: &AccountInfo) -> Result<()> {
+    let healthy = treasury.owner == &crate::ID
+        && !treasury.data_is_empty()
+        && Rent::get()?.is_exempt(treasury.lamports(), treasury.data_len());
+    require!(healthy, AgentFundError::ProviderTreasuryUnhealthy);
+    Ok(())
+}
+
+/// Move `amount` of an agent's earnings out of its treasury into `to`,
+/// keeping the treasury's rent reserve in place
+fn debit_earnings(
+    profile: &mut AgentProfile,
+    treasury: &AccountInfo,
+    to: &AccountInfo,
+    amount: u64,
+) -> Result<()> {
+    if amount > profile.available_earnings {
+        return Err(amount_error(
+            AgentFundError::InsufficientEarnings,
+            amount,
+            profile.available_earnings,
+        ));
+    }
+    let reserve = Rent::get()?.minimum_balance(treasury.data_len());
+    let withdrawable = treasury.lamports().saturating_sub(reserve);
+    if amount > withdrawable {
+        return Err(amount_error(AgentFundError::InsufficientEarnings, amount, withdrawable));
+    }
+
+    **treasury.try_borrow_mut_lamports()? = treasury
+        .lamports()
+        .checked_sub(amount)
+        .ok_or(AgentFundError::ArithmeticOverflow)?;
+    **to.try_borrow_mut_lamports()? = to
+        .lamports()
+        .checked_add(amount)
+        .ok_or(AgentFundError::ArithmeticOverflow)?;
+    profile.available_earnings -= amount;
+    Ok(())
+}
+
+/// Bytes a requester signs to authorize `request_service_delegated`
+/// The program id ties the authorization to this deployment, and the
+/// request id (a fresh PDA seed) makes each one single-use
+fn delegated_request_message(
+    request_id: &[u8; 32],
+    provider: &Pubkey,
+    capability: &str,
+    amount: u64,
+    expires_at: i64,
+) -> Vec<u8> {
+    let mut message = b"agentfund:request_service_delegated".to_vec();
+    message.extend_from_slice(crate::ID.as_ref());
+    message.extend_from_slice(request_id);
+    message.extend_from_slice(provider.as_ref());
+    message.extend_from_slice(&amount.to_le_bytes());
+    message.extend_from_slice(&expires_at.to_le_bytes());
+    message.extend_from_slice(capability.as_bytes());
+    message
+}
+
+/// Whether the instruction just before the current one is an ed25519
+/// program check of `signer`'s signature over exactly `message`
+/// The runtime has already verified the signature itself; this only
+/// confirms it was over the right key and bytes. Offsets must point into
+/// the ed25519 instruction's own data so they can't borrow another's
+fn ed25519_signature_matches(
+    instructions: &AccountInfo,
+    signer: &Pubkey,
+    message: &[u8],
+) -> Result<bool> {
+    let current = load_current_index_checked(instructions)?;
+    if current == 0 {
+        return Ok(false);
+    }
+    let ix = load_instruction_at_checked(usize::from(current - 1), instructions)?;
+    if ix.program_id != ed25519_program::ID || !ix.accounts.is_empty() {
+        return Ok(false);
+    }
+
+    // Layout: signature count, padding, then one 14-byte offsets record
+    let data = &ix.data;
+    if data.len() < 16 || data[0] != 1 {
+        return Ok(false);
+    }
+    let read_u16 = |at: usize| usize::from(u16::from_le_bytes([data[at], data[at + 1]]));
+    let own_data = usize::from(u16::MAX);
+    if read_u16(4) != own_data || read_u16(8) != own_data || read_u16(14) != own_data {
+        return Ok(false);
+    }
+
+    let key_offset = read_u16(6);
+    let message_offset = read_u16(10);
+    let signed_key = data.get(key_offset..key_offset + 32);
+    let signed_message = data.get(message_offset..message_offset + read_u16(12));
+    Ok(signed_key == Some(signer.as_ref()) && signed_message == Some(message))
+}
+
+/// Canonical form capabilities are stored and compared in, so
+/// `Translation` and `translation` name the same capability
+/// ASCII-only lowercasing keeps the byte length, and with it the length checks
+fn normalize_capability(capability: &str) -> String {
+    capability.to_ascii_lowercase()
+}
+
+/// Normalize a capability list, rejecting names that coincide once
+/// normalized since the per-capability vectors are aligned by position
+fn normalize_capabilities(capabilities: Vec<String>) -> Result<Vec<String>> {
+    let mut normalized: Vec<String> = Vec::with_capacity(capabilities.len());
+    for cap in &capabilities {
+        let cap = normalize_capability(cap);
+        require!(!normalized.contains(&cap), AgentFundError::DuplicateCapability);
+        normalized.push(cap);
+    }
+    Ok(normalized)
+}
+
+/// Id of the follow-up request created when a dispute resolves as a redo
+fn redo_request_id(parent_id: &[u8; 32]) -> [u8; 32] {
+    anchor_lang::solana_program::hash::hashv(&[b"redo", parent_id]).to_bytes()
+}
+
+/// Move lamports out of a system-owned escrow PDA, signing with its seeds
+fn transfer_from_escrow<'info>(
+    escrow: &AccountInfo<'info>,
+    to: &AccountInfo<'info>,
+    system_program: &Program<'info, System>,
+    amount: u64,
+    signer_seeds: &[&[u8]],
+) -> Result<()> {
+    let transfer_ix = anchor_lang::solana_program::system_instruction::transfer(
+        &escrow.key(),
+        &to.key(),
+        amount,
+    );
+    anchor_lang::solana_program::program::invoke_signed(
+        &transfer_ix,
+        &[
+            escrow.clone(),
+            to.clone(),
+            system_program.to_account_info(),
+        ],
+        &[signer_seeds],
+    )?;
+    Ok(())
+}
+
+/// Move lamports out of the program-owned vault
+fn pay_from_vault(vault: &AccountInfo, to: &AccountInfo, amount: u64) -> Result<()> {
+    **vault.try_borrow_mut_lamports()? = vault
+        .lamports()
+        .checked_sub(amount)
+        .ok_or(AgentFundError::ArithmeticOverflow)?;
+    **to.try_borrow_mut_lamports()? = to
+        .lamports()
+        .checked_add(amount)
+        .ok_or(AgentFundError::ArithmeticOverflow)?;
+    Ok(())
+}
+
+/// Pay a closing channel's fee and final balances out of its escrow
+fn pay_out_channel<'info>(
+    channel: &PaymentChannel,
+    escrow: &AccountInfo<'info>,
+    system_program: &Program<'info, System>,
+    payouts: [(&AccountInfo<'info>, u64); 3],
+) -> Result<()> {
+    let seeds: &[&[u8]] = &[b"escrow", channel.id.as_ref(), &[channel.escrow_bump]];
+    for (recipient, amount) in payouts {
+        if amount > 0 {
+            transfer_from_escrow(escrow, recipient, system_program, amount, seeds)?;
+        }
+    }
+    Ok(())
+}
+
+/// Trailing agent profile seed for an optional registry index
+fn index_seed(index: &Option<u8>) -> &[u8] {
+    match index {
+        Some(index) => std::slice::from_ref(index),
+        None => &[],
+    }
+}
+
+/// Validate registration input and populate a fresh agent profile
+fn init_agent_profile(
+    profile: &mut AgentProfile,
+    owner: Pubkey,
+    treasury: Pubkey,
+    registration: AgentRegistration,
+) -> Result<()> {
+    let AgentRegistration { name, description, capabilities, base_price, tags } = registration;
+    require!(name.len() <= MAX_NAME_LENGTH, AgentFundError::NameTooLong);
+    require!(description.len() <= MAX_DESCRIPTION_LENGTH, AgentFundError::DescriptionTooLong);
+    require!(capabilities.len() <= MAX_CAPABILITIES, AgentFundError::TooManyCapabilities);
+    require!(tags.len() <= MAX_TAGS, AgentFundError::TooManyTags);
+    
+    for cap in &capabilities {
+        require!(cap.len() <= MAX_CAPABILITY_LENGTH, AgentFundError::CapabilityTooLong);
+    }
+    let capabilities = normalize_capabilities(capabilities)?;
+
+    let now = Clock::get()?.unix_timestamp;
+    profile.owner = owner;
+    profile.name = name.clone();
+    profile.description = description;
+    profile.capabilities = capabilities.clone();
+    profile.capability_request_counts = vec![0; capabilities.len()];
+    profile.capability_active = vec![true; capabilities.len()];
+    profile.max_concurrent_requests = 0;
+    profile.pending_base_price = None;
+    profile.price_effective_at = 0;
+    profile.available_earnings = 0;
+    profile.base_price = base_price;
+    profile.tags = tags.clone();
+    profile.treasury = treasury;
+    profile.is_active = true;
+    profile.deactivated_at = None;
+    profile.accepting_requests = true;
+    profile.agent_index = None;
+    profile.total_requests = 0;
+    profile.total_earnings = 0;
+    profile.registered_at = now;
+    profile.last_active_at = now;
+    profile.schema_version = AGENT_PROFILE_SCHEMA_VERSION;
+
+    msg!("Agent registered: {}", name);
+    emit!(AgentRegistered {
+        agent: owner,
+        name,
+        capabilities,
+        base_price,
+        tags,
+    });
+
+    Ok(())
+}
+
+// === Account Structures ===
+
+#[account]
+#[derive(Default, InitSpace)]
+pub struct Treasury {
+    /// Owner agent's public key
+    pub owner: Pubkey,
+    /// PDA bump seed
+    pub bump: u8,
+    /// Total lamports received
+    pub total_received: u64,
+    /// Total lamports settled via batches
+    pub total_settled: u64,
+    /// Number of pending invoices
+    pub pending_invoices: u64,
+    /// Creation timestamp
+    pub created_at: i64,
+    /// Signers allowed to settle batches; empty means anyone
+    #[max_len(5)]
+    pub authorized_settlers: Vec<Pubkey>,
+}
+
+impl Treasury {
+    /// Account size (including discriminator)
+    pub const SPACE: usize = 8 + Self::INIT_SPACE;
+}
+
+/// Treasury totals captured at one point in time
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct TreasurySnapshot {
+    pub total_received: u64,
+    pub total_settled: u64,
+    pub pending_invoices: u64,
+    pub taken_at: i64,
+}
+
+/// Ring buffer of a treasury's most recent snapshots, for cheap historical
+/// reporting without replaying events
+#[account]
+#[derive(InitSpace)]
+pub struct TreasurySnapshots {
+    /// Treasury the snapshots are taken of
+    pub treasury: Pubkey,
+    /// Snapshots retained before the oldest is overwritten
+    pub capacity: u8,
+    /// Slot the next snapshot is written to once the buffer is full
+    pub next_index: u8,
+    /// Snapshots ever taken, including overwritten ones
+    pub total_taken: u64,
+    /// Retained snapshots in slot order; the oldest is at `next_index`
+    #[max_len(16)]
+    pub entries: Vec<TreasurySnapshot>,
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+impl TreasurySnapshots {
+    /// Account size at the maximum capacity (including discriminator)
+    pub const SPACE: usize = 8 + Self::INIT_SPACE;
+
+    /// Append `snapshot`, or overwrite the oldest once at capacity
+    pub fn record(&mut self, snapshot: TreasurySnapshot) -> Result<()> {
+        if self.entries.len() < usize::from(self.capacity) {
+            self.entries.push(snapshot);
+        } else {
+            self.entries[usize::from(self.next_index)] = snapshot;
+        }
+        self.next_index = (self.next_index + 1) % self.capacity;
+        self.total_taken = self.total_taken
+            .checked_add(1)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        Ok(())
+    }
+}
+
+/// One collaborator's share of a split invoice payment
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, InitSpace)]
+pub struct SplitRecipient {
+    pub recipient: Pubkey,
+    /// Share of the payment in basis points
+    pub bps: u16,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct Invoice {
+    /// Unique invoice ID
+    pub id: [u8; 32],
+    /// Recipient (invoice creator)
+    pub recipient: Pubkey,
+    /// Amount in lamports
+    pub amount: u64,
+    /// Human-readable memo, sized per invoice up to `MAX_MEMO_LENGTH_CEILING`
+    #[max_len(1024)]
+    pub memo: String,
+    /// Current status
+    pub status: InvoiceStatus,
+    /// Creation timestamp
+    pub created_at: i64,
+    /// Expiration timestamp
+    pub expires_at: i64,
+    /// Payment timestamp (if paid)
+    pub paid_at: Option<i64>,
+    /// Payer public key (if paid or preauthorized)
+    pub payer: Option<Pubkey>,
+    /// Only this payer may pay the invoice, if set (private quotes)
+    pub allowed_payer: Option<Pubkey>,
+    /// Lamports held in the invoice escrow by an open preauthorization
+    pub held_amount: u64,
+    /// Invoice that must be paid before this one can be (milestone ordering)
+    pub depends_on: Option<[u8; 32]>,
+    /// Number of invoices created with `depends_on` set to this one
+    pub dependents: u32,
+    /// Hash of the note the payer attached to the payment, if any
+    pub payer_memo_hash: Option<[u8; 32]>,
+    /// Recipients sharing the payment; empty means `recipient` takes it all
+    #[max_len(8)]
+    pub split_recipients: Vec<SplitRecipient>,
+}
+
+impl Invoice {
+    /// Account size (including discriminator) for a memo of `memo_len` bytes
+    /// split across `split_count` recipients
+    pub const fn space(memo_len: usize, split_count: usize) -> usize {
+        8 + Self::INIT_SPACE - MAX_MEMO_LENGTH_CEILING as usize + memo_len
+            - (MAX_SPLIT_RECIPIENTS - split_count) * SplitRecipient::INIT_SPACE
+    }
+
+    /// Require the invoice to pay a single recipient; split payments are
+    /// only distributed by `pay_invoice`
+    pub fn require_unsplit(&self) -> Result<()> {
+        require!(self.split_recipients.is_empty(), AgentFundError::SplitInvoiceNotSupported);
+        Ok(())
+    }
+
+    /// Require `payer` to be permitted to pay this invoice
+    pub fn require_allowed_payer(&self, payer: &Pubkey) -> Result<()> {
+        require!(
+            self.allowed_payer.unwrap_or(*payer) == *payer,
+            AgentFundError::PayerNotAllowed
+        );
+        Ok(())
+    }
+
+    /// Require the invoice this one depends on, if any, to be supplied and paid
+    pub fn require_dependency_paid(&self, dependency: Option<&Invoice>) -> Result<()> {
+        if let Some(depends_on) = self.depends_on {
+            require!(
+                dependency
+                    .map(|dep| dep.id == depends_on && dep.status == InvoiceStatus::Paid)
+                    .unwrap_or(false),
+                AgentFundError::DependencyNotPaid
+            );
+        }
+        Ok(())
+    }
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct BatchSettlement {
+    /// Unique batch ID
+    pub id: [u8; 32],
+    /// Recipient of the batch
+    pub recipient: Pubkey,
+    /// Number of invoices in batch
+    pub invoice_count: u32,
+    /// Total amount settled
+    pub total_amount: u64,
+    /// Settlement timestamp
+    pub settled_at: i64,
+    /// Who submitted the settlement
+    pub settler: Pubkey,
+    /// Whether the settlement still stands
+    pub status: BatchStatus,
+    /// Invoices the batch settled, checked when it is reversed
+    #[max_len(50)]
+    pub invoice_ids: Vec<[u8; 32]>,
+}
+
+impl BatchSettlement {
+    /// Account size (including discriminator)
+    pub const SPACE: usize = 8 + Self::INIT_SPACE;
+}
+
+/// A batch payment held in escrow until the recipient claims it
+#[account]
+#[derive(InitSpace)]
+pub struct EscrowedBatch {
+    /// Unique batch ID
+    pub id: [u8; 32],
+    /// Who funded the escrow
+    pub settler: Pubkey,
+    /// Who may claim it
+    pub recipient: Pubkey,
+    /// Lamports held in the batch escrow
+    pub total_amount: u64,
+    /// Funding timestamp
+    pub funded_at: i64,
+    /// Last moment the recipient may claim; the settler may reclaim after
+    pub claim_deadline: i64,
+    pub status: BatchEscrowStatus,
+    /// Bump of the batch escrow PDA, stored so signing skips the search
+    pub escrow_bump: u8,
+    /// PDA bump
+    pub bump: u8,
+}
+
+impl EscrowedBatch {
+    /// Account size (including discriminator)
+    pub const SPACE: usize = 8 + Self::INIT_SPACE;
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct PaymentChannel {
+    /// Unique channel ID
+    pub id: [u8; 32],
+    /// First party
+    pub party_a: Pubkey,
+    /// Second party
+    pub party_b: Pubkey,
+    /// Party A's deposit
+    pub deposit_a: u64,
+    /// Party B's deposit
+    pub deposit_b: u64,
+    /// Party A's current balance
+    pub balance_a: u64,
+    /// Party B's current balance
+    pub balance_b: u64,
+    /// State nonce (for ordering updates)
+    pub nonce: u64,
+    /// Channel status
+    pub status: ChannelStatus,
+    /// Opening timestamp
+    pub opened_at: i64,
+    /// Closing timestamp
+    pub closed_at: Option<i64>,
+    /// End of the challenge period for a unilateral close
+    pub close_deadline: Option<i64>,
+    /// Nonce of the state posted by the pending close (or latest challenge)
+    pub close_nonce: u64,
+    /// Bump of the channel's escrow PDA, stored so signing skips the search
+    pub escrow_bump: u8,
+    /// `state_commitment` of the current channel state, refreshed on every
+    /// update, payment, funding, splice and close transition
+    pub state_hash: [u8; 32],
+}
+
+impl PaymentChannel {
+    /// Account size (including discriminator)
+    pub const SPACE: usize = 8 + Self::INIT_SPACE;
+
+    /// SHA-256 of `id || balance_a || balance_b || nonce`, integers little-endian
+    /// Parties can sign this digest off-chain instead of the full state
+    pub fn state_commitment(&self) -> [u8; 32] {
+        self.commitment_for(self.balance_a, self.balance_b, self.nonce)
+    }
+
+    /// `state_commitment` of a proposed state of this channel
+    pub fn commitment_for(&self, balance_a: u64, balance_b: u64, nonce: u64) -> [u8; 32] {
+        anchor_lang::solana_program::hash::hashv(&[
+            &self.id,
+            &balance_a.to_le_bytes(),
+            &balance_b.to_le_bytes(),
+            &nonce.to_le_bytes(),
+        ])
+        .to_bytes()
+    }
+
+    /// Move `amount` of off-chain balance from one party to the other
+    /// Every in-channel debit goes through here so an overdraft is rejected
+    /// rather than saturating and breaking balance conservation. The shift is
+    /// a new channel state at `nonce`, set before the state is hashed
+    pub fn shift_balance(&mut self, from: &Pubkey, to: &Pubkey, amount: u64, nonce: u64) -> Result<()> {
+        require_newer_nonce(self.nonce, nonce)?;
+        let (debit, credit) = if *from == self.party_a && *to == self.party_b {
+            (&mut self.balance_a, &mut self.balance_b)
+        } else if *from == self.party_b && *to == self.party_a {
+            (&mut self.balance_b, &mut self.balance_a)
+        } else {
+            return err!(AgentFundError::ChannelPartyMismatch);
+        };
+        *debit = debit
+            .checked_sub(amount)
+            .ok_or(AgentFundError::InsufficientChannelBalance)?;
+        *credit = credit
+            .checked_add(amount)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        self.nonce = nonce;
+        self.state_hash = self.state_commitment();
+        Ok(())
+    }
+
+    /// Current off-chain balance of `party`
+    pub fn balance_of(&self, party: &Pubkey) -> Result<u64> {
+        if *party == self.party_a {
+            Ok(self.balance_a)
+        } else if *party == self.party_b {
+            Ok(self.balance_b)
+        } else {
+            err!(AgentFundError::ChannelPartyMismatch)
+        }
+    }
+}
+
+/// Packed invoice status returned by `get_invoice_statuses`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct InvoiceStatusEntry {
+    pub invoice_id: [u8; 32],
+    /// `InvoiceStatus` discriminant
+    pub status: u8,
+}
+
+/// Channel snapshot returned by `get_channel_state`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ChannelState {
+    /// Party A's claimable balance
+    pub balance_a: u64,
+    /// Party B's claimable balance
+    pub balance_b: u64,
+    pub nonce: u64,
+    pub status: ChannelStatus,
+    /// End of the challenge period; set only while a close is pending
+    pub challenge_deadline: Option<i64>,
+}
+
+// === Enums ===
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, InitSpace)]
+pub enum InvoiceStatus {
+    Pending,
+    Paid,
+    Expired,
+    Cancelled,
+    /// Funds held by a preauthorization, awaiting capture or void
+    Authorized,
+}
+
+impl Default for InvoiceStatus {
+    fn default() -> Self {
+        InvoiceStatus::Pending
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Default, InitSpace)]
+pub enum BatchStatus {
+    #[default]
+    Settled,
+    /// Undone by the settler and recipient within the reversal window
+    Reversed,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Default, InitSpace)]
+pub enum BatchEscrowStatus {
+    #[default]
+    Funded,
+    /// Paid out to the recipient against its invoices
+    Claimed,
+    /// Returned to the settler after the claim window
+    Reclaimed,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, InitSpace)]
+pub enum ChannelStatus {
+    Open,
+    Closing,
+    Closed,
+    Disputed,
+    /// Opened by party A, awaiting party B's acceptance
+    PendingAcceptance,
+}
+
+impl Default for ChannelStatus {
+    fn default() -> Self {
+        ChannelStatus::Open
+    }
+}
+
+// === Contexts ===
+
+#[derive(Accounts)]
+#[instruction(bump: u8)]
+pub struct InitializeTreasury<'info> {
+    #[account(
+        init,
+        payer = owner,
+        space = Treasury::SPACE,
+        seeds = [b"treasury", owner.key().as_ref()],
+        bump
+    )]
+    pub treasury: Account<'info, Treasury>,
+    
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeTreasurySnapshots<'info> {
+    #[account(
+        init,
+        payer = owner,
+        space = TreasurySnapshots::SPACE,
+        seeds = [b"treasury_snapshots", treasury.key().as_ref()],
+        bump
+    )]
+    pub snapshots: Account<'info, TreasurySnapshots>,
+    
+    #[account(
+        seeds = [b"treasury", owner.key().as_ref()],
+        bump = treasury.bump,
+        has_one = owner
+    )]
+    pub treasury: Account<'info, Treasury>,
+    
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SnapshotTreasury<'info> {
+    #[account(
+        mut,
+        seeds = [b"treasury_snapshots", treasury.key().as_ref()],
+        bump = snapshots.bump,
+        has_one = treasury
+    )]
+    pub snapshots: Account<'info, TreasurySnapshots>,
+    
+    #[account(
+        seeds = [b"treasury", owner.key().as_ref()],
+        bump = treasury.bump,
+        has_one = owner
+    )]
+    pub treasury: Account<'info, Treasury>,
+    
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetAuthorizedSettlers<'info> {
+    #[account(
+        mut,
+        seeds = [b"treasury", owner.key().as_ref()],
+        bump = treasury.bump,
+        has_one = owner
+    )]
+    pub treasury: Account<'info, Treasury>,
+    
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseTreasury<'info> {
+    #[account(
+        mut,
+        seeds = [b"treasury", owner.key().as_ref()],
+        bump = treasury.bump,
+        has_one = owner,
+        close = owner
+    )]
+    pub treasury: Account<'info, Treasury>,
+    
+    #[account(mut)]
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawEarnings<'info> {
+    #[account(
+        mut,
+        seeds = [b"agent", owner.key().as_ref(), agent_profile.index_seed()],
+        bump = agent_profile.bump,
+        has_one = owner
+    )]
+    pub agent_profile: Account<'info, AgentProfile>,
+    
+    #[account(
+        mut,
+        seeds = [b"treasury", owner.key().as_ref()],
+        bump = treasury.bump,
+        has_one = owner
+    )]
+    pub treasury: Account<'info, Treasury>,
+    
+    #[account(mut)]
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(invoice_id: [u8; 32])]
+pub struct CreateInvoice<'info> {
+    /// CHECK: Uninitialized invoice PDA; created in the handler at the memo's size
+    #[account(
+        mut,
+        seeds = [b"invoice", invoice_id.as_ref()],
+        bump
+    )]
+    pub invoice: UncheckedAccount<'info>,
+    
+    /// Recipient's treasury; when supplied, its pending count is updated
+    #[account(
+        mut,
+        seeds = [b"treasury", recipient.key().as_ref()],
+        bump = treasury.bump
+    )]
+    pub treasury: Option<Account<'info, Treasury>>,
+    
+    /// Logical owner of the invoice; pays its rent unless `rent_payer` signs
+    #[account(mut)]
+    pub recipient: Signer<'info>,
+    
+    /// Optional sponsor (e.g. a platform) funding the invoice's rent
+    #[account(mut)]
+    pub rent_payer: Option<Signer<'info>>,
+    
+    /// Protocol config; required so the memo limit and invoice floor cannot
+    /// be bypassed
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, ProtocolConfig>,
+    
+    /// Invoice this one depends on; required when `depends_on` is set
+    #[account(mut)]
+    pub dependency: Option<Account<'info, Invoice>>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ExtendInvoice<'info> {
+    #[account(mut, has_one = recipient)]
+    pub invoice: Account<'info, Invoice>,
+    
+    pub recipient: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ExpireInvoice<'info> {
+    #[account(mut)]
+    pub invoice: Account<'info, Invoice>,
+    
+    #[account(
+        mut,
+        seeds = [b"treasury", invoice.recipient.as_ref()],
+        bump = treasury.bump
+    )]
+    pub treasury: Account<'info, Treasury>,
+}
+
+#[derive(Accounts)]
+pub struct ExpireInvoicesBatch<'info> {
+    #[account(
+        mut,
+        seeds = [b"treasury", treasury.owner.as_ref()],
+        bump = treasury.bump
+    )]
+    pub treasury: Account<'info, Treasury>,
+}
+
+#[derive(Accounts)]
+pub struct PayInvoice<'info> {
+    #[account(mut)]
+    pub invoice: Account<'info, Invoice>,
+    
+    /// Recipient's treasury; when supplied, its counters are updated
+    #[account(
+        mut,
+        seeds = [b"treasury", recipient.key().as_ref()],
+        bump = treasury.bump
+    )]
+    pub treasury: Option<Account<'info, Treasury>>,
+    
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    
+    /// CHECK: Recipient is validated against invoice
+    #[account(mut, constraint = recipient.key() == invoice.recipient)]
+    pub recipient: AccountInfo<'info>,
+    
+    /// Protocol config; required so the invoice fee cannot be bypassed
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, ProtocolConfig>,
+    
+    /// CHECK: Receives the protocol fee; validated against config
+    #[account(
+        mut,
+        constraint = fee_collector.key() == config.fee_collector @ AgentFundError::InvalidFeeCollector
+    )]
+    pub fee_collector: AccountInfo<'info>,
+    
+    /// Invoice this one depends on; required when `invoice.depends_on` is set
+    pub dependency: Option<Account<'info, Invoice>>,
+    
+    /// Recipient's agent profile; when supplied, its volume tier discounts
+    /// the protocol fee
+    #[account(
+        seeds = [b"agent", recipient.key().as_ref(), recipient_profile.index_seed()],
+        bump = recipient_profile.bump
+    )]
+    pub recipient_profile: Option<Account<'info, AgentProfile>>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> PayInvoice<'info> {
+    /// Validate and settle a direct invoice payment, net of the protocol fee
+    /// `split_accounts` are the split recipients' wallets, if the invoice has any
+    fn pay(&mut self, payer_memo: Option<String>, split_accounts: &[AccountInfo<'info>]) -> Result<()> {
+        let invoice = &mut self.invoice;
+    
+        // An invoice already expired by the crank is reported as expired
+        // rather than merely not pending; the timestamp check below covers
+        // invoices that lapsed without being cranked
+        require!(
+            invoice.status != InvoiceStatus::Expired,
+            AgentFundError::InvoiceExpired
+        );
+        require!(
+            invoice.status == InvoiceStatus::Pending,
+            AgentFundError::InvoiceNotPending
+        );
+        require!(
+            Clock::get()?.unix_timestamp < invoice.expires_at,
+            AgentFundError::InvoiceExpired
+        );
+        invoice.require_allowed_payer(&self.payer.key())?;
+        invoice.require_dependency_paid(self.dependency.as_deref())?;
+        if let Some(memo) = &payer_memo {
+            require!(
+                memo.len() <= MAX_PAYER_MEMO_LENGTH,
+                AgentFundError::PayerMemoTooLong
+            );
+        }
+
+        // Protocol fee comes out of the invoice amount; the recipient is
+        // credited the net
+        let fee_bps = tiered_fee_bps(
+            &self.config,
+            self.config.invoice_fee_bps,
+            self.recipient_profile.as_deref(),
+        )?;
+        let fee = bps_of(invoice.amount, fee_bps)?;
+        let net = invoice
+            .amount
+            .checked_sub(fee)
+            .filter(|net| *net > 0)
+            .ok_or(AgentFundError::FeeExceedsAmount)?;
+        require_payer_balance(&self.payer, invoice.amount)?;
+
+        // Transfer SOL from payer to recipient, or to each split recipient
+        // in proportion; the last share takes the rounding remainder
+        let payouts: Vec<(AccountInfo<'info>, u64)> = if invoice.split_recipients.is_empty() {
+            vec![(self.recipient.to_account_info(), net)]
+        } else {
+            require!(
+                split_accounts.len() == invoice.split_recipients.len(),
+                AgentFundError::InvalidSplitAccount
+            );
+            let mut remaining = net;
+            let last = invoice.split_recipients.len() - 1;
+            let mut payouts = Vec::with_capacity(split_accounts.len());
+            for (i, (account, split)) in split_accounts.iter().zip(&invoice.split_recipients).enumerate() {
+                require_keys_eq!(account.key(), split.recipient, AgentFundError::InvalidSplitAccount);
+                let share = if i == last { remaining } else { bps_of(net, split.bps)? };
+                remaining = remaining
+                    .checked_sub(share)
+                    .ok_or(AgentFundError::ArithmeticOverflow)?;
+                payouts.push((account.clone(), share));
+            }
+            payouts
+        };
+        let mut recipient_share: u64 = 0;
+        for (account, share) in &payouts {
+            if *share == 0 {
+                continue;
+            }
+            let transfer_ix = anchor_lang::solana_program::system_instruction::transfer(
+                &self.payer.key(),
+                &account.key(),
+                *share,
+            );
+            anchor_lang::solana_program::program::invoke(
+                &transfer_ix,
+                &[
+                    self.payer.to_account_info(),
+                    account.clone(),
+                    self.system_program.to_account_info(),
+                ],
+            )?;
+            if account.key() == invoice.recipient {
+                recipient_share += share;
+            }
+            if !invoice.split_recipients.is_empty() {
+                emit!(InvoiceSplitPaid {
+                    invoice_id: invoice.id,
+                    recipient: account.key(),
+                    amount: *share,
+                });
+            }
+        }
+
+        // Transfer the fee from payer to the fee collector
+        if fee > 0 {
+            let fee_ix = anchor_lang::solana_program::system_instruction::transfer(
+                &self.payer.key(),
+                &self.fee_collector.key(),
+                fee,
+            );
+            anchor_lang::solana_program::program::invoke(
+                &fee_ix,
+                &[
+                    self.payer.to_account_info(),
+                    self.fee_collector.to_account_info(),
+                    self.system_program.to_account_info(),
+                ],
+            )?;
+        }
+
+        // Update invoice status
+        invoice.status = InvoiceStatus::Paid;
+        invoice.paid_at = Some(Clock::get()?.unix_timestamp);
+        invoice.payer = Some(self.payer.key());
+        // Only the hash is kept on-chain; the memo itself travels in the event
+        invoice.payer_memo_hash = payer_memo
+            .as_ref()
+            .map(|memo| anchor_lang::solana_program::hash::hash(memo.as_bytes()).to_bytes());
+
+        // Update treasury counters with what the recipient itself received;
+        // the payment does not depend on the recipient having a treasury
+        let treasury = match &mut self.treasury {
+            Some(treasury) => {
+                treasury.total_received = treasury
+                    .total_received
+                    .checked_add(recipient_share)
+                    .ok_or(AgentFundError::ArithmeticOverflow)?;
+                treasury.pending_invoices = treasury.pending_invoices.saturating_sub(1);
+                emit_ledger_entry(treasury, LedgerEntryKind::Received, recipient_share)?;
+                treasury.key()
+            }
+            None => treasury_address(&invoice.recipient),
+        };
+
+        msg!("Invoice paid: {} lamports ({} fee)", invoice.amount, fee);
+        let seq = bump_event_seq(&mut self.config)?;
+        emit_invoice_paid(InvoicePaid {
+            invoice_id: invoice.id,
+            payer: self.payer.key(),
+            treasury,
+            amount: invoice.amount,
+            fee,
+            payer_memo,
+            seq,
+        });
+
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct PreauthorizeInvoice<'info> {
+    #[account(mut)]
+    pub invoice: Account<'info, Invoice>,
+    
+    /// CHECK: Escrow holding the preauthorized funds
+    #[account(
+        mut,
+        seeds = [b"invoice_escrow", invoice.id.as_ref()],
+        bump
+    )]
+    pub escrow: AccountInfo<'info>,
+    
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    
+    /// Invoice this one depends on; required when `invoice.depends_on` is set
+    pub dependency: Option<Account<'info, Invoice>>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CapturePreauthorized<'info> {
+    #[account(mut, has_one = recipient)]
+    pub invoice: Account<'info, Invoice>,
+    
+    /// CHECK: Escrow holding the preauthorized funds
+    #[account(
+        mut,
+        seeds = [b"invoice_escrow", invoice.id.as_ref()],
+        bump
+    )]
+    pub escrow: AccountInfo<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"treasury", recipient.key().as_ref()],
+        bump = treasury.bump
+    )]
+    pub treasury: Account<'info, Treasury>,
+    
+    #[account(mut)]
+    pub recipient: Signer<'info>,
+    
+    /// CHECK: Payer refunded any uncaptured remainder
+    #[account(mut, constraint = invoice.payer == Some(payer.key()) @ AgentFundError::Unauthorized)]
+    pub payer: AccountInfo<'info>,
+    
+    /// Protocol config; required so the invoice fee cannot be bypassed
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, ProtocolConfig>,
+    
+    /// CHECK: Receives the protocol fee; validated against config
+    #[account(
+        mut,
+        constraint = fee_collector.key() == config.fee_collector @ AgentFundError::InvalidFeeCollector
+    )]
+    pub fee_collector: AccountInfo<'info>,
+    
+    /// Recipient's agent profile; when supplied, its volume tier discounts
+    /// the protocol fee
+    #[account(
+        seeds = [b"agent", recipient.key().as_ref(), recipient_profile.index_seed()],
+        bump = recipient_profile.bump
+    )]
+    pub recipient_profile: Option<Account<'info, AgentProfile>>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct VoidPreauthorization<'info> {
+    #[account(mut)]
+    pub invoice: Account<'info, Invoice>,
+    
+    /// CHECK: Escrow holding the preauthorized funds
+    #[account(
+        mut,
+        seeds = [b"invoice_escrow", invoice.id.as_ref()],
+        bump
+    )]
+    pub escrow: AccountInfo<'info>,
+    
+    /// CHECK: Payer receiving the hold back
+    #[account(mut, constraint = invoice.payer == Some(payer.key()) @ AgentFundError::Unauthorized)]
+    pub payer: AccountInfo<'info>,
+    
+    /// Recipient, or the payer once the invoice has expired
+    pub authority: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(channel_id: [u8; 32])]
+pub struct PayInvoiceFromChannel<'info> {
+    #[account(mut)]
+    pub invoice: Account<'info, Invoice>,
+    
+    #[account(
+        mut,
+        seeds = [b"channel", channel_id.as_ref()],
+        bump
+    )]
+    pub channel: Account<'info, PaymentChannel>,
+    
+    #[account(
+        mut,
+        seeds = [b"treasury", invoice.recipient.as_ref()],
+        bump = treasury.bump
+    )]
+    pub treasury: Account<'info, Treasury>,
+    
+    /// Channel party paying the invoice
+    pub payer: Signer<'info>,
+    
+    /// Optional protocol config; when supplied, emitted events are sequenced
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Option<Account<'info, ProtocolConfig>>,
+    
+    /// Invoice this one depends on; required when `invoice.depends_on` is set
+    pub dependency: Option<Account<'info, Invoice>>,
+    
+    /// CHECK: Instructions sysvar, read to find the recipient's ed25519
+    /// signature over the post-payment state
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    pub instructions: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(invoice_ids: Vec<[u8; 32]>, channel_id: [u8; 32])]
+pub struct SettleViaChannel<'info> {
+    #[account(
+        mut,
+        seeds = [b"channel", channel_id.as_ref()],
+        bump
+    )]
+    pub channel: Account<'info, PaymentChannel>,
+    
+    #[account(
+        mut,
+        seeds = [b"treasury", recipient.key().as_ref()],
+        bump = treasury.bump
+    )]
+    pub treasury: Account<'info, Treasury>,
+    
+    /// Channel party paying the invoices
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    
+    /// CHECK: Invoice recipient and channel counterparty; validated against
+    /// each invoice and the channel
+    #[account(mut)]
+    pub recipient: AccountInfo<'info>,
+    
+    /// Optional protocol config; when supplied, emitted events are sequenced
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Option<Account<'info, ProtocolConfig>>,
+    
+    /// CHECK: Instructions sysvar, read to find the recipient's ed25519
+    /// signature over the netted state
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    pub instructions: AccountInfo<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct GetInvoiceStatuses {}
+
+#[derive(Accounts)]
+pub struct GetInvoiceUri<'info> {
+    pub invoice: Account<'info, Invoice>,
+}
+
+#[derive(Accounts)]
+#[instruction(batch_id: [u8; 32])]
+pub struct SettleBatch<'info> {
+    /// Created on first use; an id already in use is rejected by the handler
+    #[account(
+        init_if_needed,
+        payer = settler,
+        space = BatchSettlement::SPACE,
+        seeds = [b"batch", batch_id.as_ref()],
+        bump
+    )]
+    pub batch: Account<'info, BatchSettlement>,
+    
+    #[account(
+        mut,
+        seeds = [b"treasury", recipient.key().as_ref()],
+        bump = treasury.bump
+    )]
+    pub treasury: Account<'info, Treasury>,
+    
+    #[account(mut)]
+    pub settler: Signer<'info>,
+    
+    /// CHECK: Recipient validated by treasury PDA
+    #[account(mut)]
+    pub recipient: AccountInfo<'info>,
+    
+    /// Optional protocol config; when supplied, emitted events are sequenced
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Option<Account<'info, ProtocolConfig>>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ReverseBatch<'info> {
+    #[account(
+        mut,
+        seeds = [b"batch", batch.id.as_ref()],
+        bump,
+        has_one = settler,
+        has_one = recipient
+    )]
+    pub batch: Account<'info, BatchSettlement>,
+    
+    #[account(
+        mut,
+        seeds = [b"treasury", recipient.key().as_ref()],
+        bump = treasury.bump
+    )]
+    pub treasury: Account<'info, Treasury>,
+    
+    #[account(mut)]
+    pub settler: Signer<'info>,
+    
+    /// Returns the settled lamports
+    #[account(mut)]
+    pub recipient: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(batch_id: [u8; 32])]
+pub struct GetBatchStatus<'info> {
+    /// CHECK: Batch PDA; may not exist, in which case no status is returned
+    #[account(seeds = [b"batch", batch_id.as_ref()], bump)]
+    pub batch: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(batch_id: [u8; 32])]
+pub struct FundBatchEscrow<'info> {
+    /// Created on first use; an id already in use is rejected by the handler
+    #[account(
+        init_if_needed,
+        payer = settler,
+        space = EscrowedBatch::SPACE,
+        seeds = [b"escrowed_batch", batch_id.as_ref()],
+        bump
+    )]
+    pub escrowed_batch: Account<'info, EscrowedBatch>,
+    
+    /// CHECK: Escrow PDA holding the batch payment
+    #[account(
+        mut,
+        seeds = [b"batch_escrow", batch_id.as_ref()],
+        bump
+    )]
+    pub batch_escrow: AccountInfo<'info>,
+    
+    #[account(
+        seeds = [b"treasury", recipient.key().as_ref()],
+        bump = treasury.bump
+    )]
+    pub treasury: Account<'info, Treasury>,
+    
+    #[account(mut)]
+    pub settler: Signer<'info>,
+    
+    /// CHECK: Recipient validated by treasury PDA
+    pub recipient: AccountInfo<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimBatchEscrow<'info> {
+    #[account(
+        mut,
+        seeds = [b"escrowed_batch", escrowed_batch.id.as_ref()],
+        bump = escrowed_batch.bump,
+        has_one = recipient
+    )]
+    pub escrowed_batch: Account<'info, EscrowedBatch>,
+    
+    /// CHECK: Escrow PDA holding the batch payment
+    #[account(
+        mut,
+        seeds = [b"batch_escrow", escrowed_batch.id.as_ref()],
+        bump = escrowed_batch.escrow_bump
+    )]
+    pub batch_escrow: AccountInfo<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"treasury", recipient.key().as_ref()],
+        bump = treasury.bump
+    )]
+    pub treasury: Account<'info, Treasury>,
+    
+    #[account(mut)]
+    pub recipient: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ReclaimBatchEscrow<'info> {
+    #[account(
+        mut,
+        seeds = [b"escrowed_batch", escrowed_batch.id.as_ref()],
+        bump = escrowed_batch.bump,
+        has_one = settler
+    )]
+    pub escrowed_batch: Account<'info, EscrowedBatch>,
+    
+    /// CHECK: Escrow PDA holding the batch payment
+    #[account(
+        mut,
+        seeds = [b"batch_escrow", escrowed_batch.id.as_ref()],
+        bump = escrowed_batch.escrow_bump
+    )]
+    pub batch_escrow: AccountInfo<'info>,
+    
+    #[account(mut)]
+    pub settler: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(channel_id: [u8; 32])]
+pub struct OpenChannel<'info> {
+    /// Created on first use; an id already in use is rejected by the handler
+    #[account(
+        init_if_needed,
+        payer = party_a,
+        space = PaymentChannel::SPACE,
+        seeds = [b"channel", channel_id.as_ref()],
+        bump
+    )]
+    pub channel: Account<'info, PaymentChannel>,
+    
+    /// CHECK: Escrow PDA for holding channel funds
+    #[account(
+        mut,
+        seeds = [b"escrow", channel_id.as_ref()],
+        bump
+    )]
+    pub channel_escrow: AccountInfo<'info>,
+    
+    #[account(mut)]
+    pub party_a: Signer<'info>,
+    
+    /// CHECK: Party B just needs to be a valid pubkey
+    pub party_b: AccountInfo<'info>,
+    
+    /// Protocol config; required so the minimum deposit cannot be bypassed
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, ProtocolConfig>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(channel_id: [u8; 32])]
+pub struct OpenChannelDual<'info> {
+    /// Created on first use; an id already in use is rejected by the handler
+    #[account(
+        init_if_needed,
+        payer = party_a,
+        space = PaymentChannel::SPACE,
+        seeds = [b"channel", channel_id.as_ref()],
+        bump
+    )]
+    pub channel: Account<'info, PaymentChannel>,
+    
+    /// CHECK: Escrow PDA for holding channel funds
+    #[account(
+        mut,
+        seeds = [b"escrow", channel_id.as_ref()],
+        bump
+    )]
+    pub channel_escrow: AccountInfo<'info>,
+    
+    #[account(mut)]
+    pub party_a: Signer<'info>,
+    
+    #[account(mut)]
+    pub party_b: Signer<'info>,
+    
+    /// Protocol config; required so the minimum deposit cannot be bypassed
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, ProtocolConfig>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> OpenChannelDual<'info> {
+    /// Move one party's deposit into the channel escrow
+    fn deposit(&self, party: &Signer<'info>, amount: u64) -> Result<()> {
+        require_payer_balance(party, amount)?;
+        let transfer_ix = anchor_lang::solana_program::system_instruction::transfer(
+            &party.key(),
+            &self.channel_escrow.key(),
+            amount,
+        );
+        anchor_lang::solana_program::program::invoke(
+            &transfer_ix,
+            &[
+                party.to_account_info(),
+                self.channel_escrow.to_account_info(),
+                self.system_program.to_account_info(),
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct CloseChannel<'info> {
+    #[account(mut)]
+    pub channel: Account<'info, PaymentChannel>,
+    
+    /// CHECK: Escrow PDA
+    #[account(
+        mut,
+        seeds = [b"escrow", channel.id.as_ref()],
+        bump = channel.escrow_bump
+    )]
+    pub channel_escrow: AccountInfo<'info>,
+    
+    #[account(mut, constraint = party_a.key() == channel.party_a @ AgentFundError::NotChannelParty)]
+    pub party_a: Signer<'info>,
+    
+    #[account(mut, constraint = party_b.key() == channel.party_b @ AgentFundError::NotChannelParty)]
+    pub party_b: Signer<'info>,
+    
+    /// Protocol config; required so the close fee cannot be bypassed
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, ProtocolConfig>,
+    
+    /// CHECK: Receives the protocol close fee; validated against config
+    #[account(
+        mut,
+        constraint = fee_collector.key() == config.fee_collector @ AgentFundError::InvalidFeeCollector
+    )]
+    pub fee_collector: AccountInfo<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct FundChannel<'info> {
+    #[account(mut)]
+    pub channel: Account<'info, PaymentChannel>,
+    
+    /// CHECK: Escrow PDA for holding channel funds
+    #[account(
+        mut,
+        seeds = [b"escrow", channel.id.as_ref()],
+        bump = channel.escrow_bump
+    )]
+    pub channel_escrow: AccountInfo<'info>,
+    
+    #[account(mut, constraint = party_b.key() == channel.party_b @ AgentFundError::NotChannelParty)]
+    pub party_b: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateChannel<'info> {
+    #[account(mut)]
+    pub channel: Account<'info, PaymentChannel>,
+    
+    #[account(constraint = party_a.key() == channel.party_a @ AgentFundError::NotChannelParty)]
+    pub party_a: Signer<'info>,
+    
+    #[account(constraint = party_b.key() == channel.party_b @ AgentFundError::NotChannelParty)]
+    pub party_b: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SpliceChannel<'info> {
+    #[account(mut)]
+    pub channel: Account<'info, PaymentChannel>,
+    
+    /// CHECK: Escrow PDA
+    #[account(
+        mut,
+        seeds = [b"escrow", channel.id.as_ref()],
+        bump = channel.escrow_bump
+    )]
+    pub channel_escrow: AccountInfo<'info>,
+    
+    #[account(
+        mut,
+        constraint = party_a.key() == channel.party_a @ AgentFundError::NotChannelParty
+    )]
+    pub party_a: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = party_b.key() == channel.party_b @ AgentFundError::NotChannelParty
+    )]
+    pub party_b: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptChannel<'info> {
+    #[account(
+        mut,
+        constraint = channel.party_b == party_b.key() @ AgentFundError::NotChannelParty
+    )]
+    pub channel: Account<'info, PaymentChannel>,
+    
+    pub party_b: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CancelPendingChannel<'info> {
+    #[account(
+        mut,
+        constraint = channel.party_a == party_a.key() @ AgentFundError::NotChannelParty
+    )]
+    pub channel: Account<'info, PaymentChannel>,
+    
+    /// CHECK: Escrow PDA holding party A's deposit
+    #[account(
+        mut,
+        seeds = [b"escrow", channel.id.as_ref()],
+        bump = channel.escrow_bump
+    )]
+    pub channel_escrow: AccountInfo<'info>,
+    
+    #[account(mut)]
+    pub party_a: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawUnusedDeposit<'info> {
+    #[account(
+        mut,
+        constraint = channel.party_a == party_a.key() @ AgentFundError::NotChannelParty
+    )]
+    pub channel: Account<'info, PaymentChannel>,
+    
+    /// CHECK: Escrow PDA holding party A's deposit
+    #[account(
+        mut,
+        seeds = [b"escrow", channel.id.as_ref()],
+        bump = channel.escrow_bump
+    )]
+    pub channel_escrow: AccountInfo<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, ProtocolConfig>,
+    
+    #[account(mut)]
+    pub party_a: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ChannelPartyAction<'info> {
+    #[account(mut)]
+    pub channel: Account<'info, PaymentChannel>,
+    
+    /// Either channel party
+    #[account(
+        constraint = party.key() == channel.party_a || party.key() == channel.party_b
+            @ AgentFundError::NotChannelParty
+    )]
+    pub party: Signer<'info>,
+    
+    /// CHECK: Instructions sysvar, read to find the counterparty's ed25519
+    /// signature over a posted state
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    pub instructions: AccountInfo<'info>,
+}
+
+impl<'info> ChannelPartyAction<'info> {
+    /// Require the other party's signature over the `state_commitment` of
+    /// the posted state, so neither side can rewrite the split alone
+    fn require_counterparty_signature(&self, balance_a: u64, balance_b: u64, nonce: u64) -> Result<()> {
+        let channel = &self.channel;
+        let counterparty = if self.party.key() == channel.party_a {
+            channel.party_b
+        } else {
+            channel.party_a
+        };
+        require_channel_state_signature(channel, &self.instructions, &counterparty, balance_a, balance_b, nonce)
+    }
+}
+
+#[derive(Accounts)]
+pub struct GetChannelState<'info> {
+    pub channel: Account<'info, PaymentChannel>,
+}
+
+#[derive(Accounts)]
+pub struct FinalizeChannelClose<'info> {
+    #[account(mut)]
+    pub channel: Account<'info, PaymentChannel>,
+    
+    /// CHECK: Escrow PDA
+    #[account(
+        mut,
+        seeds = [b"escrow", channel.id.as_ref()],
+        bump = channel.escrow_bump
+    )]
+    pub channel_escrow: AccountInfo<'info>,
+    
+    /// CHECK: Party A, receiving its final balance
+    #[account(mut, constraint = party_a.key() == channel.party_a @ AgentFundError::NotChannelParty)]
+    pub party_a: AccountInfo<'info>,
+    
+    /// CHECK: Party B, receiving its final balance
+    #[account(mut, constraint = party_b.key() == channel.party_b @ AgentFundError::NotChannelParty)]
+    pub party_b: AccountInfo<'info>,
+    
+    /// Protocol config; required so the close fee cannot be bypassed
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, ProtocolConfig>,
+    
+    /// CHECK: Receives the protocol close fee; validated against config
+    #[account(
+        mut,
+        constraint = fee_collector.key() == config.fee_collector @ AgentFundError::InvalidFeeCollector
+    )]
+    pub fee_collector: AccountInfo<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+// === Events ===
+
+/// Kind of treasury counter change recorded by `TreasuryLedgerEntry`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub enum LedgerEntryKind {
+    /// Payment credited to `total_received`
+    Received,
+    /// Batch credited to `total_settled`
+    Settled,
+    /// Funds withdrawn from the treasury
+    Withdrawn,
+    /// Reversed batch debited from `total_settled`
+    Reversed,
+}
+
+#[event]
+pub struct TreasuryLedgerEntry {
+    pub treasury: Pubkey,
+    pub kind: LedgerEntryKind,
+    pub delta: u64,
+    pub new_total_received: u64,
+    pub new_total_settled: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct TreasurySnapshotTaken {
+    pub treasury: Pubkey,
+    pub total_received: u64,
+    pub total_settled: u64,
+    pub pending_invoices: u64,
+    pub taken_at: i64,
+}
+
+#[event]
+pub struct TreasuryClosed {
+    pub owner: Pubkey,
+    pub total_received: u64,
+    pub total_settled: u64,
+}
+
+#[event]
+pub struct AuthorizedSettlersUpdated {
+    pub treasury: Pubkey,
+    pub settlers: Vec<Pubkey>,
+}
+
+#[event]
+pub struct InvoiceCreated {
+    pub invoice_id: [u8; 32],
+    pub recipient: Pubkey,
+    /// Recipient's treasury; shared by every treasury-scoped event for filtering
+    pub treasury: Pubkey,
+    pub amount: u64,
+    pub expires_at: i64,
+    pub seq: u64,
+}
+
+/// `InvoiceCreated` as emitted by `compact-events` builds
+#[event]
+pub struct InvoiceCreatedCompact {
+    pub invoice_id: [u8; 32],
+    pub amount: u64,
+}
+
+#[event]
+pub struct InvoiceExtended {
+    pub invoice_id: [u8; 32],
+    pub old_expires_at: i64,
+    pub new_expires_at: i64,
+}
+
+#[event]
+pub struct InvoiceMarkedExpired {
+    pub invoice_id: [u8; 32],
+    pub recipient: Pubkey,
+    pub expired_at: i64,
+}
+
+#[event]
+pub struct InvoiceClosed {
+    pub invoice_id: [u8; 32],
+    pub recipient: Pubkey,
+    pub rent_returned: u64,
+}
+
+#[event]
+pub struct InvoicePreauthorized {
+    pub invoice_id: [u8; 32],
+    pub payer: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct PreauthorizationCaptured {
+    pub invoice_id: [u8; 32],
+    pub payer: Pubkey,
+    pub captured: u64,
+    pub fee: u64,
+    pub refunded: u64,
+}
+
+#[event]
+pub struct PreauthorizationVoided {
+    pub invoice_id: [u8; 32],
+    pub payer: Pubkey,
+    pub refunded: u64,
+}
+
+#[event]
+pub struct InvoicePaid {
+    pub invoice_id: [u8; 32],
+    pub payer: Pubkey,
+    pub treasury: Pubkey,
+    pub amount: u64,
+    pub fee: u64,
+    pub payer_memo: Option<String>,
+    pub seq: u64,
+}
+
+#[event]
+pub struct InvoiceSplitPaid {
+    pub invoice_id: [u8; 32],
+    pub recipient: Pubkey,
+    pub amount: u64,
+}
+
+/// `InvoicePaid` as emitted by `compact-events` builds
+#[event]
+pub struct InvoicePaidCompact {
+    pub invoice_id: [u8; 32],
+    pub amount: u64,
+}
+
+#[event]
+pub struct ChannelSettlement {
+    pub channel_id: [u8; 32],
+    pub invoice_count: u32,
+    pub total_amount: u64,
+    /// Portion moved between channel balances
+    pub netted_amount: u64,
+    /// Portion beyond the payer's channel balance, transferred on-chain
+    pub residual_amount: u64,
+    pub seq: u64,
+}
+
+#[event]
+pub struct BatchSettled {
+    pub batch_id: [u8; 32],
+    pub invoice_count: u32,
+    pub total_amount: u64,
+    pub recipient: Pubkey,
+    pub treasury: Pubkey,
+    pub seq: u64,
+}
+
+#[event]
+pub struct BatchReversed {
+    pub batch_id: [u8; 32],
+    pub invoice_count: u32,
+    pub total_amount: u64,
+    pub recipient: Pubkey,
+    pub settler: Pubkey,
+}
+
+#[event]
+pub struct BatchEscrowFunded {
+    pub batch_id: [u8; 32],
+    pub settler: Pubkey,
+    pub recipient: Pubkey,
+    pub total_amount: u64,
+    pub claim_deadline: i64,
+}
+
+#[event]
+pub struct BatchEscrowClaimed {
+    pub batch_id: [u8; 32],
+    pub recipient: Pubkey,
+    pub invoice_count: u32,
+    pub total_amount: u64,
+}
+
+#[event]
+pub struct BatchEscrowReclaimed {
+    pub batch_id: [u8; 32],
+    pub settler: Pubkey,
+    pub total_amount: u64,
+}
+
+#[event]
+pub struct ChannelOpened {
+    pub channel_id: [u8; 32],
+    pub party_a: Pubkey,
+    pub party_b: Pubkey,
+    pub deposit: u64,
+    pub seq: u64,
+}
+
+#[event]
+pub struct ChannelAccepted {
+    pub channel_id: [u8; 32],
+    pub party_a: Pubkey,
+    pub party_b: Pubkey,
+}
+
+#[event]
+pub struct ChannelDepositReclaimed {
+    pub channel_id: [u8; 32],
+    pub party_a: Pubkey,
+    pub refunded: u64,
+    pub seq: u64,
+}
+
+#[event]
+pub struct ChannelCancelled {
+    pub channel_id: [u8; 32],
+    pub party_a: Pubkey,
+    pub refunded: u64,
+}
+
+#[event]
+pub struct ChannelFunded {
+    pub channel_id: [u8; 32],
+    pub amount: u64,
+    pub balance_a: u64,
+    pub balance_b: u64,
+    pub nonce: u64,
+}
+
+#[event]
+pub struct ChannelUpdated {
+    pub channel_id: [u8; 32],
+    pub balance_a: u64,
+    pub balance_b: u64,
+    pub nonce: u64,
+    pub state_hash: [u8; 32],
+}
+
+#[event]
+pub struct ChannelSpliced {
+    pub channel_id: [u8; 32],
+    pub withdraw_a: u64,
+    pub withdraw_b: u64,
+    pub balance_a: u64,
+    pub balance_b: u64,
+    pub nonce: u64,
+}
+
+#[event]
+pub struct ChannelClosing {
+    pub channel_id: [u8; 32],
+    pub initiator: Pubkey,
+    pub balance_a: u64,
+    pub balance_b: u64,
+    pub nonce: u64,
+    pub close_deadline: i64,
+}
+
+#[event]
+pub struct ChannelDisputed {
+    pub channel_id: [u8; 32],
+    pub challenger: Pubkey,
+    pub balance_a: u64,
+    pub balance_b: u64,
+    pub nonce: u64,
+}
+
+#[event]
+pub struct ChannelClosed {
+    pub channel_id: [u8; 32],
+    pub final_balance_a: u64,
+    pub final_balance_b: u64,
+    pub nonce: u64,
+    pub fee: u64,
+    pub seq: u64,
+}
+
+/// Party-scoped companion to `ChannelOpened`, emitted once per party
+#[event]
+pub struct PartyChannelRegistered {
+    pub party: Pubkey,
+    pub counterparty: Pubkey,
+    pub channel_id: [u8; 32],
+}
+
+/// Party-scoped companion to `ChannelClosed`, emitted once per party
+#[event]
+pub struct PartyChannelClosed {
+    pub party: Pubkey,
+    pub counterparty: Pubkey,
+    pub channel_id: [u8; 32],
+    pub final_balance: u64,
+}
+
+// === Agent Registry ===
+
+/// Maximum length for agent name
+pub const MAX_NAME_LENGTH: usize = 64;
+
+/// Maximum length for service description
+pub const MAX_DESCRIPTION_LENGTH: usize = 256;
+
+/// Maximum number of service capabilities
+pub const MAX_CAPABILITIES: usize = 10;
+
+/// Maximum length per capability
+pub const MAX_CAPABILITY_LENGTH: usize = 32;
+
+/// Maximum length of a service request's metadata URI
+pub const MAX_METADATA_URI_LENGTH: usize = 200;
+
+/// Maximum number of capability aliases per agent
+pub const MAX_CAPABILITY_ALIASES: usize = 10;
+
+/// Maximum number of marketplace tags per agent
+pub const MAX_TAGS: usize = 5;
+
+/// Current `AgentProfile` layout version
+pub const AGENT_PROFILE_SCHEMA_VERSION: u8 = 13;
+
+/// Maximum number of indexed agents one owner can register
+pub const MAX_AGENTS_PER_OWNER: u8 = 8;
+
+/// Maximum capabilities in one bundled service request
+pub const MAX_BUNDLE_CAPABILITIES: usize = 4;
+
+/// Maximum capabilities `MarketplaceStats` tracks price averages for
+pub const MAX_TRACKED_CAPABILITIES: usize = 16;
+
+/// Delay before a raised base price takes effect (1 hour)
+pub const PRICE_INCREASE_DELAY_SECONDS: i64 = 3600;
+
+#[account]
+#[derive(InitSpace)]
+pub struct AgentProfile {
+    /// Agent's public key (owner)
+    pub owner: Pubkey,
+    /// Agent's display name
+    #[max_len(64)]
+    pub name: String,
+    /// Description of agent's services
+    #[max_len(256)]
+    pub description: String,
+    /// Service capabilities (e.g., "sentiment", "translation", "image-gen")
+    #[max_len(10, 32)]
+    pub capabilities: Vec<String>,
+    /// Base price per request in lamports
+    pub base_price: u64,
+    /// Treasury account for payments
+    pub treasury: Pubkey,
+    /// Whether agent is currently active
+    pub is_active: bool,
+    /// Total requests served
+    pub total_requests: u64,
+    /// Total earnings
+    pub total_earnings: u64,
+    /// Registration timestamp
+    pub registered_at: i64,
+    /// Last active timestamp
+    pub last_active_at: i64,
+    /// PDA bump
+    pub bump: u8,
+    /// Layout version; new fields are only ever appended after this one
+    pub schema_version: u8,
+    /// Requests escrowed but not yet completed, cancelled or disputed (v2)
+    pub open_requests: u32,
+    /// Alternate names resolved to a listed capability on request (v3)
+    #[max_len(10)]
+    pub capability_aliases: Vec<CapabilityAlias>,
+    /// Coarse marketplace categories (e.g. "data", "compute"), zero-padded (v4)
+    #[max_len(5)]
+    pub tags: Vec<[u8; 16]>,
+    /// Sum of seconds between request creation and acceptance (v5)
+    pub total_response_time: u64,
+    /// Number of accepted requests in `total_response_time` (v5)
+    pub response_samples: u64,
+    /// Completed requests per capability, aligned with `capabilities` (v6)
+    #[max_len(10)]
+    pub capability_request_counts: Vec<u64>,
+    /// Most requests that may be open at once; zero means unlimited (v7)
+    pub max_concurrent_requests: u32,
+    /// Raised base price waiting out `PRICE_INCREASE_DELAY_SECONDS` (v8)
+    pub pending_base_price: Option<u64>,
+    /// When `pending_base_price` replaces `base_price` (v8)
+    pub price_effective_at: i64,
+    /// Released request payments held in the treasury, not yet withdrawn (v9)
+    pub available_earnings: u64,
+    /// Whether each capability currently takes requests, aligned with
+    /// `capabilities` (v10)
+    #[max_len(10)]
+    pub capability_active: Vec<bool>,
+    /// Whether new requests are taken; unlike `is_active`, pausing keeps
+    /// the agent listed (v11)
+    pub accepting_requests: bool,
+    /// Registry slot of an indexed agent, `None` for an owner's primary
+    /// profile (v12)
+    pub agent_index: Option<u8>,
+    /// When the agent last went inactive; cleared on reactivation (v13)
+    pub deactivated_at: Option<i64>,
+}
+
+impl AgentProfile {
+    /// Account size for the current schema version (including discriminator)
+    pub const SPACE: usize = 8 + Self::INIT_SPACE;
+
+    /// Trailing PDA seed: empty for a primary profile, so its address stays
+    /// `[b"agent", owner]`, or the one-byte registry index
+    pub fn index_seed(&self) -> &[u8] {
+        index_seed(&self.agent_index)
+    }
+
+    /// List or delist the agent, recording when it went inactive
+    pub fn set_active(&mut self, active: bool, now: i64) {
+        if !active && self.is_active {
+            self.deactivated_at = Some(now);
+        } else if active {
+            self.deactivated_at = None;
+        }
+        self.is_active = active;
+    }
+
+    /// Reject new requests once the provider's concurrency limit is reached
+    pub fn require_capacity(&self) -> Result<()> {
+        require!(
+            self.max_concurrent_requests == 0
+                || self.open_requests < self.max_concurrent_requests,
+            AgentFundError::ProviderAtCapacity
+        );
+        Ok(())
+    }
+
+    /// Change the base price; a decrease applies immediately, while an
+    /// increase is scheduled so requesters mid-negotiation aren't repriced
+    pub fn set_base_price(&mut self, price: u64, now: i64) -> Result<()> {
+        self.apply_due_price(now);
+        if price <= self.base_price {
+            self.base_price = price;
+            self.pending_base_price = None;
+            self.price_effective_at = 0;
+        } else {
+            self.pending_base_price = Some(price);
+            self.price_effective_at = now
+                .checked_add(PRICE_INCREASE_DELAY_SECONDS)
+                .ok_or(AgentFundError::ArithmeticOverflow)?;
+        }
+        Ok(())
+    }
+
+    /// Promote a scheduled price increase once its effective time has passed
+    pub fn apply_due_price(&mut self, now: i64) {
+        if let Some(price) = self.pending_base_price {
+            if now >= self.price_effective_at {
+                self.base_price = price;
+                self.pending_base_price = None;
+                self.price_effective_at = 0;
+            }
+        }
+    }
+
+    /// Volume tier reached by completed requests: 0 below every enabled
+    /// threshold, otherwise the 1-based index of the highest one met
+    pub fn volume_tier(&self, thresholds: &[u64; FEE_TIERS]) -> u8 {
+        thresholds
+            .iter()
+            .rposition(|threshold| *threshold > 0 && self.total_requests >= *threshold)
+            .map_or(0, |index| index as u8 + 1)
+    }
+
+    /// Mean seconds from request to acceptance, or 0 before any acceptance
+    pub fn average_response_seconds(&self) -> u64 {
+        self.total_response_time
+            .checked_div(self.response_samples)
+            .unwrap_or(0)
+    }
+
+    /// Replace the capability list, keeping per-capability data consistent
+    ///
+    /// Data is preserved by name, not position: a capability kept in the new
+    /// list carries its count to its new index, a new one starts at zero, and
+    /// a removed one's count is dropped (re-adding it later starts from zero).
+    /// Aliases pointing at a removed capability are dropped with it.
+    pub fn set_capabilities(&mut self, capabilities: Vec<String>) {
+        self.capability_request_counts = capabilities
+            .iter()
+            .map(|cap| {
+                self.capabilities
+                    .iter()
+                    .position(|existing| existing == cap)
+                    .and_then(|i| self.capability_request_counts.get(i).copied())
+                    .unwrap_or(0)
+            })
+            .collect();
+        self.capability_active = capabilities
+            .iter()
+            .map(|cap| self.is_capability_active(cap))
+            .collect();
+        self.capability_aliases
+            .retain(|alias| capabilities.contains(&alias.canonical));
+        self.capabilities = capabilities;
+    }
+
+    /// Count a completed request against a listed capability, returning its
+    /// new total (0 if the capability has since been removed)
+    pub fn record_capability_use(&mut self, capability: &str) -> Result<u64> {
+        let Some(index) = self.capabilities.iter().position(|cap| cap == capability) else {
+            return Ok(0);
+        };
+        self.capability_request_counts.resize(self.capabilities.len(), 0);
+        let count = &mut self.capability_request_counts[index];
+        *count = count.checked_add(1).ok_or(AgentFundError::ArithmeticOverflow)?;
+        Ok(*count)
+    }
+
+    /// Seconds since the agent was last active
+    pub fn freshness(&self, now: i64) -> i64 {
+        now.saturating_sub(self.last_active_at)
+    }
+
+    /// Whether the agent has been inactive for longer than `threshold` seconds
+    pub fn is_stale(&self, now: i64, threshold: i64) -> bool {
+        self.freshness(now) > threshold
+    }
+
+    /// Whether a listed capability takes requests; capabilities without an
+    /// entry are active, as is anything not listed
+    pub fn is_capability_active(&self, capability: &str) -> bool {
+        self.capabilities
+            .iter()
+            .position(|cap| cap == capability)
+            .and_then(|i| self.capability_active.get(i).copied())
+            .unwrap_or(true)
+    }
+
+    /// Resolve a requested capability and require it to be taking requests
+    pub fn available_capability(&self, requested: &str) -> Result<String> {
+        let capability = self
+            .resolve_capability(requested)
+            .ok_or(AgentFundError::CapabilityNotSupported)?;
+        require!(self.is_capability_active(capability), AgentFundError::CapabilityInactive);
+        Ok(capability.to_string())
+    }
+
+    /// Resolve a requested capability to the listed one it names, if any
+    /// Matching ignores case; listed names win and aliases are only
+    /// consulted otherwise
+    pub fn resolve_capability(&self, requested: &str) -> Option<&str> {
+        let requested = normalize_capability(requested);
+        if let Some(cap) = self.capabilities.iter().find(|cap| **cap == requested) {
+            return Some(cap);
+        }
+        self.capability_aliases
+            .iter()
+            .find(|entry| entry.alias == requested)
+            .map(|entry| entry.canonical.as_str())
+            .filter(|canonical| self.capabilities.iter().any(|cap| cap == canonical))
+    }
+}
+
+/// Registration details shared by every path that creates an agent profile
+pub struct AgentRegistration {
+    pub name: String,
+    pub description: String,
+    pub capabilities: Vec<String>,
+    pub base_price: u64,
+    pub tags: Vec<[u8; 16]>,
+}
+
+/// Alternate name for a listed capability (e.g. "image-generation" -> "image-gen")
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, InitSpace)]
+pub struct CapabilityAlias {
+    #[max_len(32)]
+    pub alias: String,
+    #[max_len(32)]
+    pub canonical: String,
+}
+
+/// Per-owner counter of indexed agent profiles
+#[account]
+#[derive(InitSpace)]
+pub struct AgentRegistry {
+    /// Operator owning the indexed agents
+    pub owner: Pubkey,
+    /// Number of indexed agents registered; also the next free index
+    pub agent_count: u8,
+    /// PDA bump seed
+    pub bump: u8,
+}
+
+impl AgentRegistry {
+    /// Account size (including discriminator)
+    pub const SPACE: usize = 8 + Self::INIT_SPACE;
+}
+
+/// Agents the protocol admin has banned from the marketplace
+#[account]
+#[derive(InitSpace)]
+pub struct BannedAgents {
+    /// Banned agent owners
+    #[max_len(64)]
+    pub agents: Vec<Pubkey>,
+    /// PDA bump
+    pub bump: u8,
+}
+
+impl BannedAgents {
+    /// Account size (including discriminator)
+    pub const SPACE: usize = 8 + Self::INIT_SPACE;
+}
+
+/// Marketplace-wide counters, kept current by the instructions that are
+/// passed this singleton
+#[account]
+#[derive(InitSpace)]
+pub struct MarketplaceStats {
+    /// Agent profiles registered
+    pub total_agents: u64,
+    /// Service requests opened
+    pub total_requests: u64,
+    /// Lamports escrowed across all opened requests
+    pub total_volume: u64,
+    /// Service requests the provider marked complete
+    pub total_completed: u64,
+    /// PDA bump seed
+    pub bump: u8,
+    /// Per-capability price accumulators, first come first tracked
+    #[max_len(16)]
+    pub capability_prices: Vec<CapabilityPriceTwap>,
+}
+
+/// Time-weighted price accumulator for one capability
+/// Each request prices the interval since the previous one at the previous
+/// request's amount, so `cumulative_price_time / total_elapsed` is the
+/// average price weighted by how long each price stood
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct CapabilityPriceTwap {
+    /// SHA-256 of the capability string
+    pub capability_hash: [u8; 32],
+    /// Amount of the most recent request
+    pub last_price: u64,
+    /// When the most recent request was opened
+    pub last_updated: i64,
+    /// Sum of `price * seconds` over every elapsed interval
+    pub cumulative_price_time: u128,
+    /// Seconds covered by `cumulative_price_time`
+    pub total_elapsed: u64,
+}
+
+impl MarketplaceStats {
+    /// Account size (including discriminator)
+    pub const SPACE: usize = 8 + Self::INIT_SPACE;
+
+    fn record_agent(&mut self) -> Result<()> {
+        self.total_agents = self
+            .total_agents
+            .checked_add(1)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        Ok(())
+    }
+
+    fn record_request(&mut self, amount: u64) -> Result<()> {
+        self.total_requests = self
+            .total_requests
+            .checked_add(1)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        self.total_volume = self
+            .total_volume
+            .checked_add(amount)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        Ok(())
+    }
+
+    /// Fold a request priced at `amount` into its capability's accumulator
+    /// Capabilities first seen once the table is full go untracked
+    fn record_price(&mut self, capability_hash: [u8; 32], amount: u64, now: i64) -> Result<()> {
+        let Some(entry) = self
+            .capability_prices
+            .iter_mut()
+            .find(|entry| entry.capability_hash == capability_hash)
+        else {
+            if self.capability_prices.len() < MAX_TRACKED_CAPABILITIES {
+                self.capability_prices.push(CapabilityPriceTwap {
+                    capability_hash,
+                    last_price: amount,
+                    last_updated: now,
+                    cumulative_price_time: 0,
+                    total_elapsed: 0,
+                });
+            }
+            return Ok(());
+        };
+
+        let elapsed = now.saturating_sub(entry.last_updated).max(0) as u64;
+        entry.cumulative_price_time = entry
+            .cumulative_price_time
+            .checked_add(entry.last_price as u128 * elapsed as u128)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        entry.total_elapsed = entry
+            .total_elapsed
+            .checked_add(elapsed)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        entry.last_price = amount;
+        entry.last_updated = now;
+        Ok(())
+    }
+
+    fn record_completion(&mut self) -> Result<()> {
+        self.total_completed = self
+            .total_completed
+            .checked_add(1)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        Ok(())
+    }
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct ServiceRequest {
+    /// Unique request ID
+    pub id: [u8; 32],
+    /// Requesting agent
+    pub requester: Pubkey,
+    /// Service provider agent
+    pub provider: Pubkey,
+    /// Capability being requested
+    #[max_len(32)]
+    pub capability: String,
+    /// Amount escrowed
+    pub amount: u64,
+    /// Request status
+    pub status: RequestStatus,
+    /// Creation timestamp
+    pub created_at: i64,
+    /// Completion timestamp
+    pub completed_at: Option<i64>,
+    /// Optional result hash (for verification)
+    pub result_hash: Option<[u8; 32]>,
+    /// Dispute window in seconds, measured from creation/completion
+    pub dispute_window_seconds: i64,
+    /// Timestamp the provider accepted the request
+    pub accepted_at: Option<i64>,
+    /// SLA completion deadline, in seconds after acceptance
+    pub sla_deadline_seconds: Option<i64>,
+    /// Bond posted by the provider backing the SLA
+    pub sla_bond: u64,
+    /// Time the requester has to review a completed result before release
+    pub review_period_seconds: i64,
+    /// Bump of the request's escrow PDA, stored so signing skips the search
+    pub escrow_bump: u8,
+    /// Escrowed principal currently deposited with the yield strategy
+    pub yield_principal: u64,
+    /// Third party allowed to attest completion in place of the provider
+    pub oracle: Option<Pubkey>,
+    /// Disputed request this one redoes, if created by a `RedoRequest` resolution
+    pub parent_request: Option<Pubkey>,
+    /// Every capability of a bundled order, `capability` first; empty otherwise
+    #[max_len(4, 32)]
+    pub bundle: Vec<String>,
+    /// How `result_hash` was computed, so verifiers know how to recompute it
+    pub hash_algorithm: HashAlgorithm,
+    /// Off-chain location of the request's detailed specification
+    #[max_len(200)]
+    pub metadata_uri: Option<String>,
+    /// Registry index of the provider profile serving the request
+    pub provider_index: Option<u8>,
+}
+
+impl ServiceRequest {
+    /// Account size (including discriminator)
+    pub const SPACE: usize = 8 + Self::INIT_SPACE;
+
+    /// Trailing seed of the provider profile serving the request
+    pub fn provider_index_seed(&self) -> &[u8] {
+        index_seed(&self.provider_index)
+    }
+
+    /// Require all escrowed principal to be back from the yield strategy
+    /// before the escrow is paid out
+    pub fn require_principal_in_escrow(&self) -> Result<()> {
+        require!(self.yield_principal == 0, AgentFundError::EscrowInYield);
+        Ok(())
+    }
+
+    /// Latest of creation, acceptance and completion
+    pub fn last_activity(&self) -> i64 {
+        self.created_at
+            .max(self.accepted_at.unwrap_or(0))
+            .max(self.completed_at.unwrap_or(0))
+    }
+
+    /// Last moment a dispute can be opened: the dispute window counted from
+    /// completion, or from creation for undelivered work
+    pub fn dispute_deadline(&self) -> Result<i64> {
+        self.completed_at
+            .unwrap_or(self.created_at)
+            .checked_add(self.dispute_window_seconds)
+            .ok_or_else(|| AgentFundError::ArithmeticOverflow.into())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, InitSpace)]
+pub enum RequestStatus {
+    Pending,
+    InProgress,
+    Completed,
+    Disputed,
+    Refunded,
+    Cancelled,
+    PendingReview,
+    Recovered,
+}
+
+/// Hash function a provider used to produce a request's `result_hash`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Default, InitSpace)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Keccak256,
+    Blake3,
+}
+
+/// Dispute for a service request
+#[account]
+#[derive(Default, InitSpace)]
+pub struct Dispute {
+    /// Request ID being disputed
+    pub request_id: [u8; 32],
+    /// Who initiated the dispute
+    pub initiator: Pubkey,
+    /// Reason for dispute
+    #[max_len(512)]
+    pub reason: String,
+    /// Dispute status
+    pub status: DisputeStatus,
+    /// Creation timestamp
+    pub created_at: i64,
+    /// Resolution timestamp
+    pub resolved_at: Option<i64>,
+    /// Resolution details
+    pub resolution: Option<DisputeResolution>,
+    /// Dispute fee staked by the initiator, held in this account until resolution
+    pub fee: u64,
+    /// Request status before the dispute, restored if it is withdrawn
+    pub prior_status: RequestStatus,
+    /// DAO authority the dispute was escalated to; once set, only it resolves
+    pub escalated_to: Option<Pubkey>,
+    /// Time by which the dispute is expected to be resolved; parties can extend it
+    pub review_deadline: i64,
+}
+
+impl Dispute {
+    /// Account size (including discriminator)
+    pub const SPACE: usize = 8 + Self::INIT_SPACE;
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, InitSpace)]
+pub enum DisputeStatus {
+    Open,
+    UnderReview,
+    Resolved,
+    Expired,
+    Withdrawn,
+}
+
+impl Default for DisputeStatus {
+    fn default() -> Self {
+        DisputeStatus::Open
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, InitSpace)]
+pub enum DisputeResolution {
+    /// Full refund to requester
+    RefundRequester,
+    /// Full payment to provider
+    PayProvider,
+    /// Split payment by percentage
+    Split { requester_pct: u8 },
+    /// Refund an exact amount to requester, remainder to provider
+    PartialRefund { refund_amount: u64 },
+    /// Refund the requester's share and carry the provider's share into a
+    /// linked follow-up request for the redone work
+    RedoRequest { provider_pct: u8 },
+}
+
+impl Default for RequestStatus {
+    fn default() -> Self {
+        RequestStatus::Pending
+    }
+}
+
+// === Registry Contexts ===
+
+#[derive(Accounts)]
+pub struct RegisterAgent<'info> {
+    #[account(
+        init,
+        payer = owner,
+        space = AgentProfile::SPACE,
+        seeds = [b"agent", owner.key().as_ref()],
+        bump
+    )]
+    pub agent_profile: Account<'info, AgentProfile>,
+    
+    #[account(
+        seeds = [b"treasury", owner.key().as_ref()],
+        bump = treasury.bump
+    )]
+    pub treasury: Account<'info, Treasury>,
+    
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    
+    /// Optional marketplace stats; when supplied, the global counters are updated
+    #[account(
+        mut,
+        seeds = [b"marketplace_stats"],
+        bump = stats.bump
+    )]
+    pub stats: Option<Account<'info, MarketplaceStats>>,
+    
+    /// CHECK: Protocol ban list PDA; may not exist yet, in which case no
+    /// one is banned
+    #[account(seeds = [b"banned_agents"], bump)]
+    pub banned_agents: UncheckedAccount<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RegisterAgentWithTreasury<'info> {
+    #[account(
+        init,
+        payer = owner,
+        space = AgentProfile::SPACE,
+        seeds = [b"agent", owner.key().as_ref()],
+        bump
+    )]
+    pub agent_profile: Account<'info, AgentProfile>,
+    
+    #[account(
+        init,
+        payer = owner,
+        space = Treasury::SPACE,
+        seeds = [b"treasury", owner.key().as_ref()],
+        bump
+    )]
+    pub treasury: Account<'info, Treasury>,
+    
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    
+    /// Optional marketplace stats; when supplied, the global counters are updated
+    #[account(
+        mut,
+        seeds = [b"marketplace_stats"],
+        bump = stats.bump
+    )]
+    pub stats: Option<Account<'info, MarketplaceStats>>,
+    
+    /// CHECK: Protocol ban list PDA; may not exist yet, in which case no
+    /// one is banned
+    #[account(seeds = [b"banned_agents"], bump)]
+    pub banned_agents: UncheckedAccount<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeAgentRegistry<'info> {
+    #[account(
+        init,
+        payer = owner,
+        space = AgentRegistry::SPACE,
+        seeds = [b"agent_registry", owner.key().as_ref()],
+        bump
+    )]
+    pub registry: Account<'info, AgentRegistry>,
+    
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeMarketplaceStats<'info> {
+    #[account(
+        init,
+        payer = payer,
+        space = MarketplaceStats::SPACE,
+        seeds = [b"marketplace_stats"],
+        bump
+    )]
+    pub stats: Account<'info, MarketplaceStats>,
+    
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(index: u8)]
+pub struct RegisterAgentIndexed<'info> {
+    #[account(
+        init,
+        payer = owner,
+        space = AgentProfile::SPACE,
+        seeds = [b"agent", owner.key().as_ref(), &[index]],
+        bump
+    )]
+    pub agent_profile: Account<'info, AgentProfile>,
+    
+    #[account(
+        mut,
+        seeds = [b"agent_registry", owner.key().as_ref()],
+        bump = registry.bump,
+        has_one = owner
+    )]
+    pub registry: Account<'info, AgentRegistry>,
+    
+    #[account(
+        seeds = [b"treasury", owner.key().as_ref()],
+        bump = treasury.bump
+    )]
+    pub treasury: Account<'info, Treasury>,
+    
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    
+    /// Optional marketplace stats; when supplied, the global counters are updated
+    #[account(
+        mut,
+        seeds = [b"marketplace_stats"],
+        bump = stats.bump
+    )]
+    pub stats: Option<Account<'info, MarketplaceStats>>,
+    
+    /// CHECK: Protocol ban list PDA; may not exist yet, in which case no
+    /// one is banned
+    #[account(seeds = [b"banned_agents"], bump)]
+    pub banned_agents: UncheckedAccount<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MarkStaleAgent<'info> {
+    #[account(mut)]
+    pub agent_profile: Account<'info, AgentProfile>,
+    
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProtocolConfig>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateAgentProfile<'info> {
+    #[account(
+        mut,
+        seeds = [b"agent", owner.key().as_ref(), agent_profile.index_seed()],
+        bump = agent_profile.bump,
+        has_one = owner
+    )]
+    pub agent_profile: Account<'info, AgentProfile>,
+    
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct GetCapabilitiesPage<'info> {
+    pub agent_profile: Account<'info, AgentProfile>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateAgentProfile<'info> {
+    /// CHECK: Validated and deserialized manually, since older layouts
+    /// may not fit the current struct until reallocated
+    #[account(mut, owner = crate::ID)]
+    pub agent_profile: AccountInfo<'info>,
+    
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(request_id: [u8; 32])]
+pub struct CreateServiceRequest<'info> {
+    /// Created on first use; an id already in use is rejected by `fund`
+    #[account(
+        init_if_needed,
+        payer = requester,
+        space = ServiceRequest::SPACE,
+        seeds = [b"request", request_id.as_ref()],
+        bump
+    )]
+    pub request: Account<'info, ServiceRequest>,
+    
+    #[account(
+        mut,
+        seeds = [b"agent", provider_owner.key().as_ref(), provider_profile.index_seed()],
+        bump = provider_profile.bump
+    )]
+    pub provider_profile: Account<'info, AgentProfile>,
+    
+    /// CHECK: Provider owner for profile lookup
+    pub provider_owner: AccountInfo<'info>,
+    
+    /// CHECK: Provider treasury; may have been closed, so its health is
+    /// checked by hand rather than deserialized
+    #[account(
+        seeds = [b"treasury", provider_owner.key().as_ref()],
+        bump
+    )]
+    pub provider_treasury: AccountInfo<'info>,
+    
+    /// CHECK: Escrow for holding payment
+    #[account(
+        mut,
+        seeds = [b"request_escrow", request_id.as_ref()],
+        bump
+    )]
+    pub escrow: AccountInfo<'info>,
+    
+    #[account(mut)]
+    pub requester: Signer<'info>,
+    
+    /// CHECK: Optional oracle trusted to attest completion; any key
+    pub oracle: Option<AccountInfo<'info>>,
+    
+    /// Optional protocol config; when supplied, emitted events are sequenced
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Option<Account<'info, ProtocolConfig>>,
+    
+    /// Optional marketplace stats; when supplied, the global counters are updated
+    #[account(
+        mut,
+        seeds = [b"marketplace_stats"],
+        bump = stats.bump
+    )]
+    pub stats: Option<Account<'info, MarketplaceStats>>,
+    
+    /// CHECK: Protocol ban list PDA; may not exist yet, in which case no
+    /// one is banned
+    #[account(seeds = [b"banned_agents"], bump)]
+    pub banned_agents: UncheckedAccount<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Optional terms a requester attaches when opening a service request
+#[derive(Default)]
+struct RequestTerms {
+    dispute_window_seconds: Option<i64>,
+    review_period_seconds: Option<i64>,
+    metadata_uri: Option<String>,
+}
+
+impl<'info> CreateServiceRequest<'info> {
+    /// Escrow `amount` from the requester and open the request against the
+    /// provider; the capability must already be resolved and priced
+    fn fund(
+        &mut self,
+        request_id: [u8; 32],
+        capability: String,
+        amount: u64,
+        terms: RequestTerms,
+        escrow_bump: u8,
+    ) -> Result<()> {
+        require!(self.request.created_at == 0, AgentFundError::RequestIdAlreadyExists);
+        // An agent paying itself would only fake volume through the escrow
+        require_keys_neq!(
+            self.requester.key(),
+            self.provider_owner.key(),
+            AgentFundError::SelfDealingNotAllowed
+        );
+        require!(
+            !is_banned(&self.banned_agents, &self.provider_owner.key())?,
+            AgentFundError::ProviderBanned
+        );
+        require_provider_healthy(&self.provider_treasury)?;
+        self.provider_profile.require_capacity()?;
+
+        let dispute_window_seconds = terms.dispute_window_seconds.unwrap_or(DISPUTE_WINDOW_SECONDS);
+        require!(
+            (MIN_DISPUTE_WINDOW_SECONDS..=MAX_DISPUTE_WINDOW_SECONDS).contains(&dispute_window_seconds),
+            AgentFundError::InvalidDisputeWindow
+        );
+        let review_period_seconds = terms.review_period_seconds.unwrap_or(0);
+        require!(
+            (0..=MAX_REVIEW_PERIOD_SECONDS).contains(&review_period_seconds),
+            AgentFundError::InvalidReviewPeriod
+        );
+        if let Some(uri) = &terms.metadata_uri {
+            require!(uri.len() <= MAX_METADATA_URI_LENGTH, AgentFundError::MetadataUriTooLong);
+        }
+
+        // Transfer to escrow; a free request moves no lamports and its
+        // escrow is never created
+        let escrow_before = self.escrow.lamports();
+        if amount > 0 {
+            require_payer_balance(&self.requester, amount)?;
+            let transfer_ix = anchor_lang::solana_program::system_instruction::transfer(
+                &self.requester.key(),
+                &self.escrow.key(),
+                amount,
+            );
+            anchor_lang::solana_program::program::invoke(
+                &transfer_ix,
+                &[
+                    self.requester.to_account_info(),
+                    self.escrow.to_account_info(),
+                    self.system_program.to_account_info(),
+                ],
+            )?;
+        }
+
+        // Every later release pays out `request.amount`, so the escrow must
+        // have received exactly that. A plain system transfer always does;
+        // this guards funding paths that could diverge (a fee taken in
+        // transit, a refactor crediting another account) so an escrow short
+        // of `amount` is never recorded as funded
+        let funded = self.escrow.lamports().saturating_sub(escrow_before);
+        if funded != amount {
+            return Err(amount_error(AgentFundError::EscrowFundingMismatch, amount, funded));
+        }
+        if funded > 0 {
+            emit!(EscrowFunded {
+                request_id,
+                escrow: self.escrow.key(),
+                amount: funded,
+            });
+        }
+
+        let request = &mut self.request;
+        request.id = request_id;
+        request.requester = self.requester.key();
+        request.provider = self.provider_owner.key();
+        request.capability = capability.clone();
+        request.amount = amount;
+        request.status = RequestStatus::Pending;
+        request.created_at = Clock::get()?.unix_timestamp;
+        request.completed_at = None;
+        request.result_hash = None;
+        request.dispute_window_seconds = dispute_window_seconds;
+        request.accepted_at = None;
+        request.sla_deadline_seconds = None;
+        request.sla_bond = 0;
+        request.review_period_seconds = review_period_seconds;
+        request.escrow_bump = escrow_bump;
+        request.yield_principal = 0;
+        request.oracle = self.oracle.as_ref().map(|oracle| oracle.key());
+        request.parent_request = None;
+        request.bundle = Vec::new();
+        request.hash_algorithm = HashAlgorithm::default();
+        request.metadata_uri = terms.metadata_uri;
+        request.provider_index = self.provider_profile.agent_index;
+
+        let provider = &mut self.provider_profile;
+        provider.open_requests += 1;
+
+        msg!("Service requested: {} for {} lamports", capability, amount);
+        let seq = next_event_seq(&mut self.config)?;
+        emit!(ServiceRequested {
+            request_id,
+            requester: request.requester,
+            provider: request.provider,
+            capability,
+            amount,
+            metadata_uri: request.metadata_uri.clone(),
+            seq,
+        });
+        let capability_hash = anchor_lang::solana_program::hash::hash(request.capability.as_bytes()).to_bytes();
+        let now = request.created_at;
+        update_marketplace_stats(&mut self.stats, |stats| {
+            stats.record_request(amount)?;
+            stats.record_price(capability_hash, amount, now)
+        })?;
+
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(request_id: [u8; 32])]
+pub struct CreateDelegatedServiceRequest<'info> {
+    /// Created on first use; an id already in use is rejected by `fund`
+    #[account(
+        init_if_needed,
+        payer = relayer,
+        space = ServiceRequest::SPACE,
+        seeds = [b"request", request_id.as_ref()],
+        bump
+    )]
+    pub request: Account<'info, ServiceRequest>,
+    
+    #[account(
+        mut,
+        seeds = [b"agent", provider_owner.key().as_ref(), provider_profile.index_seed()],
+        bump = provider_profile.bump
+    )]
+    pub provider_profile: Account<'info, AgentProfile>,
+    
+    /// CHECK: Provider owner for profile lookup
+    pub provider_owner: AccountInfo<'info>,
+    
+    /// CHECK: Provider treasury; may have been closed, so its health is
+    /// checked by hand rather than deserialized
+    #[account(
+        seeds = [b"treasury", provider_owner.key().as_ref()],
+        bump
+    )]
+    pub provider_treasury: AccountInfo<'info>,
+    
+    /// CHECK: Escrow for holding payment
+    #[account(
+        mut,
+        seeds = [b"request_escrow", request_id.as_ref()],
+        bump
+    )]
+    pub escrow: AccountInfo<'info>,
+    
+    /// CHECK: Requester; authorizes by an ed25519 signature instead of
+    /// signing the transaction
+    pub requester: AccountInfo<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"agent", requester.key().as_ref(), requester_profile.index_seed()],
+        bump = requester_profile.bump
+    )]
+    pub requester_profile: Account<'info, AgentProfile>,
+    
+    /// Requester treasury the escrow is funded from
+    #[account(
+        mut,
+        seeds = [b"treasury", requester.key().as_ref()],
+        bump = requester_treasury.bump
+    )]
+    pub requester_treasury: Account<'info, Treasury>,
+    
+    /// Submits the transaction and pays the request account's rent
+    #[account(mut)]
+    pub relayer: Signer<'info>,
+    
+    /// CHECK: Instructions sysvar, read to find the ed25519 verification
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    pub instructions: AccountInfo<'info>,
+    
+    /// Optional protocol config; when supplied, emitted events are sequenced
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Option<Account<'info, ProtocolConfig>>,
+    
+    /// Optional marketplace stats; when supplied, the global counters are updated
+    #[account(
+        mut,
+        seeds = [b"marketplace_stats"],
+        bump = stats.bump
+    )]
+    pub stats: Option<Account<'info, MarketplaceStats>>,
+    
+    /// CHECK: Protocol ban list PDA; may not exist yet, in which case no
+    /// one is banned
+    #[account(seeds = [b"banned_agents"], bump)]
+    pub banned_agents: UncheckedAccount<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> CreateDelegatedServiceRequest<'info> {
+    /// Escrow `amount` from the requester's treasury earnings and open the
+    /// request with the default dispute window and no review period
+    fn fund(
+        &mut self,
+        request_id: [u8; 32],
+        capability: String,
+        amount: u64,
+        escrow_bump: u8,
+    ) -> Result<()> {
+        require!(self.request.created_at == 0, AgentFundError::RequestIdAlreadyExists);
+        require_keys_neq!(
+            self.requester.key(),
+            self.provider_owner.key(),
+            AgentFundError::SelfDealingNotAllowed
+        );
+        require!(
+            !is_banned(&self.banned_agents, &self.provider_owner.key())?,
+            AgentFundError::ProviderBanned
+        );
+        require_provider_healthy(&self.provider_treasury)?;
+        self.provider_profile.require_capacity()?;
+
+        let treasury_info = self.requester_treasury.to_account_info();
+        debit_earnings(&mut self.requester_profile, &treasury_info, &self.escrow, amount)?;
+        emit_ledger_entry(&self.requester_treasury, LedgerEntryKind::Withdrawn, amount)?;
+
+        let request = &mut self.request;
+        request.id = request_id;
+        request.requester = self.requester.key();
+        request.provider = self.provider_owner.key();
+        request.capability = capability.clone();
+        request.amount = amount;
+        request.status = RequestStatus::Pending;
+        request.created_at = Clock::get()?.unix_timestamp;
+        request.completed_at = None;
+        request.result_hash = None;
+        request.dispute_window_seconds = DISPUTE_WINDOW_SECONDS;
+        request.accepted_at = None;
+        request.sla_deadline_seconds = None;
+        request.sla_bond = 0;
+        request.review_period_seconds = 0;
+        request.escrow_bump = escrow_bump;
+        request.yield_principal = 0;
+        request.oracle = None;
+        request.parent_request = None;
+        request.bundle = Vec::new();
+        request.hash_algorithm = HashAlgorithm::default();
+        request.metadata_uri = None;
+        request.provider_index = self.provider_profile.agent_index;
+
+        self.provider_profile.open_requests += 1;
+
+        msg!("Delegated service requested: {} for {} lamports", capability, amount);
+        let seq = next_event_seq(&mut self.config)?;
+        emit!(ServiceRequested {
+            request_id,
+            requester: request.requester,
+            provider: request.provider,
+            capability,
+            amount,
+            metadata_uri: None,
+            seq,
+        });
+        emit!(ServiceRequestRelayed {
+            request_id,
+            requester: request.requester,
+            relayer: self.relayer.key(),
+        });
+        let capability_hash = anchor_lang::solana_program::hash::hash(request.capability.as_bytes()).to_bytes();
+        let now = request.created_at;
+        update_marketplace_stats(&mut self.stats, |stats| {
+            stats.record_request(amount)?;
+            stats.record_price(capability_hash, amount, now)
+        })?;
+
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct AcceptServiceRequest<'info> {
+    #[account(
+        mut,
+        constraint = request.provider == provider.key() @ AgentFundError::UnauthorizedProvider
+    )]
+    pub request: Account<'info, ServiceRequest>,
+    
+    /// CHECK: Escrow PDA that also holds any SLA bond
+    #[account(
+        mut,
+        seeds = [b"request_escrow", request.id.as_ref()],
+        bump = request.escrow_bump
+    )]
+    pub escrow: AccountInfo<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"agent", provider.key().as_ref(), request.provider_index_seed()],
+        bump = provider_profile.bump
+    )]
+    pub provider_profile: Account<'info, AgentProfile>,
+    
+    #[account(mut)]
+    pub provider: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CancelServiceRequest<'info> {
+    #[account(mut, has_one = requester)]
+    pub request: Account<'info, ServiceRequest>,
+    
+    #[account(
+        mut,
+        seeds = [b"agent", request.provider.as_ref(), request.provider_index_seed()],
+        bump = provider_profile.bump
+    )]
+    pub provider_profile: Account<'info, AgentProfile>,
+    
+    /// CHECK: Escrow PDA holding the request payment
+    #[account(
+        mut,
+        seeds = [b"request_escrow", request.id.as_ref()],
+        bump = request.escrow_bump
+    )]
+    pub escrow: AccountInfo<'info>,
+    
+    #[account(mut)]
+    pub requester: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ReclaimServiceRequest<'info> {
+    #[account(mut, has_one = requester, has_one = provider)]
+    pub request: Account<'info, ServiceRequest>,
+    
+    /// CHECK: Dispute PDA for the request; must never have been created
+    #[account(
+        seeds = [b"dispute", request.id.as_ref()],
+        bump
+    )]
+    pub dispute: UncheckedAccount<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"agent", request.provider.as_ref(), request.provider_index_seed()],
+        bump = provider_profile.bump
+    )]
+    pub provider_profile: Account<'info, AgentProfile>,
+    
+    /// CHECK: Escrow PDA holding the request payment
+    #[account(
+        mut,
+        seeds = [b"request_escrow", request.id.as_ref()],
+        bump = request.escrow_bump
+    )]
+    pub escrow: AccountInfo<'info>,
+    
+    /// CHECK: Provider receiving back any SLA bond
+    #[account(mut)]
+    pub provider: AccountInfo<'info>,
+    
+    #[account(mut)]
+    pub requester: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> ReclaimServiceRequest<'info> {
+    /// Require the request to be undelivered, undisputed and fully escrowed
+    fn require_refundable(&self) -> Result<()> {
+        // Dispute accounts are never closed, so an initialized one means the
+        // request was disputed at some point
+        require!(self.dispute.data_is_empty(), AgentFundError::RequestWasDisputed);
+        require!(
+            matches!(self.request.status, RequestStatus::Pending | RequestStatus::InProgress),
+            AgentFundError::RequestNotPending
+        );
+        self.request.require_principal_in_escrow()
+    }
+
+    /// Return an undelivered request's escrow to the requester
+    fn refund(&mut self) -> Result<()> {
+        let request = &mut self.request;
+
+        // Work was never delivered, so SLA timeliness is not judged and any
+        // bond goes back to the provider
+        let escrow_seeds: &[&[u8]] = &[b"request_escrow", request.id.as_ref(), &[request.escrow_bump]];
+        for (recipient, amount) in [
+            (self.requester.to_account_info(), request.amount),
+            (self.provider.to_account_info(), request.sla_bond),
+        ] {
+            if amount > 0 {
+                transfer_from_escrow(
+                    &self.escrow,
+                    &recipient,
+                    &self.system_program,
+                    amount,
+                    escrow_seeds,
+                )?;
+            }
+        }
+
+        request.status = RequestStatus::Refunded;
+
+        let provider = &mut self.provider_profile;
+        provider.open_requests = provider.open_requests.saturating_sub(1);
+
+        msg!("Service request reclaimed, {} lamports refunded", request.amount);
+        emit!(ServiceReclaimed {
+            request_id: request.id,
+            requester: request.requester,
+            refunded: request.amount,
+            bond_returned: request.sla_bond,
+        });
+
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct CompleteServiceRequest<'info> {
+    #[account(
+        mut,
+        constraint = request.provider == provider.key() @ AgentFundError::UnauthorizedProvider
+    )]
+    pub request: Account<'info, ServiceRequest>,
+    
+    #[account(
+        mut,
+        seeds = [b"agent", provider.key().as_ref(), request.provider_index_seed()],
+        bump = provider_profile.bump,
+        has_one = owner @ AgentFundError::UnauthorizedProvider
+    )]
+    pub provider_profile: Account<'info, AgentProfile>,
+    
+    /// CHECK: Provider completing the request
+    pub provider: AccountInfo<'info>,
+    
+    /// Owner must sign to complete
+    pub owner: Signer<'info>,
+    
+    /// Optional protocol config; when supplied, emitted events are sequenced
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Option<Account<'info, ProtocolConfig>>,
+    
+    /// Optional marketplace stats; when supplied, the global counters are updated
+    #[account(
+        mut,
+        seeds = [b"marketplace_stats"],
+        bump = stats.bump
+    )]
+    pub stats: Option<Account<'info, MarketplaceStats>>,
+}
+
+#[derive(Accounts)]
+pub struct FinalizeCompletion<'info> {
+    #[account(mut)]
+    pub request: Account<'info, ServiceRequest>,
+    
+    #[account(
+        mut,
+        seeds = [b"agent", request.provider.as_ref(), request.provider_index_seed()],
+        bump = provider_profile.bump
+    )]
+    pub provider_profile: Account<'info, AgentProfile>,
+    
+    /// CHECK: Escrow holding payment
+    #[account(
+        mut,
+        seeds = [b"request_escrow", request.id.as_ref()],
+        bump = request.escrow_bump
+    )]
+    pub escrow: AccountInfo<'info>,
+    
+    /// Receives the payment; must be the treasury the provider's profile
+    /// was registered with, checked explicitly so a mismatch is named
+    #[account(
+        mut,
+        constraint = provider_treasury.key() == provider_profile.treasury
+            && provider_treasury.owner == provider_profile.owner
+            @ AgentFundError::TreasuryProfileMismatch
+    )]
+    pub provider_treasury: Account<'info, Treasury>,
+    
+    /// CHECK: Provider receiving payment
+    #[account(mut, constraint = provider.key() == request.provider)]
+    pub provider: AccountInfo<'info>,
+    
+    /// CHECK: Requester, credited with any slashed SLA bond; signing approves
+    /// the result before the review period ends
+    #[account(mut, constraint = requester.key() == request.requester)]
+    pub requester: AccountInfo<'info>,
+    
+    /// Protocol config; required so the release delay cannot be skipped.
+    /// Writable so an oracle attestation can sequence its completion event
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, ProtocolConfig>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> FinalizeCompletion<'info> {
+    /// Pay out a request under review, settling any SLA bond
+    /// `approved_early` waives the review period and release delay
+    fn release(&mut self, approved_early: bool) -> Result<()> {
+        let request = &mut self.request;
 
         require!(
-            dispute.status == DisputeStatus::Open,
-            AgentFundError::DisputeNotOpen
+            request.status == RequestStatus::PendingReview,
+            AgentFundError::RequestNotInReview
         );
+        request.require_principal_in_escrow()?;
 
         let now = Clock::get()?.unix_timestamp;
-        
-        // Apply resolution
-        match resolution {
-            DisputeResolution::RefundRequester => {
-                // Refund full amount to requester
-                request.status = RequestStatus::Refunded;
-                msg!("Dispute resolved: full refund to requester");
-            }
-            DisputeResolution::PayProvider => {
-                // Pay full amount to provider
-                request.status = RequestStatus::Completed;
-                msg!("Dispute resolved: full payment to provider");
-            }
-            DisputeResolution::Split { requester_pct } => {
-                // Split payment based on percentage
-                require!(requester_pct <= 100, AgentFundError::InvalidSplitPct);
-                request.status = RequestStatus::Completed;
-                msg!("Dispute resolved: {}% to requester, {}% to provider", 
-                     requester_pct, 100 - requester_pct);
-            }
-        }
-
-        dispute.status = DisputeStatus::Resolved;
-        dispute.resolved_at = Some(now);
-        dispute.resolution = Some(resolution.clone());
-
-        emit!(DisputeResolved {
-            request_id: request.id,
-            resolution,
-        });
+        let completed_at = request.completed_at.ok_or(AgentFundError::RequestNotInReview)?;
+        let review_ends = completed_at
+            .checked_add(request.review_period_seconds)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        require!(approved_early || now >= review_ends, AgentFundError::ReviewPeriodActive);
 
-        Ok(())
-    }
-}
+        // The protocol-wide release delay is a floor for every request; only
+        // the requester's own approval (or its oracle's attestation) waives it
+        let release_at = completed_at
+            .checked_add(self.config.completion_release_delay_seconds)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        require!(approved_early || now >= release_at, AgentFundError::ReleaseDelayActive);
 
-// === Account Structures ===
+        // Settle the SLA bond: returned in full if completed on time, partly
+        // slashed to the requester if completed after the deadline
+        let sla = if request.sla_bond > 0 {
+            let deadline = request.accepted_at
+                .zip(request.sla_deadline_seconds)
+                .and_then(|(accepted, secs)| accepted.checked_add(secs))
+                .ok_or(AgentFundError::ArithmeticOverflow)?;
+            let on_time = completed_at <= deadline;
+            let slashed = if on_time { 0 } else { bps_of(request.sla_bond, SLA_SLASH_BPS)? };
+            let returned = request.sla_bond
+                .checked_sub(slashed)
+                .ok_or(AgentFundError::ArithmeticOverflow)?;
+            Some((on_time, slashed, returned))
+        } else {
+            None
+        };
+        let (slashed, returned) = sla.map_or((0, 0), |(_, slashed, returned)| (slashed, returned));
 
-#[account]
-#[derive(Default)]
-pub struct Treasury {
-    /// Owner agent's public key
-    pub owner: Pubkey,
-    /// PDA bump seed
-    pub bump: u8,
-    /// Total lamports received
-    pub total_received: u64,
-    /// Total lamports settled via batches
-    pub total_settled: u64,
-    /// Number of pending invoices
-    pub pending_invoices: u64,
-    /// Creation timestamp
-    pub created_at: i64,
-}
+        // Release the payment (and settle the bond) before touching any state,
+        // so a failed transfer cannot leave inflated counters behind. The
+        // payment lands in the provider's treasury as withdrawable earnings
+        let escrow_seeds: &[&[u8]] = &[b"request_escrow", request.id.as_ref(), &[request.escrow_bump]];
+        for (recipient, amount) in [
+            (self.provider_treasury.to_account_info(), request.amount),
+            (self.requester.to_account_info(), slashed),
+            (self.provider.to_account_info(), returned),
+        ] {
+            if amount > 0 {
+                transfer_from_escrow(
+                    &self.escrow,
+                    &recipient,
+                    &self.system_program,
+                    amount,
+                    escrow_seeds,
+                )?;
+            }
+        }
 
-#[account]
-pub struct Invoice {
-    /// Unique invoice ID
-    pub id: [u8; 32],
-    /// Recipient (invoice creator)
-    pub recipient: Pubkey,
-    /// Amount in lamports
-    pub amount: u64,
-    /// Human-readable memo
-    pub memo: String,
-    /// Current status
-    pub status: InvoiceStatus,
-    /// Creation timestamp
-    pub created_at: i64,
-    /// Expiration timestamp
-    pub expires_at: i64,
-    /// Payment timestamp (if paid)
-    pub paid_at: Option<i64>,
-    /// Payer public key (if paid)
-    pub payer: Option<Pubkey>,
-}
+        request.status = RequestStatus::Completed;
 
-#[account]
-pub struct BatchSettlement {
-    /// Unique batch ID
-    pub id: [u8; 32],
-    /// Recipient of the batch
-    pub recipient: Pubkey,
-    /// Number of invoices in batch
-    pub invoice_count: u32,
-    /// Total amount settled
-    pub total_amount: u64,
-    /// Settlement timestamp
-    pub settled_at: i64,
-    /// Who submitted the settlement
-    pub settler: Pubkey,
-}
+        // Update provider stats
+        let profile = &mut self.provider_profile;
+        profile.total_requests += 1;
+        profile.total_earnings += request.amount;
+        profile.available_earnings = profile
+            .available_earnings
+            .checked_add(request.amount)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
 
-#[account]
-pub struct PaymentChannel {
-    /// Unique channel ID
-    pub id: [u8; 32],
-    /// First party
-    pub party_a: Pubkey,
-    /// Second party
-    pub party_b: Pubkey,
-    /// Party A's deposit
-    pub deposit_a: u64,
-    /// Party B's deposit
-    pub deposit_b: u64,
-    /// Party A's current balance
-    pub balance_a: u64,
-    /// Party B's current balance
-    pub balance_b: u64,
-    /// State nonce (for ordering updates)
-    pub nonce: u64,
-    /// Channel status
-    pub status: ChannelStatus,
-    /// Opening timestamp
-    pub opened_at: i64,
-    /// Closing timestamp
-    pub closed_at: Option<i64>,
-}
+        // Update treasury
+        let treasury = &mut self.provider_treasury;
+        treasury.total_received += request.amount;
+        emit_ledger_entry(treasury, LedgerEntryKind::Received, request.amount)?;
 
-// === Enums ===
+        if let Some((on_time, slashed, returned)) = sla {
+            emit!(SlaSettled {
+                request_id: request.id,
+                on_time,
+                slashed,
+                returned,
+            });
+        }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
-pub enum InvoiceStatus {
-    Pending,
-    Paid,
-    Expired,
-    Cancelled,
-}
+        msg!("Service finalized, {} lamports released", request.amount);
+        emit!(ServiceFinalized {
+            request_id: request.id,
+            provider: request.provider,
+            amount: request.amount,
+            approved_early,
+        });
 
-impl Default for InvoiceStatus {
-    fn default() -> Self {
-        InvoiceStatus::Pending
+        Ok(())
     }
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
-pub enum ChannelStatus {
-    Open,
-    Closing,
-    Closed,
-    Disputed,
-}
-
-impl Default for ChannelStatus {
-    fn default() -> Self {
-        ChannelStatus::Open
-    }
+#[derive(Accounts)]
+pub struct AttestCompletion<'info> {
+    /// Oracle designated on the request
+    pub oracle: Signer<'info>,
+    
+    pub completion: FinalizeCompletion<'info>,
+    
+    /// Optional marketplace stats; when supplied, the global counters are updated
+    #[account(
+        mut,
+        seeds = [b"marketplace_stats"],
+        bump = stats.bump
+    )]
+    pub stats: Option<Account<'info, MarketplaceStats>>,
 }
 
-// === Contexts ===
+// === Dispute Contexts ===
 
 #[derive(Accounts)]
-#[instruction(bump: u8)]
-pub struct InitializeTreasury<'info> {
+pub struct InitiateDispute<'info> {
+    #[account(mut)]
+    pub request: Account<'info, ServiceRequest>,
+    
     #[account(
         init,
-        payer = owner,
-        space = 8 + 32 + 1 + 8 + 8 + 8 + 8,
-        seeds = [b"treasury", owner.key().as_ref()],
+        payer = initiator,
+        space = Dispute::SPACE,
+        seeds = [b"dispute", request.id.as_ref()],
         bump
     )]
-    pub treasury: Account<'info, Treasury>,
+    pub dispute: Account<'info, Dispute>,
     
-    #[account(mut)]
-    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"agent", request.provider.as_ref(), request.provider_index_seed()],
+        bump = provider_profile.bump
+    )]
+    pub provider_profile: Account<'info, AgentProfile>,
     
-    pub system_program: Program<'info, System>,
-}
-
-#[derive(Accounts)]
-#[instruction(invoice_id: [u8; 32])]
-pub struct CreateInvoice<'info> {
+    /// Must be either requester or provider
     #[account(
-        init,
-        payer = recipient,
-        space = 8 + 32 + 32 + 8 + 4 + MAX_MEMO_LENGTH + 1 + 8 + 8 + 9 + 33,
-        seeds = [b"invoice", invoice_id.as_ref()],
-        bump
+        mut,
+        constraint = initiator.key() == request.requester || initiator.key() == request.provider
     )]
-    pub invoice: Account<'info, Invoice>,
+    pub initiator: Signer<'info>,
     
+    /// Protocol config; required so the dispute fee cannot be bypassed
     #[account(
         mut,
-        seeds = [b"treasury", recipient.key().as_ref()],
-        bump = treasury.bump
+        seeds = [b"config"],
+        bump = config.bump
     )]
-    pub treasury: Account<'info, Treasury>,
+    pub config: Account<'info, ProtocolConfig>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitiateDisputesBatch<'info> {
+    /// Profile of the provider shared by every request in the batch
+    #[account(mut)]
+    pub provider_profile: Account<'info, AgentProfile>,
     
+    /// Must be the requester or provider on every request
     #[account(mut)]
-    pub recipient: Signer<'info>,
+    pub initiator: Signer<'info>,
+    
+    /// Protocol config; required so the dispute fee cannot be bypassed
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, ProtocolConfig>,
     
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct PayInvoice<'info> {
+pub struct ResolveDispute<'info> {
     #[account(mut)]
-    pub invoice: Account<'info, Invoice>,
+    pub request: Account<'info, ServiceRequest>,
+    
+    #[account(
+        mut,
+        seeds = [b"dispute", request.id.as_ref()],
+        bump
+    )]
+    pub dispute: Account<'info, Dispute>,
+    
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, ProtocolConfig>,
+    
+    /// The designated arbiter (who receives the arbiter fee), either party
+    /// when both requester and provider also sign, or the DAO authority of
+    /// an escalated dispute
+    #[account(
+        mut,
+        constraint = resolver.key() == request.requester
+            || resolver.key() == request.provider
+            || resolver.key() == config.arbiter
+            || Some(resolver.key()) == dispute.escalated_to
+    )]
+    pub resolver: Signer<'info>,
+    
+    /// CHECK: Requester for potential refund; must sign a self-resolution
+    #[account(mut, constraint = requester.key() == request.requester)]
+    pub requester: AccountInfo<'info>,
+    
+    /// CHECK: Provider for potential payment; must sign a self-resolution
+    #[account(mut, constraint = provider.key() == request.provider)]
+    pub provider: AccountInfo<'info>,
+    
+    /// CHECK: Escrow holding funds
+    #[account(
+        mut,
+        seeds = [b"request_escrow", request.id.as_ref()],
+        bump = request.escrow_bump
+    )]
+    pub escrow: AccountInfo<'info>,
+    
+    /// Follow-up request, required for a `RedoRequest` resolution
+    #[account(
+        init,
+        payer = resolver,
+        space = ServiceRequest::SPACE,
+        seeds = [b"request", redo_request_id(&request.id).as_ref()],
+        bump
+    )]
+    pub redo_request: Option<Account<'info, ServiceRequest>>,
     
+    /// CHECK: Escrow PDA for the follow-up request
     #[account(
         mut,
-        seeds = [b"treasury", recipient.key().as_ref()],
-        bump = treasury.bump
+        seeds = [b"request_escrow", redo_request_id(&request.id).as_ref()],
+        bump
     )]
-    pub treasury: Account<'info, Treasury>,
+    pub redo_escrow: Option<AccountInfo<'info>>,
     
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    
-    /// CHECK: Recipient is validated against invoice
-    #[account(mut, constraint = recipient.key() == invoice.recipient)]
-    pub recipient: AccountInfo<'info>,
+    /// Provider's profile, whose open request count the follow-up joins
+    #[account(
+        mut,
+        seeds = [b"agent", request.provider.as_ref(), request.provider_index_seed()],
+        bump = provider_profile.bump
+    )]
+    pub provider_profile: Option<Account<'info, AgentProfile>>,
     
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-#[instruction(batch_id: [u8; 32])]
-pub struct SettleBatch<'info> {
+pub struct CancelDispute<'info> {
+    #[account(mut)]
+    pub request: Account<'info, ServiceRequest>,
+    
     #[account(
-        init,
-        payer = settler,
-        space = 8 + 32 + 32 + 4 + 8 + 8 + 32,
-        seeds = [b"batch", batch_id.as_ref()],
-        bump
+        mut,
+        seeds = [b"dispute", request.id.as_ref()],
+        bump,
+        has_one = initiator @ AgentFundError::Unauthorized
     )]
-    pub batch: Account<'info, BatchSettlement>,
+    pub dispute: Account<'info, Dispute>,
     
     #[account(
         mut,
-        seeds = [b"treasury", recipient.key().as_ref()],
-        bump = treasury.bump
+        seeds = [b"agent", request.provider.as_ref(), request.provider_index_seed()],
+        bump = provider_profile.bump
     )]
-    pub treasury: Account<'info, Treasury>,
-    
-    #[account(mut)]
-    pub settler: Signer<'info>,
+    pub provider_profile: Account<'info, AgentProfile>,
     
-    /// CHECK: Recipient validated by treasury PDA
     #[account(mut)]
-    pub recipient: AccountInfo<'info>,
-    
-    pub system_program: Program<'info, System>,
+    pub initiator: Signer<'info>,
 }
 
 #[derive(Accounts)]
-#[instruction(channel_id: [u8; 32])]
-pub struct OpenChannel<'info> {
-    #[account(
-        init,
-        payer = party_a,
-        space = 8 + 32 + 32 + 32 + 8 + 8 + 8 + 8 + 8 + 1 + 8 + 9,
-        seeds = [b"channel", channel_id.as_ref()],
-        bump
-    )]
-    pub channel: Account<'info, PaymentChannel>,
+pub struct ExtendDisputeReview<'info> {
+    pub request: Account<'info, ServiceRequest>,
     
-    /// CHECK: Escrow PDA for holding channel funds
     #[account(
         mut,
-        seeds = [b"escrow", channel_id.as_ref()],
+        seeds = [b"dispute", request.id.as_ref()],
         bump
     )]
-    pub channel_escrow: AccountInfo<'info>,
-    
-    #[account(mut)]
-    pub party_a: Signer<'info>,
+    pub dispute: Account<'info, Dispute>,
     
-    /// CHECK: Party B just needs to be a valid pubkey
-    pub party_b: AccountInfo<'info>,
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, ProtocolConfig>,
     
-    pub system_program: Program<'info, System>,
+    pub party: Signer<'info>,
 }
 
 #[derive(Accounts)]
-pub struct CloseChannel<'info> {
-    #[account(mut)]
-    pub channel: Account<'info, PaymentChannel>,
-    
-    /// CHECK: Escrow PDA
-    #[account(mut)]
-    pub channel_escrow: AccountInfo<'info>,
-    
-    #[account(mut)]
-    pub closer: Signer<'info>,
+pub struct EscalateDispute<'info> {
+    pub request: Account<'info, ServiceRequest>,
     
-    /// CHECK: Party A for receiving funds
-    #[account(mut, constraint = party_a.key() == channel.party_a)]
-    pub party_a: AccountInfo<'info>,
+    #[account(
+        mut,
+        seeds = [b"dispute", request.id.as_ref()],
+        bump
+    )]
+    pub dispute: Account<'info, Dispute>,
     
-    /// CHECK: Party B for receiving funds
-    #[account(mut, constraint = party_b.key() == channel.party_b)]
-    pub party_b: AccountInfo<'info>,
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, ProtocolConfig>,
     
-    pub system_program: Program<'info, System>,
+    pub party: Signer<'info>,
 }
 
-// === Events ===
+// === Registry Events ===
 
 #[event]
-pub struct InvoiceCreated {
-    pub invoice_id: [u8; 32],
-    pub recipient: Pubkey,
-    pub amount: u64,
-    pub expires_at: i64,
+pub struct AgentRegistered {
+    pub agent: Pubkey,
+    pub name: String,
+    pub capabilities: Vec<String>,
+    pub base_price: u64,
+    pub tags: Vec<[u8; 16]>,
 }
 
 #[event]
-pub struct InvoicePaid {
-    pub invoice_id: [u8; 32],
-    pub payer: Pubkey,
+pub struct AgentBanStatusChanged {
+    pub agent: Pubkey,
+    pub banned: bool,
+}
+
+#[event]
+pub struct MarketplaceStatsUpdated {
+    pub total_agents: u64,
+    pub total_requests: u64,
+    pub total_volume: u64,
+    pub total_completed: u64,
+}
+
+#[event]
+pub struct AgentTagsUpdated {
+    pub agent: Pubkey,
+    pub tags: Vec<[u8; 16]>,
+}
+
+#[event]
+pub struct AgentUpdated {
+    pub agent: Pubkey,
+    pub is_active: bool,
+}
+
+#[event]
+pub struct AgentPriceIncreaseScheduled {
+    pub agent: Pubkey,
+    pub base_price: u64,
+    pub pending_base_price: u64,
+    pub effective_at: i64,
+}
+
+#[event]
+pub struct EarningsWithdrawn {
+    pub agent: Pubkey,
     pub amount: u64,
+    pub remaining: u64,
 }
 
 #[event]
-pub struct BatchSettled {
-    pub batch_id: [u8; 32],
-    pub invoice_count: u32,
-    pub total_amount: u64,
-    pub recipient: Pubkey,
+pub struct AgentCapacityUpdated {
+    pub agent: Pubkey,
+    pub max_concurrent_requests: u32,
 }
 
 #[event]
-pub struct ChannelOpened {
-    pub channel_id: [u8; 32],
-    pub party_a: Pubkey,
-    pub party_b: Pubkey,
-    pub deposit: u64,
+pub struct CapabilityAvailabilityChanged {
+    pub agent: Pubkey,
+    pub capability: String,
+    pub active: bool,
 }
 
 #[event]
-pub struct ChannelClosed {
-    pub channel_id: [u8; 32],
-    pub final_balance_a: u64,
-    pub final_balance_b: u64,
+pub struct AgentMarkedStale {
+    pub agent: Pubkey,
+    pub last_active_at: i64,
+    pub marked_at: i64,
 }
 
-// === Agent Registry ===
+#[event]
+pub struct AgentAcceptingRequestsChanged {
+    pub agent: Pubkey,
+    pub accepting: bool,
+}
 
-/// Maximum length for agent name
-pub const MAX_NAME_LENGTH: usize = 64;
+#[event]
+pub struct CapabilityAliasAdded {
+    pub agent: Pubkey,
+    pub alias: String,
+    pub canonical: String,
+}
 
-/// Maximum length for service description
-pub const MAX_DESCRIPTION_LENGTH: usize = 256;
+#[event]
+pub struct AgentProfileMigrated {
+    pub agent: Pubkey,
+    pub from_version: u8,
+    pub to_version: u8,
+}
 
-/// Maximum number of service capabilities
-pub const MAX_CAPABILITIES: usize = 10;
+#[event]
+pub struct ServiceRequested {
+    pub request_id: [u8; 32],
+    pub requester: Pubkey,
+    pub provider: Pubkey,
+    pub capability: String,
+    pub amount: u64,
+    pub metadata_uri: Option<String>,
+    pub seq: u64,
+}
 
-/// Maximum length per capability
-pub const MAX_CAPABILITY_LENGTH: usize = 32;
+#[event]
+pub struct ServiceRequestRelayed {
+    pub request_id: [u8; 32],
+    pub requester: Pubkey,
+    pub relayer: Pubkey,
+}
 
-#[account]
-pub struct AgentProfile {
-    /// Agent's public key (owner)
-    pub owner: Pubkey,
-    /// Agent's display name
-    pub name: String,
-    /// Description of agent's services
-    pub description: String,
-    /// Service capabilities (e.g., "sentiment", "translation", "image-gen")
+#[event]
+pub struct ServiceBundleRequested {
+    pub request_id: [u8; 32],
     pub capabilities: Vec<String>,
-    /// Base price per request in lamports
-    pub base_price: u64,
-    /// Treasury account for payments
-    pub treasury: Pubkey,
-    /// Whether agent is currently active
-    pub is_active: bool,
-    /// Total requests served
-    pub total_requests: u64,
-    /// Total earnings
-    pub total_earnings: u64,
-    /// Registration timestamp
-    pub registered_at: i64,
-    /// Last active timestamp
-    pub last_active_at: i64,
-    /// PDA bump
-    pub bump: u8,
+    pub amount: u64,
 }
 
-#[account]
-pub struct ServiceRequest {
-    /// Unique request ID
-    pub id: [u8; 32],
-    /// Requesting agent
+#[event]
+pub struct ServiceAccepted {
+    pub request_id: [u8; 32],
+    pub provider: Pubkey,
+    pub accepted_at: i64,
+    pub average_response_seconds: u64,
+}
+
+#[event]
+pub struct ServiceCancelled {
+    pub request_id: [u8; 32],
     pub requester: Pubkey,
-    /// Service provider agent
+    pub refunded: u64,
+}
+
+/// Lamports the escrow was observed to receive when a request was funded
+#[event]
+pub struct EscrowFunded {
+    pub request_id: [u8; 32],
+    pub escrow: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct ServiceReclaimed {
+    pub request_id: [u8; 32],
+    pub requester: Pubkey,
+    pub refunded: u64,
+    pub bond_returned: u64,
+}
+
+#[event]
+pub struct SlaSettled {
+    pub request_id: [u8; 32],
+    pub on_time: bool,
+    pub slashed: u64,
+    pub returned: u64,
+}
+
+#[event]
+pub struct ServiceCompleted {
+    pub request_id: [u8; 32],
     pub provider: Pubkey,
-    /// Capability being requested
+    pub amount: u64,
     pub capability: String,
-    /// Amount escrowed
+    /// Completed requests for `capability` including this one
+    pub capability_requests: u64,
+    /// How the request's `result_hash` was computed
+    pub hash_algorithm: HashAlgorithm,
+    pub seq: u64,
+}
+
+#[event]
+pub struct CompletionAttested {
+    pub request_id: [u8; 32],
+    pub oracle: Pubkey,
+    pub result_hash: [u8; 32],
+}
+
+#[event]
+pub struct ServiceFinalized {
+    pub request_id: [u8; 32],
+    pub provider: Pubkey,
     pub amount: u64,
-    /// Request status
-    pub status: RequestStatus,
-    /// Creation timestamp
-    pub created_at: i64,
-    /// Completion timestamp
-    pub completed_at: Option<i64>,
-    /// Optional result hash (for verification)
-    pub result_hash: Option<[u8; 32]>,
+    pub approved_early: bool,
+}
+
+// === Dispute Events ===
+
+#[event]
+pub struct DisputeInitiated {
+    pub request_id: [u8; 32],
+    pub initiator: Pubkey,
+    pub reason: String,
+    pub seq: u64,
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
-pub enum RequestStatus {
-    Pending,
-    InProgress,
-    Completed,
-    Disputed,
-    Refunded,
+#[event]
+pub struct DisputeEscalated {
+    pub request_id: [u8; 32],
+    pub escalated_by: Pubkey,
+    pub escalated_to: Pubkey,
 }
 
-/// Dispute for a service request
-#[account]
-pub struct Dispute {
-    /// Request ID being disputed
+#[event]
+pub struct DisputeReviewExtended {
+    pub request_id: [u8; 32],
+    pub extended_by: Pubkey,
+    pub review_deadline: i64,
+}
+
+#[event]
+pub struct DisputeWithdrawn {
     pub request_id: [u8; 32],
-    /// Who initiated the dispute
     pub initiator: Pubkey,
-    /// Reason for dispute
-    pub reason: String,
-    /// Dispute status
-    pub status: DisputeStatus,
-    /// Creation timestamp
-    pub created_at: i64,
-    /// Resolution timestamp
-    pub resolved_at: Option<i64>,
-    /// Resolution details
-    pub resolution: Option<DisputeResolution>,
+    pub restored_status: RequestStatus,
+    pub fee_refunded: u64,
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
-pub enum DisputeStatus {
-    Open,
-    UnderReview,
-    Resolved,
-    Expired,
+#[event]
+pub struct DisputeResolved {
+    pub request_id: [u8; 32],
+    pub resolution: DisputeResolution,
+    pub requester_amount: u64,
+    pub provider_amount: u64,
+    pub arbiter_fee: u64,
+    /// Initiator's dispute fee, refunded if `initiator_won`, else paid to the counterparty
+    pub dispute_fee: u64,
+    pub initiator_won: bool,
+    pub seq: u64,
 }
 
-impl Default for DisputeStatus {
-    fn default() -> Self {
-        DisputeStatus::Open
-    }
+#[event]
+pub struct RedoRequestCreated {
+    pub parent_request_id: [u8; 32],
+    pub request_id: [u8; 32],
+    pub amount: u64,
+    pub seq: u64,
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
-pub enum DisputeResolution {
-    /// Full refund to requester
-    RefundRequester,
-    /// Full payment to provider
-    PayProvider,
-    /// Split payment by percentage
-    Split { requester_pct: u8 },
+// === Protocol Config ===
+
+/// Basis point denominator (100%)
+pub const MAX_BPS: u16 = 10_000;
+
+/// Protocol-wide settings, stored in a singleton PDA
+#[account]
+#[derive(InitSpace)]
+pub struct ProtocolConfig {
+    /// Administrator allowed to change the config
+    pub admin: Pubkey,
+    /// Designated dispute arbiter
+    pub arbiter: Pubkey,
+    /// Fee paid to the arbiter out of the disputed escrow, in basis points
+    pub arbiter_fee_bps: u16,
+    /// PDA bump
+    pub bump: u8,
+    /// Last sequence number assigned to an event (total order across slots)
+    pub event_seq: u64,
+    /// Maximum invoice memo length in bytes
+    pub max_memo_length: u16,
+    /// Protocol fee on direct invoice payments, in basis points
+    pub invoice_fee_bps: u16,
+    /// Recipient of protocol fees
+    pub fee_collector: Pubkey,
+    /// Protocol fee on cooperative channel closes, in basis points of deposits
+    pub channel_close_fee_bps: u16,
+    /// Age after which an unsettled request escrow may be recovered by the admin
+    pub escrow_recovery_seconds: i64,
+    /// Minimum wait after completion before payment is released without the
+    /// requester's approval, applied on top of per-request review periods
+    pub completion_release_delay_seconds: i64,
+    /// Lamports an initiator stakes on each dispute, returned if they win
+    pub dispute_fee: u64,
+    /// Smallest amount accepted for any new invoice, split invoices included;
+    /// 0 disables the floor
+    pub min_invoice_amount: u64,
+    /// External program idle request escrow may be routed to; none disables yield
+    pub yield_strategy: Option<Pubkey>,
+    /// Time an accepted but unused channel must sit before party A may
+    /// reclaim its deposit
+    pub channel_idle_reclaim_seconds: i64,
+    /// DAO authority open disputes may be escalated to; typically a PDA the
+    /// DAO program signs with when it calls back into `resolve_dispute`
+    pub dispute_dao: Option<Pubkey>,
+    /// Time a dispute must stay open before a party may escalate it
+    pub dispute_escalation_seconds: i64,
+    /// Smallest deposit accepted by `open_channel`; 0 disables the floor
+    pub min_channel_deposit: u64,
+    /// Completed requests an agent needs to reach each volume tier, in
+    /// ascending order; 0 disables a tier
+    pub fee_tier_thresholds: [u64; FEE_TIERS],
+    /// Share of the protocol fee waived at each volume tier, in basis points
+    pub fee_tier_discount_bps: [u16; FEE_TIERS],
+    /// Inactivity after which anyone may delist an agent as stale
+    pub staleness_threshold_seconds: i64,
+    /// Review time a new dispute gets before it can be expired
+    pub dispute_review_seconds: i64,
 }
 
-impl Default for RequestStatus {
-    fn default() -> Self {
-        RequestStatus::Pending
-    }
+impl ProtocolConfig {
+    /// Account size (including discriminator)
+    pub const SPACE: usize = 8 + Self::INIT_SPACE;
 }
 
-// === Registry Contexts ===
+/// Number of volume tiers that can discount protocol fees
+pub const FEE_TIERS: usize = 3;
+
+/// Hard floor on how long a request must sit idle before its escrow can be
+/// recovered (180 days); the configured period can only lengthen it
+pub const MIN_ESCROW_RECOVERY_SECONDS: i64 = 180 * 24 * 60 * 60;
+
+/// Default age before a stuck request escrow can be recovered (180 days)
+pub const DEFAULT_ESCROW_RECOVERY_SECONDS: i64 = MIN_ESCROW_RECOVERY_SECONDS;
+
+/// Default idle time before an unused channel deposit can be reclaimed (7 days)
+pub const DEFAULT_CHANNEL_IDLE_RECLAIM_SECONDS: i64 = 7 * 24 * 60 * 60;
+
+/// Default time a dispute stays open before it can be escalated (3 days)
+pub const DEFAULT_DISPUTE_ESCALATION_SECONDS: i64 = 3 * 24 * 60 * 60;
+
+/// Default inactivity before an agent can be marked stale (90 days)
+pub const DEFAULT_STALENESS_THRESHOLD_SECONDS: i64 = 90 * 24 * 60 * 60;
+
+/// Default review time before an unresolved dispute can be expired (7 days)
+pub const DEFAULT_DISPUTE_REVIEW_SECONDS: i64 = 7 * 24 * 60 * 60;
+
+/// Optional updates applied by `update_protocol_config`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct ProtocolConfigParams {
+    pub admin: Option<Pubkey>,
+    pub arbiter: Option<Pubkey>,
+    pub arbiter_fee_bps: Option<u16>,
+    pub max_memo_length: Option<u16>,
+    pub invoice_fee_bps: Option<u16>,
+    pub fee_collector: Option<Pubkey>,
+    pub channel_close_fee_bps: Option<u16>,
+    pub escrow_recovery_seconds: Option<i64>,
+    pub completion_release_delay_seconds: Option<i64>,
+    pub dispute_fee: Option<u64>,
+    pub min_invoice_amount: Option<u64>,
+    /// `Pubkey::default()` clears the strategy
+    pub yield_strategy: Option<Pubkey>,
+    pub channel_idle_reclaim_seconds: Option<i64>,
+    /// `Pubkey::default()` disables escalation
+    pub dispute_dao: Option<Pubkey>,
+    pub dispute_escalation_seconds: Option<i64>,
+    pub min_channel_deposit: Option<u64>,
+    pub fee_tier_thresholds: Option<[u64; FEE_TIERS]>,
+    pub fee_tier_discount_bps: Option<[u16; FEE_TIERS]>,
+    pub staleness_threshold_seconds: Option<i64>,
+    pub dispute_review_seconds: Option<i64>,
+}
 
 #[derive(Accounts)]
-pub struct RegisterAgent<'info> {
+pub struct InitializeProtocolConfig<'info> {
     #[account(
         init,
-        payer = owner,
-        space = 8 + 32 + 4 + MAX_NAME_LENGTH + 4 + MAX_DESCRIPTION_LENGTH + 
-                4 + (MAX_CAPABILITIES * (4 + MAX_CAPABILITY_LENGTH)) + 
-                8 + 32 + 1 + 8 + 8 + 8 + 8 + 1,
-        seeds = [b"agent", owner.key().as_ref()],
+        payer = admin,
+        space = ProtocolConfig::SPACE,
+        seeds = [b"config"],
         bump
     )]
-    pub agent_profile: Account<'info, AgentProfile>,
+    pub config: Account<'info, ProtocolConfig>,
+    
+    /// The program's upgrade authority
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()))]
+    pub program: Program<'info, crate::program::Agentfund>,
     
     #[account(
-        seeds = [b"treasury", owner.key().as_ref()],
-        bump = treasury.bump
+        constraint = program_data.upgrade_authority_address == Some(admin.key())
+            @ AgentFundError::Unauthorized
     )]
-    pub treasury: Account<'info, Treasury>,
+    pub program_data: Account<'info, ProgramData>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateProtocolConfig<'info> {
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = admin @ AgentFundError::Unauthorized
+    )]
+    pub config: Account<'info, ProtocolConfig>,
+    
+    pub admin: Signer<'info>,
+}
+
+#[event]
+pub struct ProtocolConfigUpdated {
+    pub admin: Pubkey,
+    pub arbiter: Pubkey,
+    pub arbiter_fee_bps: u16,
+    pub max_memo_length: u16,
+    pub invoice_fee_bps: u16,
+    pub fee_collector: Pubkey,
+    pub channel_close_fee_bps: u16,
+    pub escrow_recovery_seconds: i64,
+    pub completion_release_delay_seconds: i64,
+    pub dispute_fee: u64,
+    pub min_invoice_amount: u64,
+    pub yield_strategy: Option<Pubkey>,
+    pub channel_idle_reclaim_seconds: i64,
+    pub dispute_dao: Option<Pubkey>,
+    pub dispute_escalation_seconds: i64,
+    pub min_channel_deposit: u64,
+    pub fee_tier_thresholds: [u64; FEE_TIERS],
+    pub fee_tier_discount_bps: [u16; FEE_TIERS],
+    pub staleness_threshold_seconds: i64,
+    pub dispute_review_seconds: i64,
+}
+
+#[derive(Accounts)]
+pub struct BanAgent<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = admin @ AgentFundError::Unauthorized
+    )]
+    pub config: Account<'info, ProtocolConfig>,
+    
+    /// Created by the first ban
+    #[account(
+        init_if_needed,
+        payer = admin,
+        space = BannedAgents::SPACE,
+        seeds = [b"banned_agents"],
+        bump
+    )]
+    pub banned_agents: Account<'info, BannedAgents>,
     
     #[account(mut)]
-    pub owner: Signer<'info>,
+    pub admin: Signer<'info>,
     
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct UpdateAgentProfile<'info> {
+pub struct UnbanAgent<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = admin @ AgentFundError::Unauthorized
+    )]
+    pub config: Account<'info, ProtocolConfig>,
+    
     #[account(
         mut,
-        seeds = [b"agent", owner.key().as_ref()],
-        bump = agent_profile.bump,
-        has_one = owner
+        seeds = [b"banned_agents"],
+        bump = banned_agents.bump
     )]
-    pub agent_profile: Account<'info, AgentProfile>,
+    pub banned_agents: Account<'info, BannedAgents>,
     
-    pub owner: Signer<'info>,
+    pub admin: Signer<'info>,
 }
 
 #[derive(Accounts)]
-#[instruction(request_id: [u8; 32])]
-pub struct CreateServiceRequest<'info> {
+pub struct RecoverStuckEscrow<'info> {
     #[account(
-        init,
-        payer = requester,
-        space = 8 + 32 + 32 + 32 + 4 + MAX_CAPABILITY_LENGTH + 8 + 1 + 8 + 9 + 33,
-        seeds = [b"request", request_id.as_ref()],
-        bump
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = admin @ AgentFundError::Unauthorized
     )]
+    pub config: Account<'info, ProtocolConfig>,
+    
+    #[account(mut, has_one = requester, has_one = provider)]
     pub request: Account<'info, ServiceRequest>,
     
+    /// CHECK: Dispute PDA for the request; read for its activity when it exists
+    #[account(
+        seeds = [b"dispute", request.id.as_ref()],
+        bump
+    )]
+    pub dispute: UncheckedAccount<'info>,
+    
+    /// Provider's profile, whose open request count drops for open work
     #[account(
-        seeds = [b"agent", provider_owner.key().as_ref()],
+        mut,
+        seeds = [b"agent", request.provider.as_ref(), request.provider_index_seed()],
         bump = provider_profile.bump
     )]
     pub provider_profile: Account<'info, AgentProfile>,
     
-    /// CHECK: Provider owner for profile lookup
-    pub provider_owner: AccountInfo<'info>,
-    
-    /// CHECK: Escrow for holding payment
+    /// CHECK: Escrow holding the stuck funds
     #[account(
         mut,
-        seeds = [b"request_escrow", request_id.as_ref()],
-        bump
+        seeds = [b"request_escrow", request.id.as_ref()],
+        bump = request.escrow_bump
     )]
     pub escrow: AccountInfo<'info>,
     
+    /// CHECK: Requester the payment is returned to
+    #[account(mut)]
+    pub requester: AccountInfo<'info>,
+    
+    /// CHECK: Provider any SLA bond is returned to
+    #[account(mut)]
+    pub provider: AccountInfo<'info>,
+    
+    pub admin: Signer<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[event]
+pub struct StuckEscrowRecovered {
+    pub request_id: [u8; 32],
+    pub requester: Pubkey,
+    pub amount: u64,
+    pub bond_returned: u64,
+}
+
+/// Escrow yield routing, compiled only into `yield-routing` builds. Anchor's
+/// `#[program]` ignores `cfg` on handlers, so these instructions are reached
+/// through the program fallback and stay out of default builds and the IDL
+#[cfg(feature = "yield-routing")]
+pub mod yield_routing {
+    use super::*;
+    use anchor_lang::solana_program::hash::hash;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    /// Instruction tag for a yield strategy deposit notification
+    pub const YIELD_DEPOSIT_TAG: u8 = 0;
+
+    /// Instruction tag asking a yield strategy to return principal
+    pub const YIELD_WITHDRAW_TAG: u8 = 1;
+
+    #[derive(Accounts)]
+    pub struct EscrowYield<'info> {
+        #[account(
+            seeds = [b"config"],
+            bump = config.bump,
+            has_one = admin @ AgentFundError::Unauthorized,
+            constraint = config.yield_strategy == Some(strategy_program.key())
+                @ AgentFundError::YieldStrategyNotConfigured
+        )]
+        pub config: Account<'info, ProtocolConfig>,
+        
+        #[account(mut)]
+        pub request: Account<'info, ServiceRequest>,
+        
+        /// CHECK: Escrow holding the request funds
+        #[account(
+            mut,
+            seeds = [b"request_escrow", request.id.as_ref()],
+            bump = request.escrow_bump
+        )]
+        pub escrow: AccountInfo<'info>,
+        
+        /// CHECK: Yield strategy program; validated against config
+        #[account(executable)]
+        pub strategy_program: AccountInfo<'info>,
+        
+        /// CHECK: Vault owned by the strategy program
+        #[account(mut, owner = strategy_program.key())]
+        pub strategy_vault: AccountInfo<'info>,
+        
+        pub admin: Signer<'info>,
+        
+        pub system_program: Program<'info, System>,
+    }
+
+    /// Anchor-style sighash, so clients build these instructions the usual way
+    fn sighash(name: &str) -> [u8; 8] {
+        let mut sighash = [0u8; 8];
+        sighash.copy_from_slice(&hash(format!("global:{}", name).as_bytes()).to_bytes()[..8]);
+        sighash
+    }
+
+    /// Run a yield instruction if `data` names one
+    pub fn dispatch(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> Option<Result<()>> {
+        let (discriminator, ix_data) = data.split_at(8);
+        if discriminator == sighash("deposit_escrow_to_yield") {
+            Some(run(program_id, accounts, ix_data, true))
+        } else if discriminator == sighash("withdraw_escrow_from_yield") {
+            Some(run(program_id, accounts, ix_data, false))
+        } else {
+            None
+        }
+    }
+
+    /// Mirror the handler wrapper Anchor generates: decode the amount,
+    /// validate accounts, run the handler and persist account changes
+    fn run(program_id: &Pubkey, accounts: &[AccountInfo], ix_data: &[u8], deposit: bool) -> Result<()> {
+        #[cfg(not(feature = "no-log-ix-name"))]
+        msg!(if deposit { "Instruction: DepositEscrowToYield" } else { "Instruction: WithdrawEscrowFromYield" });
+        let amount = u64::deserialize(&mut &ix_data[..])
+            .map_err(|_| anchor_lang::error::ErrorCode::InstructionDidNotDeserialize)?;
+
+        let mut remaining = accounts;
+        let mut bumps = BTreeMap::new();
+        let mut reallocs = BTreeSet::new();
+        let mut escrow_yield =
+            EscrowYield::try_accounts(program_id, &mut remaining, ix_data, &mut bumps, &mut reallocs)?;
+        let ctx = Context::new(program_id, &mut escrow_yield, remaining, bumps);
+        if deposit {
+            deposit_escrow_to_yield(ctx, amount)?;
+        } else {
+            withdraw_escrow_from_yield(ctx, amount)?;
+        }
+        escrow_yield.exit(program_id)
+    }
+
+    /// Move part of an unsettled request escrow into the configured yield
+    /// strategy (admin only)
+    /// The lamports are transferred by this program; the strategy is only
+    /// notified, so the escrow never signs for external code
+    pub fn deposit_escrow_to_yield(ctx: Context<EscrowYield>, amount: u64) -> Result<()> {
+        let request = &mut ctx.accounts.request;
+
+        require!(
+            matches!(
+                request.status,
+                RequestStatus::Pending
+                    | RequestStatus::InProgress
+                    | RequestStatus::PendingReview
+                    | RequestStatus::Disputed
+            ),
+            AgentFundError::RequestAlreadySettled
+        );
+        require!(amount > 0, AgentFundError::InvalidAmount);
+
+        transfer_from_escrow(
+            &ctx.accounts.escrow,
+            &ctx.accounts.strategy_vault,
+            &ctx.accounts.system_program,
+            amount,
+            &[b"request_escrow", request.id.as_ref(), &[request.escrow_bump]],
+        )?;
+        invoke_yield_strategy(
+            &ctx.accounts.strategy_program,
+            &ctx.accounts.strategy_vault,
+            &ctx.accounts.escrow,
+            YIELD_DEPOSIT_TAG,
+            amount,
+        )?;
+
+        request.yield_principal = request
+            .yield_principal
+            .checked_add(amount)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+
+        emit!(EscrowYieldDeposited {
+            request_id: request.id,
+            strategy: ctx.accounts.strategy_program.key(),
+            amount,
+            yield_principal: request.yield_principal,
+        });
+
+        Ok(())
+    }
+
+    /// Reclaim principal from the yield strategy back into the request escrow
+    /// (admin only). The strategy must return at least the principal; any
+    /// yield on top stays in the escrow
+    pub fn withdraw_escrow_from_yield(ctx: Context<EscrowYield>, amount: u64) -> Result<()> {
+        let request = &mut ctx.accounts.request;
+
+        require!(amount > 0, AgentFundError::InvalidAmount);
+        require!(amount <= request.yield_principal, AgentFundError::InvalidAmount);
+
+        let escrow_before = ctx.accounts.escrow.lamports();
+        invoke_yield_strategy(
+            &ctx.accounts.strategy_program,
+            &ctx.accounts.strategy_vault,
+            &ctx.accounts.escrow,
+            YIELD_WITHDRAW_TAG,
+            amount,
+        )?;
+        let returned = ctx.accounts.escrow.lamports().saturating_sub(escrow_before);
+        if returned < amount {
+            return Err(amount_error(AgentFundError::YieldPrincipalShortfall, amount, returned));
+        }
+
+        request.yield_principal -= amount;
+
+        emit!(EscrowYieldWithdrawn {
+            request_id: request.id,
+            strategy: ctx.accounts.strategy_program.key(),
+            principal: amount,
+            yield_earned: returned - amount,
+            yield_principal: request.yield_principal,
+        });
+
+        Ok(())
+    }
+
+    /// Call the yield strategy program with `tag` and `amount`
+    /// The strategy receives its vault and the request escrow; on withdrawal it
+    /// is expected to credit the escrow from the vault it owns
+    fn invoke_yield_strategy<'info>(
+        strategy_program: &AccountInfo<'info>,
+        strategy_vault: &AccountInfo<'info>,
+        escrow: &AccountInfo<'info>,
+        tag: u8,
+        amount: u64,
+    ) -> Result<()> {
+        let mut data = Vec::with_capacity(9);
+        data.push(tag);
+        data.extend_from_slice(&amount.to_le_bytes());
+        let ix = anchor_lang::solana_program::instruction::Instruction {
+            program_id: strategy_program.key(),
+            accounts: vec![
+                AccountMeta::new(strategy_vault.key(), false),
+                AccountMeta::new(escrow.key(), false),
+            ],
+            data,
+        };
+        anchor_lang::solana_program::program::invoke(
+            &ix,
+            &[strategy_vault.clone(), escrow.clone(), strategy_program.clone()],
+        )?;
+        Ok(())
+    }
+}
+
+#[event]
+pub struct EscrowYieldDeposited {
+    pub request_id: [u8; 32],
+    pub strategy: Pubkey,
+    pub amount: u64,
+    pub yield_principal: u64,
+}
+
+#[event]
+pub struct EscrowYieldWithdrawn {
+    pub request_id: [u8; 32],
+    pub strategy: Pubkey,
+    pub principal: u64,
+    pub yield_earned: u64,
+    pub yield_principal: u64,
+}
+
+// === Quote Negotiation ===
+
+/// A requester's request for a custom price, answered by the provider
+#[account]
+#[derive(InitSpace)]
+pub struct RequestForQuote {
+    /// Unique quote request ID
+    pub id: [u8; 32],
+    /// Agent asking for the quote
+    pub requester: Pubkey,
+    /// Provider asked to quote
+    pub provider: Pubkey,
+    /// Capability being quoted (resolved to the listed name)
+    #[max_len(32)]
+    pub capability: String,
+    /// Hash of the off-chain work specification
+    pub spec_hash: [u8; 32],
+    /// Quoted price in lamports (0 until quoted)
+    pub price: u64,
+    /// Quote is acceptable strictly before this timestamp
+    pub valid_until: i64,
+    /// Negotiation status
+    pub status: QuoteStatus,
+    /// Creation timestamp
+    pub created_at: i64,
+}
+
+impl RequestForQuote {
+    /// Account size (including discriminator)
+    pub const SPACE: usize = 8 + Self::INIT_SPACE;
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, InitSpace)]
+pub enum QuoteStatus {
+    Open,
+    Quoted,
+    Accepted,
+}
+
+#[derive(Accounts)]
+#[instruction(rfq_id: [u8; 32])]
+pub struct RequestQuote<'info> {
+    #[account(
+        init,
+        payer = requester,
+        space = RequestForQuote::SPACE,
+        seeds = [b"rfq", rfq_id.as_ref()],
+        bump
+    )]
+    pub rfq: Account<'info, RequestForQuote>,
+    
+    #[account(
+        seeds = [b"agent", provider_owner.key().as_ref(), provider_profile.index_seed()],
+        bump = provider_profile.bump
+    )]
+    pub provider_profile: Account<'info, AgentProfile>,
+    
+    /// CHECK: Provider owner for profile lookup
+    pub provider_owner: AccountInfo<'info>,
+    
     #[account(mut)]
     pub requester: Signer<'info>,
     
@@ -1053,146 +8527,265 @@ pub struct CreateServiceRequest<'info> {
 }
 
 #[derive(Accounts)]
-pub struct CompleteServiceRequest<'info> {
-    #[account(mut)]
-    pub request: Account<'info, ServiceRequest>,
-    
+pub struct SubmitQuote<'info> {
     #[account(
         mut,
-        seeds = [b"agent", provider.key().as_ref()],
-        bump = provider_profile.bump,
-        has_one = owner @ AgentFundError::UnauthorizedProvider
+        constraint = rfq.provider == provider.key() @ AgentFundError::UnauthorizedProvider
     )]
-    pub provider_profile: Account<'info, AgentProfile>,
-    
-    /// CHECK: Escrow holding payment
-    #[account(mut)]
-    pub escrow: AccountInfo<'info>,
+    pub rfq: Account<'info, RequestForQuote>,
     
+    pub provider: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptQuote<'info> {
     #[account(
         mut,
-        seeds = [b"treasury", provider.key().as_ref()],
-        bump = provider_treasury.bump
+        constraint = rfq.requester == service.requester.key() @ AgentFundError::Unauthorized,
+        constraint = rfq.provider == service.provider_owner.key() @ AgentFundError::UnauthorizedProvider
     )]
-    pub provider_treasury: Account<'info, Treasury>,
+    pub rfq: Account<'info, RequestForQuote>,
     
-    /// CHECK: Provider receiving payment
-    #[account(mut)]
-    pub provider: AccountInfo<'info>,
-    
-    /// Owner must sign to complete
-    pub owner: Signer<'info>,
-    
-    pub system_program: Program<'info, System>,
+    /// Accounts for the service request opened at the quoted price
+    pub service: CreateServiceRequest<'info>,
 }
 
-// === Dispute Contexts ===
+#[event]
+pub struct QuoteRequested {
+    pub rfq_id: [u8; 32],
+    pub requester: Pubkey,
+    pub provider: Pubkey,
+    pub capability: String,
+    pub spec_hash: [u8; 32],
+}
+
+#[event]
+pub struct QuoteSubmitted {
+    pub rfq_id: [u8; 32],
+    pub price: u64,
+    pub valid_until: i64,
+}
+
+#[event]
+pub struct QuoteAccepted {
+    pub rfq_id: [u8; 32],
+    pub request_id: [u8; 32],
+    pub price: u64,
+}
+
+// === Shared Escrow Vault ===
+
+/// Maximum concurrent escrow entries in one vault
+pub const MAX_VAULT_ENTRIES: usize = 32;
+
+/// Smallest amount one vault entry can hold
+pub const MIN_VAULT_DEPOSIT: u64 = LAMPORTS_PER_SOL / 1000;
+
+/// Age after which the depositor can reclaim an entry for uncompleted work (30 days)
+pub const VAULT_ENTRY_EXPIRY_SECONDS: i64 = 30 * 24 * 60 * 60;
+
+/// Program-owned account pooling escrow for many of a provider's requests
+#[account]
+#[derive(InitSpace)]
+pub struct Vault {
+    /// Provider receiving released funds
+    pub provider: Pubkey,
+    /// PDA bump
+    pub bump: u8,
+    /// Sum of all ledger entries
+    pub total_held: u64,
+    /// Ledger of escrowed amounts by request
+    #[max_len(32)]
+    pub entries: Vec<VaultEntry>,
+}
+
+impl Vault {
+    /// Account size (including discriminator)
+    pub const SPACE: usize = 8 + Self::INIT_SPACE;
+
+    /// Remove `depositor`'s entry for `request_id` from the ledger
+    pub fn take_entry(&mut self, request_id: &[u8; 32], depositor: &Pubkey) -> Result<VaultEntry> {
+        let index = self
+            .entries
+            .iter()
+            .position(|entry| entry.request_id == *request_id)
+            .ok_or(AgentFundError::VaultEntryNotFound)?;
+        require_keys_eq!(self.entries[index].depositor, *depositor, AgentFundError::Unauthorized);
+
+        let entry = self.entries.swap_remove(index);
+        self.total_held = self
+            .total_held
+            .checked_sub(entry.amount)
+            .ok_or(AgentFundError::ArithmeticOverflow)?;
+        Ok(entry)
+    }
+}
+
+/// Funds held in a vault for a single request
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, InitSpace)]
+pub struct VaultEntry {
+    pub request_id: [u8; 32],
+    pub depositor: Pubkey,
+    pub amount: u64,
+    pub deposited_at: i64,
+}
 
 #[derive(Accounts)]
-pub struct InitiateDispute<'info> {
-    #[account(mut)]
-    pub request: Account<'info, ServiceRequest>,
-    
+pub struct InitializeVault<'info> {
     #[account(
         init,
-        payer = initiator,
-        space = 8 + 32 + 32 + 4 + MAX_DISPUTE_REASON_LENGTH + 1 + 8 + 9 + 33,
-        seeds = [b"dispute", request.id.as_ref()],
+        payer = provider,
+        space = Vault::SPACE,
+        seeds = [b"vault", provider.key().as_ref()],
         bump
     )]
-    pub dispute: Account<'info, Dispute>,
+    pub vault: Account<'info, Vault>,
     
-    /// Must be either requester or provider
-    #[account(
-        mut,
-        constraint = initiator.key() == request.requester || initiator.key() == request.provider
-    )]
-    pub initiator: Signer<'info>,
+    #[account(mut)]
+    pub provider: Signer<'info>,
     
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct ResolveDispute<'info> {
-    #[account(mut)]
-    pub request: Account<'info, ServiceRequest>,
-    
+#[instruction(request_id: [u8; 32])]
+pub struct VaultDeposit<'info> {
     #[account(
         mut,
-        seeds = [b"dispute", request.id.as_ref()],
-        bump,
-        constraint = dispute.status == DisputeStatus::Open
+        seeds = [b"vault", vault.provider.as_ref()],
+        bump = vault.bump
     )]
-    pub dispute: Account<'info, Dispute>,
+    pub vault: Account<'info, Vault>,
     
-    /// Both parties must agree, or use arbiter (simplified here)
-    /// In production: would check multi-sig or arbiter DAO vote
+    /// The request being escrowed; it must be against the vault's provider
     #[account(
-        constraint = resolver.key() == request.requester || resolver.key() == request.provider
+        seeds = [b"request", request_id.as_ref()],
+        bump,
+        constraint = request.provider == vault.provider @ AgentFundError::Unauthorized,
+        constraint = request.requester == depositor.key() @ AgentFundError::Unauthorized
     )]
-    pub resolver: Signer<'info>,
-    
-    /// CHECK: Requester for potential refund
-    #[account(mut, constraint = requester.key() == request.requester)]
-    pub requester: AccountInfo<'info>,
-    
-    /// CHECK: Provider for potential payment
-    #[account(mut, constraint = provider.key() == request.provider)]
-    pub provider: AccountInfo<'info>,
+    pub request: Account<'info, ServiceRequest>,
     
-    /// CHECK: Escrow holding funds
     #[account(mut)]
-    pub escrow: AccountInfo<'info>,
+    pub depositor: Signer<'info>,
     
     pub system_program: Program<'info, System>,
 }
 
-// === Registry Events ===
-
-#[event]
-pub struct AgentRegistered {
-    pub agent: Pubkey,
-    pub name: String,
-    pub capabilities: Vec<String>,
-    pub base_price: u64,
+#[derive(Accounts)]
+pub struct VaultRelease<'info> {
+    #[account(
+        mut,
+        seeds = [b"vault", vault.provider.as_ref()],
+        bump = vault.bump
+    )]
+    pub vault: Account<'info, Vault>,
+    
+    pub depositor: Signer<'info>,
+    
+    /// CHECK: Provider receiving released funds
+    #[account(mut, constraint = provider.key() == vault.provider)]
+    pub provider: AccountInfo<'info>,
 }
 
-#[event]
-pub struct AgentUpdated {
-    pub agent: Pubkey,
-    pub is_active: bool,
+#[derive(Accounts)]
+#[instruction(request_id: [u8; 32])]
+pub struct VaultRefund<'info> {
+    #[account(
+        mut,
+        seeds = [b"vault", vault.provider.as_ref()],
+        bump = vault.bump
+    )]
+    pub vault: Account<'info, Vault>,
+    
+    #[account(seeds = [b"request", request_id.as_ref()], bump)]
+    pub request: Account<'info, ServiceRequest>,
+    
+    #[account(mut)]
+    pub depositor: Signer<'info>,
 }
 
 #[event]
-pub struct ServiceRequested {
-    pub request_id: [u8; 32],
-    pub requester: Pubkey,
+pub struct VaultDeposited {
     pub provider: Pubkey,
-    pub capability: String,
+    pub request_id: [u8; 32],
+    pub depositor: Pubkey,
     pub amount: u64,
 }
 
 #[event]
-pub struct ServiceCompleted {
-    pub request_id: [u8; 32],
+pub struct VaultReleased {
     pub provider: Pubkey,
+    pub request_id: [u8; 32],
     pub amount: u64,
 }
 
-// === Dispute Events ===
-
 #[event]
-pub struct DisputeInitiated {
+pub struct VaultRefunded {
+    pub provider: Pubkey,
     pub request_id: [u8; 32],
-    pub initiator: Pubkey,
-    pub reason: String,
+    pub depositor: Pubkey,
+    pub amount: u64,
 }
 
-#[event]
-pub struct DisputeResolved {
-    pub request_id: [u8; 32],
-    pub resolution: DisputeResolution,
-}
+// === Account Size Checks ===
+
+// Allocations come from `InitSpace`, whose `max_len` bounds must be literals.
+// Restating each documented layout in terms of the validation limits fails
+// the build if a bound drifts from its constant or a field is added unsized
+const _: () = assert!(
+    Treasury::SPACE == 8 + 32 + 1 + 8 + 8 + 8 + 8 + 4 + MAX_AUTHORIZED_SETTLERS * 32
+);
+const _: () = assert!(
+    TreasurySnapshots::SPACE == 8 + 32 + 1 + 1 + 8 + 4 + MAX_TREASURY_SNAPSHOTS * (8 + 8 + 8 + 8) + 1
+);
+const _: () = assert!(
+    Invoice::space(0, 0) == 8 + 32 + 32 + 8 + 4 + 1 + 8 + 8 + 9 + 33 + 33 + 8 + 33 + 4 + 33 + 4
+);
+const _: () = assert!(BatchSettlement::SPACE == 8 + 32 + 32 + 4 + 8 + 8 + 32 + 1 + 4 + MAX_BATCH_SIZE * 32);
+const _: () = assert!(EscrowedBatch::SPACE == 8 + 32 + 32 + 32 + 8 + 8 + 8 + 1 + 1 + 1);
+const _: () = assert!(
+    PaymentChannel::SPACE == 8 + 32 + 32 + 32 + 8 + 8 + 8 + 8 + 8 + 1 + 8 + 9 + 9 + 8 + 1 + 32
+);
+const _: () = assert!(
+    AgentProfile::SPACE == 8 + 32 + 4 + MAX_NAME_LENGTH + 4 + MAX_DESCRIPTION_LENGTH +
+        4 + (MAX_CAPABILITIES * (4 + MAX_CAPABILITY_LENGTH)) +
+        8 + 32 + 1 + 8 + 8 + 8 + 8 + 1 + 1 + 4 +
+        4 + (MAX_CAPABILITY_ALIASES * 2 * (4 + MAX_CAPABILITY_LENGTH)) +
+        4 + (MAX_TAGS * 16) +
+        8 + 8 +
+        4 + (MAX_CAPABILITIES * 8) +
+        4 +
+        9 + 8 +
+        8 +
+        4 + MAX_CAPABILITIES +
+        1 +
+        2 +
+        9
+);
+const _: () = assert!(AgentRegistry::SPACE == 8 + 32 + 1 + 1);
+const _: () = assert!(CapabilityPriceTwap::INIT_SPACE == 32 + 8 + 8 + 16 + 8);
+const _: () = assert!(
+    MarketplaceStats::SPACE == 8 + 8 + 8 + 8 + 8 + 1 + 4 + MAX_TRACKED_CAPABILITIES * CapabilityPriceTwap::INIT_SPACE
+);
+const _: () = assert!(BannedAgents::SPACE == 8 + 4 + MAX_BANNED_AGENTS * 32 + 1);
+const _: () = assert!(
+    ServiceRequest::SPACE == 8 + 32 + 32 + 32 + 4 + MAX_CAPABILITY_LENGTH +
+        8 + 1 + 8 + 9 + 33 + 8 + 9 + 9 + 8 + 8 + 1 + 8 + 33 + 33 +
+        4 + MAX_BUNDLE_CAPABILITIES * (4 + MAX_CAPABILITY_LENGTH) + 1 + 1 + 4 + MAX_METADATA_URI_LENGTH + 2
+);
+// The resolution is the largest `DisputeResolution` variant: tag + u64
+const _: () = assert!(
+    Dispute::SPACE == 8 + 32 + 32 + 4 + MAX_DISPUTE_REASON_LENGTH + 1 + 8 + 9 + (1 + 1 + 8) + 8 + 1 + 33 + 8
+);
+const _: () = assert!(
+    ProtocolConfig::SPACE == 8 + 32 + 32 + 2 + 1 + 8 + 2 + 2 + 32 + 2 + 8 + 8 + 8 + 8 + 33 + 8 + 33 + 8 + 8 +
+        8 * FEE_TIERS + 2 * FEE_TIERS + 8 + 8
+);
+const _: () = assert!(
+    RequestForQuote::SPACE == 8 + 32 + 32 + 32 + 4 + MAX_CAPABILITY_LENGTH + 32 + 8 + 8 + 1 + 8
+);
+const _: () = assert!(Vault::SPACE == 8 + 32 + 1 + 8 + 4 + MAX_VAULT_ENTRIES * (32 + 32 + 8 + 8));
 
 // === Errors ===
 
@@ -1263,4 +8856,355 @@ pub enum AgentFundError {
     
     #[msg("Invalid split percentage")]
     InvalidSplitPct,
+    
+    #[msg("Dispute window is out of bounds")]
+    InvalidDisputeWindow,
+    
+    #[msg("Account is not a valid agent profile")]
+    InvalidAgentProfile,
+    
+    #[msg("Signer is not authorized for this action")]
+    Unauthorized,
+    
+    #[msg("Insufficient channel balance")]
+    InsufficientChannelBalance,
+    
+    #[msg("Payer and recipient are not the parties of this channel")]
+    ChannelPartyMismatch,
+    
+    #[msg("Arithmetic overflow")]
+    ArithmeticOverflow,
+    
+    #[msg("Invalid pagination offset or limit")]
+    InvalidPagination,
+    
+    #[msg("Signer is not a party to this channel")]
+    NotChannelParty,
+    
+    #[msg("Channel is not closing")]
+    ChannelNotClosing,
+    
+    #[msg("Channel challenge period is still active")]
+    ChallengePeriodActive,
+    
+    #[msg("Channel challenge period has expired")]
+    ChallengePeriodExpired,
+    
+    #[msg("Request has already been accepted by the provider")]
+    RequestAlreadyAccepted,
+    
+    #[msg("Fee basis points out of range")]
+    InvalidFeeBps,
+    
+    #[msg("Fee exceeds escrowed amount")]
+    FeeExceedsEscrow,
+    
+    #[msg("Too many accounts supplied")]
+    TooManyAccounts,
+    
+    #[msg("Account is not a valid invoice")]
+    InvalidInvoiceAccount,
+    
+    #[msg("Challenge nonce does not beat the pending close")]
+    StaleChallengeNonce,
+    
+    #[msg("SLA bond requires a positive deadline")]
+    InvalidSlaTerms,
+    
+    #[msg("Refund exceeds escrowed amount")]
+    RefundExceedsEscrow,
+    
+    #[msg("Treasury still has pending invoices")]
+    TreasuryNotEmpty,
+    
+    #[msg("Memo limit must be between 1 and the hard ceiling")]
+    InvalidMemoLimit,
+    
+    #[msg("Both requester and provider must sign a resolution without the arbiter")]
+    BothPartiesMustSign,
+    
+    #[msg("Protocol fee must be less than the invoice amount")]
+    FeeExceedsAmount,
+    
+    #[msg("Fee collector does not match protocol config")]
+    InvalidFeeCollector,
+    
+    #[msg("Alias already names a capability or alias")]
+    DuplicateCapabilityAlias,
+    
+    #[msg("Too many capability aliases")]
+    TooManyCapabilityAliases,
+    
+    #[msg("Request already has funds in this vault")]
+    DuplicateVaultEntry,
+    
+    #[msg("Vault has no free entries")]
+    VaultFull,
+    
+    #[msg("No vault entry for this request")]
+    VaultEntryNotFound,
+    
+    #[msg("Timestamp is too far in the future")]
+    ExpiryTooFar,
+    
+    #[msg("Invoice is restricted to a different payer")]
+    PayerNotAllowed,
+    
+    #[msg("Review period must be between zero and 30 days")]
+    InvalidReviewPeriod,
+    
+    #[msg("Request is not awaiting review")]
+    RequestNotInReview,
+    
+    #[msg("Review period has not ended and the requester has not approved")]
+    ReviewPeriodActive,
+    
+    #[msg("Escrow balance change does not match the requested amount")]
+    EscrowFundingMismatch,
+    
+    #[msg("Too many tags")]
+    TooManyTags,
+    
+    #[msg("Too many authorized settlers")]
+    TooManySettlers,
+    
+    #[msg("Settler is not authorized for this treasury")]
+    UnauthorizedSettler,
+    
+    #[msg("Recovery period must be positive")]
+    InvalidRecoveryPeriod,
+    
+    #[msg("Escrow is not stuck or not yet past the recovery period")]
+    EscrowNotStuck,
+    
+    #[msg("Dispute batch must be (request, dispute) pairs within the size limit")]
+    InvalidDisputeBatch,
+    
+    #[msg("Batched requests must share the same requester and provider")]
+    DisputeBatchMismatch,
+    
+    #[msg("Dispute reason too long")]
+    DisputeReasonTooLong,
+    
+    #[msg("Invoice has no open preauthorization")]
+    InvoiceNotAuthorized,
+    
+    #[msg("Capture exceeds the preauthorized amount")]
+    CaptureExceedsHold,
+    
+    #[msg("Protocol release delay has not elapsed")]
+    ReleaseDelayActive,
+    
+    #[msg("Invoice dependency has not been paid")]
+    DependencyNotPaid,
+    
+    #[msg("Quote request is no longer open")]
+    QuoteClosed,
+    
+    #[msg("Provider has not submitted a quote")]
+    QuoteNotSubmitted,
+    
+    #[msg("Quote has expired")]
+    QuoteExpired,
+    
+    #[msg("Invoice has not expired yet")]
+    InvoiceNotExpired,
+    
+    #[msg("Provider treasury is closed or below its rent-exempt minimum")]
+    ProviderTreasuryUnhealthy,
+    
+    #[msg("Payer memo too long")]
+    PayerMemoTooLong,
+    
+    #[msg("Agent index must be the owner's next free index")]
+    InvalidAgentIndex,
+    
+    #[msg("Owner has registered the maximum number of agents")]
+    AgentRegistryFull,
+    
+    #[msg("Invoice amount is below the protocol minimum")]
+    AmountBelowMinimum,
+    
+    #[msg("Channel is not awaiting acceptance")]
+    ChannelNotPendingAcceptance,
+    
+    #[msg("Yield routing is not enabled in this build")]
+    YieldRoutingDisabled,
+    
+    #[msg("Yield strategy does not match the protocol config")]
+    YieldStrategyNotConfigured,
+    
+    #[msg("Yield strategy returned less than the principal")]
+    YieldPrincipalShortfall,
+    
+    #[msg("Escrow principal is deposited with the yield strategy")]
+    EscrowInYield,
+    
+    #[msg("Request is already settled")]
+    RequestAlreadySettled,
+    
+    #[msg("Signer is not the request's designated oracle")]
+    UnauthorizedOracle,
+    
+    #[msg("An agent cannot transact with itself")]
+    SelfDealingNotAllowed,
+    
+    #[msg("Invoice URI exceeds the return data limit")]
+    InvoiceUriTooLong,
+    
+    #[msg("Provider has reached its concurrent request limit")]
+    ProviderAtCapacity,
+    
+    #[msg("A redo resolution requires the follow-up request accounts")]
+    RedoAccountsMissing,
+    
+    #[msg("Payer balance does not cover the amount and rent-exempt minimum")]
+    InsufficientPayerBalance,
+    
+    #[msg("Channel has seen activity since opening")]
+    ChannelNotPristine,
+    
+    #[msg("Channel has not been idle long enough to reclaim the deposit")]
+    ChannelNotIdle,
+    
+    #[msg("A bundle must list between one and the maximum number of capabilities")]
+    InvalidBundleSize,
+    
+    #[msg("A capability appears more than once in the bundle")]
+    DuplicateBundleCapability,
+    
+    #[msg("Withdrawal exceeds available earnings")]
+    InsufficientEarnings,
+    
+    #[msg("Delegated request lacks a matching requester ed25519 signature")]
+    InvalidDelegatedSignature,
+    
+    #[msg("Delegated request authorization has expired")]
+    DelegationExpired,
+    
+    #[msg("Snapshot capacity must be between one and the maximum")]
+    InvalidSnapshotCapacity,
+    
+    #[msg("No dispute DAO is configured")]
+    DisputeDaoNotConfigured,
+    
+    #[msg("Dispute has not been open long enough to escalate")]
+    EscalationTooEarly,
+    
+    #[msg("Channel deposit is below the protocol minimum")]
+    DepositBelowMinimum,
+    
+    #[msg("Treasury does not belong to the provider's profile")]
+    TreasuryProfileMismatch,
+    
+    #[msg("An invoice with this id already exists")]
+    InvoiceIdAlreadyExists,
+    
+    #[msg("A batch with this id already exists")]
+    BatchIdAlreadyExists,
+    
+    #[msg("A channel with this id already exists")]
+    ChannelIdAlreadyExists,
+    
+    #[msg("A service request with this id already exists")]
+    RequestIdAlreadyExists,
+    
+    #[msg("Request was disputed and can only be settled through the dispute")]
+    RequestWasDisputed,
+    
+    #[msg("Request cannot be reclaimed until its dispute window has closed")]
+    ReclaimTooEarly,
+    
+    #[msg("Batch is not in the settled state")]
+    BatchNotSettled,
+    
+    #[msg("Batch can no longer be reversed")]
+    BatchReversalWindowClosed,
+    
+    #[msg("Account is not a batch settlement")]
+    InvalidBatchAccount,
+    
+    #[msg("Enabled fee tier thresholds must be strictly ascending")]
+    InvalidFeeTiers,
+    
+    #[msg("Capability is temporarily not taking requests")]
+    CapabilityInactive,
+    
+    #[msg("Batch escrow is no longer funded")]
+    BatchEscrowNotFunded,
+    
+    #[msg("Batch escrow claim window has closed")]
+    BatchClaimWindowClosed,
+    
+    #[msg("Batch escrow can still be claimed by the recipient")]
+    BatchClaimWindowOpen,
+    
+    #[msg("Invoices do not add up to the escrowed batch total")]
+    BatchTotalMismatch,
+    
+    #[msg("Provider is banned from the marketplace")]
+    ProviderBanned,
+    
+    #[msg("Owner is banned from registering agents")]
+    AgentBanned,
+    
+    #[msg("Ban list is full")]
+    BanListFull,
+    
+    #[msg("Agent is not on the ban list")]
+    AgentNotBanned,
+    
+    #[msg("Batch total does not match the sum of its invoices")]
+    BatchAmountMismatch,
+    
+    #[msg("Provider is still active")]
+    ProviderStillActive,
+    
+    #[msg("Agent is not accepting new requests")]
+    AgentNotAcceptingRequests,
+    
+    #[msg("Capability is listed more than once")]
+    DuplicateCapability,
+    
+    #[msg("Split invoices need between one and eight recipients")]
+    InvalidSplitRecipients,
+    
+    #[msg("Split recipient shares must add up to 10000 bps")]
+    SplitBpsMismatch,
+    
+    #[msg("Split recipient account does not match the invoice")]
+    InvalidSplitAccount,
+    
+    #[msg("Split invoices can only be paid directly")]
+    SplitInvoiceNotSupported,
+    
+    #[msg("Agent has been active within the staleness threshold")]
+    AgentNotStale,
+    
+    #[msg("Dispute review time is invalid or exceeds the cap")]
+    InvalidDisputeReview,
+    
+    #[msg("Metadata URI too long")]
+    MetadataUriTooLong,
+    
+    #[msg("Channel state is not signed by the counterparty")]
+    InvalidCounterpartySignature,
+    
+    #[msg("Escrow recovery period is below the 180-day minimum")]
+    RecoveryPeriodTooShort,
+    
+    #[msg("Vault deposit is below the minimum")]
+    VaultDepositTooSmall,
+    
+    #[msg("Vault entry cannot be refunded yet")]
+    VaultEntryNotRefundable,
+    
+    #[msg("Request was accepted before the provider deactivated")]
+    AcceptedBeforeDeactivation,
+    
+    #[msg("Invoice dependency was not supplied")]
+    DependencyNotFound,
+    
+    #[msg("Other invoices depend on this invoice")]
+    InvoiceHasDependents,
 }
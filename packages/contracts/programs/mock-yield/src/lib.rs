@@ -0,0 +1,47 @@
+//! Minimal yield strategy used by the agentfund tests
+//!
+//! Speaks the notification protocol of `agentfund::yield_routing`: a one byte
+//! tag followed by a little-endian u64 amount, with the strategy vault and
+//! the request escrow as accounts. Deposits are only acknowledged; withdrawals
+//! pay the principal back plus a fixed yield from the vault this program owns
+
+use anchor_lang::solana_program::account_info::{next_account_info, AccountInfo};
+use anchor_lang::solana_program::entrypoint::ProgramResult;
+use anchor_lang::solana_program::program_error::ProgramError;
+use anchor_lang::solana_program::pubkey::Pubkey;
+
+/// Tag of a deposit notification (`agentfund::yield_routing::YIELD_DEPOSIT_TAG`)
+pub const DEPOSIT_TAG: u8 = 0;
+
+/// Tag of a withdrawal request (`agentfund::yield_routing::YIELD_WITHDRAW_TAG`)
+pub const WITHDRAW_TAG: u8 = 1;
+
+/// Lamports paid on top of the principal for every withdrawal
+pub const YIELD_PER_WITHDRAWAL: u64 = 1_000;
+
+#[cfg(not(feature = "no-entrypoint"))]
+anchor_lang::solana_program::entrypoint!(process_instruction);
+
+pub fn process_instruction(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let accounts = &mut accounts.iter();
+    let vault = next_account_info(accounts)?;
+    let escrow = next_account_info(accounts)?;
+    if vault.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let (tag, amount) = data.split_first().ok_or(ProgramError::InvalidInstructionData)?;
+    let amount = u64::from_le_bytes(amount.try_into().map_err(|_| ProgramError::InvalidInstructionData)?);
+
+    match *tag {
+        DEPOSIT_TAG => Ok(()),
+        WITHDRAW_TAG => {
+            let payout = amount.checked_add(YIELD_PER_WITHDRAWAL).ok_or(ProgramError::InvalidArgument)?;
+            let remaining = vault.lamports().checked_sub(payout).ok_or(ProgramError::InsufficientFunds)?;
+            **vault.try_borrow_mut_lamports()? = remaining;
+            **escrow.try_borrow_mut_lamports()? += payout;
+            Ok(())
+        }
+        _ => Err(ProgramError::InvalidInstructionData),
+    }
+}
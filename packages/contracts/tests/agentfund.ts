@@ -2,7 +2,11 @@ import * as anchor from "@coral-xyz/anchor";
 import { Program } from "@coral-xyz/anchor";
 import { Agentfund } from "../target/types/agentfund";
 import { expect } from "chai";
-import { Keypair, LAMPORTS_PER_SOL, PublicKey } from "@solana/web3.js";
+import { Ed25519Program, Keypair, LAMPORTS_PER_SOL, PublicKey } from "@solana/web3.js";
+import { createHash } from "crypto";
+import { readFileSync } from "fs";
+import { Clock, startAnchor } from "solana-bankrun";
+import { BankrunProvider } from "anchor-bankrun";
 
 describe("agentfund", () => {
   const provider = anchor.AnchorProvider.env();
@@ -13,14 +17,355 @@ describe("agentfund", () => {
   let treasuryPDA: PublicKey;
   let treasuryBump: number;
 
+  // Protocol config singleton, administered by the test wallet
+  const [configPDA] = PublicKey.findProgramAddressSync(
+    [Buffer.from("config")],
+    program.programId
+  );
+  const [bannedAgentsPDA] = PublicKey.findProgramAddressSync(
+    [Buffer.from("banned_agents")],
+    program.programId
+  );
+  // Program data account of the upgradeable deployment; its upgrade
+  // authority (the test wallet) is the only key allowed to create the config
+  const [programDataPDA] = PublicKey.findProgramAddressSync(
+    [program.programId.toBuffer()],
+    anchor.web3.BPF_LOADER_UPGRADEABLE_PROGRAM_ID
+  );
+  const arbiter = Keypair.generate();
+  const ARBITER_FEE_BPS = 500;
+
   before(async () => {
     // Derive treasury PDA
     [treasuryPDA, treasuryBump] = PublicKey.findProgramAddressSync(
       [Buffer.from("treasury"), provider.wallet.publicKey.toBuffer()],
       program.programId
     );
+
+    await program.methods
+      .initializeProtocolConfig(arbiter.publicKey, ARBITER_FEE_BPS)
+      .accounts({
+        config: configPDA,
+        admin: provider.wallet.publicKey,
+        program: program.programId,
+        programData: programDataPDA,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .rpc();
   });
 
+  const pda = (...seeds: Buffer[]) =>
+    PublicKey.findProgramAddressSync(seeds, program.programId);
+
+  const airdrop = async (pubkey: PublicKey, sol = 1) => {
+    const sig = await provider.connection.requestAirdrop(
+      pubkey,
+      sol * LAMPORTS_PER_SOL
+    );
+    await provider.connection.confirmTransaction(sig);
+  };
+
+  // Decodes the program events emitted by a confirmed transaction
+  const eventsOf = async (signature: string) => {
+    const tx = await provider.connection.getTransaction(signature, {
+      commitment: "confirmed",
+      maxSupportedTransactionVersion: 0,
+    });
+    const parser = new anchor.EventParser(program.programId, program.coder);
+    return Array.from(parser.parseLogs(tx?.meta?.logMessages ?? []));
+  };
+
+  // Registers a fresh provider agent (with treasury) owned by a new keypair
+  const registerProvider = async (
+    capabilities: string[],
+    basePrice = new anchor.BN(1000)
+  ) => {
+    const owner = Keypair.generate();
+    await airdrop(owner.publicKey);
+
+    const [treasury, bump] = pda(
+      Buffer.from("treasury"),
+      owner.publicKey.toBuffer()
+    );
+    const [profile, profileBump] = pda(
+      Buffer.from("agent"),
+      owner.publicKey.toBuffer()
+    );
+
+    await program.methods
+      .initializeTreasury(bump)
+      .accounts({
+        treasury,
+        owner: owner.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([owner])
+      .rpc();
+
+    await program.methods
+      .registerAgent("Test Agent", "Test provider", capabilities, basePrice, profileBump, [])
+      .accounts({
+        agentProfile: profile,
+        treasury,
+        owner: owner.publicKey,
+        bannedAgents: bannedAgentsPDA,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([owner])
+      .rpc();
+
+    return { owner, treasury, profile };
+  };
+
+  type ProviderAgent = Awaited<ReturnType<typeof registerProvider>>;
+
+  // Accepts a request as the provider, optionally posting an SLA bond
+  const acceptRequest = async (
+    agent: ProviderAgent,
+    request: PublicKey,
+    escrow: PublicKey,
+    sla: { deadlineSeconds: number; bond: anchor.BN } | null = null
+  ) => {
+    await program.methods
+      .acceptServiceRequest(
+        sla ? new anchor.BN(sla.deadlineSeconds) : null,
+        sla ? sla.bond : new anchor.BN(0)
+      )
+      .accounts({
+        request,
+        escrow,
+        providerProfile: agent.profile,
+        provider: agent.owner.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([agent.owner])
+      .rpc({ commitment: "confirmed" });
+  };
+
+  // Completes a request as the provider, starting the requester's review
+  const completeService = async (
+    agent: ProviderAgent,
+    request: PublicKey,
+    resultHash: number[] = Array(32).fill(7),
+    hashAlgorithm: any = null
+  ) =>
+    program.methods
+      .completeService(resultHash, hashAlgorithm)
+      .accounts({
+        request,
+        providerProfile: agent.profile,
+        provider: agent.owner.publicKey,
+        owner: agent.owner.publicKey,
+      })
+      .signers([agent.owner])
+      .rpc({ commitment: "confirmed" });
+
+  // Releases a completed request's payment. Sent by the test wallet (the
+  // requester) it counts as early approval; sent by `keeper` it does not
+  const finalizeCompletion = async (
+    agent: ProviderAgent,
+    request: PublicKey,
+    escrow: PublicKey,
+    keeper?: Keypair
+  ) => {
+    const builder = program.methods.finalizeCompletion().accounts({
+      request,
+      providerProfile: agent.profile,
+      escrow,
+      providerTreasury: agent.treasury,
+      provider: agent.owner.publicKey,
+      requester: provider.wallet.publicKey,
+      config: configPDA,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    });
+    if (!keeper) {
+      return builder.rpc({ commitment: "confirmed" });
+    }
+    const tx = await builder.transaction();
+    tx.feePayer = keeper.publicKey;
+    return anchor.web3.sendAndConfirmTransaction(provider.connection, tx, [keeper], {
+      commitment: "confirmed",
+    });
+  };
+
+  const sleep = (ms: number) => new Promise((resolve) => setTimeout(resolve, ms));
+
+  // Opens a dispute on a service request
+  const initiateDispute = async (
+    agent: ProviderAgent,
+    request: PublicKey,
+    requestId: Buffer,
+    initiator?: Keypair
+  ) => {
+    const [dispute] = pda(Buffer.from("dispute"), requestId);
+    const builder = program.methods
+      .initiateDispute("Result was incomplete")
+      .accounts({
+        request,
+        dispute,
+        providerProfile: agent.profile,
+        initiator: initiator?.publicKey ?? provider.wallet.publicKey,
+        config: configPDA,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      });
+    await (initiator ? builder.signers([initiator]) : builder).rpc();
+    return dispute;
+  };
+
+  // Resolves a dispute as the requester (test wallet)
+  const resolveDispute = (
+    agent: ProviderAgent,
+    request: PublicKey,
+    dispute: PublicKey,
+    escrow: PublicKey,
+    resolution: any
+  ) =>
+    program.methods
+      .resolveDispute(resolution)
+      .accounts({
+        request,
+        dispute,
+        config: configPDA,
+        resolver: provider.wallet.publicKey,
+        requester: provider.wallet.publicKey,
+        provider: agent.owner.publicKey,
+        escrow,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([agent.owner])
+      .rpc({ commitment: "confirmed" });
+
+  // Creates an invoice issued by `recipient`, whose treasury must exist
+  const createInvoice = async (
+    recipient: Keypair,
+    treasury: PublicKey,
+    amount = new anchor.BN(0.001 * LAMPORTS_PER_SOL),
+    allowedPayer: PublicKey | null = null,
+    dependsOn: Buffer | null = null,
+    expiresInSeconds = 3600
+  ) => {
+    const invoiceId = Keypair.generate().publicKey.toBuffer();
+    const [invoice] = pda(Buffer.from("invoice"), invoiceId);
+    const expiresAt = new anchor.BN(Math.floor(Date.now() / 1000) + expiresInSeconds);
+
+    await program.methods
+      .createInvoice(
+        Array.from(invoiceId) as number[],
+        amount,
+        "Test",
+        expiresAt,
+        allowedPayer,
+        dependsOn ? (Array.from(dependsOn) as number[]) : null
+      )
+      .accounts({
+        invoice,
+        treasury,
+        recipient: recipient.publicKey,
+        config: configPDA,
+        dependency: dependsOn ? pda(Buffer.from("invoice"), dependsOn)[0] : null,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([recipient])
+      .rpc();
+
+    return { invoiceId, invoice };
+  };
+
+  // Opens a channel funded by the test wallet as party A and has party B
+  // accept it, leaving it open
+  const openChannel = async (
+    partyB: Keypair,
+    deposit = new anchor.BN(0.1 * LAMPORTS_PER_SOL)
+  ) => {
+    const channelId = Keypair.generate().publicKey.toBuffer();
+    const [channel] = pda(Buffer.from("channel"), channelId);
+    const [channelEscrow] = pda(Buffer.from("escrow"), channelId);
+
+    const sig = await program.methods
+      .openChannel(Array.from(channelId) as number[], deposit)
+      .accounts({
+        channel,
+        channelEscrow,
+        partyA: provider.wallet.publicKey,
+        partyB: partyB.publicKey,
+        config: configPDA,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .rpc({ commitment: "confirmed" });
+
+    await program.methods
+      .acceptChannel()
+      .accounts({ channel, partyB: partyB.publicKey })
+      .signers([partyB])
+      .rpc();
+
+    return { channelId, channel, channelEscrow, sig };
+  };
+
+  // Counterparty signature over a channel state's commitment, checked by
+  // the unilateral close paths
+  const signChannelState = (
+    signer: Keypair,
+    channelId: Buffer,
+    balanceA: anchor.BN,
+    balanceB: anchor.BN,
+    nonce: anchor.BN
+  ) =>
+    Ed25519Program.createInstructionWithPrivateKey({
+      privateKey: signer.secretKey,
+      message: createHash("sha256")
+        .update(channelId)
+        .update(balanceA.toArrayLike(Buffer, "le", 8))
+        .update(balanceB.toArrayLike(Buffer, "le", 8))
+        .update(nonce.toArrayLike(Buffer, "le", 8))
+        .digest(),
+    });
+
+  // Writable remaining accounts for the invoices a batch settles
+  const invoiceAccounts = (invoices: PublicKey[]) =>
+    invoices.map((pubkey) => ({ pubkey, isWritable: true, isSigner: false }));
+
+  // Requests a service from `agent`, paid by the test wallet
+  const requestService = async (
+    agent: ProviderAgent,
+    capability: string,
+    amount = new anchor.BN(0.01 * LAMPORTS_PER_SOL),
+    opts: {
+      disputeWindowSeconds?: anchor.BN;
+      reviewPeriodSeconds?: anchor.BN;
+      oracle?: PublicKey;
+      metadataUri?: string;
+    } = {}
+  ) => {
+    const requestId = Keypair.generate().publicKey.toBuffer();
+    const [request] = pda(Buffer.from("request"), requestId);
+    const [escrow] = pda(Buffer.from("request_escrow"), requestId);
+
+    await program.methods
+      .requestService(
+        Array.from(requestId) as number[],
+        capability,
+        amount,
+        opts.disputeWindowSeconds ?? null,
+        opts.reviewPeriodSeconds ?? null,
+        opts.metadataUri ?? null
+      )
+      .accounts({
+        request,
+        providerProfile: agent.profile,
+        providerOwner: agent.owner.publicKey,
+        providerTreasury: agent.treasury,
+        escrow,
+        requester: provider.wallet.publicKey,
+        oracle: opts.oracle ?? null,
+        bannedAgents: bannedAgentsPDA,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .rpc();
+
+    return { requestId, request, escrow };
+  };
+
   describe("initialize_treasury", () => {
     it("should initialize a treasury account", async () => {
       const tx = await program.methods
@@ -62,12 +407,15 @@ describe("agentfund", () => {
           Array.from(invoiceId) as number[],
           amount,
           memo,
-          expiresAt
+          expiresAt,
+          null,
+          null
         )
         .accounts({
           invoice: invoicePDA,
           treasury: treasuryPDA,
           recipient: provider.wallet.publicKey,
+          config: configPDA,
           systemProgram: anchor.web3.SystemProgram.programId,
         })
         .rpc();
@@ -100,12 +448,15 @@ describe("agentfund", () => {
           Array.from(invoiceId) as number[],
           amount,
           "Payment test",
-          expiresAt
+          expiresAt,
+          null,
+          null
         )
         .accounts({
           invoice: invoicePDA,
           treasury: treasuryPDA,
           recipient: provider.wallet.publicKey,
+          config: configPDA,
           systemProgram: anchor.web3.SystemProgram.programId,
         })
         .rpc();
@@ -125,12 +476,14 @@ describe("agentfund", () => {
       );
 
       const tx = await program.methods
-        .payInvoice()
+        .payInvoice(null)
         .accounts({
           invoice: invoicePDA,
           treasury: treasuryPDA,
           payer: payer.publicKey,
           recipient: provider.wallet.publicKey,
+          config: configPDA,
+          feeCollector: provider.wallet.publicKey,
           systemProgram: anchor.web3.SystemProgram.programId,
         })
         .signers([payer])
@@ -159,11 +512,13 @@ describe("agentfund", () => {
         program.programId
       );
 
-      const invoiceIds = [
-        Keypair.generate().publicKey.toBuffer(),
-        Keypair.generate().publicKey.toBuffer(),
-        Keypair.generate().publicKey.toBuffer(),
+      const agent = await registerProvider(["sentiment"]);
+      const invoices = [
+        await createInvoice(agent.owner, agent.treasury),
+        await createInvoice(agent.owner, agent.treasury),
+        await createInvoice(agent.owner, agent.treasury),
       ];
+      const invoiceIds = invoices.map(({ invoiceId }) => invoiceId);
 
       const totalAmount = new anchor.BN(0.003 * LAMPORTS_PER_SOL);
 
@@ -182,11 +537,12 @@ describe("agentfund", () => {
         )
         .accounts({
           batch: batchPDA,
-          treasury: treasuryPDA,
+          treasury: agent.treasury,
           settler: settler.publicKey,
-          recipient: provider.wallet.publicKey,
+          recipient: agent.owner.publicKey,
           systemProgram: anchor.web3.SystemProgram.programId,
         })
+        .remainingAccounts(invoiceAccounts(invoices.map(({ invoice }) => invoice)))
         .signers([settler])
         .rpc();
 
@@ -195,6 +551,54 @@ describe("agentfund", () => {
       const batch = await program.account.batchSettlement.fetch(batchPDA);
       expect(batch.invoiceCount).to.equal(3);
       expect(batch.totalAmount.toNumber()).to.equal(totalAmount.toNumber());
+
+      const treasury = await program.account.treasury.fetch(agent.treasury);
+      expect(treasury.pendingInvoices.toNumber()).to.equal(0);
+      for (const { invoice } of invoices) {
+        const settled = await program.account.invoice.fetch(invoice);
+        expect(settled.status).to.deep.equal({ paid: {} });
+        expect(settled.payer.toBase58()).to.equal(settler.publicKey.toBase58());
+      }
+    });
+
+    it("should reject settling an invoice already settled by another batch", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const shared = await createInvoice(agent.owner, agent.treasury, new anchor.BN(1000));
+      const other = await createInvoice(agent.owner, agent.treasury, new anchor.BN(1000));
+
+      const settle = (invoices: { invoiceId: Buffer; invoice: PublicKey }[]) => {
+        const batchId = Keypair.generate().publicKey.toBuffer();
+        const [batch] = pda(Buffer.from("batch"), batchId);
+        return program.methods
+          .settleBatch(
+            Array.from(batchId) as number[],
+            invoices.map(({ invoiceId }) => Array.from(invoiceId) as number[]),
+            new anchor.BN(1000)
+          )
+          .accounts({
+            batch,
+            treasury: agent.treasury,
+            settler: provider.wallet.publicKey,
+            recipient: agent.owner.publicKey,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .remainingAccounts(invoiceAccounts(invoices.map(({ invoice }) => invoice)))
+          .rpc();
+      };
+
+      await settle([shared]);
+      try {
+        await settle([other, shared]);
+        expect.fail("expected InvoiceNotPending");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InvoiceNotPending");
+      }
+
+      // The failed batch left the other invoice and the pending count alone
+      const treasury = await program.account.treasury.fetch(agent.treasury);
+      expect(treasury.pendingInvoices.toNumber()).to.equal(1);
+      const untouched = await program.account.invoice.fetch(other.invoice);
+      expect(untouched.status).to.deep.equal({ pending: {} });
     });
   });
 
@@ -220,6 +624,7 @@ describe("agentfund", () => {
           channelEscrow: escrowPDA,
           partyA: provider.wallet.publicKey,
           partyB: partyB,
+          config: configPDA,
           systemProgram: anchor.web3.SystemProgram.programId,
         })
         .rpc();
@@ -232,7 +637,6725 @@ describe("agentfund", () => {
       );
       expect(channel.partyB.toString()).to.equal(partyB.toString());
       expect(channel.depositA.toNumber()).to.equal(deposit.toNumber());
-      expect(channel.status).to.deep.equal({ open: {} });
+      expect(channel.status).to.deep.equal({ pendingAcceptance: {} });
+    });
+  });
+
+  describe("service_requests", () => {
+    let agent: ProviderAgent;
+
+    before(async () => {
+      agent = await registerProvider(["sentiment"]);
+    });
+
+    it("should default the dispute window to 24 hours", async () => {
+      const { request } = await requestService(agent, "sentiment");
+
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.disputeWindowSeconds.toNumber()).to.equal(86400);
+    });
+
+    it("should accept a custom short dispute window", async () => {
+      const { request } = await requestService(agent, "sentiment", undefined, {
+        disputeWindowSeconds: new anchor.BN(3600),
+      });
+
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.disputeWindowSeconds.toNumber()).to.equal(3600);
+    });
+
+    it("should accept a custom long dispute window", async () => {
+      const thirtyDays = 30 * 86400;
+      const { request } = await requestService(agent, "sentiment", undefined, {
+        disputeWindowSeconds: new anchor.BN(thirtyDays),
+      });
+
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.disputeWindowSeconds.toNumber()).to.equal(thirtyDays);
+    });
+
+    it("should reject an out-of-bounds dispute window", async () => {
+      try {
+        await requestService(agent, "sentiment", undefined, {
+          disputeWindowSeconds: new anchor.BN(60),
+        });
+        expect.fail("expected InvalidDisputeWindow");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InvalidDisputeWindow");
+      }
+    });
+  });
+
+  describe("migrate_agent_profile", () => {
+    it("should be a no-op for a profile already at the current version", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const before = await program.account.agentProfile.fetch(agent.profile);
+
+      for (let i = 0; i < 2; i++) {
+        await program.methods
+          .migrateAgentProfile()
+          .accounts({
+            agentProfile: agent.profile,
+            owner: agent.owner.publicKey,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .signers([agent.owner])
+          .rpc();
+      }
+
+      const after = await program.account.agentProfile.fetch(agent.profile);
+      expect(after.schemaVersion).to.equal(before.schemaVersion);
+      expect(after.name).to.equal(before.name);
+    });
+
+    it("should reject migration by a non-owner", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const intruder = Keypair.generate();
+      await airdrop(intruder.publicKey);
+
+      try {
+        await program.methods
+          .migrateAgentProfile()
+          .accounts({
+            agentProfile: agent.profile,
+            owner: intruder.publicKey,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .signers([intruder])
+          .rpc();
+        expect.fail("expected Unauthorized");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("Unauthorized");
+      }
+    });
+  });
+
+  describe("pay_invoice_from_channel", () => {
+    it("should shift channel balances and mark the invoice paid", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const deposit = new anchor.BN(0.1 * LAMPORTS_PER_SOL);
+      const amount = new anchor.BN(0.002 * LAMPORTS_PER_SOL);
+      const { channelId, channel } = await openChannel(
+        recipient.owner,
+        deposit
+      );
+      const { invoice } = await createInvoice(
+        recipient.owner,
+        recipient.treasury,
+        amount
+      );
+      const escrowBefore = await provider.connection.getBalance(
+        pda(Buffer.from("escrow"), channelId)[0]
+      );
+
+      await program.methods
+        .payInvoiceFromChannel(Array.from(channelId) as number[], new anchor.BN(1))
+        .accounts({
+          invoice,
+          channel,
+          treasury: recipient.treasury,
+          payer: provider.wallet.publicKey,
+          instructions: anchor.web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+        })
+        .preInstructions([signChannelState(recipient.owner, channelId, deposit.sub(amount), amount, new anchor.BN(1))])
+        .rpc();
+
+      const channelAccount = await program.account.paymentChannel.fetch(channel);
+      expect(channelAccount.balanceA.toNumber()).to.equal(
+        deposit.sub(amount).toNumber()
+      );
+      expect(channelAccount.balanceB.toNumber()).to.equal(amount.toNumber());
+      expect(channelAccount.nonce.toNumber()).to.equal(1);
+      const expectedHash = createHash("sha256")
+        .update(channelId)
+        .update(channelAccount.balanceA.toArrayLike(Buffer, "le", 8))
+        .update(channelAccount.balanceB.toArrayLike(Buffer, "le", 8))
+        .update(channelAccount.nonce.toArrayLike(Buffer, "le", 8))
+        .digest();
+      expect(channelAccount.stateHash).to.deep.equal(Array.from(expectedHash));
+
+      const invoiceAccount = await program.account.invoice.fetch(invoice);
+      expect(invoiceAccount.status).to.deep.equal({ paid: {} });
+
+      const escrowAfter = await provider.connection.getBalance(
+        pda(Buffer.from("escrow"), channelId)[0]
+      );
+      expect(escrowAfter).to.equal(escrowBefore);
+    });
+
+    it("should reject a payment exceeding the payer's channel balance", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const { channelId, channel } = await openChannel(
+        recipient.owner,
+        new anchor.BN(0.001 * LAMPORTS_PER_SOL)
+      );
+      const { invoice } = await createInvoice(
+        recipient.owner,
+        recipient.treasury,
+        new anchor.BN(0.002 * LAMPORTS_PER_SOL)
+      );
+
+      try {
+        await program.methods
+          .payInvoiceFromChannel(Array.from(channelId) as number[], new anchor.BN(1))
+          .accounts({
+            invoice,
+            channel,
+            treasury: recipient.treasury,
+            payer: provider.wallet.publicKey,
+            instructions: anchor.web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+          })
+          .rpc();
+        expect.fail("expected InsufficientChannelBalance");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InsufficientChannelBalance");
+      }
+    });
+
+    it("should require the recipient's signature and outrank its older states", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const deposit = new anchor.BN(0.1 * LAMPORTS_PER_SOL);
+      const amount = new anchor.BN(0.002 * LAMPORTS_PER_SOL);
+      const { channelId, channel } = await openChannel(recipient.owner, deposit);
+      const { invoice } = await createInvoice(recipient.owner, recipient.treasury, amount);
+      const pay = (signature?: any) =>
+        program.methods
+          .payInvoiceFromChannel(Array.from(channelId) as number[], new anchor.BN(4))
+          .accounts({
+            invoice,
+            channel,
+            treasury: recipient.treasury,
+            payer: provider.wallet.publicKey,
+            instructions: anchor.web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+          })
+          .preInstructions(signature ? [signature] : [])
+          .rpc();
+
+      try {
+        await pay();
+        expect.fail("expected InvalidCounterpartySignature");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InvalidCounterpartySignature");
+      }
+
+      // A state the recipient signed off-chain before the payment
+      const older = signChannelState(recipient.owner, channelId, deposit, new anchor.BN(0), new anchor.BN(3));
+      await pay(signChannelState(recipient.owner, channelId, deposit.sub(amount), amount, new anchor.BN(4)));
+
+      try {
+        await program.methods
+          .initiateChannelClose(deposit, new anchor.BN(0), new anchor.BN(3))
+          .accounts({
+            channel,
+            party: provider.wallet.publicKey,
+            instructions: anchor.web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+          })
+          .preInstructions([older])
+          .rpc();
+        expect.fail("expected InvalidNonce");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InvalidNonce");
+      }
+
+      const account = await program.account.paymentChannel.fetch(channel);
+      expect(account.balanceB.toString()).to.equal(amount.toString());
+      expect(account.status).to.deep.equal({ open: {} });
+    });
+  });
+
+  describe("get_capabilities_page", () => {
+    it("should page through all capabilities without overlap", async () => {
+      const capabilities = Array.from({ length: 10 }, (_, i) => `cap-${i}`);
+      const agent = await registerProvider(capabilities);
+
+      const seen: string[] = [];
+      for (let offset = 0; offset < capabilities.length; offset += 3) {
+        const page: string[] = await program.methods
+          .getCapabilitiesPage(offset, 3)
+          .accounts({ agentProfile: agent.profile })
+          .view();
+        expect(page.length).to.equal(Math.min(3, capabilities.length - offset));
+        seen.push(...page);
+      }
+
+      expect(seen).to.deep.equal(capabilities);
+      expect(new Set(seen).size).to.equal(capabilities.length);
+    });
+
+    it("should reject an offset past the end", async () => {
+      const agent = await registerProvider(["sentiment"]);
+
+      try {
+        await program.methods
+          .getCapabilitiesPage(2, 3)
+          .accounts({ agentProfile: agent.profile })
+          .view();
+        expect.fail("expected InvalidPagination");
+      } catch (err: any) {
+        expect(err.toString()).to.contain("InvalidPagination");
+      }
+    });
+  });
+
+  describe("register_agent_with_treasury", () => {
+    it("should create the profile and treasury in one transaction", async () => {
+      const owner = Keypair.generate();
+      await airdrop(owner.publicKey);
+      const [treasury, treasuryBump] = pda(
+        Buffer.from("treasury"),
+        owner.publicKey.toBuffer()
+      );
+      const [profile, profileBump] = pda(
+        Buffer.from("agent"),
+        owner.publicKey.toBuffer()
+      );
+
+      await program.methods
+        .registerAgentWithTreasury("New Agent", "One-shot", ["sentiment"], new anchor.BN(1000), [])
+        .accounts({
+          agentProfile: profile,
+          treasury,
+          owner: owner.publicKey,
+          bannedAgents: bannedAgentsPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([owner])
+        .rpc();
+
+      const treasuryAccount = await program.account.treasury.fetch(treasury);
+      expect(treasuryAccount.owner.toString()).to.equal(owner.publicKey.toString());
+      expect(treasuryAccount.bump).to.equal(treasuryBump);
+      expect(treasuryAccount.totalReceived.toNumber()).to.equal(0);
+      expect(treasuryAccount.totalSettled.toNumber()).to.equal(0);
+      expect(treasuryAccount.pendingInvoices.toNumber()).to.equal(0);
+
+      const profileAccount = await program.account.agentProfile.fetch(profile);
+      expect(profileAccount.treasury.toString()).to.equal(treasury.toString());
+      expect(profileAccount.bump).to.equal(profileBump);
+      expect(profileAccount.isActive).to.equal(true);
+    });
+  });
+
+  describe("channel_lifecycle_events", () => {
+    it("should emit an event for every channel state change", async () => {
+      const partyB = Keypair.generate();
+      await airdrop(partyB.publicKey);
+      const deposit = new anchor.BN(0.1 * LAMPORTS_PER_SOL);
+      const funding = new anchor.BN(0.05 * LAMPORTS_PER_SOL);
+      const { channelId, channel, channelEscrow } = await openChannel(
+        partyB,
+        deposit
+      );
+      const total = deposit.add(funding);
+
+      const fundSig = await program.methods
+        .fundChannel(funding)
+        .accounts({
+          channel,
+          channelEscrow,
+          partyB: partyB.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([partyB])
+        .rpc({ commitment: "confirmed" });
+      const [funded] = await eventsOf(fundSig);
+      expect(funded.name).to.equal("ChannelFunded");
+      expect(funded.data.balanceB.toNumber()).to.equal(funding.toNumber());
+
+      const updateSig = await program.methods
+        .updateChannel(total.subn(1000), new anchor.BN(1000), new anchor.BN(1))
+        .accounts({
+          channel,
+          partyA: provider.wallet.publicKey,
+          partyB: partyB.publicKey,
+        })
+        .signers([partyB])
+        .rpc({ commitment: "confirmed" });
+      const [updated] = await eventsOf(updateSig);
+      expect(updated.name).to.equal("ChannelUpdated");
+      expect(updated.data.nonce.toNumber()).to.equal(1);
+
+      const closingSig = await program.methods
+        .initiateChannelClose(total.subn(1000), new anchor.BN(1000), new anchor.BN(1))
+        .accounts({
+          channel,
+          party: provider.wallet.publicKey,
+          instructions: anchor.web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+        })
+        .rpc({ commitment: "confirmed" });
+      const [closing] = await eventsOf(closingSig);
+      expect(closing.name).to.equal("ChannelClosing");
+      expect(closing.data.nonce.toNumber()).to.equal(1);
+
+      const partyA = (provider.wallet as anchor.Wallet).payer;
+      const disputeSig = await program.methods
+        .challengeChannelClose(total.subn(2000), new anchor.BN(2000), new anchor.BN(2))
+        .accounts({
+          channel,
+          party: partyB.publicKey,
+          instructions: anchor.web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+        })
+        .preInstructions([
+          signChannelState(partyA, channelId, total.subn(2000), new anchor.BN(2000), new anchor.BN(2)),
+        ])
+        .signers([partyB])
+        .rpc({ commitment: "confirmed" });
+      const [disputed] = await eventsOf(disputeSig);
+      expect(disputed.name).to.equal("ChannelDisputed");
+      expect(disputed.data.channelId).to.deep.equal(Array.from(channelId));
+      expect(disputed.data.balanceB.toNumber()).to.equal(2000);
+      expect(disputed.data.nonce.toNumber()).to.equal(2);
+
+      const channelAccount = await program.account.paymentChannel.fetch(channel);
+      expect(channelAccount.status).to.deep.equal({ disputed: {} });
+    });
+  });
+
+  describe("cancel_service_request", () => {
+    let agent: ProviderAgent;
+
+    before(async () => {
+      agent = await registerProvider(["sentiment"]);
+    });
+
+    const cancel = (request: PublicKey, escrow: PublicKey) =>
+      program.methods
+        .cancelServiceRequest()
+        .accounts({
+          request,
+          providerProfile: agent.profile,
+          escrow,
+          requester: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+    it("should refund the escrow for a pending request", async () => {
+      const { request, escrow } = await requestService(agent, "sentiment");
+      const openBefore = (await program.account.agentProfile.fetch(agent.profile))
+        .openRequests;
+
+      await cancel(request, escrow);
+
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.status).to.deep.equal({ cancelled: {} });
+      expect(await provider.connection.getBalance(escrow)).to.equal(0);
+
+      const profile = await program.account.agentProfile.fetch(agent.profile);
+      expect(profile.openRequests).to.equal(openBefore - 1);
+    });
+
+    it("should reject cancelling an accepted request", async () => {
+      const { request, escrow } = await requestService(agent, "sentiment");
+
+      await acceptRequest(agent, request, escrow);
+
+      try {
+        await cancel(request, escrow);
+        expect.fail("expected RequestAlreadyAccepted");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("RequestAlreadyAccepted");
+      }
+    });
+  });
+
+  describe("dispute_arbiter_fee", () => {
+    let agent: ProviderAgent;
+
+    before(async () => {
+      agent = await registerProvider(["sentiment"]);
+      await airdrop(arbiter.publicKey);
+    });
+
+    it("should pay the arbiter fee when the arbiter resolves", async () => {
+      const amount = new anchor.BN(0.01 * LAMPORTS_PER_SOL);
+      const { requestId, request, escrow } = await requestService(agent, "sentiment", amount);
+      const dispute = await initiateDispute(agent, request, requestId);
+
+      const providerBefore = await provider.connection.getBalance(agent.owner.publicKey);
+      const sig = await program.methods
+        .resolveDispute({ payProvider: {} })
+        .accounts({
+          request,
+          dispute,
+          config: configPDA,
+          resolver: arbiter.publicKey,
+          requester: provider.wallet.publicKey,
+          provider: agent.owner.publicKey,
+          escrow,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([arbiter])
+        .rpc({ commitment: "confirmed" });
+
+      const fee = amount.toNumber() * ARBITER_FEE_BPS / 10_000;
+      const [resolved] = await eventsOf(sig);
+      expect(resolved.data.arbiterFee.toNumber()).to.equal(fee);
+
+      const providerAfter = await provider.connection.getBalance(agent.owner.publicKey);
+      expect(providerAfter - providerBefore).to.equal(amount.toNumber() - fee);
+    });
+
+    it("should not charge a fee when a party resolves", async () => {
+      const amount = new anchor.BN(0.01 * LAMPORTS_PER_SOL);
+      const { requestId, request, escrow } = await requestService(agent, "sentiment", amount);
+      const dispute = await initiateDispute(agent, request, requestId);
+
+      const sig = await resolveDispute(agent, request, dispute, escrow, {
+        refundRequester: {},
+      });
+
+      const [resolved] = await eventsOf(sig);
+      expect(resolved.data.arbiterFee.toNumber()).to.equal(0);
+      expect(resolved.data.requesterAmount.toNumber()).to.equal(amount.toNumber());
+    });
+  });
+
+  describe("get_invoice_statuses", () => {
+    it("should return packed statuses for invoices in mixed states", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const invoices = [];
+      for (let i = 0; i < 5; i++) {
+        invoices.push(await createInvoice(recipient.owner, recipient.treasury));
+      }
+
+      // Pay the second and fourth invoices
+      for (const { invoice } of [invoices[1], invoices[3]]) {
+        await program.methods
+          .payInvoice(null)
+          .accounts({
+            invoice,
+            treasury: recipient.treasury,
+            payer: provider.wallet.publicKey,
+            recipient: recipient.owner.publicKey,
+            config: configPDA,
+            feeCollector: provider.wallet.publicKey,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .rpc();
+      }
+
+      const entries = await program.methods
+        .getInvoiceStatuses()
+        .remainingAccounts(
+          invoices.map(({ invoice }) => ({
+            pubkey: invoice,
+            isWritable: false,
+            isSigner: false,
+          }))
+        )
+        .view();
+
+      // InvoiceStatus discriminants: Pending = 0, Paid = 1
+      expect(entries.map((e: any) => e.status)).to.deep.equal([0, 1, 0, 1, 0]);
+      entries.forEach((e: any, i: number) =>
+        expect(e.invoiceId).to.deep.equal(Array.from(invoices[i].invoiceId))
+      );
+    });
+  });
+
+  describe("channel_nonce_replay", () => {
+    it("should accept a monotonic progression and reject replayed states", async () => {
+      const partyB = Keypair.generate();
+      await airdrop(partyB.publicKey);
+      const deposit = new anchor.BN(0.1 * LAMPORTS_PER_SOL);
+      const { channel } = await openChannel(partyB, deposit);
+
+      const update = (toB: number, nonce: number) =>
+        program.methods
+          .updateChannel(deposit.subn(toB), new anchor.BN(toB), new anchor.BN(nonce))
+          .accounts({
+            channel,
+            partyA: provider.wallet.publicKey,
+            partyB: partyB.publicKey,
+          })
+          .signers([partyB])
+          .rpc();
+
+      await update(1000, 1);
+      await update(2000, 2);
+      await update(5000, 5); // gaps are allowed
+
+      for (const nonce of [3, 5]) {
+        try {
+          await update(1000, nonce);
+          expect.fail("expected InvalidNonce");
+        } catch (err: any) {
+          expect(err.error.errorCode.code).to.equal("InvalidNonce");
+        }
+      }
+
+      // Posting a different split at the current nonce is a replay as well
+      try {
+        await program.methods
+          .initiateChannelClose(deposit, new anchor.BN(0), new anchor.BN(5))
+          .accounts({
+            channel,
+            party: provider.wallet.publicKey,
+            instructions: anchor.web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+          })
+          .rpc();
+        expect.fail("expected InvalidNonce");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InvalidNonce");
+      }
+
+      const account = await program.account.paymentChannel.fetch(channel);
+      expect(account.nonce.toNumber()).to.equal(5);
+      expect(account.balanceB.toNumber()).to.equal(5000);
+    });
+  });
+
+  describe("sla_bonds", () => {
+    let agent: ProviderAgent;
+    const bond = new anchor.BN(0.01 * LAMPORTS_PER_SOL);
+
+    before(async () => {
+      agent = await registerProvider(["sentiment"]);
+    });
+
+    it("should return the full bond on on-time completion", async () => {
+      const { request, escrow } = await requestService(agent, "sentiment");
+      await acceptRequest(agent, request, escrow, { deadlineSeconds: 3600, bond });
+
+      await completeService(agent, request);
+      const sig = await finalizeCompletion(agent, request, escrow);
+
+      const sla = (await eventsOf(sig)).find((e) => e.name === "SlaSettled");
+      expect(sla.data.onTime).to.equal(true);
+      expect(sla.data.slashed.toNumber()).to.equal(0);
+      expect(sla.data.returned.toNumber()).to.equal(bond.toNumber());
+    });
+
+    it("should slash the bond to the requester on late completion", async () => {
+      const { request, escrow } = await requestService(agent, "sentiment");
+      await acceptRequest(agent, request, escrow, { deadlineSeconds: 1, bond });
+      await sleep(3000);
+
+      await completeService(agent, request);
+      const sig = await finalizeCompletion(agent, request, escrow);
+
+      const sla = (await eventsOf(sig)).find((e) => e.name === "SlaSettled");
+      expect(sla.data.onTime).to.equal(false);
+      expect(sla.data.slashed.toNumber()).to.equal(bond.toNumber() / 2);
+      expect(sla.data.returned.toNumber()).to.equal(bond.toNumber() / 2);
+    });
+  });
+
+  describe("event_sequence_numbers", () => {
+    it("should assign increasing sequence numbers across instructions", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const invoiceId = Keypair.generate().publicKey.toBuffer();
+      const [invoice] = pda(Buffer.from("invoice"), invoiceId);
+      const expiresAt = new anchor.BN(Math.floor(Date.now() / 1000) + 3600);
+
+      const createSig = await program.methods
+        .createInvoice(
+          Array.from(invoiceId) as number[],
+          new anchor.BN(0.001 * LAMPORTS_PER_SOL),
+          "Sequenced",
+          expiresAt,
+          null,
+          null
+        )
+        .accounts({
+          invoice,
+          treasury: recipient.treasury,
+          recipient: recipient.owner.publicKey,
+          config: configPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([recipient.owner])
+        .rpc({ commitment: "confirmed" });
+
+      const paySig = await program.methods
+        .payInvoice(null)
+        .accounts({
+          invoice,
+          treasury: recipient.treasury,
+          payer: provider.wallet.publicKey,
+          recipient: recipient.owner.publicKey,
+          config: configPDA,
+          feeCollector: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc({ commitment: "confirmed" });
+
+      const [created] = await eventsOf(createSig);
+      const [paid] = await eventsOf(paySig);
+      expect(created.data.seq.toNumber()).to.be.greaterThan(0);
+      expect(paid.data.seq.toNumber()).to.equal(created.data.seq.toNumber() + 1);
+
+      const config = await program.account.protocolConfig.fetch(configPDA);
+      expect(config.eventSeq.toNumber()).to.equal(paid.data.seq.toNumber());
+    });
+  });
+
+  describe("partial_refund_resolution", () => {
+    let agent: ProviderAgent;
+    const amount = new anchor.BN(0.01 * LAMPORTS_PER_SOL);
+
+    before(async () => {
+      agent = await registerProvider(["sentiment"]);
+    });
+
+    it("should refund an exact amount and pay the rest to the provider", async () => {
+      const { requestId, request, escrow } = await requestService(agent, "sentiment", amount);
+      const dispute = await initiateDispute(agent, request, requestId);
+      const refund = new anchor.BN(1_234_567);
+
+      const providerBefore = await provider.connection.getBalance(agent.owner.publicKey);
+      const sig = await resolveDispute(agent, request, dispute, escrow, {
+        partialRefund: { refundAmount: refund },
+      });
+      const providerAfter = await provider.connection.getBalance(agent.owner.publicKey);
+
+      const [resolved] = await eventsOf(sig);
+      expect(resolved.data.requesterAmount.toNumber()).to.equal(refund.toNumber());
+      expect(providerAfter - providerBefore).to.equal(amount.sub(refund).toNumber());
+    });
+
+    it("should reject a refund larger than the escrow", async () => {
+      const { requestId, request, escrow } = await requestService(agent, "sentiment", amount);
+      const dispute = await initiateDispute(agent, request, requestId);
+
+      try {
+        await resolveDispute(agent, request, dispute, escrow, {
+          partialRefund: { refundAmount: amount.addn(1) },
+        });
+        expect.fail("expected RefundExceedsEscrow");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("RefundExceedsEscrow");
+      }
+    });
+  });
+
+  describe("close_treasury", () => {
+    const closeTreasury = (agent: ProviderAgent) =>
+      program.methods
+        .closeTreasury()
+        .accounts({ treasury: agent.treasury, owner: agent.owner.publicKey })
+        .signers([agent.owner])
+        .rpc();
+
+    it("should close an empty treasury and return rent", async () => {
+      const agent = await registerProvider(["sentiment"]);
+
+      await closeTreasury(agent);
+
+      expect(await provider.connection.getAccountInfo(agent.treasury)).to.equal(null);
+    });
+
+    it("should reject closing a treasury with pending invoices", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      await createInvoice(agent.owner, agent.treasury);
+
+      try {
+        await closeTreasury(agent);
+        expect.fail("expected TreasuryNotEmpty");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("TreasuryNotEmpty");
+      }
+    });
+
+    it("should reject closing a treasury holding unwithdrawn earnings", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const amount = new anchor.BN(0.01 * LAMPORTS_PER_SOL);
+      const { request, escrow } = await requestService(agent, "sentiment", amount);
+      await completeService(agent, request);
+      await finalizeCompletion(agent, request, escrow);
+
+      try {
+        await closeTreasury(agent);
+        expect.fail("expected TreasuryNotEmpty");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("TreasuryNotEmpty");
+      }
+
+      // Once the earnings are withdrawn only rent is left, so closing succeeds
+      const { availableEarnings } = await program.account.agentProfile.fetch(agent.profile);
+      await program.methods
+        .withdrawEarnings(availableEarnings)
+        .accounts({ agentProfile: agent.profile, treasury: agent.treasury, owner: agent.owner.publicKey })
+        .signers([agent.owner])
+        .rpc();
+      await closeTreasury(agent);
+
+      expect(await provider.connection.getAccountInfo(agent.treasury)).to.equal(null);
+    });
+  });
+
+  describe("configurable memo limit", () => {
+    const setMemoLimit = (maxMemoLength: number) =>
+      program.methods
+        .updateProtocolConfig({ maxMemoLength } as any)
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+
+    const createInvoiceWithMemo = async (recipient: Keypair, treasury: PublicKey, memo: string) => {
+      const invoiceId = Keypair.generate().publicKey.toBuffer();
+      const [invoice] = pda(Buffer.from("invoice"), invoiceId);
+      const expiresAt = new anchor.BN(Math.floor(Date.now() / 1000) + 3600);
+
+      await program.methods
+        .createInvoice(Array.from(invoiceId) as number[], new anchor.BN(1000), memo, expiresAt, null, null)
+        .accounts({
+          invoice,
+          treasury,
+          recipient: recipient.publicKey,
+          config: configPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([recipient])
+        .rpc();
+
+      return invoice;
+    };
+
+    after(() => setMemoLimit(256));
+
+    it("should accept a memo at the configured limit", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      await setMemoLimit(300);
+
+      const invoice = await createInvoiceWithMemo(agent.owner, agent.treasury, "m".repeat(300));
+
+      const account = await program.account.invoice.fetch(invoice);
+      expect(account.memo.length).to.equal(300);
+    });
+
+    it("should reject a memo just over the configured limit", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      await setMemoLimit(300);
+
+      try {
+        await createInvoiceWithMemo(agent.owner, agent.treasury, "m".repeat(301));
+        expect.fail("expected MemoTooLong");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("MemoTooLong");
+      }
+    });
+
+    it("should reject a memo limit above the hard ceiling", async () => {
+      try {
+        await setMemoLimit(1025);
+        expect.fail("expected InvalidMemoLimit");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InvalidMemoLimit");
+      }
+    });
+  });
+
+  describe("dispute_self_resolution", () => {
+    let agent: ProviderAgent;
+
+    before(async () => {
+      agent = await registerProvider(["sentiment"]);
+    });
+
+    it("should reject a provider resolving alone in their own favor", async () => {
+      const { requestId, request, escrow } = await requestService(agent, "sentiment");
+      const dispute = await initiateDispute(agent, request, requestId);
+
+      try {
+        await program.methods
+          .resolveDispute({ payProvider: {} })
+          .accounts({
+            request,
+            dispute,
+            config: configPDA,
+            resolver: agent.owner.publicKey,
+            requester: provider.wallet.publicKey,
+            provider: agent.owner.publicKey,
+            escrow,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .signers([agent.owner])
+          .rpc();
+        expect.fail("expected BothPartiesMustSign");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("BothPartiesMustSign");
+      }
+    });
+
+    it("should accept a resolution signed by both parties", async () => {
+      const { requestId, request, escrow } = await requestService(agent, "sentiment");
+      const dispute = await initiateDispute(agent, request, requestId);
+
+      await resolveDispute(agent, request, dispute, escrow, { payProvider: {} });
+
+      const account = await program.account.dispute.fetch(dispute);
+      expect(account.status).to.deep.equal({ resolved: {} });
+    });
+  });
+
+  describe("invoice_fee", () => {
+    const feeCollector = Keypair.generate();
+    const amount = new anchor.BN(0.01 * LAMPORTS_PER_SOL);
+
+    const setInvoiceFee = (invoiceFeeBps: number, collector = feeCollector.publicKey) =>
+      program.methods
+        .updateProtocolConfig({ invoiceFeeBps, feeCollector: collector } as any)
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+
+    const payInvoice = (recipient: ProviderAgent, invoice: PublicKey) =>
+      program.methods
+        .payInvoice(null)
+        .accounts({
+          invoice,
+          treasury: recipient.treasury,
+          payer: provider.wallet.publicKey,
+          recipient: recipient.owner.publicKey,
+          config: configPDA,
+          feeCollector: feeCollector.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc({ commitment: "confirmed" });
+
+    before(async () => {
+      await airdrop(feeCollector.publicKey);
+    });
+
+    after(() => setInvoiceFee(0, provider.wallet.publicKey));
+
+    it("should credit the recipient the net and the collector the fee", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const { invoice } = await createInvoice(recipient.owner, recipient.treasury, amount);
+      await setInvoiceFee(250);
+
+      const recipientBefore = await provider.connection.getBalance(recipient.owner.publicKey);
+      const collectorBefore = await provider.connection.getBalance(feeCollector.publicKey);
+      const sig = await payInvoice(recipient, invoice);
+
+      const fee = amount.toNumber() * 250 / 10_000;
+      const net = amount.toNumber() - fee;
+      const recipientAfter = await provider.connection.getBalance(recipient.owner.publicKey);
+      const collectorAfter = await provider.connection.getBalance(feeCollector.publicKey);
+      expect(recipientAfter - recipientBefore).to.equal(net);
+      expect(collectorAfter - collectorBefore).to.equal(fee);
+
+      const treasury = await program.account.treasury.fetch(recipient.treasury);
+      expect(treasury.totalReceived.toNumber()).to.equal(net);
+
+      const [paid] = await eventsOf(sig);
+      expect(paid.data.amount.toNumber()).to.equal(amount.toNumber());
+      expect(paid.data.fee.toNumber()).to.equal(fee);
+    });
+
+    it("should pass the full amount through when the fee is zero", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const { invoice } = await createInvoice(recipient.owner, recipient.treasury, amount);
+      await setInvoiceFee(0);
+
+      const recipientBefore = await provider.connection.getBalance(recipient.owner.publicKey);
+      const collectorBefore = await provider.connection.getBalance(feeCollector.publicKey);
+      await payInvoice(recipient, invoice);
+
+      const recipientAfter = await provider.connection.getBalance(recipient.owner.publicKey);
+      const collectorAfter = await provider.connection.getBalance(feeCollector.publicKey);
+      expect(recipientAfter - recipientBefore).to.equal(amount.toNumber());
+      expect(collectorAfter).to.equal(collectorBefore);
+
+      const treasury = await program.account.treasury.fetch(recipient.treasury);
+      expect(treasury.totalReceived.toNumber()).to.equal(amount.toNumber());
+    });
+  });
+
+  describe("complete_service_release", () => {
+    let agent: ProviderAgent;
+    const amount = new anchor.BN(0.01 * LAMPORTS_PER_SOL);
+
+    before(async () => {
+      agent = await registerProvider(["sentiment"]);
+    });
+
+    it("should release the escrowed payment to the provider's treasury", async () => {
+      const { request, escrow } = await requestService(agent, "sentiment", amount);
+      await completeService(agent, request);
+
+      const treasuryBefore = await provider.connection.getBalance(agent.treasury);
+      await finalizeCompletion(agent, request, escrow);
+      const treasuryAfter = await provider.connection.getBalance(agent.treasury);
+
+      expect(treasuryAfter - treasuryBefore).to.equal(amount.toNumber());
+      expect(await provider.connection.getBalance(escrow)).to.equal(0);
+    });
+
+    it("should leave state untouched when the escrow release fails", async () => {
+      const { request, escrow } = await requestService(agent, "sentiment", amount);
+      await completeService(agent, request);
+      const profileBefore = await program.account.agentProfile.fetch(agent.profile);
+      const treasuryBefore = await program.account.treasury.fetch(agent.treasury);
+
+      // A stray lamport leaves the escrow below rent exemption once the
+      // payment is released, so the system transfer is rejected
+      await provider.sendAndConfirm(
+        new anchor.web3.Transaction().add(
+          anchor.web3.SystemProgram.transfer({
+            fromPubkey: provider.wallet.publicKey,
+            toPubkey: escrow,
+            lamports: 1,
+          })
+        )
+      );
+
+      try {
+        await finalizeCompletion(agent, request, escrow);
+        expect.fail("expected the escrow release to fail");
+      } catch (err: any) {
+        expect(err.toString()).to.match(/insufficient funds for rent/i);
+      }
+
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.status).to.deep.equal({ pendingReview: {} });
+
+      const profileAfter = await program.account.agentProfile.fetch(agent.profile);
+      expect(profileAfter.totalRequests.toNumber()).to.equal(profileBefore.totalRequests.toNumber());
+      expect(profileAfter.totalEarnings.toNumber()).to.equal(profileBefore.totalEarnings.toNumber());
+
+      const treasuryAfter = await program.account.treasury.fetch(agent.treasury);
+      expect(treasuryAfter.totalReceived.toNumber()).to.equal(treasuryBefore.totalReceived.toNumber());
+    });
+  });
+
+  describe("capability_aliases", () => {
+    let agent: ProviderAgent;
+
+    before(async () => {
+      agent = await registerProvider(["image-gen"]);
+      await program.methods
+        .addCapabilityAlias("image-generation", "image-gen")
+        .accounts({ agentProfile: agent.profile, owner: agent.owner.publicKey })
+        .signers([agent.owner])
+        .rpc();
+    });
+
+    it("should accept a request for an aliased capability", async () => {
+      const { request } = await requestService(agent, "image-generation");
+
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.capability).to.equal("image-gen");
+    });
+
+    it("should still reject an unknown capability", async () => {
+      try {
+        await requestService(agent, "image-editing");
+        expect.fail("expected CapabilityNotSupported");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("CapabilityNotSupported");
+      }
+    });
+
+    it("should reject an alias for an unlisted capability", async () => {
+      try {
+        await program.methods
+          .addCapabilityAlias("tts", "text-to-speech")
+          .accounts({ agentProfile: agent.profile, owner: agent.owner.publicKey })
+          .signers([agent.owner])
+          .rpc();
+        expect.fail("expected CapabilityNotSupported");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("CapabilityNotSupported");
+      }
+    });
+  });
+
+  describe("shared_vault", () => {
+    const amount = new anchor.BN(0.01 * LAMPORTS_PER_SOL);
+
+    const initVault = async (agent: ProviderAgent) => {
+      const [vault] = pda(Buffer.from("vault"), agent.owner.publicKey.toBuffer());
+      await program.methods
+        .initializeVault()
+        .accounts({
+          vault,
+          provider: agent.owner.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([agent.owner])
+        .rpc();
+      return vault;
+    };
+
+    const deposit = (vault: PublicKey, requestId: Buffer, lamports = amount) =>
+      program.methods
+        .vaultDeposit(Array.from(requestId) as number[], lamports)
+        .accounts({
+          vault,
+          request: pda(Buffer.from("request"), requestId)[0],
+          depositor: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+    const refund = (vault: PublicKey, requestId: Buffer) =>
+      program.methods
+        .vaultRefund(Array.from(requestId) as number[])
+        .accounts({
+          vault,
+          request: pda(Buffer.from("request"), requestId)[0],
+          depositor: provider.wallet.publicKey,
+        })
+        .rpc();
+
+    const expectCodeOf = async (action: Promise<unknown>, code: string) => {
+      try {
+        await action;
+        expect.fail(`expected ${code}`);
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal(code);
+      }
+    };
+
+    it("should release one request without affecting the others", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const vault = await initVault(agent);
+
+      const requestIds: Buffer[] = [];
+      for (let i = 0; i < 3; i++) {
+        const { requestId } = await requestService(agent, "sentiment");
+        await deposit(vault, requestId);
+        requestIds.push(requestId);
+      }
+
+      const providerBefore = await provider.connection.getBalance(agent.owner.publicKey);
+      await program.methods
+        .vaultRelease(Array.from(requestIds[1]) as number[])
+        .accounts({
+          vault,
+          depositor: provider.wallet.publicKey,
+          provider: agent.owner.publicKey,
+        })
+        .rpc();
+      const providerAfter = await provider.connection.getBalance(agent.owner.publicKey);
+
+      expect(providerAfter - providerBefore).to.equal(amount.toNumber());
+
+      const account = await program.account.vault.fetch(vault);
+      expect(account.totalHeld.toNumber()).to.equal(amount.toNumber() * 2);
+      const remaining = account.entries.map((e: any) => Buffer.from(e.requestId).toString("hex"));
+      expect(remaining).to.have.members([
+        requestIds[0].toString("hex"),
+        requestIds[2].toString("hex"),
+      ]);
+    });
+
+    it("should only accept deposits for the provider's own requests above the minimum", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const other = await registerProvider(["sentiment"]);
+      const vault = await initVault(agent);
+
+      // Made-up ids with no request behind them cannot fill the ledger
+      await expectCodeOf(deposit(vault, Keypair.generate().publicKey.toBuffer()), "AccountNotInitialized");
+
+      const { requestId: otherRequestId } = await requestService(other, "sentiment");
+      await expectCodeOf(deposit(vault, otherRequestId), "Unauthorized");
+
+      const { requestId } = await requestService(agent, "sentiment");
+      await expectCodeOf(deposit(vault, requestId, new anchor.BN(1)), "VaultDepositTooSmall");
+
+      const account = await program.account.vault.fetch(vault);
+      expect(account.entries).to.have.length(0);
+    });
+
+    it("should refund the depositor once the request is cancelled", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const vault = await initVault(agent);
+      const { requestId, request, escrow } = await requestService(agent, "sentiment");
+      await deposit(vault, requestId);
+
+      await expectCodeOf(refund(vault, requestId), "VaultEntryNotRefundable");
+
+      await program.methods
+        .cancelServiceRequest()
+        .accounts({
+          request,
+          escrow,
+          providerProfile: agent.profile,
+          requester: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+      const vaultBefore = await provider.connection.getBalance(vault);
+      await refund(vault, requestId);
+      const vaultAfter = await provider.connection.getBalance(vault);
+
+      expect(vaultBefore - vaultAfter).to.equal(amount.toNumber());
+      const account = await program.account.vault.fetch(vault);
+      expect(account.totalHeld.toNumber()).to.equal(0);
+      expect(account.entries).to.have.length(0);
+    });
+  });
+
+  describe("timestamp_bounds", () => {
+    const MAX_FUTURE_SECONDS = 10 * 365 * 86400;
+
+    const createInvoiceExpiring = async (agent: ProviderAgent, expiresAt: number) => {
+      const invoiceId = Keypair.generate().publicKey.toBuffer();
+      const [invoice] = pda(Buffer.from("invoice"), invoiceId);
+
+      await program.methods
+        .createInvoice(
+          Array.from(invoiceId) as number[],
+          new anchor.BN(1000),
+          "Far future",
+          new anchor.BN(expiresAt),
+          null,
+          null
+        )
+        .accounts({
+          invoice,
+          treasury: agent.treasury,
+          recipient: agent.owner.publicKey,
+          config: configPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([agent.owner])
+        .rpc();
+
+      return invoice;
+    };
+
+    it("should accept an expiry just inside the bound", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const expiresAt = Math.floor(Date.now() / 1000) + MAX_FUTURE_SECONDS - 3600;
+
+      const invoice = await createInvoiceExpiring(agent, expiresAt);
+
+      const account = await program.account.invoice.fetch(invoice);
+      expect(account.expiresAt.toNumber()).to.equal(expiresAt);
+    });
+
+    it("should reject an expiry just beyond the bound", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const expiresAt = Math.floor(Date.now() / 1000) + MAX_FUTURE_SECONDS + 3600;
+
+      try {
+        await createInvoiceExpiring(agent, expiresAt);
+        expect.fail("expected ExpiryTooFar");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("ExpiryTooFar");
+      }
+    });
+
+    it("should reject an SLA deadline beyond the bound", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { request, escrow } = await requestService(agent, "sentiment");
+
+      try {
+        await acceptRequest(agent, request, escrow, {
+          deadlineSeconds: MAX_FUTURE_SECONDS + 1,
+          bond: new anchor.BN(1000),
+        });
+        expect.fail("expected ExpiryTooFar");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("ExpiryTooFar");
+      }
+    });
+  });
+
+  describe("channel_close_fee", () => {
+    const feeCollector = Keypair.generate();
+    const deposit = new anchor.BN(0.1 * LAMPORTS_PER_SOL);
+
+    const setCloseFee = (channelCloseFeeBps: number, collector = feeCollector.publicKey) =>
+      program.methods
+        .updateProtocolConfig({ channelCloseFeeBps, feeCollector: collector } as any)
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+
+    const closeChannel = (
+      opened: Awaited<ReturnType<typeof openChannel>>,
+      partyB: Keypair,
+      balanceA: number,
+      balanceB: number
+    ) =>
+      program.methods
+        .closeChannel(new anchor.BN(balanceA), new anchor.BN(balanceB), new anchor.BN(1))
+        .accounts({
+          channel: opened.channel,
+          channelEscrow: opened.channelEscrow,
+          partyA: provider.wallet.publicKey,
+          partyB: partyB.publicKey,
+          config: configPDA,
+          feeCollector: feeCollector.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([partyB])
+        .rpc({ commitment: "confirmed" });
+
+    before(async () => {
+      await airdrop(feeCollector.publicKey);
+    });
+
+    after(() => setCloseFee(0, provider.wallet.publicKey));
+
+    it("should conserve balances without a fee", async () => {
+      await setCloseFee(0);
+      const partyB = Keypair.generate();
+      await airdrop(partyB.publicKey);
+      const opened = await openChannel(partyB, deposit);
+
+      const partyBBefore = await provider.connection.getBalance(partyB.publicKey);
+      const sig = await closeChannel(opened, partyB, deposit.toNumber() - 1000, 1000);
+
+      const closed = (await eventsOf(sig)).find((e) => e.name === "ChannelClosed");
+      expect(closed.data.fee.toNumber()).to.equal(0);
+      const partyBAfter = await provider.connection.getBalance(partyB.publicKey, "confirmed");
+      expect(partyBAfter - partyBBefore).to.equal(1000);
+      expect(await provider.connection.getBalance(opened.channelEscrow, "confirmed")).to.equal(0);
+    });
+
+    it("should conserve balances net of the close fee and pay the collector", async () => {
+      await setCloseFee(100);
+      const partyB = Keypair.generate();
+      await airdrop(partyB.publicKey);
+      const opened = await openChannel(partyB, deposit);
+      const fee = deposit.toNumber() * 100 / 10_000;
+
+      const collectorBefore = await provider.connection.getBalance(feeCollector.publicKey);
+      const sig = await closeChannel(opened, partyB, deposit.toNumber() - fee - 1000, 1000);
+      const collectorAfter = await provider.connection.getBalance(feeCollector.publicKey);
+
+      expect(collectorAfter - collectorBefore).to.equal(fee);
+      const closed = (await eventsOf(sig)).find((e) => e.name === "ChannelClosed");
+      expect(closed.data.fee.toNumber()).to.equal(fee);
+    });
+
+    it("should reject balances that ignore the close fee", async () => {
+      await setCloseFee(100);
+      const partyB = Keypair.generate();
+      const opened = await openChannel(partyB, deposit);
+
+      try {
+        await closeChannel(opened, partyB, deposit.toNumber() - 1000, 1000);
+        expect.fail("expected BalanceMismatch");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("BalanceMismatch");
+      }
+    });
+
+    it("should reject a close party B did not sign", async () => {
+      await setCloseFee(0);
+      const partyB = Keypair.generate();
+      const opened = await openChannel(partyB, deposit);
+
+      try {
+        await program.methods
+          .closeChannel(deposit, new anchor.BN(0), new anchor.BN(1))
+          .accounts({
+            channel: opened.channel,
+            channelEscrow: opened.channelEscrow,
+            partyA: provider.wallet.publicKey,
+            partyB: partyB.publicKey,
+            config: configPDA,
+            feeCollector: feeCollector.publicKey,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .rpc();
+        expect.fail("expected a missing signature error");
+      } catch (err: any) {
+        expect(err.message).to.include("Signature verification failed");
+      }
+
+      const account = await program.account.paymentChannel.fetch(opened.channel);
+      expect(account.status).to.deep.equal({ open: {} });
+    });
+  });
+
+  describe("restricted_payer", () => {
+    const payInvoice = (recipient: ProviderAgent, invoice: PublicKey, payer: Keypair) =>
+      program.methods
+        .payInvoice(null)
+        .accounts({
+          invoice,
+          treasury: recipient.treasury,
+          payer: payer.publicKey,
+          recipient: recipient.owner.publicKey,
+          config: configPDA,
+          feeCollector: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([payer])
+        .rpc();
+
+    it("should let the allowed payer pay a restricted invoice", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const payer = Keypair.generate();
+      await airdrop(payer.publicKey);
+      const { invoice } = await createInvoice(
+        recipient.owner,
+        recipient.treasury,
+        undefined,
+        payer.publicKey
+      );
+
+      await payInvoice(recipient, invoice, payer);
+
+      const account = await program.account.invoice.fetch(invoice);
+      expect(account.status).to.deep.equal({ paid: {} });
+    });
+
+    it("should reject any other payer", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const stranger = Keypair.generate();
+      await airdrop(stranger.publicKey);
+      const { invoice } = await createInvoice(
+        recipient.owner,
+        recipient.treasury,
+        undefined,
+        Keypair.generate().publicKey
+      );
+
+      try {
+        await payInvoice(recipient, invoice, stranger);
+        expect.fail("expected PayerNotAllowed");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("PayerNotAllowed");
+      }
+    });
+  });
+
+  describe("completion_review_period", () => {
+    let agent: ProviderAgent;
+    const keeper = Keypair.generate();
+
+    before(async () => {
+      agent = await registerProvider(["sentiment"]);
+      await airdrop(keeper.publicKey);
+    });
+
+    it("should hold payment until the review period ends", async () => {
+      const { request, escrow } = await requestService(agent, "sentiment", undefined, {
+        reviewPeriodSeconds: new anchor.BN(3600),
+      });
+      await completeService(agent, request);
+
+      try {
+        await finalizeCompletion(agent, request, escrow, keeper);
+        expect.fail("expected ReviewPeriodActive");
+      } catch (err: any) {
+        expect(anchor.AnchorError.parse(err.logs).error.errorCode.code).to.equal(
+          "ReviewPeriodActive"
+        );
+      }
+    });
+
+    it("should release immediately on the requester's early approval", async () => {
+      const { request, escrow } = await requestService(agent, "sentiment", undefined, {
+        reviewPeriodSeconds: new anchor.BN(3600),
+      });
+      await completeService(agent, request);
+
+      const sig = await finalizeCompletion(agent, request, escrow);
+
+      const finalized = (await eventsOf(sig)).find((e) => e.name === "ServiceFinalized");
+      expect(finalized.data.approvedEarly).to.equal(true);
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.status).to.deep.equal({ completed: {} });
+    });
+
+    it("should let anyone finalize after the review period", async () => {
+      const { request, escrow } = await requestService(agent, "sentiment", undefined, {
+        reviewPeriodSeconds: new anchor.BN(1),
+      });
+      await completeService(agent, request);
+      await sleep(3000);
+
+      const sig = await finalizeCompletion(agent, request, escrow, keeper);
+
+      const finalized = (await eventsOf(sig)).find((e) => e.name === "ServiceFinalized");
+      expect(finalized.data.approvedEarly).to.equal(false);
+    });
+
+    it("should allow the requester to dispute during review", async () => {
+      const { requestId, request, escrow } = await requestService(agent, "sentiment", undefined, {
+        reviewPeriodSeconds: new anchor.BN(3600),
+      });
+      await completeService(agent, request);
+
+      await initiateDispute(agent, request, requestId);
+
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.status).to.deep.equal({ disputed: {} });
+      try {
+        await finalizeCompletion(agent, request, escrow);
+        expect.fail("expected RequestNotInReview");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("RequestNotInReview");
+      }
+    });
+  });
+
+  describe("escrow_funding_check", () => {
+    it("should record a request whose escrow received exactly the amount", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const amount = new anchor.BN(0.02 * LAMPORTS_PER_SOL);
+
+      const { request, escrow } = await requestService(agent, "sentiment", amount);
+
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.amount.toNumber()).to.equal(amount.toNumber());
+      expect(await provider.connection.getBalance(escrow)).to.equal(amount.toNumber());
+    });
+  });
+
+  describe("agent_tags", () => {
+    // Tags are fixed 16-byte, zero-padded labels
+    const tag = (label: string) => {
+      const bytes = Buffer.alloc(16);
+      bytes.write(label);
+      return Array.from(bytes);
+    };
+
+    const updateTags = (agent: ProviderAgent, tags: number[][]) =>
+      program.methods
+        .updateAgentProfile(null, null, null, null, null, tags)
+        .accounts({ agentProfile: agent.profile, owner: agent.owner.publicKey })
+        .signers([agent.owner])
+        .rpc({ commitment: "confirmed" });
+
+    it("should set tags at registration", async () => {
+      const owner = Keypair.generate();
+      await airdrop(owner.publicKey);
+      const [treasury] = pda(Buffer.from("treasury"), owner.publicKey.toBuffer());
+      const [profile] = pda(Buffer.from("agent"), owner.publicKey.toBuffer());
+
+      await program.methods
+        .registerAgentWithTreasury("Tagged", "Tagged agent", ["sentiment"], new anchor.BN(1000), [
+          tag("data"),
+          tag("compute"),
+        ])
+        .accounts({
+          agentProfile: profile,
+          treasury,
+          owner: owner.publicKey,
+          bannedAgents: bannedAgentsPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([owner])
+        .rpc();
+
+      const account = await program.account.agentProfile.fetch(profile);
+      expect(account.tags).to.deep.equal([tag("data"), tag("compute")]);
+    });
+
+    it("should update tags and emit AgentTagsUpdated", async () => {
+      const agent = await registerProvider(["sentiment"]);
+
+      const sig = await updateTags(agent, [tag("creative")]);
+
+      const account = await program.account.agentProfile.fetch(agent.profile);
+      expect(account.tags).to.deep.equal([tag("creative")]);
+      const updated = (await eventsOf(sig)).find((e) => e.name === "AgentTagsUpdated");
+      expect(updated.data.tags).to.deep.equal([tag("creative")]);
+    });
+
+    it("should reject more tags than the cap", async () => {
+      const agent = await registerProvider(["sentiment"]);
+
+      try {
+        await updateTags(agent, ["a", "b", "c", "d", "e", "f"].map(tag));
+        expect.fail("expected TooManyTags");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("TooManyTags");
+      }
+    });
+  });
+
+  describe("extend_invoice", () => {
+    const extendInvoice = (agent: ProviderAgent, invoice: PublicKey, newExpiresAt: anchor.BN) =>
+      program.methods
+        .extendInvoice(newExpiresAt)
+        .accounts({ invoice, recipient: agent.owner.publicKey })
+        .signers([agent.owner])
+        .rpc({ commitment: "confirmed" });
+
+    it("should extend a pending invoice's expiry", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { invoice } = await createInvoice(agent.owner, agent.treasury);
+      const before = await program.account.invoice.fetch(invoice);
+      const newExpiresAt = before.expiresAt.addn(86400);
+
+      const sig = await extendInvoice(agent, invoice, newExpiresAt);
+
+      const after = await program.account.invoice.fetch(invoice);
+      expect(after.expiresAt.toNumber()).to.equal(newExpiresAt.toNumber());
+      const [extended] = await eventsOf(sig);
+      expect(extended.data.oldExpiresAt.toNumber()).to.equal(before.expiresAt.toNumber());
+      expect(extended.data.newExpiresAt.toNumber()).to.equal(newExpiresAt.toNumber());
+    });
+
+    it("should reject moving the expiry earlier", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { invoice } = await createInvoice(agent.owner, agent.treasury);
+      const before = await program.account.invoice.fetch(invoice);
+
+      try {
+        await extendInvoice(agent, invoice, before.expiresAt.subn(60));
+        expect.fail("expected InvalidExpiry");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InvalidExpiry");
+      }
+    });
+  });
+
+  describe("authorized_settlers", () => {
+    let agent: ProviderAgent;
+    const trusted = Keypair.generate();
+
+    const settleBatch = async (settler: Keypair) => {
+      const batchId = Keypair.generate().publicKey.toBuffer();
+      const [batch] = pda(Buffer.from("batch"), batchId);
+      const { invoiceId, invoice } = await createInvoice(agent.owner, agent.treasury, new anchor.BN(1000));
+      return program.methods
+        .settleBatch(
+          Array.from(batchId) as number[],
+          [Array.from(invoiceId) as number[]],
+          new anchor.BN(1000)
+        )
+        .accounts({
+          batch,
+          treasury: agent.treasury,
+          settler: settler.publicKey,
+          recipient: agent.owner.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .remainingAccounts(invoiceAccounts([invoice]))
+        .signers([settler])
+        .rpc();
+    };
+
+    before(async () => {
+      agent = await registerProvider(["sentiment"]);
+      await airdrop(trusted.publicKey);
+      await program.methods
+        .setAuthorizedSettlers([trusted.publicKey])
+        .accounts({ treasury: agent.treasury, owner: agent.owner.publicKey })
+        .signers([agent.owner])
+        .rpc();
+    });
+
+    it("should let an authorized settler settle", async () => {
+      await settleBatch(trusted);
+
+      const treasury = await program.account.treasury.fetch(agent.treasury);
+      expect(treasury.totalSettled.toNumber()).to.equal(1000);
+    });
+
+    it("should reject a settler not on the list", async () => {
+      const stranger = Keypair.generate();
+      await airdrop(stranger.publicKey);
+
+      try {
+        await settleBatch(stranger);
+        expect.fail("expected UnauthorizedSettler");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("UnauthorizedSettler");
+      }
+    });
+  });
+
+  describe("pay_and_close_invoice", () => {
+    const payAndClose = (recipient: ProviderAgent, invoice: PublicKey) =>
+      program.methods
+        .payAndCloseInvoice()
+        .accounts({
+          invoice,
+          treasury: recipient.treasury,
+          payer: provider.wallet.publicKey,
+          recipient: recipient.owner.publicKey,
+          config: configPDA,
+          feeCollector: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc({ commitment: "confirmed" });
+
+    it("should pay the invoice and return its rent in one call", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const amount = new anchor.BN(0.001 * LAMPORTS_PER_SOL);
+      const { invoice } = await createInvoice(recipient.owner, recipient.treasury, amount);
+      const rent = await provider.connection.getBalance(invoice);
+
+      const recipientBefore = await provider.connection.getBalance(recipient.owner.publicKey);
+      const sig = await payAndClose(recipient, invoice);
+      const recipientAfter = await provider.connection.getBalance(recipient.owner.publicKey);
+
+      expect(recipientAfter - recipientBefore).to.equal(amount.toNumber() + rent);
+      expect(await provider.connection.getAccountInfo(invoice)).to.equal(null);
+
+      const names = (await eventsOf(sig)).map((e) => e.name);
+      expect(names).to.deep.equal(["TreasuryLedgerEntry", "InvoicePaid", "InvoiceClosed"]);
+      const treasury = await program.account.treasury.fetch(recipient.treasury);
+      expect(treasury.totalReceived.toNumber()).to.equal(amount.toNumber());
+    });
+
+    it("should keep an invoice other invoices depend on", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const first = await createInvoice(recipient.owner, recipient.treasury);
+      await createInvoice(recipient.owner, recipient.treasury, undefined, null, first.invoiceId);
+      expect((await program.account.invoice.fetch(first.invoice)).dependents).to.equal(1);
+
+      try {
+        await payAndClose(recipient, first.invoice);
+        expect.fail("expected InvoiceHasDependents");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InvoiceHasDependents");
+      }
+
+      const invoice = await program.account.invoice.fetch(first.invoice);
+      expect(invoice.status).to.deep.equal({ pending: {} });
+    });
+  });
+
+  describe("response_time_tracking", () => {
+    it("should average the delay between request and acceptance", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const first = await requestService(agent, "sentiment");
+      const second = await requestService(agent, "sentiment");
+
+      await sleep(1000);
+      await acceptRequest(agent, first.request, first.escrow);
+      await sleep(2000);
+      const sig = await acceptRequest(agent, second.request, second.escrow);
+
+      const delays = await Promise.all(
+        [first.request, second.request].map(async (key) => {
+          const req = await program.account.serviceRequest.fetch(key);
+          return (req.acceptedAt as anchor.BN).toNumber() - req.createdAt.toNumber();
+        })
+      );
+      const total = delays[0] + delays[1];
+
+      const profile = await program.account.agentProfile.fetch(agent.profile);
+      expect(profile.responseSamples.toNumber()).to.equal(2);
+      expect(profile.totalResponseTime.toNumber()).to.equal(total);
+
+      const accepted = (await eventsOf(sig)).find((e) => e.name === "ServiceAccepted");
+      expect(accepted.data.averageResponseSeconds.toNumber()).to.equal(Math.floor(total / 2));
+    });
+  });
+
+  describe("recover_stuck_escrow", () => {
+    const recover = (agent: ProviderAgent, requestId: Buffer, request: PublicKey, escrow: PublicKey) =>
+      program.methods
+        .recoverStuckEscrow()
+        .accounts({
+          config: configPDA,
+          request,
+          dispute: pda(Buffer.from("dispute"), requestId)[0],
+          providerProfile: agent.profile,
+          escrow,
+          requester: provider.wallet.publicKey,
+          provider: agent.owner.publicKey,
+          admin: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc({ commitment: "confirmed" });
+
+    it("should reject recovery before the safety period", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { requestId, request, escrow } = await requestService(agent, "sentiment");
+      await acceptRequest(agent, request, escrow);
+      await initiateDispute(agent, request, requestId);
+
+      try {
+        await recover(agent, requestId, request, escrow);
+        expect.fail("expected EscrowNotStuck");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("EscrowNotStuck");
+      }
+      const req = await program.account.serviceRequest.fetch(request);
+      expect(req.status).to.deep.equal({ disputed: {} });
+    });
+
+    it("should leave requests with another settlement path alone", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      // Undisputed open work is reclaimable by the requester
+      const open = await requestService(agent, "sentiment");
+      // Delivered work is released to the provider by `finalize_completion`
+      const delivered = await requestService(agent, "sentiment");
+      await acceptRequest(agent, delivered.request, delivered.escrow);
+      await completeService(agent, delivered.request);
+
+      for (const { requestId, request, escrow } of [open, delivered]) {
+        try {
+          await recover(agent, requestId, request, escrow);
+          expect.fail("expected EscrowNotStuck");
+        } catch (err: any) {
+          expect(err.error.errorCode.code).to.equal("EscrowNotStuck");
+        }
+      }
+      const req = await program.account.serviceRequest.fetch(delivered.request);
+      expect(req.status).to.deep.equal({ pendingReview: {} });
+    });
+
+    // The local validator's clock cannot be moved, so the success path runs in
+    // a bankrun bank seeded with the live config and warped past the floor
+    it("should return the escrow once the safety period has passed", async () => {
+      const admin = (provider.wallet as anchor.Wallet).payer;
+      const owner = Keypair.generate();
+      const funded = {
+        lamports: 10 * LAMPORTS_PER_SOL,
+        data: Buffer.alloc(0),
+        owner: anchor.web3.SystemProgram.programId,
+        executable: false,
+      };
+      const context = await startAnchor(".", [], [
+        { address: configPDA, info: await provider.connection.getAccountInfo(configPDA) },
+        { address: admin.publicKey, info: funded },
+        { address: owner.publicKey, info: funded },
+      ]);
+      const bank = new Program<Agentfund>(
+        program.idl,
+        program.programId,
+        new BankrunProvider(context, new anchor.Wallet(admin))
+      );
+      const systemProgram = anchor.web3.SystemProgram.programId;
+
+      const [treasury, treasuryBump] = pda(Buffer.from("treasury"), owner.publicKey.toBuffer());
+      const [profile, profileBump] = pda(Buffer.from("agent"), owner.publicKey.toBuffer());
+      await bank.methods
+        .initializeTreasury(treasuryBump)
+        .accounts({ treasury, owner: owner.publicKey, systemProgram })
+        .signers([owner])
+        .rpc();
+      await bank.methods
+        .registerAgent("Stuck Agent", "Never delivers", ["sentiment"], new anchor.BN(1000), profileBump, [])
+        .accounts({ agentProfile: profile, treasury, owner: owner.publicKey, bannedAgents: bannedAgentsPDA, systemProgram })
+        .signers([owner])
+        .rpc();
+
+      const requestId = Keypair.generate().publicKey.toBuffer();
+      const [request] = pda(Buffer.from("request"), requestId);
+      const [escrow] = pda(Buffer.from("request_escrow"), requestId);
+      const [dispute] = pda(Buffer.from("dispute"), requestId);
+      await bank.methods
+        .requestService(Array.from(requestId) as number[], "sentiment", new anchor.BN(0.01 * LAMPORTS_PER_SOL), null, null, null)
+        .accounts({
+          request,
+          providerProfile: profile,
+          providerOwner: owner.publicKey,
+          providerTreasury: treasury,
+          escrow,
+          requester: admin.publicKey,
+          oracle: null,
+          bannedAgents: bannedAgentsPDA,
+          systemProgram,
+        })
+        .rpc();
+      // A withdrawn dispute leaves the request pending but no longer reclaimable
+      await bank.methods
+        .initiateDispute("Never delivered")
+        .accounts({ request, dispute, providerProfile: profile, initiator: admin.publicKey, config: configPDA, systemProgram })
+        .rpc();
+      await bank.methods
+        .cancelDispute()
+        .accounts({ request, dispute, providerProfile: profile, initiator: admin.publicKey })
+        .rpc();
+      expect((await bank.account.agentProfile.fetch(profile)).openRequests).to.equal(1);
+
+      const clock = await context.banksClient.getClock();
+      context.setClock(
+        new Clock(
+          clock.slot,
+          clock.epochStartTimestamp,
+          clock.epoch,
+          clock.leaderScheduleEpoch,
+          clock.unixTimestamp + BigInt(181 * 24 * 60 * 60)
+        )
+      );
+      const held = await context.banksClient.getBalance(escrow);
+
+      await bank.methods
+        .recoverStuckEscrow()
+        .accounts({
+          config: configPDA,
+          request,
+          dispute,
+          providerProfile: profile,
+          escrow,
+          requester: admin.publicKey,
+          provider: owner.publicKey,
+          admin: admin.publicKey,
+          systemProgram,
+        })
+        .rpc();
+
+      expect(held > BigInt(0)).to.be.true;
+      expect(await context.banksClient.getBalance(escrow)).to.equal(BigInt(0));
+      const recovered = await bank.account.serviceRequest.fetch(request);
+      expect(recovered.status).to.deep.equal({ recovered: {} });
+      expect((await bank.account.agentProfile.fetch(profile)).openRequests).to.equal(0);
+    });
+
+    it("should reject a recovery period below the 180-day floor", async () => {
+      try {
+        await program.methods
+          .updateProtocolConfig({ escrowRecoverySeconds: new anchor.BN(179 * 24 * 60 * 60) } as any)
+          .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+          .rpc();
+        expect.fail("expected RecoveryPeriodTooShort");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("RecoveryPeriodTooShort");
+      }
+      const config = await program.account.protocolConfig.fetch(configPDA);
+      expect(config.escrowRecoverySeconds.toNumber()).to.equal(180 * 24 * 60 * 60);
+    });
+
+    it("should never touch a settled request", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { requestId, request, escrow } = await requestService(agent, "sentiment");
+      await program.methods
+        .cancelServiceRequest()
+        .accounts({
+          request,
+          escrow,
+          providerProfile: agent.profile,
+          requester: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+      try {
+        await recover(agent, requestId, request, escrow);
+        expect.fail("expected EscrowNotStuck");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("EscrowNotStuck");
+      }
+    });
+  });
+
+  describe("capability_request_counts", () => {
+    const serve = async (agent: ProviderAgent, capability: string) => {
+      const { request, escrow } = await requestService(agent, capability);
+      await acceptRequest(agent, request, escrow);
+      return completeService(agent, request);
+    };
+
+    it("should count completions per capability", async () => {
+      const agent = await registerProvider(["sentiment", "translation"]);
+
+      await serve(agent, "sentiment");
+      await serve(agent, "translation");
+      const sig = await serve(agent, "translation");
+
+      const profile = await program.account.agentProfile.fetch(agent.profile);
+      expect(profile.capabilityRequestCounts.map((c: anchor.BN) => c.toNumber())).to.deep.equal([1, 2]);
+
+      const completed = (await eventsOf(sig)).find((e) => e.name === "ServiceCompleted");
+      expect(completed.data.capability).to.equal("translation");
+      expect(completed.data.capabilityRequests.toNumber()).to.equal(2);
+    });
+
+    it("should keep counts aligned when capabilities change", async () => {
+      const agent = await registerProvider(["sentiment", "translation"]);
+      await serve(agent, "translation");
+
+      await program.methods
+        .updateAgentProfile(null, null, ["summarize", "translation"], null, null, null)
+        .accounts({ agentProfile: agent.profile, owner: agent.owner.publicKey })
+        .signers([agent.owner])
+        .rpc();
+
+      const profile = await program.account.agentProfile.fetch(agent.profile);
+      expect(profile.capabilityRequestCounts.map((c: anchor.BN) => c.toNumber())).to.deep.equal([0, 1]);
+    });
+  });
+
+  describe("initiate_disputes_batch", () => {
+    const disputeBatch = (agent: ProviderAgent, requests: { requestId: Buffer; request: PublicKey }[]) =>
+      program.methods
+        .initiateDisputesBatch("Repeated bad results")
+        .accounts({
+          providerProfile: agent.profile,
+          initiator: provider.wallet.publicKey,
+          config: configPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .remainingAccounts(
+          requests.flatMap(({ requestId, request }) => [
+            { pubkey: request, isSigner: false, isWritable: true },
+            { pubkey: pda(Buffer.from("dispute"), requestId)[0], isSigner: false, isWritable: true },
+          ])
+        )
+        .rpc({ commitment: "confirmed" });
+
+    it("should open a dispute for every request in the batch", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const requests = [];
+      for (let i = 0; i < 3; i++) {
+        requests.push(await requestService(agent, "sentiment"));
+      }
+
+      const sig = await disputeBatch(agent, requests);
+
+      for (const { requestId, request } of requests) {
+        const req = await program.account.serviceRequest.fetch(request);
+        expect(req.status).to.deep.equal({ disputed: {} });
+        const dispute = await program.account.dispute.fetch(pda(Buffer.from("dispute"), requestId)[0]);
+        expect(dispute.initiator.toString()).to.equal(provider.wallet.publicKey.toString());
+        expect(dispute.status).to.deep.equal({ open: {} });
+      }
+      const initiated = (await eventsOf(sig)).filter((e) => e.name === "DisputeInitiated");
+      expect(initiated.length).to.equal(3);
+      const profile = await program.account.agentProfile.fetch(agent.profile);
+      expect(profile.openRequests).to.equal(0);
+    });
+
+    it("should fail atomically when one request is ineligible", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const first = await requestService(agent, "sentiment");
+      const second = await requestService(agent, "sentiment");
+      const third = await requestService(agent, "sentiment");
+
+      // Paid out requests are past the point of dispute
+      await acceptRequest(agent, second.request, second.escrow);
+      await completeService(agent, second.request);
+      await finalizeCompletion(agent, second.request, second.escrow);
+
+      try {
+        await disputeBatch(agent, [first, second, third]);
+        expect.fail("expected CannotDispute");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("CannotDispute");
+      }
+
+      const req = await program.account.serviceRequest.fetch(first.request);
+      expect(req.status).to.deep.equal({ pending: {} });
+      expect(
+        await provider.connection.getAccountInfo(pda(Buffer.from("dispute"), first.requestId)[0])
+      ).to.equal(null);
+    });
+
+    it("should reject requests with a different provider", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const other = await registerProvider(["sentiment"]);
+      const ours = await requestService(agent, "sentiment");
+      const theirs = await requestService(other, "sentiment");
+
+      try {
+        await disputeBatch(agent, [ours, theirs]);
+        expect.fail("expected DisputeBatchMismatch");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("DisputeBatchMismatch");
+      }
+    });
+  });
+
+  describe("invoice_preauthorization", () => {
+    const hold = new anchor.BN(0.01 * LAMPORTS_PER_SOL);
+
+    const preauthorize = async (agent: ProviderAgent) => {
+      const payer = Keypair.generate();
+      await airdrop(payer.publicKey);
+      const { invoiceId, invoice } = await createInvoice(agent.owner, agent.treasury, hold);
+      const [escrow] = pda(Buffer.from("invoice_escrow"), invoiceId);
+
+      await program.methods
+        .preauthorizeInvoice(hold)
+        .accounts({
+          invoice,
+          escrow,
+          payer: payer.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([payer])
+        .rpc();
+
+      return { payer, invoice, escrow };
+    };
+
+    const capture = (
+      agent: ProviderAgent,
+      held: Awaited<ReturnType<typeof preauthorize>>,
+      amount: anchor.BN
+    ) =>
+      program.methods
+        .capturePreauthorized(amount)
+        .accounts({
+          invoice: held.invoice,
+          escrow: held.escrow,
+          treasury: agent.treasury,
+          recipient: agent.owner.publicKey,
+          payer: held.payer.publicKey,
+          config: configPDA,
+          feeCollector: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([agent.owner])
+        .rpc({ commitment: "confirmed" });
+
+    const balance = (key: PublicKey) => provider.connection.getBalance(key);
+
+    it("should hold funds without paying the invoice", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const held = await preauthorize(agent);
+
+      expect(await balance(held.escrow)).to.equal(hold.toNumber());
+      const invoice = await program.account.invoice.fetch(held.invoice);
+      expect(invoice.status).to.deep.equal({ authorized: {} });
+      expect(invoice.heldAmount.toNumber()).to.equal(hold.toNumber());
+    });
+
+    it("should capture less than the hold and refund the rest", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const held = await preauthorize(agent);
+      const captured = hold.divn(4).muln(3);
+
+      const recipientBefore = await balance(agent.owner.publicKey);
+      const payerBefore = await balance(held.payer.publicKey);
+      const sig = await capture(agent, held, captured);
+
+      expect((await balance(agent.owner.publicKey)) - recipientBefore).to.equal(captured.toNumber());
+      expect((await balance(held.payer.publicKey)) - payerBefore).to.equal(
+        hold.sub(captured).toNumber()
+      );
+      expect(await balance(held.escrow)).to.equal(0);
+
+      const invoice = await program.account.invoice.fetch(held.invoice);
+      expect(invoice.status).to.deep.equal({ paid: {} });
+      const treasury = await program.account.treasury.fetch(agent.treasury);
+      expect(treasury.totalReceived.toNumber()).to.equal(captured.toNumber());
+
+      const event = (await eventsOf(sig)).find((e) => e.name === "PreauthorizationCaptured");
+      expect(event.data.refunded.toNumber()).to.equal(hold.sub(captured).toNumber());
+    });
+
+    it("should capture the full hold", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const held = await preauthorize(agent);
+
+      const recipientBefore = await balance(agent.owner.publicKey);
+      const sig = await capture(agent, held, hold);
+
+      expect((await balance(agent.owner.publicKey)) - recipientBefore).to.equal(hold.toNumber());
+      const event = (await eventsOf(sig)).find((e) => e.name === "PreauthorizationCaptured");
+      expect(event.data.captured.toNumber()).to.equal(hold.toNumber());
+      expect(event.data.refunded.toNumber()).to.equal(0);
+    });
+
+    it("should reject a capture above the hold", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const held = await preauthorize(agent);
+
+      try {
+        await capture(agent, held, hold.addn(1));
+        expect.fail("expected CaptureExceedsHold");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("CaptureExceedsHold");
+      }
+    });
+
+    it("should void the hold back to the payer", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const held = await preauthorize(agent);
+
+      const payerBefore = await balance(held.payer.publicKey);
+      await program.methods
+        .voidPreauthorization()
+        .accounts({
+          invoice: held.invoice,
+          escrow: held.escrow,
+          payer: held.payer.publicKey,
+          authority: agent.owner.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([agent.owner])
+        .rpc();
+
+      expect((await balance(held.payer.publicKey)) - payerBefore).to.equal(hold.toNumber());
+      const invoice = await program.account.invoice.fetch(held.invoice);
+      expect(invoice.status).to.deep.equal({ pending: {} });
+      expect(invoice.heldAmount.toNumber()).to.equal(0);
+    });
+  });
+
+  describe("party_channel_events", () => {
+    it("should emit a party-scoped event for each party on open and close", async () => {
+      const partyA = provider.wallet.publicKey;
+      const partyBSigner = Keypair.generate();
+      const partyB = partyBSigner.publicKey;
+      await airdrop(partyB);
+      const deposit = new anchor.BN(0.1 * LAMPORTS_PER_SOL);
+      const opened = await openChannel(partyBSigner, deposit);
+
+      const registered = (await eventsOf(opened.sig)).filter((e) => e.name === "PartyChannelRegistered");
+      expect(registered.map((e) => e.data.party.toString())).to.deep.equal([
+        partyA.toString(),
+        partyB.toString(),
+      ]);
+      expect(registered.map((e) => e.data.counterparty.toString())).to.deep.equal([
+        partyB.toString(),
+        partyA.toString(),
+      ]);
+      for (const e of registered) {
+        expect(Buffer.from(e.data.channelId).equals(opened.channelId)).to.equal(true);
+      }
+
+      const sig = await program.methods
+        .closeChannel(new anchor.BN(deposit.toNumber() - 1000), new anchor.BN(1000), new anchor.BN(1))
+        .accounts({
+          channel: opened.channel,
+          channelEscrow: opened.channelEscrow,
+          partyA,
+          partyB,
+          config: configPDA,
+          feeCollector: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([partyBSigner])
+        .rpc({ commitment: "confirmed" });
+
+      const closed = (await eventsOf(sig)).filter((e) => e.name === "PartyChannelClosed");
+      expect(closed.map((e) => e.data.party.toString())).to.deep.equal([
+        partyA.toString(),
+        partyB.toString(),
+      ]);
+      expect(closed.map((e) => e.data.finalBalance.toNumber())).to.deep.equal([
+        deposit.toNumber() - 1000,
+        1000,
+      ]);
+    });
+  });
+
+  describe("completion_release_delay", () => {
+    const keeper = Keypair.generate();
+
+    const setReleaseDelay = (completionReleaseDelaySeconds: number) =>
+      program.methods
+        .updateProtocolConfig({
+          completionReleaseDelaySeconds: new anchor.BN(completionReleaseDelaySeconds),
+        } as any)
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+
+    // Completed with no per-request review period, so only the protocol
+    // delay stands between completion and release
+    const completed = async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { request, escrow } = await requestService(agent, "sentiment");
+      await acceptRequest(agent, request, escrow);
+      await completeService(agent, request);
+      return { agent, request, escrow };
+    };
+
+    before(async () => {
+      await airdrop(keeper.publicKey);
+    });
+
+    after(() => setReleaseDelay(0));
+
+    it("should release immediately with no delay", async () => {
+      await setReleaseDelay(0);
+      const { agent, request, escrow } = await completed();
+
+      await finalizeCompletion(agent, request, escrow, keeper);
+
+      const req = await program.account.serviceRequest.fetch(request);
+      expect(req.status).to.deep.equal({ completed: {} });
+    });
+
+    it("should defer release until the delay has passed", async () => {
+      await setReleaseDelay(2);
+      const { agent, request, escrow } = await completed();
+
+      try {
+        await finalizeCompletion(agent, request, escrow, keeper);
+        expect.fail("expected ReleaseDelayActive");
+      } catch (err: any) {
+        expect(anchor.AnchorError.parse(err.logs).error.errorCode.code).to.equal(
+          "ReleaseDelayActive"
+        );
+      }
+
+      await sleep(3000);
+      await finalizeCompletion(agent, request, escrow, keeper);
+
+      const req = await program.account.serviceRequest.fetch(request);
+      expect(req.status).to.deep.equal({ completed: {} });
+    });
+
+    it("should let the requester approve before the delay", async () => {
+      await setReleaseDelay(3600);
+      const { agent, request, escrow } = await completed();
+
+      await finalizeCompletion(agent, request, escrow);
+
+      const req = await program.account.serviceRequest.fetch(request);
+      expect(req.status).to.deep.equal({ completed: {} });
+    });
+  });
+
+  describe("invoice_dependencies", () => {
+    const payInvoice = (
+      recipient: ProviderAgent,
+      invoice: PublicKey,
+      dependency: PublicKey | null
+    ) =>
+      program.methods
+        .payInvoice(null)
+        .accounts({
+          invoice,
+          treasury: recipient.treasury,
+          payer: provider.wallet.publicKey,
+          recipient: recipient.owner.publicKey,
+          config: configPDA,
+          feeCollector: provider.wallet.publicKey,
+          dependency,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+    const milestones = async (recipient: ProviderAgent) => {
+      const first = await createInvoice(recipient.owner, recipient.treasury);
+      const second = await createInvoice(
+        recipient.owner,
+        recipient.treasury,
+        undefined,
+        null,
+        first.invoiceId
+      );
+      return { first, second };
+    };
+
+    it("should pay a dependent invoice once its dependency is paid", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const { first, second } = await milestones(recipient);
+
+      await payInvoice(recipient, first.invoice, null);
+      await payInvoice(recipient, second.invoice, first.invoice);
+
+      const invoice = await program.account.invoice.fetch(second.invoice);
+      expect(invoice.status).to.deep.equal({ paid: {} });
+      expect(Buffer.from(invoice.dependsOn).equals(first.invoiceId)).to.equal(true);
+    });
+
+    it("should reject paying a dependent invoice while its dependency is pending", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const { first, second } = await milestones(recipient);
+
+      try {
+        await payInvoice(recipient, second.invoice, first.invoice);
+        expect.fail("expected DependencyNotPaid");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("DependencyNotPaid");
+      }
+    });
+
+    it("should reject creating a dependent invoice without its dependency", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const invoiceId = Keypair.generate().publicKey.toBuffer();
+
+      try {
+        await program.methods
+          .createInvoice(
+            Array.from(invoiceId) as number[],
+            new anchor.BN(0.001 * LAMPORTS_PER_SOL),
+            "Test",
+            new anchor.BN(Math.floor(Date.now() / 1000) + 3600),
+            null,
+            Array.from(Keypair.generate().publicKey.toBuffer()) as number[]
+          )
+          .accounts({
+            invoice: pda(Buffer.from("invoice"), invoiceId)[0],
+            treasury: recipient.treasury,
+            recipient: recipient.owner.publicKey,
+            config: configPDA,
+            dependency: null,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .signers([recipient.owner])
+          .rpc();
+        expect.fail("expected DependencyNotFound");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("DependencyNotFound");
+      }
+    });
+
+    it("should reject paying a dependent invoice without its dependency", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const { first, second } = await milestones(recipient);
+      await payInvoice(recipient, first.invoice, null);
+
+      try {
+        await payInvoice(recipient, second.invoice, null);
+        expect.fail("expected DependencyNotPaid");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("DependencyNotPaid");
+      }
+    });
+  });
+
+  describe("treasury_ledger_entries", () => {
+    const ledgerEntry = async (sig: string) =>
+      (await eventsOf(sig)).find((e) => e.name === "TreasuryLedgerEntry");
+
+    it("should record a received entry for an invoice payment", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const amount = new anchor.BN(0.001 * LAMPORTS_PER_SOL);
+      const { invoice } = await createInvoice(recipient.owner, recipient.treasury, amount);
+
+      const sig = await program.methods
+        .payInvoice(null)
+        .accounts({
+          invoice,
+          treasury: recipient.treasury,
+          payer: provider.wallet.publicKey,
+          recipient: recipient.owner.publicKey,
+          config: configPDA,
+          feeCollector: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc({ commitment: "confirmed" });
+
+      const entry = await ledgerEntry(sig);
+      expect(entry.data.treasury.toString()).to.equal(recipient.treasury.toString());
+      expect(entry.data.kind).to.deep.equal({ received: {} });
+      expect(entry.data.delta.toNumber()).to.equal(amount.toNumber());
+      expect(entry.data.newTotalReceived.toNumber()).to.equal(amount.toNumber());
+      expect(entry.data.newTotalSettled.toNumber()).to.equal(0);
+    });
+
+    it("should record a settled entry for a batch settlement", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const batchId = Keypair.generate().publicKey.toBuffer();
+      const [batch] = pda(Buffer.from("batch"), batchId);
+      const total = new anchor.BN(5000);
+      const { invoiceId, invoice } = await createInvoice(recipient.owner, recipient.treasury, total);
+
+      const sig = await program.methods
+        .settleBatch(
+          Array.from(batchId) as number[],
+          [Array.from(invoiceId) as number[]],
+          total
+        )
+        .accounts({
+          batch,
+          treasury: recipient.treasury,
+          settler: provider.wallet.publicKey,
+          recipient: recipient.owner.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .remainingAccounts(invoiceAccounts([invoice]))
+        .rpc({ commitment: "confirmed" });
+
+      const entry = await ledgerEntry(sig);
+      expect(entry.data.kind).to.deep.equal({ settled: {} });
+      expect(entry.data.delta.toNumber()).to.equal(total.toNumber());
+      expect(entry.data.newTotalSettled.toNumber()).to.equal(total.toNumber());
+      expect(entry.data.newTotalReceived.toNumber()).to.equal(0);
+    });
+  });
+
+  describe("request_for_quote", () => {
+    const specHash = Array(32).fill(9);
+
+    const requestQuote = async (agent: ProviderAgent) => {
+      const rfqId = Keypair.generate().publicKey.toBuffer();
+      const [rfq] = pda(Buffer.from("rfq"), rfqId);
+      await program.methods
+        .requestQuote(Array.from(rfqId) as number[], "sentiment", specHash)
+        .accounts({
+          rfq,
+          providerProfile: agent.profile,
+          providerOwner: agent.owner.publicKey,
+          requester: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+      return rfq;
+    };
+
+    const submitQuote = (agent: ProviderAgent, rfq: PublicKey, price: anchor.BN, validFor: number) =>
+      program.methods
+        .submitQuote(price, new anchor.BN(Math.floor(Date.now() / 1000) + validFor))
+        .accounts({ rfq, provider: agent.owner.publicKey })
+        .signers([agent.owner])
+        .rpc();
+
+    const acceptQuote = async (agent: ProviderAgent, rfq: PublicKey) => {
+      const requestId = Keypair.generate().publicKey.toBuffer();
+      const [request] = pda(Buffer.from("request"), requestId);
+      const [escrow] = pda(Buffer.from("request_escrow"), requestId);
+      await program.methods
+        .acceptQuote(Array.from(requestId) as number[], null, null)
+        .accounts({
+          rfq,
+          service: {
+            request,
+            providerProfile: agent.profile,
+            providerOwner: agent.owner.publicKey,
+            providerTreasury: agent.treasury,
+            escrow,
+            requester: provider.wallet.publicKey,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          },
+        } as any)
+        .rpc();
+      return { request, escrow };
+    };
+
+    it("should open a service request at the quoted price", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const price = new anchor.BN(0.02 * LAMPORTS_PER_SOL);
+
+      const rfq = await requestQuote(agent);
+      await submitQuote(agent, rfq, price, 3600);
+      const { request, escrow } = await acceptQuote(agent, rfq);
+
+      const req = await program.account.serviceRequest.fetch(request);
+      expect(req.amount.toNumber()).to.equal(price.toNumber());
+      expect(req.capability).to.equal("sentiment");
+      expect(req.status).to.deep.equal({ pending: {} });
+      expect(await provider.connection.getBalance(escrow)).to.equal(price.toNumber());
+
+      const quote = await program.account.requestForQuote.fetch(rfq);
+      expect(quote.status).to.deep.equal({ accepted: {} });
+      expect(Buffer.from(quote.specHash)).to.deep.equal(Buffer.from(specHash));
+    });
+
+    it("should reject accepting an expired quote", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const rfq = await requestQuote(agent);
+      await submitQuote(agent, rfq, new anchor.BN(0.02 * LAMPORTS_PER_SOL), 2);
+      await sleep(4000);
+
+      try {
+        await acceptQuote(agent, rfq);
+        expect.fail("expected QuoteExpired");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("QuoteExpired");
+      }
+    });
+
+    it("should reject accepting before a quote is submitted", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const rfq = await requestQuote(agent);
+
+      try {
+        await acceptQuote(agent, rfq);
+        expect.fail("expected QuoteNotSubmitted");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("QuoteNotSubmitted");
+      }
+    });
+  });
+
+  describe("channel_balance_debits", () => {
+    it("should reject an overdraft from party B's side of the channel", async () => {
+      const partyB = Keypair.generate();
+      await airdrop(partyB.publicKey);
+      const { channelId, channel } = await openChannel(partyB);
+
+      // Party B has deposited nothing, so any in-channel payment overdraws it
+      const invoiceId = Keypair.generate().publicKey.toBuffer();
+      const [invoice] = pda(Buffer.from("invoice"), invoiceId);
+      await program.methods
+        .createInvoice(
+          Array.from(invoiceId) as number[],
+          new anchor.BN(1000),
+          "Overdraft",
+          new anchor.BN(Math.floor(Date.now() / 1000) + 3600),
+          null,
+          null
+        )
+        .accounts({
+          invoice,
+          treasury: treasuryPDA,
+          recipient: provider.wallet.publicKey,
+          config: configPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+      try {
+        await program.methods
+          .payInvoiceFromChannel(Array.from(channelId) as number[], new anchor.BN(1))
+          .accounts({
+            invoice,
+            channel,
+            treasury: treasuryPDA,
+            payer: partyB.publicKey,
+            instructions: anchor.web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+          })
+          .signers([partyB])
+          .rpc();
+        expect.fail("expected InsufficientChannelBalance");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InsufficientChannelBalance");
+      }
+
+      const channelAccount = await program.account.paymentChannel.fetch(channel);
+      expect(channelAccount.balanceB.toNumber()).to.equal(0);
+      expect(channelAccount.nonce.toNumber()).to.equal(0);
+    });
+  });
+
+  describe("capability_removal", () => {
+    it("should keep per-capability data with its capability when a middle one is removed", async () => {
+      const agent = await registerProvider(["sentiment", "translation", "image-gen"]);
+      for (const capability of ["sentiment", "image-gen", "image-gen"]) {
+        const { request, escrow } = await requestService(agent, capability);
+        await acceptRequest(agent, request, escrow);
+        await completeService(agent, request);
+      }
+      for (const [alias, canonical] of [
+        ["translate", "translation"],
+        ["img", "image-gen"],
+      ]) {
+        await program.methods
+          .addCapabilityAlias(alias, canonical)
+          .accounts({ agentProfile: agent.profile, owner: agent.owner.publicKey })
+          .signers([agent.owner])
+          .rpc();
+      }
+
+      await program.methods
+        .updateAgentProfile(null, null, ["sentiment", "image-gen"], null, null, null)
+        .accounts({ agentProfile: agent.profile, owner: agent.owner.publicKey })
+        .signers([agent.owner])
+        .rpc();
+
+      const profile = await program.account.agentProfile.fetch(agent.profile);
+      expect(profile.capabilities).to.deep.equal(["sentiment", "image-gen"]);
+      expect(profile.capabilityRequestCounts.map((c: anchor.BN) => c.toNumber())).to.deep.equal([1, 2]);
+      expect(profile.capabilityAliases.map((a: any) => a.alias)).to.deep.equal(["img"]);
+    });
+  });
+
+  describe("dispute_fee", () => {
+    const disputeFee = new anchor.BN(0.005 * LAMPORTS_PER_SOL);
+
+    const setDisputeFee = (fee: anchor.BN) =>
+      program.methods
+        .updateProtocolConfig({ disputeFee: fee } as any)
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+
+    // The provider disputes, so fee movements are visible on their balance
+    const providerDispute = async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { requestId, request, escrow } = await requestService(agent, "sentiment");
+      await acceptRequest(agent, request, escrow);
+      const dispute = await initiateDispute(agent, request, requestId, agent.owner);
+      return { agent, request, escrow, dispute };
+    };
+
+    before(() => setDisputeFee(disputeFee));
+    after(() => setDisputeFee(new anchor.BN(0)));
+
+    it("should stake the fee in the dispute account", async () => {
+      const { dispute } = await providerDispute();
+
+      const account = await program.account.dispute.fetch(dispute);
+      expect(account.fee.toNumber()).to.equal(disputeFee.toNumber());
+      const rent = await provider.connection.getMinimumBalanceForRentExemption(
+        (await provider.connection.getAccountInfo(dispute)).data.length
+      );
+      expect(await provider.connection.getBalance(dispute)).to.equal(rent + disputeFee.toNumber());
+    });
+
+    it("should refund the fee to a winning initiator", async () => {
+      const { agent, request, escrow, dispute } = await providerDispute();
+      const amount = (await program.account.serviceRequest.fetch(request)).amount.toNumber();
+
+      const before = await provider.connection.getBalance(agent.owner.publicKey);
+      const sig = await resolveDispute(agent, request, dispute, escrow, { payProvider: {} });
+      const after = await provider.connection.getBalance(agent.owner.publicKey);
+
+      expect(after - before).to.equal(amount + disputeFee.toNumber());
+      const resolved = (await eventsOf(sig)).find((e) => e.name === "DisputeResolved");
+      expect(resolved.data.initiatorWon).to.equal(true);
+      expect(resolved.data.disputeFee.toNumber()).to.equal(disputeFee.toNumber());
+    });
+
+    it("should forfeit the fee of a losing initiator to the counterparty", async () => {
+      const { agent, request, escrow, dispute } = await providerDispute();
+
+      const before = await provider.connection.getBalance(agent.owner.publicKey);
+      const sig = await resolveDispute(agent, request, dispute, escrow, { refundRequester: {} });
+      const after = await provider.connection.getBalance(agent.owner.publicKey);
+
+      // The provider receives nothing: no payment and no fee refund
+      expect(after).to.equal(before);
+      const resolved = (await eventsOf(sig)).find((e) => e.name === "DisputeResolved");
+      expect(resolved.data.initiatorWon).to.equal(false);
+      const account = await program.account.dispute.fetch(dispute);
+      const rent = await provider.connection.getMinimumBalanceForRentExemption(
+        (await provider.connection.getAccountInfo(dispute)).data.length
+      );
+      expect(await provider.connection.getBalance(dispute)).to.equal(rent);
+      expect(account.status).to.deep.equal({ resolved: {} });
+    });
+  });
+
+  describe("invoice_expiry", () => {
+    const payInvoice = (recipient: ProviderAgent, invoice: PublicKey) =>
+      program.methods
+        .payInvoice(null)
+        .accounts({
+          invoice,
+          treasury: recipient.treasury,
+          payer: provider.wallet.publicKey,
+          recipient: recipient.owner.publicKey,
+          config: configPDA,
+          feeCollector: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+    it("should reject paying an invoice expired by the crank", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const { invoice } = await createInvoice(
+        recipient.owner,
+        recipient.treasury,
+        undefined,
+        null,
+        null,
+        2
+      );
+      await sleep(3000);
+
+      await program.methods
+        .expireInvoice()
+        .accounts({ invoice, treasury: recipient.treasury })
+        .rpc();
+
+      const expired = await program.account.invoice.fetch(invoice);
+      expect(expired.status).to.deep.equal({ expired: {} });
+      const treasury = await program.account.treasury.fetch(recipient.treasury);
+      expect(treasury.pendingInvoices.toNumber()).to.equal(0);
+
+      try {
+        await payInvoice(recipient, invoice);
+        expect.fail("expected InvoiceExpired");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InvoiceExpired");
+      }
+    });
+
+    it("should reject paying a lapsed invoice that is still pending", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const { invoice } = await createInvoice(
+        recipient.owner,
+        recipient.treasury,
+        undefined,
+        null,
+        null,
+        2
+      );
+      await sleep(3000);
+
+      const pending = await program.account.invoice.fetch(invoice);
+      expect(pending.status).to.deep.equal({ pending: {} });
+
+      try {
+        await payInvoice(recipient, invoice);
+        expect.fail("expected InvoiceExpired");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InvoiceExpired");
+      }
+    });
+
+    it("should not expire an invoice before its expiry", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const { invoice } = await createInvoice(recipient.owner, recipient.treasury);
+
+      try {
+        await program.methods
+          .expireInvoice()
+          .accounts({ invoice, treasury: recipient.treasury })
+          .rpc();
+        expect.fail("expected InvoiceNotExpired");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InvoiceNotExpired");
+      }
+    });
+  });
+
+  describe("provider_treasury_health", () => {
+    it("should accept requests against a provider with a healthy treasury", async () => {
+      const agent = await registerProvider(["sentiment"]);
+
+      const { request } = await requestService(agent, "sentiment");
+
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.status).to.deep.equal({ pending: {} });
+    });
+
+    it("should reject requests against a provider whose treasury is below rent", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      await program.methods
+        .closeTreasury()
+        .accounts({ treasury: agent.treasury, owner: agent.owner.publicKey })
+        .signers([agent.owner])
+        .rpc();
+
+      const balance = await provider.connection.getBalance(agent.treasury);
+      expect(balance).to.equal(0);
+
+      try {
+        await requestService(agent, "sentiment");
+        expect.fail("expected ProviderTreasuryUnhealthy");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("ProviderTreasuryUnhealthy");
+      }
+    });
+  });
+
+  describe("stored_escrow_bumps", () => {
+    it("should store the canonical escrow bump on a service request", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { requestId, request } = await requestService(agent, "sentiment");
+
+      const [, canonicalBump] = pda(Buffer.from("request_escrow"), requestId);
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.escrowBump).to.equal(canonicalBump);
+    });
+
+    it("should store the canonical escrow bump on a payment channel", async () => {
+      const { channelId, channel } = await openChannel(Keypair.generate());
+
+      const [, canonicalBump] = pda(Buffer.from("escrow"), channelId);
+      const account = await program.account.paymentChannel.fetch(channel);
+      expect(account.escrowBump).to.equal(canonicalBump);
+    });
+
+    it("should sign escrow refunds with the stored bump", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const amount = new anchor.BN(0.01 * LAMPORTS_PER_SOL);
+      const { request, escrow } = await requestService(agent, "sentiment", amount);
+      expect(await provider.connection.getBalance(escrow)).to.equal(amount.toNumber());
+
+      await program.methods
+        .cancelServiceRequest()
+        .accounts({
+          request,
+          providerProfile: agent.profile,
+          escrow,
+          requester: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+      expect(await provider.connection.getBalance(escrow)).to.equal(0);
+    });
+  });
+
+  describe("payer_memo", () => {
+    const payWithMemo = (recipient: ProviderAgent, invoice: PublicKey, memo: string | null) =>
+      program.methods
+        .payInvoice(memo)
+        .accounts({
+          invoice,
+          treasury: recipient.treasury,
+          payer: provider.wallet.publicKey,
+          recipient: recipient.owner.publicKey,
+          config: configPDA,
+          feeCollector: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc({ commitment: "confirmed" });
+
+    it("should record a payer memo hash and emit the memo", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const { invoice } = await createInvoice(recipient.owner, recipient.treasury);
+      const memo = "order #4821";
+
+      const sig = await payWithMemo(recipient, invoice, memo);
+
+      const [paid] = (await eventsOf(sig)).filter((e) => e.name === "InvoicePaid");
+      expect(paid.data.payerMemo).to.equal(memo);
+      const account = await program.account.invoice.fetch(invoice);
+      const expected = createHash("sha256").update(memo).digest();
+      expect(Buffer.from(account.payerMemoHash as number[]).equals(expected)).to.be.true;
+    });
+
+    it("should pay without a memo", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const { invoice } = await createInvoice(recipient.owner, recipient.treasury);
+
+      const sig = await payWithMemo(recipient, invoice, null);
+
+      const [paid] = (await eventsOf(sig)).filter((e) => e.name === "InvoicePaid");
+      expect(paid.data.payerMemo).to.be.null;
+      const account = await program.account.invoice.fetch(invoice);
+      expect(account.payerMemoHash).to.be.null;
+    });
+
+    it("should reject an oversized payer memo", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const { invoice } = await createInvoice(recipient.owner, recipient.treasury);
+
+      try {
+        await payWithMemo(recipient, invoice, "x".repeat(129));
+        expect.fail("expected PayerMemoTooLong");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("PayerMemoTooLong");
+      }
+    });
+  });
+
+  describe("indexed_agents", () => {
+    const MAX_AGENTS_PER_OWNER = 8;
+
+    const setupOwner = async () => {
+      const owner = Keypair.generate();
+      await airdrop(owner.publicKey, 2);
+      const [treasury, bump] = pda(Buffer.from("treasury"), owner.publicKey.toBuffer());
+      const [registry] = pda(Buffer.from("agent_registry"), owner.publicKey.toBuffer());
+
+      await program.methods
+        .initializeTreasury(bump)
+        .accounts({
+          treasury,
+          owner: owner.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([owner])
+        .rpc();
+      await program.methods
+        .initializeAgentRegistry()
+        .accounts({
+          registry,
+          owner: owner.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([owner])
+        .rpc();
+
+      return { owner, treasury, registry };
+    };
+
+    const registerIndexed = async (
+      { owner, treasury, registry }: Awaited<ReturnType<typeof setupOwner>>,
+      index: number
+    ) => {
+      const [agentProfile] = pda(
+        Buffer.from("agent"),
+        owner.publicKey.toBuffer(),
+        Buffer.from([index])
+      );
+      await program.methods
+        .registerAgentIndexed(index, `Agent ${index}`, "Indexed agent", ["sentiment"], new anchor.BN(1000), [])
+        .accounts({
+          agentProfile,
+          registry,
+          treasury,
+          owner: owner.publicKey,
+          bannedAgents: bannedAgentsPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([owner])
+        .rpc();
+      return agentProfile;
+    };
+
+    it("should register two agents for one owner", async () => {
+      const ctx = await setupOwner();
+
+      const first = await registerIndexed(ctx, 0);
+      const second = await registerIndexed(ctx, 1);
+
+      const a = await program.account.agentProfile.fetch(first);
+      const b = await program.account.agentProfile.fetch(second);
+      expect(a.owner.toString()).to.equal(ctx.owner.publicKey.toString());
+      expect(b.owner.toString()).to.equal(ctx.owner.publicKey.toString());
+      expect(a.name).to.equal("Agent 0");
+      expect(b.name).to.equal("Agent 1");
+      const registry = await program.account.agentRegistry.fetch(ctx.registry);
+      expect(registry.agentCount).to.equal(2);
+    });
+
+    it("should reject registering past the per-owner cap", async () => {
+      const ctx = await setupOwner();
+      for (let index = 0; index < MAX_AGENTS_PER_OWNER; index++) {
+        await registerIndexed(ctx, index);
+      }
+
+      try {
+        await registerIndexed(ctx, MAX_AGENTS_PER_OWNER);
+        expect.fail("expected AgentRegistryFull");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("AgentRegistryFull");
+      }
+    });
+
+    it("should serve a request through the indexed profile it was made against", async () => {
+      const ctx = await setupOwner();
+      const first = await registerIndexed(ctx, 0);
+      const second = await registerIndexed(ctx, 1);
+      const agent = { owner: ctx.owner, treasury: ctx.treasury, profile: second };
+
+      const { request, escrow } = await requestService(agent, "sentiment");
+      expect((await program.account.serviceRequest.fetch(request)).providerIndex).to.equal(1);
+      expect((await program.account.agentProfile.fetch(second)).agentIndex).to.equal(1);
+
+      try {
+        await acceptRequest({ ...agent, profile: first }, request, escrow);
+        expect.fail("expected ConstraintSeeds");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("ConstraintSeeds");
+      }
+
+      await acceptRequest(agent, request, escrow);
+      await completeService(agent, request);
+      expect((await program.account.agentProfile.fetch(second)).openRequests).to.equal(0);
+      expect((await program.account.agentProfile.fetch(first)).openRequests).to.equal(0);
+    });
+  });
+
+  describe("structured_error_context", () => {
+    const contextLine = (err: any) =>
+      (err.logs as string[]).find((line) => line.includes("error_context:"));
+
+    it("should log expected and actual totals on a balance mismatch", async () => {
+      const deposit = new anchor.BN(0.1 * LAMPORTS_PER_SOL);
+      const partyBSigner = Keypair.generate();
+      const partyB = partyBSigner.publicKey;
+      const opened = await openChannel(partyBSigner, deposit);
+
+      try {
+        await program.methods
+          .closeChannel(new anchor.BN(deposit.toNumber()), new anchor.BN(1000), new anchor.BN(1))
+          .accounts({
+            channel: opened.channel,
+            channelEscrow: opened.channelEscrow,
+            partyA: provider.wallet.publicKey,
+            partyB,
+            config: configPDA,
+            feeCollector: provider.wallet.publicKey,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .signers([partyBSigner])
+          .rpc();
+        expect.fail("expected BalanceMismatch");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("BalanceMismatch");
+        expect(contextLine(err)).to.include(
+          `error=BalanceMismatch expected=${deposit.toNumber()} actual=${deposit.toNumber() + 1000}`
+        );
+      }
+    });
+
+    it("should log the base price when a request is underpriced", async () => {
+      const agent = await registerProvider(["sentiment"], new anchor.BN(5000));
+
+      try {
+        await requestService(agent, "sentiment", new anchor.BN(4000));
+        expect.fail("expected InvalidAmount");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InvalidAmount");
+        expect(contextLine(err)).to.include("error=InvalidAmount expected=5000 actual=4000");
+      }
+    });
+  });
+
+  describe("settle_via_channel", () => {
+    const invoiceAmount = new anchor.BN(0.001 * LAMPORTS_PER_SOL);
+
+    // Signs the netted state as the recipient unless `signed` is false
+    const settle = async (
+      recipient: ProviderAgent,
+      opened: Awaited<ReturnType<typeof openChannel>>,
+      invoices: { invoiceId: Buffer; invoice: PublicKey }[],
+      nonce?: anchor.BN,
+      signed = true
+    ) => {
+      const channel = await program.account.paymentChannel.fetch(opened.channel);
+      let total = new anchor.BN(0);
+      for (const { invoice } of invoices) {
+        total = total.add((await program.account.invoice.fetch(invoice)).amount);
+      }
+      const netted = anchor.BN.min(total, channel.balanceA);
+      const newNonce = nonce ?? channel.nonce.addn(1);
+      const signature = signChannelState(
+        recipient.owner,
+        opened.channelId,
+        channel.balanceA.sub(netted),
+        channel.balanceB.add(netted),
+        newNonce
+      );
+
+      return program.methods
+        .settleViaChannel(
+          invoices.map(({ invoiceId }) => Array.from(invoiceId) as number[]),
+          Array.from(opened.channelId) as number[],
+          newNonce
+        )
+        .accounts({
+          channel: opened.channel,
+          treasury: recipient.treasury,
+          payer: provider.wallet.publicKey,
+          recipient: recipient.owner.publicKey,
+          instructions: anchor.web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .remainingAccounts(
+          invoices.map(({ invoice }) => ({ pubkey: invoice, isWritable: true, isSigner: false }))
+        )
+        .preInstructions(signed ? [signature] : [])
+        .rpc({ commitment: "confirmed" });
+    };
+
+    it("should net invoices entirely within the channel balance", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const opened = await openChannel(recipient.owner);
+      const invoices = [
+        await createInvoice(recipient.owner, recipient.treasury, invoiceAmount),
+        await createInvoice(recipient.owner, recipient.treasury, invoiceAmount),
+      ];
+      const balanceBefore = await provider.connection.getBalance(recipient.owner.publicKey);
+
+      const sig = await settle(recipient, opened, invoices);
+
+      const settlement = (await eventsOf(sig)).find((e) => e.name === "ChannelSettlement");
+      expect(settlement.data.nettedAmount.toNumber()).to.equal(invoiceAmount.toNumber() * 2);
+      expect(settlement.data.residualAmount.toNumber()).to.equal(0);
+      const channel = await program.account.paymentChannel.fetch(opened.channel);
+      expect(channel.balanceB.toNumber()).to.equal(invoiceAmount.toNumber() * 2);
+      expect(channel.nonce.toNumber()).to.equal(1);
+      expect(await provider.connection.getBalance(recipient.owner.publicKey)).to.equal(balanceBefore);
+      for (const { invoice } of invoices) {
+        const account = await program.account.invoice.fetch(invoice);
+        expect(account.status).to.deep.equal({ paid: {} });
+      }
+    });
+
+    it("should transfer the residual beyond the channel balance on-chain", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const opened = await openChannel(recipient.owner, invoiceAmount);
+      const invoices = [
+        await createInvoice(recipient.owner, recipient.treasury, invoiceAmount),
+        await createInvoice(recipient.owner, recipient.treasury, invoiceAmount.muln(2)),
+      ];
+      const balanceBefore = await provider.connection.getBalance(recipient.owner.publicKey);
+
+      const sig = await settle(recipient, opened, invoices);
+
+      const settlement = (await eventsOf(sig)).find((e) => e.name === "ChannelSettlement");
+      expect(settlement.data.nettedAmount.toNumber()).to.equal(invoiceAmount.toNumber());
+      expect(settlement.data.residualAmount.toNumber()).to.equal(invoiceAmount.toNumber() * 2);
+      const channel = await program.account.paymentChannel.fetch(opened.channel);
+      expect(channel.balanceA.toNumber()).to.equal(0);
+      expect(channel.balanceB.toNumber()).to.equal(invoiceAmount.toNumber());
+      expect(await provider.connection.getBalance(recipient.owner.publicKey)).to.equal(
+        balanceBefore + invoiceAmount.toNumber() * 2
+      );
+    });
+
+    it("should reject settling an invoice that is already paid", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const opened = await openChannel(recipient.owner);
+      const invoice = await createInvoice(recipient.owner, recipient.treasury, invoiceAmount);
+      await settle(recipient, opened, [invoice]);
+
+      try {
+        await settle(recipient, opened, [invoice]);
+        expect.fail("expected InvoiceNotPending");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InvoiceNotPending");
+      }
+    });
+
+    it("should require the recipient's signature and outrank its older states", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const deposit = new anchor.BN(0.1 * LAMPORTS_PER_SOL);
+      const opened = await openChannel(recipient.owner, deposit);
+      const invoice = await createInvoice(recipient.owner, recipient.treasury, invoiceAmount);
+
+      try {
+        await settle(recipient, opened, [invoice], new anchor.BN(6), false);
+        expect.fail("expected InvalidCounterpartySignature");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InvalidCounterpartySignature");
+      }
+
+      // A state the recipient signed off-chain before the settlement
+      const older = signChannelState(recipient.owner, opened.channelId, deposit, new anchor.BN(0), new anchor.BN(5));
+      await settle(recipient, opened, [invoice], new anchor.BN(6));
+
+      try {
+        await program.methods
+          .initiateChannelClose(deposit, new anchor.BN(0), new anchor.BN(5))
+          .accounts({
+            channel: opened.channel,
+            party: provider.wallet.publicKey,
+            instructions: anchor.web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+          })
+          .preInstructions([older])
+          .rpc();
+        expect.fail("expected InvalidNonce");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InvalidNonce");
+      }
+
+      const channel = await program.account.paymentChannel.fetch(opened.channel);
+      expect(channel.balanceB.toString()).to.equal(invoiceAmount.toString());
+      expect(channel.nonce.toNumber()).to.equal(6);
+    });
+  });
+
+  describe("min_invoice_amount", () => {
+    const floor = 5000;
+
+    const setMinInvoiceAmount = (minInvoiceAmount: number) =>
+      program.methods
+        .updateProtocolConfig({ minInvoiceAmount: new anchor.BN(minInvoiceAmount) } as any)
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+
+    const createInvoiceFor = async (agent: ProviderAgent, amount: number) => {
+      const invoiceId = Keypair.generate().publicKey.toBuffer();
+      const [invoice] = pda(Buffer.from("invoice"), invoiceId);
+      const expiresAt = new anchor.BN(Math.floor(Date.now() / 1000) + 3600);
+
+      await program.methods
+        .createInvoice(Array.from(invoiceId) as number[], new anchor.BN(amount), "Dust", expiresAt, null, null)
+        .accounts({
+          invoice,
+          treasury: agent.treasury,
+          recipient: agent.owner.publicKey,
+          config: configPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([agent.owner])
+        .rpc();
+
+      return invoice;
+    };
+
+    before(() => setMinInvoiceAmount(floor));
+    after(() => setMinInvoiceAmount(0));
+
+    it("should accept an invoice at the floor", async () => {
+      const agent = await registerProvider(["sentiment"]);
+
+      const invoice = await createInvoiceFor(agent, floor);
+
+      const account = await program.account.invoice.fetch(invoice);
+      expect(account.amount.toNumber()).to.equal(floor);
+    });
+
+    it("should reject an invoice below the floor", async () => {
+      const agent = await registerProvider(["sentiment"]);
+
+      try {
+        await createInvoiceFor(agent, floor - 1);
+        expect.fail("expected AmountBelowMinimum");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("AmountBelowMinimum");
+      }
+    });
+
+    it("should reject a split invoice below the floor", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const invoiceId = Keypair.generate().publicKey.toBuffer();
+      const [invoice] = pda(Buffer.from("invoice"), invoiceId);
+
+      try {
+        await program.methods
+          .createSplitInvoice(
+            Array.from(invoiceId) as number[],
+            new anchor.BN(floor - 1),
+            "Dust",
+            new anchor.BN(Math.floor(Date.now() / 1000) + 3600),
+            [{ recipient: agent.owner.publicKey, bps: 10_000 }]
+          )
+          .accounts({
+            invoice,
+            treasury: agent.treasury,
+            recipient: agent.owner.publicKey,
+            config: configPDA,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .signers([agent.owner])
+          .rpc();
+        expect.fail("expected AmountBelowMinimum");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("AmountBelowMinimum");
+      }
+    });
+  });
+
+  describe("channel_acceptance", () => {
+    const deposit = new anchor.BN(0.05 * LAMPORTS_PER_SOL);
+
+    // Opens a channel without party B's acceptance
+    const openPending = async (partyB: PublicKey) => {
+      const channelId = Keypair.generate().publicKey.toBuffer();
+      const [channel] = pda(Buffer.from("channel"), channelId);
+      const [channelEscrow] = pda(Buffer.from("escrow"), channelId);
+
+      await program.methods
+        .openChannel(Array.from(channelId) as number[], deposit)
+        .accounts({
+          channel,
+          channelEscrow,
+          partyA: provider.wallet.publicKey,
+          partyB,
+          config: configPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+      return { channel, channelEscrow };
+    };
+
+    it("should activate the channel once party B accepts", async () => {
+      const partyB = Keypair.generate();
+      const { channel } = await openPending(partyB.publicKey);
+
+      await program.methods
+        .acceptChannel()
+        .accounts({ channel, partyB: partyB.publicKey })
+        .signers([partyB])
+        .rpc();
+
+      const account = await program.account.paymentChannel.fetch(channel);
+      expect(account.status).to.deep.equal({ open: {} });
+    });
+
+    it("should reject funding a channel party B has not accepted", async () => {
+      const partyB = Keypair.generate();
+      await airdrop(partyB.publicKey);
+      const { channel, channelEscrow } = await openPending(partyB.publicKey);
+
+      try {
+        await program.methods
+          .fundChannel(new anchor.BN(1000))
+          .accounts({
+            channel,
+            channelEscrow,
+            partyB: partyB.publicKey,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .signers([partyB])
+          .rpc();
+        expect.fail("expected ChannelNotOpen");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("ChannelNotOpen");
+      }
+    });
+
+    it("should refund party A when a pending channel is cancelled", async () => {
+      const { channel, channelEscrow } = await openPending(Keypair.generate().publicKey);
+      expect(await provider.connection.getBalance(channelEscrow)).to.equal(deposit.toNumber());
+
+      await program.methods
+        .cancelPendingChannel()
+        .accounts({
+          channel,
+          channelEscrow,
+          partyA: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+      expect(await provider.connection.getBalance(channelEscrow)).to.equal(0);
+      const account = await program.account.paymentChannel.fetch(channel);
+      expect(account.status).to.deep.equal({ closed: {} });
+    });
+
+    it("should reject cancelling a channel that was already accepted", async () => {
+      const partyB = Keypair.generate();
+      const { channel, channelEscrow } = await openChannel(partyB, deposit);
+
+      try {
+        await program.methods
+          .cancelPendingChannel()
+          .accounts({
+            channel,
+            channelEscrow,
+            partyA: provider.wallet.publicKey,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .rpc();
+        expect.fail("expected ChannelNotPendingAcceptance");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("ChannelNotPendingAcceptance");
+      }
+    });
+  });
+
+  describe("escrow_yield_routing", () => {
+    // Set to a comma-separated feature list when the program under test was
+    // built with `anchor test -- --features ...`
+    const yieldRouting = (process.env.AGENTFUND_FEATURES ?? "").split(",").includes("yield-routing");
+
+    const setYieldStrategy = (yieldStrategy: PublicKey) =>
+      program.methods
+        .updateProtocolConfig({ yieldStrategy } as any)
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+
+    // Yield instructions are kept out of the IDL, so build them by sighash
+    const yieldIx = (
+      name: "deposit_escrow_to_yield" | "withdraw_escrow_from_yield",
+      amount: anchor.BN,
+      accounts: { request: PublicKey; escrow: PublicKey; strategyProgram: PublicKey; strategyVault: PublicKey }
+    ) =>
+      new anchor.web3.TransactionInstruction({
+        programId: program.programId,
+        keys: [
+          { pubkey: configPDA, isSigner: false, isWritable: false },
+          { pubkey: accounts.request, isSigner: false, isWritable: true },
+          { pubkey: accounts.escrow, isSigner: false, isWritable: true },
+          { pubkey: accounts.strategyProgram, isSigner: false, isWritable: false },
+          { pubkey: accounts.strategyVault, isSigner: false, isWritable: true },
+          { pubkey: provider.wallet.publicKey, isSigner: true, isWritable: false },
+          { pubkey: anchor.web3.SystemProgram.programId, isSigner: false, isWritable: false },
+        ],
+        data: Buffer.concat([
+          createHash("sha256").update(`global:${name}`).digest().subarray(0, 8),
+          amount.toArrayLike(Buffer, "le", 8),
+        ]),
+      });
+
+    after(() => setYieldStrategy(PublicKey.default));
+
+    it("should leave yield routing off by default and allow clearing the strategy", async () => {
+      const strategy = Keypair.generate().publicKey;
+
+      await setYieldStrategy(strategy);
+      let config = await program.account.protocolConfig.fetch(configPDA);
+      expect(config.yieldStrategy.toString()).to.equal(strategy.toString());
+
+      await setYieldStrategy(PublicKey.default);
+      config = await program.account.protocolConfig.fetch(configPDA);
+      expect(config.yieldStrategy).to.be.null;
+    });
+
+    (yieldRouting ? it.skip : it)("should not know yield instructions in builds without yield routing", async () => {
+      expect(program.idl.instructions.map((ix) => ix.name)).to.not.include("depositEscrowToYield");
+
+      const agent = await registerProvider(["sentiment"]);
+      const amount = new anchor.BN(0.01 * LAMPORTS_PER_SOL);
+      const { request, escrow } = await requestService(agent, "sentiment", amount);
+      // The system program stands in for a strategy so account checks would pass
+      const strategyProgram = anchor.web3.SystemProgram.programId;
+      await setYieldStrategy(strategyProgram);
+
+      try {
+        await provider.sendAndConfirm(
+          new anchor.web3.Transaction().add(
+            yieldIx("deposit_escrow_to_yield", new anchor.BN(1000), {
+              request,
+              escrow,
+              strategyProgram,
+              strategyVault: Keypair.generate().publicKey,
+            })
+          )
+        );
+        expect.fail("expected InstructionFallbackNotFound");
+      } catch (err: any) {
+        expect(anchor.AnchorError.parse(err.logs).error.errorCode.code).to.equal(
+          "InstructionFallbackNotFound"
+        );
+      }
+
+      // Principal never left the escrow
+      expect(await provider.connection.getBalance(escrow)).to.equal(amount.toNumber());
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.yieldPrincipal.toNumber()).to.equal(0);
+    });
+
+    // Run with AGENTFUND_FEATURES=yield-routing anchor test -- --features yield-routing
+    (yieldRouting ? it : it.skip)("should return the full principal from a yield round trip", async () => {
+      // programs/mock-yield, deployed by `anchor test` under its generated keypair
+      const strategyProgram = Keypair.fromSecretKey(
+        Uint8Array.from(JSON.parse(readFileSync("target/deploy/mock_yield-keypair.json", "utf8")))
+      ).publicKey;
+      // Matches mock_yield::YIELD_PER_WITHDRAWAL
+      const yieldPerWithdrawal = 1_000;
+
+      const agent = await registerProvider(["sentiment"]);
+      const amount = new anchor.BN(0.01 * LAMPORTS_PER_SOL);
+      const { request, escrow } = await requestService(agent, "sentiment", amount);
+      await setYieldStrategy(strategyProgram);
+
+      // The strategy's vault holds its rent plus the yield it will pay out
+      const vault = Keypair.generate();
+      const rent = await provider.connection.getMinimumBalanceForRentExemption(0);
+      await provider.sendAndConfirm(
+        new anchor.web3.Transaction().add(
+          anchor.web3.SystemProgram.createAccount({
+            fromPubkey: provider.wallet.publicKey,
+            newAccountPubkey: vault.publicKey,
+            lamports: rent + yieldPerWithdrawal,
+            space: 0,
+            programId: strategyProgram,
+          })
+        ),
+        [vault]
+      );
+      const accounts = { request, escrow, strategyProgram, strategyVault: vault.publicKey };
+
+      const principal = new anchor.BN(0.004 * LAMPORTS_PER_SOL);
+      await provider.sendAndConfirm(
+        new anchor.web3.Transaction().add(yieldIx("deposit_escrow_to_yield", principal, accounts))
+      );
+      expect(await provider.connection.getBalance(escrow)).to.equal(amount.sub(principal).toNumber());
+      expect(await provider.connection.getBalance(vault.publicKey)).to.equal(
+        rent + yieldPerWithdrawal + principal.toNumber()
+      );
+      let account = await program.account.serviceRequest.fetch(request);
+      expect(account.yieldPrincipal.toNumber()).to.equal(principal.toNumber());
+
+      // Only deposited principal can be reclaimed
+      try {
+        await provider.sendAndConfirm(
+          new anchor.web3.Transaction().add(
+            yieldIx("withdraw_escrow_from_yield", principal.addn(1), accounts)
+          )
+        );
+        expect.fail("expected InvalidAmount");
+      } catch (err: any) {
+        expect(anchor.AnchorError.parse(err.logs).error.errorCode.code).to.equal("InvalidAmount");
+      }
+
+      const signature = await provider.sendAndConfirm(
+        new anchor.web3.Transaction().add(yieldIx("withdraw_escrow_from_yield", principal, accounts))
+      );
+
+      // Principal is back in full and the yield stays with the escrow
+      expect(await provider.connection.getBalance(escrow)).to.equal(
+        amount.toNumber() + yieldPerWithdrawal
+      );
+      expect(await provider.connection.getBalance(vault.publicKey)).to.equal(rent);
+      account = await program.account.serviceRequest.fetch(request);
+      expect(account.yieldPrincipal.toNumber()).to.equal(0);
+
+      const [withdrawn] = await eventsOf(signature);
+      expect(withdrawn.name).to.equal("EscrowYieldWithdrawn");
+      expect(withdrawn.data.principal.toNumber()).to.equal(principal.toNumber());
+      expect(withdrawn.data.yieldEarned.toNumber()).to.equal(yieldPerWithdrawal);
+      expect(withdrawn.data.yieldPrincipal.toNumber()).to.equal(0);
+    });
+  });
+
+  describe("oracle_attestation", () => {
+    const attest = (
+      agent: ProviderAgent,
+      request: PublicKey,
+      escrow: PublicKey,
+      oracle: Keypair,
+      hashAlgorithm: any = null
+    ) =>
+      program.methods
+        .attestCompletion(Array.from(Buffer.alloc(32, 7)) as number[], hashAlgorithm)
+        .accounts({
+          oracle: oracle.publicKey,
+          completion: {
+            request,
+            providerProfile: agent.profile,
+            escrow,
+            providerTreasury: agent.treasury,
+            provider: agent.owner.publicKey,
+            requester: provider.wallet.publicKey,
+            config: configPDA,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          },
+          stats: null,
+        } as any)
+        .signers([oracle])
+        .rpc({ commitment: "confirmed" });
+
+    it("should complete the request and release funds on oracle attestation", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const oracle = Keypair.generate();
+      const amount = new anchor.BN(0.01 * LAMPORTS_PER_SOL);
+      const { request, escrow } = await requestService(agent, "sentiment", amount, {
+        reviewPeriodSeconds: new anchor.BN(3600),
+        oracle: oracle.publicKey,
+      });
+      const treasuryBefore = await provider.connection.getBalance(agent.treasury);
+
+      await attest(agent, request, escrow, oracle);
+
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.status).to.deep.equal({ completed: {} });
+      expect(Buffer.from(account.resultHash as number[]).equals(Buffer.alloc(32, 7))).to.be.true;
+      expect(await provider.connection.getBalance(agent.treasury)).to.equal(
+        treasuryBefore + amount.toNumber()
+      );
+    });
+
+    it("should record an attested completion like a provider completion", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const oracle = Keypair.generate();
+      const { request, escrow } = await requestService(agent, "sentiment", undefined, {
+        oracle: oracle.publicKey,
+      });
+
+      const sig = await attest(agent, request, escrow, oracle, { keccak256: {} });
+
+      const events = await eventsOf(sig);
+      const completed = events.find((e) => e.name === "ServiceCompleted");
+      expect(completed.data.capability).to.equal("sentiment");
+      expect(completed.data.capabilityRequests.toNumber()).to.equal(1);
+      expect(completed.data.hashAlgorithm).to.deep.equal({ keccak256: {} });
+      const config = await program.account.protocolConfig.fetch(configPDA);
+      expect(completed.data.seq.toNumber()).to.equal(config.eventSeq.toNumber());
+      expect(events.map((e) => e.name)).to.include("CompletionAttested");
+
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.hashAlgorithm).to.deep.equal({ keccak256: {} });
+      const profile = await program.account.agentProfile.fetch(agent.profile);
+      expect(profile.openRequests).to.equal(0);
+    });
+
+    it("should reject an attestation from anyone but the designated oracle", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const oracle = Keypair.generate();
+      const { request, escrow } = await requestService(agent, "sentiment", undefined, {
+        oracle: oracle.publicKey,
+      });
+
+      try {
+        await attest(agent, request, escrow, Keypair.generate());
+        expect.fail("expected UnauthorizedOracle");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("UnauthorizedOracle");
+      }
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.status).to.deep.equal({ pending: {} });
+    });
+  });
+
+  describe("self_dealing", () => {
+    it("should reject a provider requesting its own service", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const requestId = Keypair.generate().publicKey.toBuffer();
+      const [request] = pda(Buffer.from("request"), requestId);
+      const [escrow] = pda(Buffer.from("request_escrow"), requestId);
+
+      try {
+        await program.methods
+          .requestService(Array.from(requestId) as number[], "sentiment", new anchor.BN(10_000), null, null, null)
+          .accounts({
+            request,
+            providerProfile: agent.profile,
+            providerOwner: agent.owner.publicKey,
+            providerTreasury: agent.treasury,
+            escrow,
+            requester: agent.owner.publicKey,
+            oracle: null,
+            bannedAgents: bannedAgentsPDA,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .signers([agent.owner])
+          .rpc();
+        expect.fail("expected SelfDealingNotAllowed");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("SelfDealingNotAllowed");
+      }
+    });
+
+    it("should reject a channel opened with itself", async () => {
+      const channelId = Keypair.generate().publicKey.toBuffer();
+      const [channel] = pda(Buffer.from("channel"), channelId);
+      const [channelEscrow] = pda(Buffer.from("escrow"), channelId);
+
+      try {
+        await program.methods
+          .openChannel(Array.from(channelId) as number[], new anchor.BN(10_000))
+          .accounts({
+            channel,
+            channelEscrow,
+            partyA: provider.wallet.publicKey,
+            partyB: provider.wallet.publicKey,
+            config: configPDA,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .rpc();
+        expect.fail("expected SelfDealingNotAllowed");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("SelfDealingNotAllowed");
+      }
+    });
+  });
+
+  describe("invoice_uri", () => {
+    it("should encode recipient, amount, reference and memo", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const invoiceId = Keypair.generate().publicKey.toBuffer();
+      const [invoice] = pda(Buffer.from("invoice"), invoiceId);
+      const memo = "Order #42 & more";
+
+      await program.methods
+        .createInvoice(
+          Array.from(invoiceId) as number[],
+          new anchor.BN(1_500_000_000),
+          memo,
+          new anchor.BN(Math.floor(Date.now() / 1000) + 3600),
+          null,
+          null
+        )
+        .accounts({
+          invoice,
+          treasury: agent.treasury,
+          recipient: agent.owner.publicKey,
+          config: configPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([agent.owner])
+        .rpc();
+
+      const uri: string = await program.methods.getInvoiceUri().accounts({ invoice }).view();
+      const parsed = new URL(uri);
+
+      expect(parsed.protocol).to.equal("solana:");
+      expect(parsed.pathname).to.equal(agent.owner.publicKey.toBase58());
+      expect(parsed.searchParams.get("amount")).to.equal("1.5");
+      expect(parsed.searchParams.get("reference")).to.equal(new PublicKey(invoiceId).toBase58());
+      expect(parsed.searchParams.get("memo")).to.equal(memo);
+    });
+
+    it("should reject an invoice that is no longer pending", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { invoice } = await createInvoice(agent.owner, agent.treasury);
+
+      await program.methods
+        .payInvoice(null)
+        .accounts({
+          invoice,
+          treasury: agent.treasury,
+          payer: provider.wallet.publicKey,
+          recipient: agent.owner.publicKey,
+          config: configPDA,
+          feeCollector: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+      try {
+        await program.methods.getInvoiceUri().accounts({ invoice }).view();
+        expect.fail("should have rejected a paid invoice");
+      } catch (err: any) {
+        expect(err.toString()).to.contain("InvoiceNotPending");
+      }
+    });
+  });
+
+  describe("provider_capacity", () => {
+    const setCapacity = (agent: ProviderAgent, max: number) =>
+      program.methods
+        .setMaxConcurrentRequests(max)
+        .accounts({ agentProfile: agent.profile, owner: agent.owner.publicKey })
+        .signers([agent.owner])
+        .rpc();
+
+    it("should reject requests at capacity and accept again after one completes", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      await setCapacity(agent, 2);
+
+      const first = await requestService(agent, "sentiment");
+      await requestService(agent, "sentiment");
+
+      try {
+        await requestService(agent, "sentiment");
+        expect.fail("should have rejected a request beyond capacity");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("ProviderAtCapacity");
+      }
+
+      await acceptRequest(agent, first.request, first.escrow);
+      await completeService(agent, first.request);
+      await finalizeCompletion(agent, first.request, first.escrow);
+
+      await requestService(agent, "sentiment");
+      const profile = await program.account.agentProfile.fetch(agent.profile);
+      expect(profile.openRequests).to.equal(2);
+    });
+
+    it("should treat zero as unlimited", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      await setCapacity(agent, 1);
+      await requestService(agent, "sentiment");
+      await setCapacity(agent, 0);
+
+      await requestService(agent, "sentiment");
+
+      const profile = await program.account.agentProfile.fetch(agent.profile);
+      expect(profile.maxConcurrentRequests).to.equal(0);
+      expect(profile.openRequests).to.equal(2);
+    });
+  });
+
+  describe("redo_request_resolution", () => {
+    let agent: ProviderAgent;
+    const amount = new anchor.BN(0.01 * LAMPORTS_PER_SOL);
+
+    before(async () => {
+      agent = await registerProvider(["sentiment"]);
+    });
+
+    // Mirrors the program's redo id: sha256("redo" || parent id)
+    const redoAccounts = (requestId: Buffer) => {
+      const redoId = createHash("sha256").update(Buffer.from("redo")).update(requestId).digest();
+      const [redoRequest] = pda(Buffer.from("request"), redoId);
+      const [redoEscrow] = pda(Buffer.from("request_escrow"), redoId);
+      return { redoId, redoRequest, redoEscrow };
+    };
+
+    it("should refund the requester and carry the provider's share into a linked request", async () => {
+      const { requestId, request, escrow } = await requestService(agent, "sentiment", amount);
+      const dispute = await initiateDispute(agent, request, requestId);
+      const { redoId, redoRequest, redoEscrow } = redoAccounts(requestId);
+      const openBefore = (await program.account.agentProfile.fetch(agent.profile)).openRequests;
+
+      const requesterBefore = await provider.connection.getBalance(provider.wallet.publicKey);
+      const sig = await program.methods
+        .resolveDispute({ redoRequest: { providerPct: 40 } })
+        .accounts({
+          request,
+          dispute,
+          config: configPDA,
+          resolver: provider.wallet.publicKey,
+          requester: provider.wallet.publicKey,
+          provider: agent.owner.publicKey,
+          escrow,
+          redoRequest,
+          redoEscrow,
+          providerProfile: agent.profile,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([agent.owner])
+        .rpc({ commitment: "confirmed" });
+      const requesterAfter = await provider.connection.getBalance(provider.wallet.publicKey);
+
+      const carried = amount.toNumber() * 0.4;
+      const [resolved, created] = await eventsOf(sig);
+      expect(resolved.data.requesterAmount.toNumber()).to.equal(amount.toNumber() - carried);
+      expect(resolved.data.providerAmount.toNumber()).to.equal(carried);
+      expect(created.name).to.equal("RedoRequestCreated");
+      expect(created.data.amount.toNumber()).to.equal(carried);
+
+      // The requester paid the fee and the redo request's rent out of the refund
+      const tx = await provider.connection.getTransaction(sig, {
+        commitment: "confirmed",
+        maxSupportedTransactionVersion: 0,
+      });
+      const redoInfo = await provider.connection.getAccountInfo(redoRequest);
+      const rent = await provider.connection.getMinimumBalanceForRentExemption(redoInfo.data.length);
+      const disputeFee = resolved.data.initiatorWon ? resolved.data.disputeFee.toNumber() : 0;
+      expect(requesterAfter - requesterBefore).to.equal(
+        amount.toNumber() - carried + disputeFee - rent - tx.meta.fee
+      );
+
+      expect(await provider.connection.getBalance(redoEscrow)).to.equal(carried);
+      const redo = await program.account.serviceRequest.fetch(redoRequest);
+      expect(redo.id).to.deep.equal(Array.from(redoId));
+      expect(redo.parentRequest.toBase58()).to.equal(request.toBase58());
+      expect(redo.amount.toNumber()).to.equal(carried);
+      expect(redo.capability).to.equal("sentiment");
+      expect(redo.status).to.deep.equal({ pending: {} });
+
+      const profile = await program.account.agentProfile.fetch(agent.profile);
+      expect(profile.openRequests).to.equal(openBefore + 1);
+    });
+
+    it("should reject a redo without the follow-up request accounts", async () => {
+      const { requestId, request, escrow } = await requestService(agent, "sentiment", amount);
+      const dispute = await initiateDispute(agent, request, requestId);
+
+      try {
+        await resolveDispute(agent, request, dispute, escrow, { redoRequest: { providerPct: 40 } });
+        expect.fail("expected RedoAccountsMissing");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("RedoAccountsMissing");
+      }
+    });
+  });
+
+  describe("account_sizes", () => {
+    // Documented layouts (discriminator included); the program asserts the
+    // same figures against its `InitSpace`-derived allocations at build time
+    const expected = {
+      treasury: 237,
+      agentProfile: 1774,
+      serviceRequest: 672,
+      dispute: 666,
+      protocolConfig: 289,
+      paymentChannel: 212,
+      // Invoices are sized to their memo; the helper's memo is "Test"
+      invoice: 254 + "Test".length,
+    };
+
+    const sizeOf = async (account: PublicKey) =>
+      (await provider.connection.getAccountInfo(account)).data.length;
+
+    it("should allocate each account at its documented size", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { requestId, request } = await requestService(agent, "sentiment");
+      const dispute = await initiateDispute(agent, request, requestId);
+      const { invoice } = await createInvoice(agent.owner, agent.treasury);
+      const partyB = Keypair.generate();
+      await airdrop(partyB.publicKey);
+      const { channel } = await openChannel(partyB);
+
+      expect(await sizeOf(agent.treasury)).to.equal(expected.treasury);
+      expect(await sizeOf(agent.profile)).to.equal(expected.agentProfile);
+      expect(await sizeOf(request)).to.equal(expected.serviceRequest);
+      expect(await sizeOf(dispute)).to.equal(expected.dispute);
+      expect(await sizeOf(configPDA)).to.equal(expected.protocolConfig);
+      expect(await sizeOf(channel)).to.equal(expected.paymentChannel);
+      expect(await sizeOf(invoice)).to.equal(expected.invoice);
+    });
+  });
+
+  describe("payer_balance_precheck", () => {
+    const amount = new anchor.BN(0.05 * LAMPORTS_PER_SOL);
+    let agent: ProviderAgent;
+    let poor: Keypair;
+
+    before(async () => {
+      agent = await registerProvider(["sentiment"]);
+      poor = Keypair.generate();
+      await airdrop(poor.publicKey, 0.02);
+    });
+
+    const expectShortfall = (err: any) => {
+      expect(err.error.errorCode.code).to.equal("InsufficientPayerBalance");
+      expect(err.logs.some((line: string) => line.includes("Payer short by"))).to.be.true;
+      expect(
+        err.logs.some((line: string) =>
+          line.includes(`error=InsufficientPayerBalance expected=${amount.toString()}`)
+        )
+      ).to.be.true;
+    };
+
+    it("should reject an invoice payment the payer cannot cover", async () => {
+      const { invoice } = await createInvoice(agent.owner, agent.treasury, amount);
+
+      try {
+        await program.methods
+          .payInvoice(null)
+          .accounts({
+            invoice,
+            treasury: agent.treasury,
+            payer: poor.publicKey,
+            recipient: agent.owner.publicKey,
+            config: configPDA,
+            feeCollector: provider.wallet.publicKey,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .signers([poor])
+          .rpc();
+        expect.fail("expected InsufficientPayerBalance");
+      } catch (err: any) {
+        expectShortfall(err);
+      }
+    });
+
+    it("should reject a service request the requester cannot cover", async () => {
+      const requestId = Keypair.generate().publicKey.toBuffer();
+      const [request] = pda(Buffer.from("request"), requestId);
+      const [escrow] = pda(Buffer.from("request_escrow"), requestId);
+
+      try {
+        await program.methods
+          .requestService(Array.from(requestId) as number[], "sentiment", amount, null, null, null)
+          .accounts({
+            request,
+            providerProfile: agent.profile,
+            providerOwner: agent.owner.publicKey,
+            providerTreasury: agent.treasury,
+            escrow,
+            requester: poor.publicKey,
+            oracle: null,
+            bannedAgents: bannedAgentsPDA,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .signers([poor])
+          .rpc();
+        expect.fail("expected InsufficientPayerBalance");
+      } catch (err: any) {
+        expectShortfall(err);
+      }
+    });
+
+    it("should reject a channel deposit party A cannot cover", async () => {
+      const channelId = Keypair.generate().publicKey.toBuffer();
+      const [channel] = pda(Buffer.from("channel"), channelId);
+      const [channelEscrow] = pda(Buffer.from("escrow"), channelId);
+
+      try {
+        await program.methods
+          .openChannel(Array.from(channelId) as number[], amount)
+          .accounts({
+            channel,
+            channelEscrow,
+            partyA: poor.publicKey,
+            partyB: agent.owner.publicKey,
+            config: configPDA,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .signers([poor])
+          .rpc();
+        expect.fail("expected InsufficientPayerBalance");
+      } catch (err: any) {
+        expectShortfall(err);
+      }
+    });
+  });
+
+  describe("price_increase_timelock", () => {
+    const PRICE_INCREASE_DELAY_SECONDS = 3600;
+    const price = 0.01 * LAMPORTS_PER_SOL;
+
+    const setBasePrice = (agent: ProviderAgent, price: number) =>
+      program.methods
+        .updateAgentProfile(null, null, null, new anchor.BN(price), null, null)
+        .accounts({ agentProfile: agent.profile, owner: agent.owner.publicKey })
+        .signers([agent.owner])
+        .rpc();
+
+    it("should keep charging the old price until a raise takes effect", async () => {
+      const agent = await registerProvider(["sentiment"], new anchor.BN(price));
+      const before = Math.floor(Date.now() / 1000);
+
+      await setBasePrice(agent, 5 * price);
+
+      const profile = await program.account.agentProfile.fetch(agent.profile);
+      expect(profile.basePrice.toNumber()).to.equal(price);
+      expect(profile.pendingBasePrice.toNumber()).to.equal(5 * price);
+      expect(profile.priceEffectiveAt.toNumber()).to.be.at.least(
+        before + PRICE_INCREASE_DELAY_SECONDS - 5
+      );
+
+      // Still payable at the old price
+      await requestService(agent, "sentiment", new anchor.BN(price));
+      const after = await program.account.agentProfile.fetch(agent.profile);
+      expect(after.basePrice.toNumber()).to.equal(price);
+    });
+
+    it("should apply a lowered price immediately", async () => {
+      const agent = await registerProvider(["sentiment"], new anchor.BN(price));
+      await setBasePrice(agent, 5 * price);
+
+      await setBasePrice(agent, price / 2);
+
+      const profile = await program.account.agentProfile.fetch(agent.profile);
+      expect(profile.basePrice.toNumber()).to.equal(price / 2);
+      expect(profile.pendingBasePrice).to.be.null;
+      await requestService(agent, "sentiment", new anchor.BN(price / 2));
+    });
+  });
+
+  describe("withdraw_unused_deposit", () => {
+    const deposit = new anchor.BN(0.1 * LAMPORTS_PER_SOL);
+
+    const setIdlePeriod = (seconds: number) =>
+      program.methods
+        .updateProtocolConfig({ channelIdleReclaimSeconds: new anchor.BN(seconds) } as any)
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+
+    const withdrawUnused = (channel: PublicKey, channelEscrow: PublicKey) =>
+      program.methods
+        .withdrawUnusedDeposit()
+        .accounts({
+          channel,
+          channelEscrow,
+          config: configPDA,
+          partyA: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc({ commitment: "confirmed" });
+
+    before(() => setIdlePeriod(1));
+    after(() => setIdlePeriod(7 * 24 * 60 * 60));
+
+    it("should return an untouched deposit and close the channel", async () => {
+      const partyB = Keypair.generate();
+      await airdrop(partyB.publicKey);
+      const { channel, channelEscrow } = await openChannel(partyB, deposit);
+      await sleep(2000);
+
+      const escrowBefore = await provider.connection.getBalance(channelEscrow);
+      const sig = await withdrawUnused(channel, channelEscrow);
+      const escrowAfter = await provider.connection.getBalance(channelEscrow);
+
+      expect(escrowBefore - escrowAfter).to.equal(deposit.toNumber());
+      const [reclaimed] = await eventsOf(sig);
+      expect(reclaimed.name).to.equal("ChannelDepositReclaimed");
+      expect(reclaimed.data.refunded.toNumber()).to.equal(deposit.toNumber());
+      const account = await program.account.paymentChannel.fetch(channel);
+      expect(account.status).to.deep.equal({ closed: {} });
+    });
+
+    it("should reject a reclaim once the nonce has advanced", async () => {
+      const partyB = Keypair.generate();
+      await airdrop(partyB.publicKey);
+      const { channel, channelEscrow } = await openChannel(partyB, deposit);
+      await program.methods
+        .updateChannel(deposit.subn(1000), new anchor.BN(1000), new anchor.BN(1))
+        .accounts({ channel, partyA: provider.wallet.publicKey, partyB: partyB.publicKey })
+        .signers([partyB])
+        .rpc();
+      await sleep(2000);
+
+      try {
+        await withdrawUnused(channel, channelEscrow);
+        expect.fail("expected ChannelNotPristine");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("ChannelNotPristine");
+      }
+    });
+
+    it("should reject a reclaim before the idle period has passed", async () => {
+      await setIdlePeriod(3600);
+      const partyB = Keypair.generate();
+      await airdrop(partyB.publicKey);
+      const { channel, channelEscrow } = await openChannel(partyB, deposit);
+
+      try {
+        await withdrawUnused(channel, channelEscrow);
+        expect.fail("expected ChannelNotIdle");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("ChannelNotIdle");
+      } finally {
+        await setIdlePeriod(1);
+      }
+    });
+  });
+
+  describe("service_bundles", () => {
+    const price = 0.01 * LAMPORTS_PER_SOL;
+    let agent: ProviderAgent;
+
+    before(async () => {
+      agent = await registerProvider(["sentiment", "translation"], new anchor.BN(price));
+    });
+
+    const requestBundle = async (capabilities: string[], amount: number) => {
+      const requestId = Keypair.generate().publicKey.toBuffer();
+      const [request] = pda(Buffer.from("request"), requestId);
+      const [escrow] = pda(Buffer.from("request_escrow"), requestId);
+
+      await program.methods
+        .requestServiceBundle(Array.from(requestId) as number[], capabilities, new anchor.BN(amount))
+        .accounts({
+          request,
+          providerProfile: agent.profile,
+          providerOwner: agent.owner.publicKey,
+          providerTreasury: agent.treasury,
+          escrow,
+          requester: provider.wallet.publicKey,
+          oracle: null,
+          bannedAgents: bannedAgentsPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+      return { request, escrow };
+    };
+
+    it("should escrow a bundle priced per capability", async () => {
+      const { request, escrow } = await requestBundle(["sentiment", "translation"], 2 * price);
+
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.capability).to.equal("sentiment");
+      expect(account.bundle).to.deep.equal(["sentiment", "translation"]);
+      expect(account.amount.toNumber()).to.equal(2 * price);
+      expect(await provider.connection.getBalance(escrow)).to.equal(2 * price);
+    });
+
+    it("should reject a bundle priced below the sum of its capabilities", async () => {
+      try {
+        await requestBundle(["sentiment", "translation"], 2 * price - 1);
+        expect.fail("expected InvalidAmount");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InvalidAmount");
+      }
+    });
+
+    it("should reject a bundle with an unsupported capability", async () => {
+      try {
+        await requestBundle(["sentiment", "image-gen"], 2 * price);
+        expect.fail("expected CapabilityNotSupported");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("CapabilityNotSupported");
+      }
+    });
+  });
+
+  describe("treasury_event_keys", () => {
+    let agent: ProviderAgent;
+
+    before(async () => {
+      agent = await registerProvider(["sentiment"]);
+    });
+
+    it("should tag invoice creation, payment and batch settlement with the treasury", async () => {
+      const invoiceId = Keypair.generate().publicKey.toBuffer();
+      const [invoice] = pda(Buffer.from("invoice"), invoiceId);
+
+      const createSig = await program.methods
+        .createInvoice(
+          Array.from(invoiceId) as number[],
+          new anchor.BN(0.001 * LAMPORTS_PER_SOL),
+          "Test",
+          new anchor.BN(Math.floor(Date.now() / 1000) + 3600),
+          null,
+          null
+        )
+        .accounts({
+          invoice,
+          treasury: agent.treasury,
+          recipient: agent.owner.publicKey,
+          config: configPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([agent.owner])
+        .rpc({ commitment: "confirmed" });
+      const [created] = await eventsOf(createSig);
+      expect(created.name).to.equal("InvoiceCreated");
+      expect(created.data.treasury.toBase58()).to.equal(agent.treasury.toBase58());
+
+      const paySig = await program.methods
+        .payInvoice(null)
+        .accounts({
+          invoice,
+          treasury: agent.treasury,
+          payer: provider.wallet.publicKey,
+          recipient: agent.owner.publicKey,
+          config: configPDA,
+          feeCollector: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc({ commitment: "confirmed" });
+      const paid = (await eventsOf(paySig)).find((e) => e.name === "InvoicePaid");
+      expect(paid.data.treasury.toBase58()).to.equal(agent.treasury.toBase58());
+
+      const batchId = Keypair.generate().publicKey.toBuffer();
+      const [batch] = pda(Buffer.from("batch"), batchId);
+      const unpaid = await createInvoice(agent.owner, agent.treasury, new anchor.BN(1000));
+      const settleSig = await program.methods
+        .settleBatch(
+          Array.from(batchId) as number[],
+          [Array.from(unpaid.invoiceId) as number[]],
+          new anchor.BN(1000)
+        )
+        .accounts({
+          batch,
+          treasury: agent.treasury,
+          settler: provider.wallet.publicKey,
+          recipient: agent.owner.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .remainingAccounts(invoiceAccounts([unpaid.invoice]))
+        .rpc({ commitment: "confirmed" });
+      const settled = (await eventsOf(settleSig)).find((e) => e.name === "BatchSettled");
+      expect(settled.data.treasury.toBase58()).to.equal(agent.treasury.toBase58());
+    });
+  });
+
+  describe("cancel_dispute", () => {
+    let agent: ProviderAgent;
+
+    before(async () => {
+      agent = await registerProvider(["sentiment"]);
+    });
+
+    const cancelDispute = (request: PublicKey, dispute: PublicKey) =>
+      program.methods
+        .cancelDispute()
+        .accounts({
+          request,
+          dispute,
+          providerProfile: agent.profile,
+          initiator: provider.wallet.publicKey,
+        })
+        .rpc({ commitment: "confirmed" });
+
+    it("should return a pending request to pending", async () => {
+      const { requestId, request } = await requestService(agent, "sentiment");
+      const openBefore = (await program.account.agentProfile.fetch(agent.profile)).openRequests;
+      const dispute = await initiateDispute(agent, request, requestId);
+
+      const sig = await cancelDispute(request, dispute);
+
+      const [withdrawn] = await eventsOf(sig);
+      expect(withdrawn.name).to.equal("DisputeWithdrawn");
+      expect((await program.account.serviceRequest.fetch(request)).status).to.deep.equal({
+        pending: {},
+      });
+      expect((await program.account.dispute.fetch(dispute)).status).to.deep.equal({
+        withdrawn: {},
+      });
+      const profile = await program.account.agentProfile.fetch(agent.profile);
+      expect(profile.openRequests).to.equal(openBefore);
+    });
+
+    it("should return a completed request to review", async () => {
+      const { requestId, request, escrow } = await requestService(agent, "sentiment");
+      await acceptRequest(agent, request, escrow);
+      await completeService(agent, request);
+      const dispute = await initiateDispute(agent, request, requestId);
+
+      await cancelDispute(request, dispute);
+
+      expect((await program.account.serviceRequest.fetch(request)).status).to.deep.equal({
+        pendingReview: {},
+      });
+    });
+
+    it("should reject withdrawal by anyone but the initiator", async () => {
+      const { requestId, request } = await requestService(agent, "sentiment");
+      const dispute = await initiateDispute(agent, request, requestId);
+
+      try {
+        await program.methods
+          .cancelDispute()
+          .accounts({
+            request,
+            dispute,
+            providerProfile: agent.profile,
+            initiator: agent.owner.publicKey,
+          })
+          .signers([agent.owner])
+          .rpc();
+        expect.fail("expected Unauthorized");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("Unauthorized");
+      }
+    });
+  });
+
+  describe("expire_invoices_batch", () => {
+    it("should expire only past-due pending invoices and report the count", async () => {
+      const recipient = await registerProvider(["sentiment"]);
+      const stale = [];
+      for (let i = 0; i < 2; i++) {
+        stale.push(await createInvoice(recipient.owner, recipient.treasury, undefined, null, null, 2));
+      }
+      const fresh = [];
+      for (let i = 0; i < 2; i++) {
+        fresh.push(await createInvoice(recipient.owner, recipient.treasury));
+      }
+      await sleep(3000);
+
+      const batch = [stale[0], fresh[0], stale[1], fresh[1]];
+      const sig = await program.methods
+        .expireInvoicesBatch()
+        .accounts({ treasury: recipient.treasury })
+        .remainingAccounts(
+          batch.map(({ invoice }) => ({ pubkey: invoice, isWritable: true, isSigner: false }))
+        )
+        .rpc({ commitment: "confirmed" });
+
+      // The count is returned as a little-endian u32 in the program's return data
+      const tx = await provider.connection.getTransaction(sig, {
+        commitment: "confirmed",
+        maxSupportedTransactionVersion: 0,
+      });
+      const prefix = `Program return: ${program.programId.toBase58()} `;
+      const returned = tx.meta.logMessages.find((line) => line.startsWith(prefix));
+      expect(Buffer.from(returned.slice(prefix.length), "base64").readUInt32LE(0)).to.equal(2);
+
+      for (const { invoice } of stale) {
+        expect((await program.account.invoice.fetch(invoice)).status).to.deep.equal({ expired: {} });
+      }
+      for (const { invoice } of fresh) {
+        expect((await program.account.invoice.fetch(invoice)).status).to.deep.equal({ pending: {} });
+      }
+      const treasury = await program.account.treasury.fetch(recipient.treasury);
+      expect(treasury.pendingInvoices.toNumber()).to.equal(2);
+    });
+  });
+
+  describe("withdraw_earnings", () => {
+    const amount = new anchor.BN(0.01 * LAMPORTS_PER_SOL);
+
+    const withdrawEarnings = (agent: ProviderAgent, lamports: anchor.BN) =>
+      program.methods
+        .withdrawEarnings(lamports)
+        .accounts({
+          agentProfile: agent.profile,
+          treasury: agent.treasury,
+          owner: agent.owner.publicKey,
+        })
+        .signers([agent.owner])
+        .rpc({ commitment: "confirmed" });
+
+    it("should accrue earnings across completions and withdraw them", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      for (let i = 0; i < 2; i++) {
+        const { request, escrow } = await requestService(agent, "sentiment", amount);
+        await completeService(agent, request);
+        await finalizeCompletion(agent, request, escrow);
+      }
+
+      const accrued = await program.account.agentProfile.fetch(agent.profile);
+      expect(accrued.availableEarnings.toNumber()).to.equal(2 * amount.toNumber());
+
+      const treasuryBefore = await provider.connection.getBalance(agent.treasury);
+      const sig = await withdrawEarnings(agent, amount.muln(2));
+
+      const treasuryAfter = await provider.connection.getBalance(agent.treasury);
+      expect(treasuryBefore - treasuryAfter).to.equal(2 * amount.toNumber());
+      const withdrawn = (await eventsOf(sig)).find((e) => e.name === "EarningsWithdrawn");
+      expect(withdrawn.data.amount.toNumber()).to.equal(2 * amount.toNumber());
+      const profile = await program.account.agentProfile.fetch(agent.profile);
+      expect(profile.availableEarnings.toNumber()).to.equal(0);
+    });
+
+    it("should reject withdrawing more than the available earnings", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { request, escrow } = await requestService(agent, "sentiment", amount);
+      await completeService(agent, request);
+      await finalizeCompletion(agent, request, escrow);
+
+      try {
+        await withdrawEarnings(agent, amount.addn(1));
+        expect.fail("expected InsufficientEarnings");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InsufficientEarnings");
+      }
+    });
+  });
+
+  describe("request_service_delegated", () => {
+    const amount = new anchor.BN(0.01 * LAMPORTS_PER_SOL);
+
+    // Mirrors `delegated_request_message` in the program
+    const delegatedMessage = (
+      requestId: Buffer,
+      providerOwner: PublicKey,
+      capability: string,
+      lamports: anchor.BN,
+      expiresAt: anchor.BN
+    ) =>
+      Buffer.concat([
+        Buffer.from("agentfund:request_service_delegated"),
+        program.programId.toBuffer(),
+        requestId,
+        providerOwner.toBuffer(),
+        lamports.toArrayLike(Buffer, "le", 8),
+        expiresAt.toTwos(64).toArrayLike(Buffer, "le", 8),
+        Buffer.from(capability),
+      ]);
+
+    // A requester agent holding one completed request's worth of earnings
+    const fundedRequester = async () => {
+      const requester = await registerProvider(["sentiment"]);
+      const { request, escrow } = await requestService(requester, "sentiment", amount);
+      await completeService(requester, request);
+      await finalizeCompletion(requester, request, escrow);
+      return requester;
+    };
+
+    const submitDelegated = (
+      requester: ProviderAgent,
+      agent: ProviderAgent,
+      requestId: Buffer,
+      expiresAt: anchor.BN,
+      signer: Keypair
+    ) => {
+      const [request] = pda(Buffer.from("request"), requestId);
+      const [escrow] = pda(Buffer.from("request_escrow"), requestId);
+      const signature = Ed25519Program.createInstructionWithPrivateKey({
+        privateKey: signer.secretKey,
+        message: delegatedMessage(requestId, agent.owner.publicKey, "sentiment", amount, expiresAt),
+      });
+      return program.methods
+        .requestServiceDelegated(Array.from(requestId) as number[], "sentiment", amount, expiresAt)
+        .accounts({
+          request,
+          providerProfile: agent.profile,
+          providerOwner: agent.owner.publicKey,
+          providerTreasury: agent.treasury,
+          escrow,
+          requester: requester.owner.publicKey,
+          requesterProfile: requester.profile,
+          requesterTreasury: requester.treasury,
+          relayer: provider.wallet.publicKey,
+          instructions: anchor.web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+          config: null,
+          bannedAgents: bannedAgentsPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .preInstructions([signature])
+        .rpc({ commitment: "confirmed" });
+    };
+
+    it("should open a relayed request funded from the requester's earnings", async () => {
+      const requester = await fundedRequester();
+      const agent = await registerProvider(["sentiment"]);
+      const requestId = Keypair.generate().publicKey.toBuffer();
+      const expiresAt = new anchor.BN(Math.floor(Date.now() / 1000) + 600);
+
+      const treasuryBefore = await provider.connection.getBalance(requester.treasury);
+      const sig = await submitDelegated(requester, agent, requestId, expiresAt, requester.owner);
+
+      const [request] = pda(Buffer.from("request"), requestId);
+      const [escrow] = pda(Buffer.from("request_escrow"), requestId);
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.requester.toBase58()).to.equal(requester.owner.publicKey.toBase58());
+      expect(account.amount.toNumber()).to.equal(amount.toNumber());
+      expect(await provider.connection.getBalance(escrow)).to.equal(amount.toNumber());
+
+      const treasuryAfter = await provider.connection.getBalance(requester.treasury);
+      expect(treasuryBefore - treasuryAfter).to.equal(amount.toNumber());
+      const profile = await program.account.agentProfile.fetch(requester.profile);
+      expect(profile.availableEarnings.toNumber()).to.equal(0);
+
+      const relayed = (await eventsOf(sig)).find((e) => e.name === "ServiceRequestRelayed");
+      expect(relayed.data.relayer.toBase58()).to.equal(provider.wallet.publicKey.toBase58());
+    });
+
+    it("should reject a request signed by someone other than the requester", async () => {
+      const requester = await fundedRequester();
+      const agent = await registerProvider(["sentiment"]);
+      const forger = Keypair.generate();
+      const requestId = Keypair.generate().publicKey.toBuffer();
+      const expiresAt = new anchor.BN(Math.floor(Date.now() / 1000) + 600);
+
+      try {
+        await submitDelegated(requester, agent, requestId, expiresAt, forger);
+        expect.fail("expected InvalidDelegatedSignature");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InvalidDelegatedSignature");
+      }
+
+      const profile = await program.account.agentProfile.fetch(requester.profile);
+      expect(profile.availableEarnings.toNumber()).to.equal(amount.toNumber());
+    });
+  });
+
+  describe("treasury_snapshots", () => {
+    it("should keep only the most recent snapshots in the ring buffer", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const [snapshots] = pda(Buffer.from("treasury_snapshots"), agent.treasury.toBuffer());
+
+      await program.methods
+        .initializeTreasurySnapshots(2)
+        .accounts({
+          snapshots,
+          treasury: agent.treasury,
+          owner: agent.owner.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([agent.owner])
+        .rpc();
+
+      const snapshot = () =>
+        program.methods
+          .snapshotTreasury()
+          .accounts({ snapshots, treasury: agent.treasury, owner: agent.owner.publicKey })
+          .signers([agent.owner])
+          .rpc({ commitment: "confirmed" });
+
+      // Each snapshot sees one more pending invoice than the last
+      await snapshot();
+      await createInvoice(agent.owner, agent.treasury);
+      await snapshot();
+      await createInvoice(agent.owner, agent.treasury);
+      const sig = await snapshot();
+
+      const taken = (await eventsOf(sig)).find((e) => e.name === "TreasurySnapshotTaken");
+      expect(taken.data.pendingInvoices.toNumber()).to.equal(2);
+
+      const ring = await program.account.treasurySnapshots.fetch(snapshots);
+      expect(ring.totalTaken.toNumber()).to.equal(3);
+      expect(ring.entries.length).to.equal(2);
+      // The third snapshot overwrote the first, so the oldest now sits in slot 1
+      expect(ring.nextIndex).to.equal(1);
+      expect(ring.entries.map((e) => e.pendingInvoices.toNumber())).to.deep.equal([2, 1]);
+    });
+
+    it("should reject a capacity above the maximum", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const [snapshots] = pda(Buffer.from("treasury_snapshots"), agent.treasury.toBuffer());
+
+      try {
+        await program.methods
+          .initializeTreasurySnapshots(17)
+          .accounts({
+            snapshots,
+            treasury: agent.treasury,
+            owner: agent.owner.publicKey,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .signers([agent.owner])
+          .rpc();
+        expect.fail("expected InvalidSnapshotCapacity");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InvalidSnapshotCapacity");
+      }
+    });
+  });
+
+  describe("capability_case_insensitivity", () => {
+    it("should match a differently cased request against a stored capability", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { request } = await requestService(agent, "Sentiment");
+
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.capability).to.equal("sentiment");
+    });
+
+    it("should store capabilities and aliases in lowercase", async () => {
+      const agent = await registerProvider(["Translation"]);
+      await program.methods
+        .addCapabilityAlias("Translate", "TRANSLATION")
+        .accounts({ agentProfile: agent.profile, owner: agent.owner.publicKey })
+        .signers([agent.owner])
+        .rpc();
+
+      const profile = await program.account.agentProfile.fetch(agent.profile);
+      expect(profile.capabilities).to.deep.equal(["translation"]);
+      expect(profile.capabilityAliases[0].alias).to.equal("translate");
+
+      const { request } = await requestService(agent, "TRANSLATE");
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.capability).to.equal("translation");
+    });
+  });
+
+  describe("splice_channel", () => {
+    const deposit = new anchor.BN(0.1 * LAMPORTS_PER_SOL);
+    const toB = new anchor.BN(0.03 * LAMPORTS_PER_SOL);
+
+    // An open channel where party A has paid `toB` to party B at nonce 1
+    const paidChannel = async () => {
+      const partyB = Keypair.generate();
+      await airdrop(partyB.publicKey);
+      const { channel, channelEscrow } = await openChannel(partyB, deposit);
+      await program.methods
+        .updateChannel(deposit.sub(toB), toB, new anchor.BN(1))
+        .accounts({ channel, partyA: provider.wallet.publicKey, partyB: partyB.publicKey })
+        .signers([partyB])
+        .rpc();
+      return { partyB, channel, channelEscrow };
+    };
+
+    const splice = (
+      partyB: Keypair,
+      channel: PublicKey,
+      channelEscrow: PublicKey,
+      withdrawA: anchor.BN,
+      withdrawB: anchor.BN,
+      nonce: number
+    ) =>
+      program.methods
+        .spliceChannel(withdrawA, withdrawB, new anchor.BN(nonce))
+        .accounts({
+          channel,
+          channelEscrow,
+          partyA: provider.wallet.publicKey,
+          partyB: partyB.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([partyB])
+        .rpc({ commitment: "confirmed" });
+
+    it("should pay out partial balances and keep the channel open", async () => {
+      const { partyB, channel, channelEscrow } = await paidChannel();
+      const withdrawA = new anchor.BN(0.02 * LAMPORTS_PER_SOL);
+      const withdrawB = new anchor.BN(0.01 * LAMPORTS_PER_SOL);
+
+      const escrowBefore = await provider.connection.getBalance(channelEscrow);
+      const partyBBefore = await provider.connection.getBalance(partyB.publicKey);
+      const sig = await splice(partyB, channel, channelEscrow, withdrawA, withdrawB, 2);
+
+      const escrowAfter = await provider.connection.getBalance(channelEscrow);
+      expect(escrowBefore - escrowAfter).to.equal(withdrawA.add(withdrawB).toNumber());
+      const partyBAfter = await provider.connection.getBalance(partyB.publicKey);
+      expect(partyBAfter - partyBBefore).to.equal(withdrawB.toNumber());
+
+      const state = await program.account.paymentChannel.fetch(channel);
+      const remainingA = deposit.sub(toB).sub(withdrawA).toNumber();
+      const remainingB = toB.sub(withdrawB).toNumber();
+      expect(state.balanceA.toNumber()).to.equal(remainingA);
+      expect(state.balanceB.toNumber()).to.equal(remainingB);
+      expect(state.depositA.toNumber()).to.equal(remainingA);
+      expect(state.depositB.toNumber()).to.equal(remainingB);
+      expect(state.nonce.toNumber()).to.equal(2);
+      expect(state.status).to.deep.equal({ open: {} });
+
+      const [spliced] = await eventsOf(sig);
+      expect(spliced.name).to.equal("ChannelSpliced");
+      expect(spliced.data.withdrawB.toNumber()).to.equal(withdrawB.toNumber());
+    });
+
+    it("should reject withdrawing more than a party's balance", async () => {
+      const { partyB, channel, channelEscrow } = await paidChannel();
+
+      try {
+        await splice(partyB, channel, channelEscrow, new anchor.BN(0), toB.addn(1), 2);
+        expect.fail("expected InsufficientChannelBalance");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InsufficientChannelBalance");
+      }
+    });
+
+    it("should reject a splice that does not advance the nonce", async () => {
+      const { partyB, channel, channelEscrow } = await paidChannel();
+
+      try {
+        await splice(partyB, channel, channelEscrow, new anchor.BN(1000), new anchor.BN(0), 1);
+        expect.fail("expected InvalidNonce");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InvalidNonce");
+      }
+    });
+  });
+
+  describe("sponsored_invoice_rent", () => {
+    const createSponsored = (agent: ProviderAgent, sponsor: Keypair | null) => {
+      const invoiceId = Keypair.generate().publicKey.toBuffer();
+      const [invoice] = pda(Buffer.from("invoice"), invoiceId);
+      const signers = sponsor ? [agent.owner, sponsor] : [agent.owner];
+      return program.methods
+        .createInvoice(
+          Array.from(invoiceId) as number[],
+          new anchor.BN(0.001 * LAMPORTS_PER_SOL),
+          "Test",
+          new anchor.BN(Math.floor(Date.now() / 1000) + 3600),
+          null,
+          null
+        )
+        .accounts({
+          invoice,
+          treasury: agent.treasury,
+          recipient: agent.owner.publicKey,
+          rentPayer: sponsor ? sponsor.publicKey : null,
+          config: configPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers(signers)
+        .rpc({ commitment: "confirmed" })
+        .then(() => invoice);
+    };
+
+    it("should let a sponsor pay the rent while the recipient owns the invoice", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const sponsor = Keypair.generate();
+      await airdrop(sponsor.publicKey);
+
+      const recipientBefore = await provider.connection.getBalance(agent.owner.publicKey);
+      const sponsorBefore = await provider.connection.getBalance(sponsor.publicKey);
+      const invoice = await createSponsored(agent, sponsor);
+
+      const rent = await provider.connection.getBalance(invoice);
+      expect(sponsorBefore - (await provider.connection.getBalance(sponsor.publicKey))).to.equal(rent);
+      expect(await provider.connection.getBalance(agent.owner.publicKey)).to.equal(recipientBefore);
+
+      const account = await program.account.invoice.fetch(invoice);
+      expect(account.recipient.toBase58()).to.equal(agent.owner.publicKey.toBase58());
+      expect(account.status).to.deep.equal({ pending: {} });
+      const treasury = await program.account.treasury.fetch(agent.treasury);
+      expect(treasury.pendingInvoices.toNumber()).to.equal(1);
+    });
+
+    it("should charge the recipient when no sponsor signs", async () => {
+      const agent = await registerProvider(["sentiment"]);
+
+      const recipientBefore = await provider.connection.getBalance(agent.owner.publicKey);
+      const invoice = await createSponsored(agent, null);
+
+      const rent = await provider.connection.getBalance(invoice);
+      expect(recipientBefore - (await provider.connection.getBalance(agent.owner.publicKey))).to.equal(rent);
+    });
+  });
+
+  describe("dispute_escalation", () => {
+    // Stands in for the DAO program's signing PDA
+    const dao = Keypair.generate();
+
+    const setEscalation = (disputeDao: PublicKey, disputeEscalationSeconds: number) =>
+      program.methods
+        .updateProtocolConfig({
+          disputeDao,
+          disputeEscalationSeconds: new anchor.BN(disputeEscalationSeconds),
+        } as any)
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+
+    const escalate = (request: PublicKey, dispute: PublicKey, party: Keypair) =>
+      program.methods
+        .escalateDispute()
+        .accounts({ request, dispute, config: configPDA, party: party.publicKey })
+        .signers([party])
+        .rpc({ commitment: "confirmed" });
+
+    const openDispute = async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { requestId, request, escrow } = await requestService(agent, "sentiment");
+      const dispute = await initiateDispute(agent, request, requestId);
+      return { agent, request, escrow, dispute };
+    };
+
+    before(async () => {
+      await setEscalation(dao.publicKey, 0);
+    });
+
+    after(async () => {
+      await setEscalation(PublicKey.default, 3 * 24 * 60 * 60);
+    });
+
+    it("should hand an escalated dispute to the DAO for resolution", async () => {
+      const { agent, request, escrow, dispute } = await openDispute();
+
+      const sig = await escalate(request, dispute, agent.owner);
+      const [escalated] = await eventsOf(sig);
+      expect(escalated.name).to.equal("DisputeEscalated");
+      expect(escalated.data.escalatedTo.toBase58()).to.equal(dao.publicKey.toBase58());
+      const pending = await program.account.dispute.fetch(dispute);
+      expect(pending.status).to.deep.equal({ underReview: {} });
+
+      // The parties can no longer settle it between themselves
+      try {
+        await resolveDispute(agent, request, dispute, escrow, { payProvider: {} });
+        expect.fail("expected Unauthorized");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("Unauthorized");
+      }
+
+      await program.methods
+        .resolveDispute({ refundRequester: {} })
+        .accounts({
+          request,
+          dispute,
+          config: configPDA,
+          resolver: dao.publicKey,
+          requester: provider.wallet.publicKey,
+          provider: agent.owner.publicKey,
+          escrow,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([dao])
+        .rpc();
+
+      const resolved = await program.account.dispute.fetch(dispute);
+      expect(resolved.status).to.deep.equal({ resolved: {} });
+      expect(resolved.resolution).to.deep.equal({ refundRequester: {} });
+    });
+
+    it("should reject escalation by someone outside the dispute", async () => {
+      const { request, dispute } = await openDispute();
+      const stranger = Keypair.generate();
+
+      try {
+        await escalate(request, dispute, stranger);
+        expect.fail("expected Unauthorized");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("Unauthorized");
+      }
+    });
+
+    it("should reject escalation before the threshold", async () => {
+      await setEscalation(dao.publicKey, 3600);
+      try {
+        const { agent, request, dispute } = await openDispute();
+        await escalate(request, dispute, agent.owner);
+        expect.fail("expected EscalationTooEarly");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("EscalationTooEarly");
+      } finally {
+        await setEscalation(dao.publicKey, 0);
+      }
+    });
+  });
+
+  describe("min_channel_deposit", () => {
+    const floor = 0.05 * LAMPORTS_PER_SOL;
+
+    const setMinChannelDeposit = (minChannelDeposit: number) =>
+      program.methods
+        .updateProtocolConfig({ minChannelDeposit: new anchor.BN(minChannelDeposit) } as any)
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+
+    const openChannelWithConfig = async (deposit: number) => {
+      const channelId = Keypair.generate().publicKey.toBuffer();
+      const [channel] = pda(Buffer.from("channel"), channelId);
+      const [channelEscrow] = pda(Buffer.from("escrow"), channelId);
+
+      await program.methods
+        .openChannel(Array.from(channelId) as number[], new anchor.BN(deposit))
+        .accounts({
+          channel,
+          channelEscrow,
+          partyA: provider.wallet.publicKey,
+          partyB: Keypair.generate().publicKey,
+          config: configPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+      return channel;
+    };
+
+    before(() => setMinChannelDeposit(floor));
+    after(() => setMinChannelDeposit(0));
+
+    it("should accept a deposit at the minimum", async () => {
+      const channel = await openChannelWithConfig(floor);
+
+      const account = await program.account.paymentChannel.fetch(channel);
+      expect(account.depositA.toNumber()).to.equal(floor);
+    });
+
+    it("should reject a deposit below the minimum", async () => {
+      try {
+        await openChannelWithConfig(floor - 1);
+        expect.fail("expected DepositBelowMinimum");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("DepositBelowMinimum");
+      }
+    });
+  });
+
+  describe("result_hash_algorithm", () => {
+    const completeWith = async (hashAlgorithm: any) => {
+      const agent = await registerProvider(["sentiment"]);
+      const { request } = await requestService(agent, "sentiment");
+      const sig = await completeService(agent, request, Array(32).fill(7), hashAlgorithm);
+      const completed = (await eventsOf(sig)).find((e) => e.name === "ServiceCompleted");
+      const account = await program.account.serviceRequest.fetch(request);
+      return { event: completed.data.hashAlgorithm, stored: account.hashAlgorithm };
+    };
+
+    it("should default to sha256 when no algorithm is given", async () => {
+      const { event, stored } = await completeWith(null);
+      expect(event).to.deep.equal({ sha256: {} });
+      expect(stored).to.deep.equal({ sha256: {} });
+    });
+
+    it("should round-trip keccak256 and blake3 tags", async () => {
+      for (const tag of [{ keccak256: {} }, { blake3: {} }]) {
+        const { event, stored } = await completeWith(tag);
+        expect(event).to.deep.equal(tag);
+        expect(stored).to.deep.equal(tag);
+      }
+    });
+  });
+
+  describe("treasury_profile_mismatch", () => {
+    it("should reject releasing payment into another agent's treasury", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const other = await registerProvider(["sentiment"]);
+      const { request, escrow } = await requestService(agent, "sentiment");
+      await completeService(agent, request);
+
+      try {
+        await program.methods
+          .finalizeCompletion()
+          .accounts({
+            request,
+            providerProfile: agent.profile,
+            escrow,
+            providerTreasury: other.treasury,
+            provider: agent.owner.publicKey,
+            requester: provider.wallet.publicKey,
+            config: configPDA,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .rpc();
+        expect.fail("expected TreasuryProfileMismatch");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("TreasuryProfileMismatch");
+      }
+
+      const pending = await program.account.serviceRequest.fetch(request);
+      expect(pending.status).to.deep.equal({ pendingReview: {} });
+    });
+  });
+
+  describe("duplicate_ids", () => {
+    const expectCode = async (action: () => Promise<unknown>, code: string) => {
+      try {
+        await action();
+        expect.fail(`expected ${code}`);
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal(code);
+      }
+    };
+
+    it("should reject reusing an invoice id", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { invoiceId, invoice } = await createInvoice(agent.owner, agent.treasury);
+
+      await expectCode(
+        () =>
+          program.methods
+            .createInvoice(
+              Array.from(invoiceId) as number[],
+              new anchor.BN(0.001 * LAMPORTS_PER_SOL),
+              "Test",
+              new anchor.BN(Math.floor(Date.now() / 1000) + 3600),
+              null,
+              null
+            )
+            .accounts({
+              invoice,
+              treasury: agent.treasury,
+              recipient: agent.owner.publicKey,
+              config: configPDA,
+              systemProgram: anchor.web3.SystemProgram.programId,
+            })
+            .signers([agent.owner])
+            .rpc(),
+        "InvoiceIdAlreadyExists"
+      );
+    });
+
+    it("should reject reusing a batch id", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const batchId = Keypair.generate().publicKey.toBuffer();
+      const [batch] = pda(Buffer.from("batch"), batchId);
+      const settle = async () => {
+        const { invoiceId, invoice } = await createInvoice(agent.owner, agent.treasury, new anchor.BN(1000));
+        return program.methods
+          .settleBatch(Array.from(batchId) as number[], [Array.from(invoiceId) as number[]], new anchor.BN(1000))
+          .accounts({
+            batch,
+            treasury: agent.treasury,
+            settler: provider.wallet.publicKey,
+            recipient: agent.owner.publicKey,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .remainingAccounts(invoiceAccounts([invoice]))
+          .rpc();
+      };
+
+      await settle();
+      await expectCode(settle, "BatchIdAlreadyExists");
+    });
+
+    it("should reject reusing a channel id", async () => {
+      const channelId = Keypair.generate().publicKey.toBuffer();
+      const [channel] = pda(Buffer.from("channel"), channelId);
+      const [channelEscrow] = pda(Buffer.from("escrow"), channelId);
+      const open = () =>
+        program.methods
+          .openChannel(Array.from(channelId) as number[], new anchor.BN(0.01 * LAMPORTS_PER_SOL))
+          .accounts({
+            channel,
+            channelEscrow,
+            partyA: provider.wallet.publicKey,
+            partyB: Keypair.generate().publicKey,
+            config: configPDA,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .rpc();
+
+      await open();
+      await expectCode(open, "ChannelIdAlreadyExists");
+    });
+
+    it("should reject reusing a request id", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { requestId, request, escrow } = await requestService(agent, "sentiment");
+
+      await expectCode(
+        () =>
+          program.methods
+            .requestService(
+              Array.from(requestId) as number[],
+              "sentiment",
+              new anchor.BN(0.01 * LAMPORTS_PER_SOL),
+              null,
+              null,
+              null
+            )
+            .accounts({
+              request,
+              providerProfile: agent.profile,
+              providerOwner: agent.owner.publicKey,
+              providerTreasury: agent.treasury,
+              escrow,
+              requester: provider.wallet.publicKey,
+              oracle: null,
+              bannedAgents: bannedAgentsPDA,
+              systemProgram: anchor.web3.SystemProgram.programId,
+            })
+            .rpc(),
+        "RequestIdAlreadyExists"
+      );
+    });
+  });
+
+  describe("marketplace_stats", () => {
+    const [stats] = pda(Buffer.from("marketplace_stats"));
+
+    before(async () => {
+      await program.methods
+        .initializeMarketplaceStats()
+        .accounts({
+          stats,
+          payer: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+    });
+
+    it("should count registered agents", async () => {
+      const before = await program.account.marketplaceStats.fetch(stats);
+      const owner = Keypair.generate();
+      await airdrop(owner.publicKey);
+      const [treasury] = pda(Buffer.from("treasury"), owner.publicKey.toBuffer());
+      const [agentProfile] = pda(Buffer.from("agent"), owner.publicKey.toBuffer());
+
+      const sig = await program.methods
+        .registerAgentWithTreasury("Stats Agent", "Counted agent", ["sentiment"], new anchor.BN(1000), [])
+        .accounts({
+          agentProfile,
+          treasury,
+          owner: owner.publicKey,
+          stats,
+          bannedAgents: bannedAgentsPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([owner])
+        .rpc({ commitment: "confirmed" });
+
+      const after = await program.account.marketplaceStats.fetch(stats);
+      expect(after.totalAgents.toNumber()).to.equal(before.totalAgents.toNumber() + 1);
+      const event = (await eventsOf(sig)).find((e) => e.name === "MarketplaceStatsUpdated");
+      expect(event?.data.totalAgents.toNumber()).to.equal(after.totalAgents.toNumber());
+    });
+
+    it("should count requests, volume and completions", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const before = await program.account.marketplaceStats.fetch(stats);
+      const amount = new anchor.BN(0.02 * LAMPORTS_PER_SOL);
+
+      for (let i = 0; i < 2; i++) {
+        const requestId = Keypair.generate().publicKey.toBuffer();
+        const [request] = pda(Buffer.from("request"), requestId);
+        const [escrow] = pda(Buffer.from("request_escrow"), requestId);
+        await program.methods
+          .requestService(Array.from(requestId) as number[], "sentiment", amount, null, null, null)
+          .accounts({
+            request,
+            providerProfile: agent.profile,
+            providerOwner: agent.owner.publicKey,
+            providerTreasury: agent.treasury,
+            escrow,
+            requester: provider.wallet.publicKey,
+            oracle: null,
+            stats,
+            bannedAgents: bannedAgentsPDA,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .rpc();
+
+        if (i === 0) {
+          await program.methods
+            .completeService(Array(32).fill(7), null)
+            .accounts({
+              request,
+              providerProfile: agent.profile,
+              provider: agent.owner.publicKey,
+              owner: agent.owner.publicKey,
+              stats,
+            })
+            .signers([agent.owner])
+            .rpc();
+        }
+      }
+
+      const after = await program.account.marketplaceStats.fetch(stats);
+      expect(after.totalRequests.toNumber()).to.equal(before.totalRequests.toNumber() + 2);
+      expect(after.totalVolume.toNumber()).to.equal(
+        before.totalVolume.toNumber() + 2 * amount.toNumber()
+      );
+      expect(after.totalCompleted.toNumber()).to.equal(before.totalCompleted.toNumber() + 1);
+      expect(after.totalAgents.toNumber()).to.equal(before.totalAgents.toNumber());
+    });
+
+    it("should count oracle-attested completions", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const oracle = Keypair.generate();
+      const { request, escrow } = await requestService(agent, "sentiment", undefined, {
+        oracle: oracle.publicKey,
+      });
+      const before = await program.account.marketplaceStats.fetch(stats);
+
+      await program.methods
+        .attestCompletion(Array(32).fill(7), null)
+        .accounts({
+          oracle: oracle.publicKey,
+          completion: {
+            request,
+            providerProfile: agent.profile,
+            escrow,
+            providerTreasury: agent.treasury,
+            provider: agent.owner.publicKey,
+            requester: provider.wallet.publicKey,
+            config: configPDA,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          },
+          stats,
+        } as any)
+        .signers([oracle])
+        .rpc();
+
+      const after = await program.account.marketplaceStats.fetch(stats);
+      expect(after.totalCompleted.toNumber()).to.equal(before.totalCompleted.toNumber() + 1);
+    });
+
+    it("should leave the counters alone when stats are not supplied", async () => {
+      const before = await program.account.marketplaceStats.fetch(stats);
+      const agent = await registerProvider(["sentiment"]);
+      await requestService(agent, "sentiment");
+
+      const after = await program.account.marketplaceStats.fetch(stats);
+      expect(after.totalAgents.toNumber()).to.equal(before.totalAgents.toNumber());
+      expect(after.totalRequests.toNumber()).to.equal(before.totalRequests.toNumber());
+    });
+
+    it("should track a time-weighted average price per capability", async () => {
+      const capability = `twap-${Keypair.generate().publicKey.toBase58().slice(0, 8)}`;
+      const capabilityHash = createHash("sha256").update(capability).digest();
+      const agent = await registerProvider([capability]);
+      const requestAt = async (price: number) => {
+        const requestId = Keypair.generate().publicKey.toBuffer();
+        await program.methods
+          .requestService(Array.from(requestId) as number[], capability, new anchor.BN(price), null, null, null)
+          .accounts({
+            request: pda(Buffer.from("request"), requestId)[0],
+            providerProfile: agent.profile,
+            providerOwner: agent.owner.publicKey,
+            providerTreasury: agent.treasury,
+            escrow: pda(Buffer.from("request_escrow"), requestId)[0],
+            requester: provider.wallet.publicKey,
+            oracle: null,
+            stats,
+            bannedAgents: bannedAgentsPDA,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .rpc();
+        const account = await program.account.marketplaceStats.fetch(stats);
+        return account.capabilityPrices.find((entry) =>
+          Buffer.from(entry.capabilityHash).equals(capabilityHash)
+        );
+      };
+
+      const first = await requestAt(10_000);
+      expect(first.lastPrice.toNumber()).to.equal(10_000);
+      expect(first.totalElapsed.toNumber()).to.equal(0);
+
+      await sleep(2000);
+      const second = await requestAt(30_000);
+      const firstInterval = second.totalElapsed.toNumber();
+      expect(firstInterval).to.be.greaterThan(0);
+      expect(second.cumulativePriceTime.toNumber()).to.equal(10_000 * firstInterval);
+
+      await sleep(2000);
+      const third = await requestAt(20_000);
+      const secondInterval = third.totalElapsed.toNumber() - firstInterval;
+      expect(secondInterval).to.be.greaterThan(0);
+      expect(third.cumulativePriceTime.toNumber()).to.equal(
+        10_000 * firstInterval + 30_000 * secondInterval
+      );
+      expect(third.lastPrice.toNumber()).to.equal(20_000);
+
+      const twap = third.cumulativePriceTime.toNumber() / third.totalElapsed.toNumber();
+      expect(twap).to.be.within(10_000, 30_000);
+    });
+  });
+
+  describe("get_channel_state", () => {
+    it("should report balances, nonce and status of an open channel", async () => {
+      const partyB = Keypair.generate();
+      const deposit = new anchor.BN(0.1 * LAMPORTS_PER_SOL);
+      const { channel } = await openChannel(partyB, deposit);
+
+      await program.methods
+        .updateChannel(deposit.subn(3000), new anchor.BN(3000), new anchor.BN(4))
+        .accounts({ channel, partyA: provider.wallet.publicKey, partyB: partyB.publicKey })
+        .signers([partyB])
+        .rpc();
+
+      const state = await program.methods.getChannelState().accounts({ channel }).view();
+      expect(state.balanceA.toString()).to.equal(deposit.subn(3000).toString());
+      expect(state.balanceB.toNumber()).to.equal(3000);
+      expect(state.nonce.toNumber()).to.equal(4);
+      expect(state.status).to.deep.equal({ open: {} });
+      expect(state.challengeDeadline).to.be.null;
+    });
+
+    it("should include the challenge deadline while a close is pending", async () => {
+      const partyB = Keypair.generate();
+      const deposit = new anchor.BN(0.1 * LAMPORTS_PER_SOL);
+      const { channelId, channel } = await openChannel(partyB, deposit);
+
+      await program.methods
+        .initiateChannelClose(deposit.subn(1000), new anchor.BN(1000), new anchor.BN(1))
+        .accounts({
+          channel,
+          party: provider.wallet.publicKey,
+          instructions: anchor.web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+        })
+        .preInstructions([
+          signChannelState(partyB, channelId, deposit.subn(1000), new anchor.BN(1000), new anchor.BN(1)),
+        ])
+        .rpc();
+
+      const account = await program.account.paymentChannel.fetch(channel);
+      const state = await program.methods.getChannelState().accounts({ channel }).view();
+      expect(state.status).to.deep.equal({ closing: {} });
+      expect(state.nonce.toNumber()).to.equal(1);
+      expect(state.balanceB.toNumber()).to.equal(1000);
+      expect(state.challengeDeadline.toNumber()).to.equal(account.closeDeadline.toNumber());
+    });
+  });
+
+  describe("reclaim_service_request", () => {
+    const reclaim = (agent: ProviderAgent, request: PublicKey, requestId: Buffer, escrow: PublicKey) =>
+      program.methods
+        .reclaimServiceRequest()
+        .accounts({
+          request,
+          dispute: pda(Buffer.from("dispute"), requestId)[0],
+          providerProfile: agent.profile,
+          escrow,
+          provider: agent.owner.publicKey,
+          requester: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+    const expectReclaimError = async (action: Promise<unknown>, code: string) => {
+      try {
+        await action;
+        expect.fail(`expected ${code}`);
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal(code);
+      }
+    };
+
+    it("should block reclaiming a request disputed just before", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { requestId, request, escrow } = await requestService(agent, "sentiment");
+      await acceptRequest(agent, request, escrow);
+
+      await initiateDispute(agent, request, requestId);
+      await expectReclaimError(reclaim(agent, request, requestId, escrow), "RequestWasDisputed");
+
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.status).to.deep.equal({ disputed: {} });
+    });
+
+    it("should keep blocking reclaim after the dispute is withdrawn", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { requestId, request, escrow } = await requestService(agent, "sentiment");
+      const dispute = await initiateDispute(agent, request, requestId);
+      await program.methods
+        .cancelDispute()
+        .accounts({
+          request,
+          dispute,
+          providerProfile: agent.profile,
+          initiator: provider.wallet.publicKey,
+        })
+        .rpc();
+
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.status).to.deep.equal({ pending: {} });
+      await expectReclaimError(reclaim(agent, request, requestId, escrow), "RequestWasDisputed");
+    });
+
+    it("should reject reclaiming while the dispute window is open", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { requestId, request, escrow } = await requestService(agent, "sentiment");
+      await acceptRequest(agent, request, escrow);
+
+      await expectReclaimError(reclaim(agent, request, requestId, escrow), "ReclaimTooEarly");
+    });
+  });
+
+  describe("free_services", () => {
+    it("should carry a zero-amount request through completion without moving lamports", async () => {
+      const agent = await registerProvider(["sentiment"], new anchor.BN(0));
+      const treasuryBefore = await provider.connection.getBalance(agent.treasury);
+
+      const { request, escrow } = await requestService(agent, "sentiment", new anchor.BN(0));
+      expect(await provider.connection.getAccountInfo(escrow)).to.be.null;
+
+      await completeService(agent, request);
+      await finalizeCompletion(agent, request, escrow);
+
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.amount.toNumber()).to.equal(0);
+      expect(account.status).to.deep.equal({ completed: {} });
+      expect(await provider.connection.getAccountInfo(escrow)).to.be.null;
+      expect(await provider.connection.getBalance(agent.treasury)).to.equal(treasuryBefore);
+
+      const profile = await program.account.agentProfile.fetch(agent.profile);
+      expect(profile.totalRequests.toNumber()).to.equal(1);
+      expect(profile.availableEarnings.toNumber()).to.equal(0);
+    });
+
+    it("should let a free request be cancelled", async () => {
+      const agent = await registerProvider(["sentiment"], new anchor.BN(0));
+      const { request, escrow } = await requestService(agent, "sentiment", new anchor.BN(0));
+
+      await program.methods
+        .cancelServiceRequest()
+        .accounts({
+          request,
+          providerProfile: agent.profile,
+          escrow,
+          requester: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.status).to.deep.equal({ cancelled: {} });
+    });
+
+    it("should still enforce a nonzero base price", async () => {
+      const agent = await registerProvider(["sentiment"], new anchor.BN(1000));
+      try {
+        await requestService(agent, "sentiment", new anchor.BN(0));
+        expect.fail("expected InvalidAmount");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InvalidAmount");
+      }
+    });
+  });
+
+  describe("batch_reversal", () => {
+    // Settles two fresh invoices for `agent` from the test wallet
+    const settleInvoices = async (agent: ProviderAgent, totalAmount: anchor.BN) => {
+      const invoices = [
+        await createInvoice(agent.owner, agent.treasury),
+        await createInvoice(agent.owner, agent.treasury),
+      ];
+      const batchId = Keypair.generate().publicKey.toBuffer();
+      const [batch] = pda(Buffer.from("batch"), batchId);
+
+      await program.methods
+        .settleBatch(
+          Array.from(batchId) as number[],
+          invoices.map(({ invoiceId }) => Array.from(invoiceId) as number[]),
+          totalAmount
+        )
+        .accounts({
+          batch,
+          treasury: agent.treasury,
+          settler: provider.wallet.publicKey,
+          recipient: agent.owner.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .remainingAccounts(invoiceAccounts(invoices.map(({ invoice }) => invoice)))
+        .rpc();
+
+      return { batchId, batch, invoices: invoices.map(({ invoice }) => invoice) };
+    };
+
+    const batchStatus = (batchId: Buffer) =>
+      program.methods
+        .getBatchStatus(Array.from(batchId) as number[])
+        .accounts({ batch: pda(Buffer.from("batch"), batchId)[0] })
+        .view();
+
+    it("should report a settled batch and no status for an unused id", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { batchId, batch } = await settleInvoices(agent, new anchor.BN(0.002 * LAMPORTS_PER_SOL));
+
+      const account = await program.account.batchSettlement.fetch(batch);
+      expect(account.status).to.deep.equal({ settled: {} });
+      expect(account.invoiceIds).to.have.length(2);
+      expect(await batchStatus(batchId)).to.deep.equal({ settled: {} });
+      expect(await batchStatus(Keypair.generate().publicKey.toBuffer())).to.be.null;
+    });
+
+    it("should return funds and reopen invoices on a cooperative reversal", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const totalAmount = new anchor.BN(0.002 * LAMPORTS_PER_SOL);
+      const { batchId, batch, invoices } = await settleInvoices(agent, totalAmount);
+      const recipientBefore = await provider.connection.getBalance(agent.owner.publicKey);
+
+      await program.methods
+        .reverseBatch()
+        .accounts({
+          batch,
+          treasury: agent.treasury,
+          settler: provider.wallet.publicKey,
+          recipient: agent.owner.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .remainingAccounts(invoiceAccounts(invoices))
+        .signers([agent.owner])
+        .rpc();
+
+      expect(await provider.connection.getBalance(agent.owner.publicKey)).to.equal(
+        recipientBefore - totalAmount.toNumber()
+      );
+      expect(await batchStatus(batchId)).to.deep.equal({ reversed: {} });
+
+      const treasury = await program.account.treasury.fetch(agent.treasury);
+      expect(treasury.totalSettled.toNumber()).to.equal(0);
+      expect(treasury.pendingInvoices.toNumber()).to.equal(2);
+      for (const invoice of invoices) {
+        const reopened = await program.account.invoice.fetch(invoice);
+        expect(reopened.status).to.deep.equal({ pending: {} });
+        expect(reopened.payer).to.be.null;
+      }
+    });
+
+    it("should not reverse a batch twice", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { batch, invoices } = await settleInvoices(agent, new anchor.BN(0.002 * LAMPORTS_PER_SOL));
+      const reverse = () =>
+        program.methods
+          .reverseBatch()
+          .accounts({
+            batch,
+            treasury: agent.treasury,
+            settler: provider.wallet.publicKey,
+            recipient: agent.owner.publicKey,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .remainingAccounts(invoiceAccounts(invoices))
+          .signers([agent.owner])
+          .rpc();
+
+      await reverse();
+      try {
+        await reverse();
+        expect.fail("expected BatchNotSettled");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("BatchNotSettled");
+      }
+    });
+
+    it("should not reopen invoices settled by another batch", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const amount = new anchor.BN(0.002 * LAMPORTS_PER_SOL);
+      const first = await settleInvoices(agent, amount);
+      const second = await settleInvoices(agent, amount);
+
+      try {
+        await program.methods
+          .reverseBatch()
+          .accounts({
+            batch: first.batch,
+            treasury: agent.treasury,
+            settler: provider.wallet.publicKey,
+            recipient: agent.owner.publicKey,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .remainingAccounts(invoiceAccounts(second.invoices))
+          .signers([agent.owner])
+          .rpc();
+        expect.fail("expected InvalidInvoiceAccount");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InvalidInvoiceAccount");
+      }
+
+      for (const invoice of second.invoices) {
+        const account = await program.account.invoice.fetch(invoice);
+        expect(account.status).to.deep.equal({ paid: {} });
+      }
+    });
+  });
+
+  describe("fee_volume_tiers", () => {
+    const feeCollector = Keypair.generate();
+    const amount = new anchor.BN(0.01 * LAMPORTS_PER_SOL);
+    const feeBps = 200;
+
+    const setConfig = (params: Record<string, unknown>) =>
+      program.methods
+        .updateProtocolConfig(params as any)
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+
+    // Pays a fresh invoice to `recipient` and returns the fee collected
+    const payWithProfile = async (recipient: ProviderAgent) => {
+      const { invoice } = await createInvoice(recipient.owner, recipient.treasury, amount);
+      const before = await provider.connection.getBalance(feeCollector.publicKey);
+      await program.methods
+        .payInvoice(null)
+        .accounts({
+          invoice,
+          treasury: recipient.treasury,
+          payer: provider.wallet.publicKey,
+          recipient: recipient.owner.publicKey,
+          config: configPDA,
+          feeCollector: feeCollector.publicKey,
+          recipientProfile: recipient.profile,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+      return (await provider.connection.getBalance(feeCollector.publicKey)) - before;
+    };
+
+    before(async () => {
+      await airdrop(feeCollector.publicKey);
+      await setConfig({
+        invoiceFeeBps: feeBps,
+        feeCollector: feeCollector.publicKey,
+        feeTierThresholds: [new anchor.BN(1), new anchor.BN(0), new anchor.BN(0)],
+        feeTierDiscountBps: [5000, 0, 0],
+      });
+    });
+
+    after(() =>
+      setConfig({
+        invoiceFeeBps: 0,
+        feeCollector: provider.wallet.publicKey,
+        feeTierThresholds: [new anchor.BN(0), new anchor.BN(0), new anchor.BN(0)],
+        feeTierDiscountBps: [0, 0, 0],
+      })
+    );
+
+    it("should charge a new agent the full fee", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      expect(await payWithProfile(agent)).to.equal((amount.toNumber() * feeBps) / 10_000);
+    });
+
+    it("should discount the fee for an agent past the volume threshold", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { request, escrow } = await requestService(agent, "sentiment");
+      await completeService(agent, request);
+      await finalizeCompletion(agent, request, escrow);
+
+      const profile = await program.account.agentProfile.fetch(agent.profile);
+      expect(profile.totalRequests.toNumber()).to.equal(1);
+      expect(await payWithProfile(agent)).to.equal((amount.toNumber() * feeBps) / 2 / 10_000);
+    });
+
+    it("should reject thresholds that are not ascending", async () => {
+      try {
+        await setConfig({
+          feeTierThresholds: [new anchor.BN(10), new anchor.BN(5), new anchor.BN(0)],
+        });
+        expect.fail("expected InvalidFeeTiers");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InvalidFeeTiers");
+      }
+    });
+  });
+
+  describe("channel_state_hash", () => {
+    const u64le = (value: anchor.BN) => value.toArrayLike(Buffer, "le", 8);
+    const commitment = (channelId: Buffer, balanceA: anchor.BN, balanceB: anchor.BN, nonce: anchor.BN) =>
+      Array.from(
+        createHash("sha256")
+          .update(Buffer.concat([channelId, u64le(balanceA), u64le(balanceB), u64le(nonce)]))
+          .digest()
+      );
+
+    it("should commit to the latest state on each update", async () => {
+      const partyB = Keypair.generate();
+      const deposit = new anchor.BN(0.1 * LAMPORTS_PER_SOL);
+      const { channelId, channel } = await openChannel(partyB, deposit);
+
+      let account = await program.account.paymentChannel.fetch(channel);
+      expect(account.stateHash).to.deep.equal(
+        commitment(channelId, deposit, new anchor.BN(0), new anchor.BN(0))
+      );
+
+      for (const [toB, nonce] of [[1000, 1], [2500, 3]]) {
+        const balanceA = deposit.subn(toB);
+        const sig = await program.methods
+          .updateChannel(balanceA, new anchor.BN(toB), new anchor.BN(nonce))
+          .accounts({ channel, partyA: provider.wallet.publicKey, partyB: partyB.publicKey })
+          .signers([partyB])
+          .rpc({ commitment: "confirmed" });
+
+        const expected = commitment(channelId, balanceA, new anchor.BN(toB), new anchor.BN(nonce));
+        account = await program.account.paymentChannel.fetch(channel);
+        expect(account.stateHash).to.deep.equal(expected);
+
+        const [updated] = await eventsOf(sig);
+        expect(updated.name).to.equal("ChannelUpdated");
+        expect(updated.data.stateHash).to.deep.equal(expected);
+      }
+    });
+  });
+
+  describe("invoice_without_treasury", () => {
+    it("should pay an invoice whose recipient has no treasury", async () => {
+      const recipient = Keypair.generate();
+      await airdrop(recipient.publicKey);
+      const [treasury] = pda(Buffer.from("treasury"), recipient.publicKey.toBuffer());
+      const invoiceId = Keypair.generate().publicKey.toBuffer();
+      const [invoice] = pda(Buffer.from("invoice"), invoiceId);
+      const amount = new anchor.BN(0.01 * LAMPORTS_PER_SOL);
+
+      await program.methods
+        .createInvoice(
+          Array.from(invoiceId) as number[],
+          amount,
+          "Test",
+          new anchor.BN(Math.floor(Date.now() / 1000) + 3600),
+          null,
+          null
+        )
+        .accounts({
+          invoice,
+          treasury: null,
+          recipient: recipient.publicKey,
+          config: configPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([recipient])
+        .rpc();
+
+      const before = await provider.connection.getBalance(recipient.publicKey);
+      const sig = await program.methods
+        .payInvoice(null)
+        .accounts({
+          invoice,
+          treasury: null,
+          payer: provider.wallet.publicKey,
+          recipient: recipient.publicKey,
+          config: configPDA,
+          feeCollector: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc({ commitment: "confirmed" });
+
+      expect(await provider.connection.getBalance(recipient.publicKey)).to.equal(
+        before + amount.toNumber()
+      );
+      expect(await provider.connection.getAccountInfo(treasury)).to.be.null;
+      const paid = await program.account.invoice.fetch(invoice);
+      expect(paid.status).to.deep.equal({ paid: {} });
+
+      // Events still name the recipient's treasury address for filtering
+      const event = (await eventsOf(sig)).find((e) => e.name === "InvoicePaid");
+      expect(event.data.treasury.toBase58()).to.equal(treasury.toBase58());
+    });
+  });
+
+  describe("capability_availability", () => {
+    const setActive = (agent: ProviderAgent, capability: string, active: boolean) =>
+      program.methods
+        .setCapabilityActive(capability, active)
+        .accounts({ agentProfile: agent.profile, owner: agent.owner.publicKey })
+        .signers([agent.owner])
+        .rpc();
+
+    it("should reject requests only for the paused capability", async () => {
+      const agent = await registerProvider(["sentiment", "translation"]);
+      await setActive(agent, "sentiment", false);
+
+      const profile = await program.account.agentProfile.fetch(agent.profile);
+      expect(profile.isActive).to.be.true;
+      expect(profile.capabilityActive).to.deep.equal([false, true]);
+
+      try {
+        await requestService(agent, "sentiment");
+        expect.fail("expected CapabilityInactive");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("CapabilityInactive");
+      }
+      await requestService(agent, "translation");
+    });
+
+    it("should accept requests again once resumed", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      await setActive(agent, "sentiment", false);
+      await setActive(agent, "sentiment", true);
+
+      const { request } = await requestService(agent, "sentiment");
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.capability).to.equal("sentiment");
+    });
+
+    it("should reject toggling a capability the agent does not list", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      try {
+        await setActive(agent, "image-gen", false);
+        expect.fail("expected CapabilityNotSupported");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("CapabilityNotSupported");
+      }
+    });
+  });
+
+  describe("batch_escrow", () => {
+    const fundEscrow = async (agent: ProviderAgent, totalAmount: anchor.BN, claimWindowSeconds: number) => {
+      const batchId = Keypair.generate().publicKey.toBuffer();
+      const [escrowedBatch] = pda(Buffer.from("escrowed_batch"), batchId);
+      const [batchEscrow] = pda(Buffer.from("batch_escrow"), batchId);
+
+      await program.methods
+        .fundBatchEscrow(Array.from(batchId) as number[], totalAmount, new anchor.BN(claimWindowSeconds))
+        .accounts({
+          escrowedBatch,
+          batchEscrow,
+          treasury: agent.treasury,
+          settler: provider.wallet.publicKey,
+          recipient: agent.owner.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+      return { escrowedBatch, batchEscrow };
+    };
+
+    it("should let the recipient claim the escrow against its invoices", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const invoices = [
+        await createInvoice(agent.owner, agent.treasury),
+        await createInvoice(agent.owner, agent.treasury),
+      ];
+      const totalAmount = new anchor.BN(0.002 * LAMPORTS_PER_SOL);
+      const { escrowedBatch, batchEscrow } = await fundEscrow(agent, totalAmount, 3600);
+      expect(await provider.connection.getBalance(batchEscrow)).to.equal(totalAmount.toNumber());
+
+      const recipientBefore = await provider.connection.getBalance(agent.owner.publicKey);
+      await program.methods
+        .claimBatchEscrow(invoices.map(({ invoiceId }) => Array.from(invoiceId) as number[]))
+        .accounts({
+          escrowedBatch,
+          batchEscrow,
+          treasury: agent.treasury,
+          recipient: agent.owner.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .remainingAccounts(invoiceAccounts(invoices.map(({ invoice }) => invoice)))
+        .signers([agent.owner])
+        .rpc();
+
+      // The recipient pays only the transaction fee out of what it claims
+      const recipientAfter = await provider.connection.getBalance(agent.owner.publicKey);
+      expect(recipientAfter - recipientBefore).to.be.closeTo(totalAmount.toNumber(), 10_000);
+      expect(await provider.connection.getBalance(batchEscrow)).to.equal(0);
+
+      const escrowed = await program.account.escrowedBatch.fetch(escrowedBatch);
+      expect(escrowed.status).to.deep.equal({ claimed: {} });
+      const treasury = await program.account.treasury.fetch(agent.treasury);
+      expect(treasury.totalSettled.toNumber()).to.equal(totalAmount.toNumber());
+      expect(treasury.pendingInvoices.toNumber()).to.equal(0);
+      for (const { invoice } of invoices) {
+        const paid = await program.account.invoice.fetch(invoice);
+        expect(paid.status).to.deep.equal({ paid: {} });
+        expect(paid.payer.toBase58()).to.equal(provider.wallet.publicKey.toBase58());
+      }
+    });
+
+    it("should reject a claim whose invoices do not match the total", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { invoiceId, invoice } = await createInvoice(agent.owner, agent.treasury);
+      const { escrowedBatch, batchEscrow } = await fundEscrow(
+        agent,
+        new anchor.BN(0.002 * LAMPORTS_PER_SOL),
+        3600
+      );
+
+      try {
+        await program.methods
+          .claimBatchEscrow([Array.from(invoiceId) as number[]])
+          .accounts({
+            escrowedBatch,
+            batchEscrow,
+            treasury: agent.treasury,
+            recipient: agent.owner.publicKey,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .remainingAccounts(invoiceAccounts([invoice]))
+          .signers([agent.owner])
+          .rpc();
+        expect.fail("expected BatchTotalMismatch");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("BatchTotalMismatch");
+      }
+    });
+
+    it("should return unclaimed funds to the settler after the claim window", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const totalAmount = new anchor.BN(0.002 * LAMPORTS_PER_SOL);
+      const { escrowedBatch, batchEscrow } = await fundEscrow(agent, totalAmount, 1);
+      const reclaim = () =>
+        program.methods
+          .reclaimBatchEscrow()
+          .accounts({
+            escrowedBatch,
+            batchEscrow,
+            settler: provider.wallet.publicKey,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .rpc();
+
+      await sleep(2500);
+      await reclaim();
+
+      expect(await provider.connection.getBalance(batchEscrow)).to.equal(0);
+      const escrowed = await program.account.escrowedBatch.fetch(escrowedBatch);
+      expect(escrowed.status).to.deep.equal({ reclaimed: {} });
+
+      try {
+        await reclaim();
+        expect.fail("expected BatchEscrowNotFunded");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("BatchEscrowNotFunded");
+      }
+    });
+
+    it("should not let the settler reclaim while the window is open", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { escrowedBatch, batchEscrow } = await fundEscrow(
+        agent,
+        new anchor.BN(0.002 * LAMPORTS_PER_SOL),
+        3600
+      );
+
+      try {
+        await program.methods
+          .reclaimBatchEscrow()
+          .accounts({
+            escrowedBatch,
+            batchEscrow,
+            settler: provider.wallet.publicKey,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .rpc();
+        expect.fail("expected BatchClaimWindowOpen");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("BatchClaimWindowOpen");
+      }
+    });
+  });
+
+  describe("banned_agents", () => {
+    const setBanned = (agent: PublicKey, banned: boolean) =>
+      banned
+        ? program.methods
+            .banAgent(agent)
+            .accounts({
+              config: configPDA,
+              bannedAgents: bannedAgentsPDA,
+              admin: provider.wallet.publicKey,
+              systemProgram: anchor.web3.SystemProgram.programId,
+            })
+            .rpc({ commitment: "confirmed" })
+        : program.methods
+            .unbanAgent(agent)
+            .accounts({
+              config: configPDA,
+              bannedAgents: bannedAgentsPDA,
+              admin: provider.wallet.publicKey,
+            })
+            .rpc({ commitment: "confirmed" });
+
+    it("should reject requests to a banned provider until unbanned", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const sig = await setBanned(agent.owner.publicKey, true);
+
+      const list = await program.account.bannedAgents.fetch(bannedAgentsPDA);
+      expect(list.agents.map((a) => a.toBase58())).to.include(agent.owner.publicKey.toBase58());
+      const event = (await eventsOf(sig)).find((e) => e.name === "AgentBanStatusChanged");
+      expect(event?.data.banned).to.be.true;
+
+      try {
+        await requestService(agent, "sentiment");
+        expect.fail("expected ProviderBanned");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("ProviderBanned");
+      }
+
+      await setBanned(agent.owner.publicKey, false);
+      const { request } = await requestService(agent, "sentiment");
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.provider.toBase58()).to.equal(agent.owner.publicKey.toBase58());
+    });
+
+    it("should prevent a banned owner from registering", async () => {
+      const owner = Keypair.generate();
+      await airdrop(owner.publicKey);
+      const [treasury] = pda(Buffer.from("treasury"), owner.publicKey.toBuffer());
+      const [agentProfile] = pda(Buffer.from("agent"), owner.publicKey.toBuffer());
+      await setBanned(owner.publicKey, true);
+
+      try {
+        await program.methods
+          .registerAgentWithTreasury("Banned", "Banned agent", ["sentiment"], new anchor.BN(1000), [])
+          .accounts({
+            agentProfile,
+            treasury,
+            owner: owner.publicKey,
+            bannedAgents: bannedAgentsPDA,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .signers([owner])
+          .rpc();
+        expect.fail("expected AgentBanned");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("AgentBanned");
+      } finally {
+        await setBanned(owner.publicKey, false);
+      }
+    });
+
+    it("should reject unbanning an agent that is not banned", async () => {
+      try {
+        await setBanned(Keypair.generate().publicKey, false);
+        expect.fail("expected AgentNotBanned");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("AgentNotBanned");
+      }
+    });
+  });
+
+  describe("batch_amount_verification", () => {
+    const settle = (agent: ProviderAgent, invoices: { invoiceId: Buffer; invoice: PublicKey }[], total: anchor.BN) => {
+      const batchId = Keypair.generate().publicKey.toBuffer();
+      const [batch] = pda(Buffer.from("batch"), batchId);
+      return program.methods
+        .settleBatch(
+          Array.from(batchId) as number[],
+          invoices.map(({ invoiceId }) => Array.from(invoiceId) as number[]),
+          total
+        )
+        .accounts({
+          batch,
+          treasury: agent.treasury,
+          settler: provider.wallet.publicKey,
+          recipient: agent.owner.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .remainingAccounts(invoiceAccounts(invoices.map(({ invoice }) => invoice)))
+        .rpc();
+    };
+
+    it("should credit a batch whose total matches its invoices", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const invoices = [
+        await createInvoice(agent.owner, agent.treasury, new anchor.BN(3000)),
+        await createInvoice(agent.owner, agent.treasury, new anchor.BN(4000)),
+      ];
+
+      await settle(agent, invoices, new anchor.BN(7000));
+
+      const treasury = await program.account.treasury.fetch(agent.treasury);
+      expect(treasury.totalSettled.toNumber()).to.equal(7000);
+      expect(treasury.pendingInvoices.toNumber()).to.equal(0);
+    });
+
+    it("should reject a batch that overstates its total", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const invoices = [
+        await createInvoice(agent.owner, agent.treasury, new anchor.BN(3000)),
+        await createInvoice(agent.owner, agent.treasury, new anchor.BN(4000)),
+      ];
+
+      try {
+        await settle(agent, invoices, new anchor.BN(1_000_000));
+        expect.fail("expected BatchAmountMismatch");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("BatchAmountMismatch");
+      }
+
+      const treasury = await program.account.treasury.fetch(agent.treasury);
+      expect(treasury.totalSettled.toNumber()).to.equal(0);
+      expect(treasury.pendingInvoices.toNumber()).to.equal(2);
+      const invoice = await program.account.invoice.fetch(invoices[0].invoice);
+      expect(invoice.status).to.deep.equal({ pending: {} });
+    });
+  });
+
+  describe("refund_on_provider_deactivation", () => {
+    const refund = (agent: ProviderAgent, request: PublicKey, requestId: Buffer, escrow: PublicKey) =>
+      program.methods
+        .refundOnProviderDeactivation()
+        .accounts({
+          request,
+          dispute: pda(Buffer.from("dispute"), requestId)[0],
+          providerProfile: agent.profile,
+          escrow,
+          provider: agent.owner.publicKey,
+          requester: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+
+    const deactivate = (agent: ProviderAgent) =>
+      program.methods
+        .updateAgentProfile(null, null, null, null, false, null)
+        .accounts({ agentProfile: agent.profile, owner: agent.owner.publicKey })
+        .signers([agent.owner])
+        .rpc();
+
+    it("should refund a pending request right after the provider deactivates", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const amount = new anchor.BN(0.01 * LAMPORTS_PER_SOL);
+      const { requestId, request, escrow } = await requestService(agent, "sentiment", amount);
+      await deactivate(agent);
+
+      const escrowBefore = await provider.connection.getBalance(escrow);
+      await refund(agent, request, requestId, escrow);
+
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.status).to.deep.equal({ refunded: {} });
+      expect(await provider.connection.getBalance(escrow)).to.equal(escrowBefore - amount.toNumber());
+      const profile = await program.account.agentProfile.fetch(agent.profile);
+      expect(profile.openRequests).to.equal(0);
+    });
+
+    it("should reject the refund while the provider is active", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { requestId, request, escrow } = await requestService(agent, "sentiment");
+
+      try {
+        await refund(agent, request, requestId, escrow);
+        expect.fail("expected ProviderStillActive");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("ProviderStillActive");
+      }
+    });
+
+    it("should leave work accepted before the deactivation with the provider", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { requestId, request, escrow } = await requestService(agent, "sentiment");
+      const before = await program.account.agentProfile.fetch(agent.profile);
+      await sleep(1000);
+      await acceptRequest(agent, request, escrow);
+      const accepted = await program.account.agentProfile.fetch(agent.profile);
+      expect(accepted.lastActiveAt.toNumber()).to.be.greaterThan(before.lastActiveAt.toNumber());
+
+      await deactivate(agent);
+      expect((await program.account.agentProfile.fetch(agent.profile)).deactivatedAt).to.not.be.null;
+
+      try {
+        await refund(agent, request, requestId, escrow);
+        expect.fail("expected AcceptedBeforeDeactivation");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("AcceptedBeforeDeactivation");
+      }
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.status).to.deep.equal({ inProgress: {} });
+    });
+  });
+
+  describe("compact_events", () => {
+    // Set when the program under test was built with
+    // `AGENTFUND_FEATURES=compact-events anchor test -- --features compact-events`
+    const compactEvents = (process.env.AGENTFUND_FEATURES ?? "").split(",").includes("compact-events");
+
+    const createInvoiceLogged = async (agent: ProviderAgent, amount: anchor.BN) => {
+      const invoiceId = Keypair.generate().publicKey.toBuffer();
+      const [invoice] = pda(Buffer.from("invoice"), invoiceId);
+      const sig = await program.methods
+        .createInvoice(
+          Array.from(invoiceId) as number[],
+          amount,
+          "Test",
+          new anchor.BN(Math.floor(Date.now() / 1000) + 3600),
+          null,
+          null
+        )
+        .accounts({
+          invoice,
+          treasury: agent.treasury,
+          recipient: agent.owner.publicKey,
+          config: configPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([agent.owner])
+        .rpc({ commitment: "confirmed" });
+      return { invoiceId, invoice, sig };
+    };
+
+    // Encodes an event the way `emit!` logs it: discriminator then borsh fields
+    const compactLog = (name: string, invoiceId: Buffer, amount: anchor.BN) =>
+      Buffer.concat([
+        createHash("sha256").update(`event:${name}`).digest().subarray(0, 8),
+        invoiceId,
+        amount.toArrayLike(Buffer, "le", 8),
+      ]).toString("base64");
+
+    for (const name of ["InvoiceCreatedCompact", "InvoicePaidCompact"]) {
+      it(`should decode ${name} with only the id and amount`, () => {
+        const fields = program.idl.events.find((e) => e.name === name).fields.map((f) => f.name);
+        expect(fields).to.deep.equal(["invoiceId", "amount"]);
+
+        const invoiceId = Keypair.generate().publicKey.toBuffer();
+        const event = program.coder.events.decode(compactLog(name, invoiceId, new anchor.BN(12_345)));
+        expect(event.name).to.equal(name);
+        expect(Buffer.from(event.data.invoiceId as number[]).equals(invoiceId)).to.be.true;
+        expect((event.data.amount as anchor.BN).toNumber()).to.equal(12_345);
+      });
+    }
+
+    (compactEvents ? it.skip : it)("should emit the full invoice events by default", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { sig } = await createInvoiceLogged(agent, new anchor.BN(1000));
+
+      const names = (await eventsOf(sig)).map((e) => e.name);
+      expect(names).to.include("InvoiceCreated");
+      expect(names).to.not.include("InvoiceCreatedCompact");
+    });
+
+    (compactEvents ? it : it.skip)("should emit only the compact invoice events", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const amount = new anchor.BN(0.001 * LAMPORTS_PER_SOL);
+      const { invoiceId, invoice, sig: createSig } = await createInvoiceLogged(agent, amount);
+      const paySig = await program.methods
+        .payInvoice(null)
+        .accounts({
+          invoice,
+          treasury: agent.treasury,
+          payer: provider.wallet.publicKey,
+          recipient: agent.owner.publicKey,
+          config: configPDA,
+          feeCollector: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc({ commitment: "confirmed" });
+
+      for (const [sig, compact, full] of [
+        [createSig, "InvoiceCreatedCompact", "InvoiceCreated"],
+        [paySig, "InvoicePaidCompact", "InvoicePaid"],
+      ]) {
+        const events = await eventsOf(sig);
+        expect(events.map((e) => e.name)).to.not.include(full);
+        const event = events.find((e) => e.name === compact);
+        expect(Buffer.from(event.data.invoiceId as number[]).equals(invoiceId)).to.be.true;
+        expect((event.data.amount as anchor.BN).toNumber()).to.equal(amount.toNumber());
+      }
+    });
+  });
+
+  describe("accepting_requests", () => {
+    const setAccepting = (agent: ProviderAgent, accepting: boolean) =>
+      program.methods
+        .setAcceptingRequests(accepting)
+        .accounts({ agentProfile: agent.profile, owner: agent.owner.publicKey })
+        .signers([agent.owner])
+        .rpc();
+
+    it("should reject new requests while paused but keep the agent listed", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      await setAccepting(agent, false);
+
+      const profile = await program.account.agentProfile.fetch(agent.profile);
+      expect(profile.isActive).to.be.true;
+      expect(profile.acceptingRequests).to.be.false;
+
+      try {
+        await requestService(agent, "sentiment");
+        expect.fail("expected AgentNotAcceptingRequests");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("AgentNotAcceptingRequests");
+      }
+
+      await setAccepting(agent, true);
+      await requestService(agent, "sentiment");
+    });
+
+    it("should let requests opened before the pause complete", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { request, escrow } = await requestService(agent, "sentiment");
+      await setAccepting(agent, false);
+
+      await completeService(agent, request);
+      await finalizeCompletion(agent, request, escrow);
+
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.status).to.deep.equal({ completed: {} });
+    });
+  });
+
+  describe("duplicate_capabilities", () => {
+    const registerWith = async (capabilities: string[]) => {
+      const owner = Keypair.generate();
+      await airdrop(owner.publicKey);
+      const [treasury] = pda(Buffer.from("treasury"), owner.publicKey.toBuffer());
+      const [agentProfile] = pda(Buffer.from("agent"), owner.publicKey.toBuffer());
+      await program.methods
+        .registerAgentWithTreasury("Agent", "Capability check", capabilities, new anchor.BN(1000), [])
+        .accounts({
+          agentProfile,
+          treasury,
+          owner: owner.publicKey,
+          bannedAgents: bannedAgentsPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([owner])
+        .rpc();
+      return agentProfile;
+    };
+
+    it("should reject registering a capability twice, ignoring case", async () => {
+      try {
+        await registerWith(["sentiment", "translation", "Sentiment"]);
+        expect.fail("expected DuplicateCapability");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("DuplicateCapability");
+      }
+    });
+
+    it("should accept a list without duplicates", async () => {
+      const profile = await registerWith(["sentiment", "Translation"]);
+      const account = await program.account.agentProfile.fetch(profile);
+      expect(account.capabilities).to.deep.equal(["sentiment", "translation"]);
+    });
+
+    it("should reject duplicates when updating capabilities", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      try {
+        await program.methods
+          .updateAgentProfile(null, null, ["summarize", "SUMMARIZE"], null, null, null)
+          .accounts({ agentProfile: agent.profile, owner: agent.owner.publicKey })
+          .signers([agent.owner])
+          .rpc();
+        expect.fail("expected DuplicateCapability");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("DuplicateCapability");
+      }
+    });
+  });
+
+  describe("split_invoices", () => {
+    const createSplitInvoice = async (
+      agent: ProviderAgent,
+      amount: anchor.BN,
+      splits: { recipient: PublicKey; bps: number }[]
+    ) => {
+      const invoiceId = Keypair.generate().publicKey.toBuffer();
+      const [invoice] = pda(Buffer.from("invoice"), invoiceId);
+      await program.methods
+        .createSplitInvoice(
+          Array.from(invoiceId) as number[],
+          amount,
+          "Split",
+          new anchor.BN(Math.floor(Date.now() / 1000) + 3600),
+          splits
+        )
+        .accounts({
+          invoice,
+          treasury: agent.treasury,
+          recipient: agent.owner.publicKey,
+          config: configPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([agent.owner])
+        .rpc();
+      return invoice;
+    };
+
+    it("should distribute a payment three ways by basis points", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const collaborators = [Keypair.generate(), Keypair.generate()];
+      for (const collaborator of collaborators) {
+        await airdrop(collaborator.publicKey);
+      }
+      const recipients = [agent.owner.publicKey, ...collaborators.map((c) => c.publicKey)];
+      const bps = [5000, 3000, 2000];
+      const amount = new anchor.BN(0.1 * LAMPORTS_PER_SOL);
+      const invoice = await createSplitInvoice(
+        agent,
+        amount,
+        recipients.map((recipient, i) => ({ recipient, bps: bps[i] }))
+      );
+
+      const before = await Promise.all(recipients.map((r) => provider.connection.getBalance(r)));
+      const sig = await program.methods
+        .payInvoice(null)
+        .accounts({
+          invoice,
+          treasury: agent.treasury,
+          payer: provider.wallet.publicKey,
+          recipient: agent.owner.publicKey,
+          config: configPDA,
+          feeCollector: provider.wallet.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .remainingAccounts(recipients.map((pubkey) => ({ pubkey, isWritable: true, isSigner: false })))
+        .rpc({ commitment: "confirmed" });
+
+      const events = await eventsOf(sig);
+      const fee = events.find((e) => e.name === "InvoicePaid").data.fee.toNumber();
+      const net = amount.toNumber() - fee;
+      const expected = [Math.floor((net * 5000) / 10_000), Math.floor((net * 3000) / 10_000)];
+      expected.push(net - expected[0] - expected[1]);
+
+      const after = await Promise.all(recipients.map((r) => provider.connection.getBalance(r)));
+      expect(after.map((balance, i) => balance - before[i])).to.deep.equal(expected);
+      const shares = events.filter((e) => e.name === "InvoiceSplitPaid").map((e) => e.data.amount.toNumber());
+      expect(shares).to.deep.equal(expected);
+
+      const treasury = await program.account.treasury.fetch(agent.treasury);
+      expect(treasury.totalReceived.toNumber()).to.equal(expected[0]);
+    });
+
+    it("should reject shares that do not add up to 10000 bps", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      try {
+        await createSplitInvoice(agent, new anchor.BN(0.1 * LAMPORTS_PER_SOL), [
+          { recipient: agent.owner.publicKey, bps: 5000 },
+          { recipient: Keypair.generate().publicKey, bps: 4000 },
+        ]);
+        expect.fail("expected SplitBpsMismatch");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("SplitBpsMismatch");
+      }
+    });
+  });
+
+  describe("stale_agents", () => {
+    const setStalenessThreshold = (seconds: number) =>
+      program.methods
+        .updateProtocolConfig({ stalenessThresholdSeconds: new anchor.BN(seconds) } as any)
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+
+    const markStale = (agent: ProviderAgent) =>
+      program.methods
+        .markStaleAgent()
+        .accounts({ agentProfile: agent.profile, config: configPDA })
+        .rpc({ commitment: "confirmed" });
+
+    before(async () => {
+      await setStalenessThreshold(3);
+    });
+
+    after(async () => {
+      await setStalenessThreshold(90 * 24 * 60 * 60);
+    });
+
+    it("should delist an agent inactive beyond the threshold", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      await sleep(5000);
+
+      const sig = await markStale(agent);
+
+      const profile = await program.account.agentProfile.fetch(agent.profile);
+      expect(profile.isActive).to.be.false;
+      const event = (await eventsOf(sig)).find((e) => e.name === "AgentMarkedStale");
+      expect(event.data.agent.toBase58()).to.equal(agent.owner.publicKey.toBase58());
+      expect(event.data.lastActiveAt.toNumber()).to.equal(profile.lastActiveAt.toNumber());
+    });
+
+    it("should reject marking a recently active agent", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      try {
+        await markStale(agent);
+        expect.fail("expected AgentNotStale");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("AgentNotStale");
+      }
+
+      const profile = await program.account.agentProfile.fetch(agent.profile);
+      expect(profile.isActive).to.be.true;
+    });
+  });
+
+  describe("escrow_funded", () => {
+    it("should confirm the lamports the escrow received", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const amount = new anchor.BN(0.02 * LAMPORTS_PER_SOL);
+      const requestId = Keypair.generate().publicKey.toBuffer();
+      const [request] = pda(Buffer.from("request"), requestId);
+      const [escrow] = pda(Buffer.from("request_escrow"), requestId);
+      const escrowBefore = await provider.connection.getBalance(escrow);
+
+      const sig = await program.methods
+        .requestService(Array.from(requestId) as number[], "sentiment", amount, null, null, null)
+        .accounts({
+          request,
+          providerProfile: agent.profile,
+          providerOwner: agent.owner.publicKey,
+          providerTreasury: agent.treasury,
+          escrow,
+          requester: provider.wallet.publicKey,
+          oracle: null,
+          bannedAgents: bannedAgentsPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc({ commitment: "confirmed" });
+
+      const escrowAfter = await provider.connection.getBalance(escrow, "confirmed");
+      expect(escrowAfter - escrowBefore).to.equal(amount.toNumber());
+
+      const event = (await eventsOf(sig)).find((e) => e.name === "EscrowFunded");
+      expect(Buffer.from(event.data.requestId).equals(requestId)).to.be.true;
+      expect(event.data.escrow.toBase58()).to.equal(escrow.toBase58());
+      expect(event.data.amount.toNumber()).to.equal(amount.toNumber());
+    });
+  });
+
+  describe("dispute_review_extension", () => {
+    const setReviewSeconds = (seconds: number) =>
+      program.methods
+        .updateProtocolConfig({ disputeReviewSeconds: new anchor.BN(seconds) } as any)
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+
+    const extend = (request: PublicKey, dispute: PublicKey, seconds: number) =>
+      program.methods
+        .extendDisputeReview(new anchor.BN(seconds))
+        .accounts({ request, dispute, config: configPDA, party: provider.wallet.publicKey })
+        .rpc();
+
+    const expectCodeOf = async (action: Promise<unknown>, code: string) => {
+      try {
+        await action;
+        expect.fail(`expected ${code}`);
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal(code);
+      }
+    };
+
+    before(async () => {
+      await setReviewSeconds(2);
+    });
+
+    after(async () => {
+      await setReviewSeconds(7 * 24 * 60 * 60);
+    });
+
+    it("should push the stored review deadline back by the extension", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { requestId, request } = await requestService(agent, "sentiment");
+      const dispute = await initiateDispute(agent, request, requestId);
+      const opened = await program.account.dispute.fetch(dispute);
+      expect(opened.reviewDeadline.toNumber()).to.equal(opened.createdAt.toNumber() + 2);
+
+      await extend(request, dispute, 5);
+      const extended = await program.account.dispute.fetch(dispute);
+      expect(extended.reviewDeadline.toNumber()).to.equal(opened.reviewDeadline.toNumber() + 5);
+      expect(extended.status).to.deep.equal({ open: {} });
+    });
+
+    it("should reject extending beyond the review cap", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { requestId, request } = await requestService(agent, "sentiment");
+      const dispute = await initiateDispute(agent, request, requestId);
+
+      await expectCodeOf(extend(request, dispute, 31 * 24 * 60 * 60), "InvalidDisputeReview");
+    });
+  });
+
+  describe("request_metadata_uri", () => {
+    const openRequest = (agent: ProviderAgent, requestId: Buffer, metadataUri: string | null) =>
+      program.methods
+        .requestService(
+          Array.from(requestId) as number[],
+          "sentiment",
+          new anchor.BN(0.01 * LAMPORTS_PER_SOL),
+          null,
+          null,
+          metadataUri
+        )
+        .accounts({
+          request: pda(Buffer.from("request"), requestId)[0],
+          providerProfile: agent.profile,
+          providerOwner: agent.owner.publicKey,
+          providerTreasury: agent.treasury,
+          escrow: pda(Buffer.from("request_escrow"), requestId)[0],
+          requester: provider.wallet.publicKey,
+          oracle: null,
+          bannedAgents: bannedAgentsPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc({ commitment: "confirmed" });
+
+    it("should store the metadata URI and include it in ServiceRequested", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const requestId = Keypair.generate().publicKey.toBuffer();
+      const uri = "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi";
+
+      const sig = await openRequest(agent, requestId, uri);
+
+      const account = await program.account.serviceRequest.fetch(pda(Buffer.from("request"), requestId)[0]);
+      expect(account.metadataUri).to.equal(uri);
+
+      const event = (await eventsOf(sig)).find((e) => e.name === "ServiceRequested");
+      expect(event.data.metadataUri).to.equal(uri);
+    });
+
+    it("should leave the metadata URI empty when none is given", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const { request } = await requestService(agent, "sentiment");
+
+      const account = await program.account.serviceRequest.fetch(request);
+      expect(account.metadataUri).to.be.null;
+    });
+
+    it("should reject a metadata URI over the length cap", async () => {
+      const agent = await registerProvider(["sentiment"]);
+      const requestId = Keypair.generate().publicKey.toBuffer();
+
+      try {
+        await openRequest(agent, requestId, "https://" + "a".repeat(200));
+        expect.fail("expected MetadataUriTooLong");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("MetadataUriTooLong");
+      }
+    });
+  });
+
+  describe("open_channel_dual", () => {
+    const openDual = async (depositA: number, depositB: number) => {
+      const partyB = Keypair.generate();
+      await airdrop(partyB.publicKey);
+      const channelId = Keypair.generate().publicKey.toBuffer();
+      const [channel] = pda(Buffer.from("channel"), channelId);
+      const [channelEscrow] = pda(Buffer.from("escrow"), channelId);
+
+      const sig = await program.methods
+        .openChannelDual(Array.from(channelId) as number[], new anchor.BN(depositA), new anchor.BN(depositB))
+        .accounts({
+          channel,
+          channelEscrow,
+          partyA: provider.wallet.publicKey,
+          partyB: partyB.publicKey,
+          config: configPDA,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([partyB])
+        .rpc({ commitment: "confirmed" });
+
+      return { channel, channelEscrow, partyB, sig };
+    };
+
+    it("should open an active channel holding both deposits", async () => {
+      const depositA = 0.03 * LAMPORTS_PER_SOL;
+      const depositB = 0.02 * LAMPORTS_PER_SOL;
+      const { channel, channelEscrow, partyB, sig } = await openDual(depositA, depositB);
+
+      expect(await provider.connection.getBalance(channelEscrow)).to.equal(depositA + depositB);
+
+      // A single open event reports the whole escrow
+      const events = await eventsOf(sig);
+      const names = events.map((e) => e.name);
+      expect(names).to.not.include("ChannelAccepted");
+      expect(names).to.not.include("ChannelFunded");
+      const opened = events.find((e) => e.name === "ChannelOpened");
+      expect(opened.data.deposit.toNumber()).to.equal(depositA + depositB);
+
+      const account = await program.account.paymentChannel.fetch(channel);
+      expect(account.partyB.toBase58()).to.equal(partyB.publicKey.toBase58());
+      expect(account.status).to.deep.equal({ open: {} });
+      expect(account.depositA.toNumber()).to.equal(depositA);
+      expect(account.depositB.toNumber()).to.equal(depositB);
+      expect(account.balanceA.toNumber()).to.equal(depositA);
+      expect(account.balanceB.toNumber()).to.equal(depositB);
+    });
+
+    it("should reject a deposit below the channel minimum", async () => {
+      const floor = 0.05 * LAMPORTS_PER_SOL;
+      await program.methods
+        .updateProtocolConfig({ minChannelDeposit: new anchor.BN(floor) } as any)
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+
+      try {
+        await openDual(floor, floor - 1);
+        expect.fail("expected DepositBelowMinimum");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("DepositBelowMinimum");
+      } finally {
+        await program.methods
+          .updateProtocolConfig({ minChannelDeposit: new anchor.BN(0) } as any)
+          .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+          .rpc();
+      }
+    });
+  });
+
+  describe("channel_close_counterparty_signature", () => {
+    const initiate = (channel: PublicKey, balanceA: anchor.BN, balanceB: anchor.BN, nonce: number, signature?: any) =>
+      program.methods
+        .initiateChannelClose(balanceA, balanceB, new anchor.BN(nonce))
+        .accounts({
+          channel,
+          party: provider.wallet.publicKey,
+          instructions: anchor.web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+        })
+        .preInstructions(signature ? [signature] : [])
+        .rpc();
+
+    it("should reject a new state the counterparty did not sign", async () => {
+      const partyB = Keypair.generate();
+      const deposit = new anchor.BN(0.1 * LAMPORTS_PER_SOL);
+      const { channelId, channel } = await openChannel(partyB, deposit);
+      const partyA = (provider.wallet as anchor.Wallet).payer;
+
+      for (const signature of [
+        undefined,
+        // Party A signing its own favourable split
+        signChannelState(partyA, channelId, deposit, new anchor.BN(0), new anchor.BN(1)),
+        // Party B's signature over a different split
+        signChannelState(partyB, channelId, deposit.subn(5000), new anchor.BN(5000), new anchor.BN(1)),
+      ]) {
+        try {
+          await initiate(channel, deposit, new anchor.BN(0), 1, signature);
+          expect.fail("expected InvalidCounterpartySignature");
+        } catch (err: any) {
+          expect(err.error.errorCode.code).to.equal("InvalidCounterpartySignature");
+        }
+      }
+
+      const account = await program.account.paymentChannel.fetch(channel);
+      expect(account.status).to.deep.equal({ open: {} });
+    });
+
+    it("should reject a challenge the counterparty did not sign", async () => {
+      const partyB = Keypair.generate();
+      await airdrop(partyB.publicKey);
+      const deposit = new anchor.BN(0.1 * LAMPORTS_PER_SOL);
+      const { channel } = await openChannel(partyB, deposit);
+
+      // Closing on the recorded state needs no extra signature
+      await initiate(channel, deposit, new anchor.BN(0), 0);
+
+      try {
+        await program.methods
+          .challengeChannelClose(new anchor.BN(0), deposit, new anchor.BN(1))
+          .accounts({
+            channel,
+            party: partyB.publicKey,
+            instructions: anchor.web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+          })
+          .signers([partyB])
+          .rpc();
+        expect.fail("expected InvalidCounterpartySignature");
+      } catch (err: any) {
+        expect(err.error.errorCode.code).to.equal("InvalidCounterpartySignature");
+      }
+
+      const account = await program.account.paymentChannel.fetch(channel);
+      expect(account.status).to.deep.equal({ closing: {} });
+      expect(account.balanceA.toString()).to.equal(deposit.toString());
+    });
+  });
+
+  describe("protocol_config_authority", () => {
+    it("should only let the upgrade authority create the config", async () => {
+      const intruder = Keypair.generate();
+      await airdrop(intruder.publicKey);
+
+      try {
+        await program.methods
+          .initializeProtocolConfig(intruder.publicKey, 0)
+          .accounts({
+            config: configPDA,
+            admin: intruder.publicKey,
+            program: program.programId,
+            programData: programDataPDA,
+            systemProgram: anchor.web3.SystemProgram.programId,
+          })
+          .signers([intruder])
+          .rpc();
+        expect.fail("expected the initialization to be rejected");
+      } catch (err: any) {
+        expect(err.message).to.not.equal("expected the initialization to be rejected");
+      }
+
+      const config = await program.account.protocolConfig.fetch(configPDA);
+      expect(config.admin.toBase58()).to.equal(provider.wallet.publicKey.toBase58());
+      expect(config.arbiter.toBase58()).to.equal(arbiter.publicKey.toBase58());
     });
   });
 });